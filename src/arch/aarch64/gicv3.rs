@@ -0,0 +1,933 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use tock_registers::interfaces::*;
+use tock_registers::registers::*;
+use tock_registers::*;
+
+use crate::arch::INTERRUPT_NUM_MAX;
+use crate::board::{static_config, PlatOperation, Platform, PLAT_DESC};
+use crate::kernel::current_cpu;
+use crate::util::{bit_extract, device_ref::DeviceRef};
+
+// GICv3 with affinity routing and the system register cpu interface. It offers the same interface as the GICv2
+// driver in gic.rs, so that the vgic works on both. The list registers are converted from and to the GICv2
+// GICH_LR layout by `GicHypervisorInterface`.
+
+// GICD BITS
+const GICD_CTLR_ENABLE_G1_BIT: usize = 1 << 0;
+const GICD_CTLR_ENABLE_G1A_BIT: usize = 1 << 1;
+const GICD_CTLR_ARE_NS_BIT: usize = 1 << 4;
+const GICD_CTLR_RWP_BIT: usize = 1 << 31;
+
+// GICR BITS
+const GICR_CTLR_ENABLE_LPIS_BIT: usize = 1 << 0;
+const GICR_CTLR_RWP_BIT: usize = 1 << 3;
+const GICR_WAKER_PROCESSORSLEEP_BIT: usize = 1 << 1;
+const GICR_WAKER_CHILDRENASLEEP_BIT: usize = 1 << 2;
+pub const GICR_TYPER_PLPIS_BIT: usize = 1 << 0;
+const GICR_TYPER_VLPIS_BIT: usize = 1 << 1;
+pub const GICR_TYPER_LAST_BIT: usize = 1 << 4;
+
+// ICC BITS, there is no enable bit in ICC_CTLR_EL1, group 1 is enabled by ICC_IGRPEN1_EL1
+pub const GICC_CTLR_EN_BIT: usize = 0;
+pub const GICC_CTLR_EOIMODENS_BIT: usize = 1 << 1;
+const ICC_SRE_SRE_BIT: usize = 1 << 0;
+const ICC_SRE_ENABLE_BIT: usize = 1 << 3;
+
+// GICH BITS
+const GICH_HCR_LRENPIE_BIT: usize = 1 << 2;
+
+pub const GIC_SGIS_NUM: usize = 16;
+const GIC_PPIS_NUM: usize = 16;
+pub const GIC_INTS_MAX: usize = INTERRUPT_NUM_MAX;
+pub const GIC_PRIVINT_NUM: usize = GIC_SGIS_NUM + GIC_PPIS_NUM;
+pub const GIC_SPI_MAX: usize = INTERRUPT_NUM_MAX - GIC_PRIVINT_NUM;
+pub const GIC_PRIO_BITS: usize = 8;
+pub const GIC_TARGET_BITS: usize = 8;
+pub const GIC_TARGETS_MAX: usize = GIC_TARGET_BITS;
+pub const GIC_CONFIG_BITS: usize = 2;
+// interrupt ids from 8192 are LPIs, which are only delivered by the ITS
+pub const GIC_LPI_BASE: usize = 8192;
+
+const GIC_INT_REGS_NUM: usize = GIC_INTS_MAX / 32;
+const GIC_PRIO_REGS_NUM: usize = GIC_INTS_MAX * 8 / 32;
+const GIC_CONFIG_REGS_NUM: usize = GIC_INTS_MAX * 2 / 32;
+const GIC_PRIV_PRIO_REGS_NUM: usize = GIC_PRIVINT_NUM * 8 / 32;
+const GIC_PRIV_CONFIG_REGS_NUM: usize = GIC_PRIVINT_NUM * 2 / 32;
+
+// ICH_LR<n>_EL2
+pub const GIC_LIST_REGS_NUM: usize = 16;
+
+pub const GICD_TYPER_CPUNUM_OFF: u32 = 5;
+pub const GICD_TYPER_CPUNUM_LEN: u32 = 3;
+pub const GICD_TYPER_CPUNUM_MSK: u32 = bit_mask!(GICD_TYPER_CPUNUM_OFF, GICD_TYPER_CPUNUM_LEN);
+pub const GICD_TYPER_ITLINESNUM_MSK: u32 = 0b11111;
+pub const GICD_TYPER_IDBITS_OFF: u32 = 19;
+
+pub const GICD_TYPER_LPIS_BIT: u32 = 1 << 17;
+
+// the virtual distributor has 10 bits interrupt ids, or 16 bits with LPIs if the VM has a virtual ITS
+pub const VGICD_TYPER_IDBITS: u32 = 9 << GICD_TYPER_IDBITS_OFF;
+pub const VGICD_TYPER_LPI_IDBITS: u32 = 15 << GICD_TYPER_IDBITS_OFF;
+pub const VGICD_CTLR_ENABLE_MSK: u32 = (GICD_CTLR_ENABLE_G1_BIT | GICD_CTLR_ENABLE_G1A_BIT) as u32;
+pub const VGICD_CTLR_ARE_NS: u32 = GICD_CTLR_ARE_NS_BIT as u32;
+pub const GICD_IROUTER_OFFSET: usize = 0x6000;
+pub const GICD_IROUTER_IRM_BIT: usize = 1 << 31;
+pub const GIC_PIDR2_OFFSET: usize = 0xffe8;
+pub const GIC_PIDR2_ARCH_GICV3: u32 = 0x3 << 4;
+pub const GICR_FRAME_SIZE: usize = 0x20000;
+pub const GICR_SGI_OFFSET: usize = 0x10000;
+
+static GIC_LRS_NUM: AtomicUsize = AtomicUsize::new(0);
+
+static GICD_LOCK: Mutex<()> = Mutex::new(());
+
+// address of the redistributor of each core, found by gic_cpu_init
+static GICR_LIST: [AtomicUsize; static_config::CORE_NUM] = [const { AtomicUsize::new(0) }; static_config::CORE_NUM];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IrqState {
+    Inactive = 0b00,
+    Pend = 0b01,
+    Active = 0b10,
+    PendActive = 0b11,
+}
+
+impl From<u32> for IrqState {
+    fn from(num: u32) -> Self {
+        match num & 0b11 {
+            0b00 => IrqState::Inactive,
+            0b01 => IrqState::Pend,
+            0b10 => IrqState::Active,
+            _ => IrqState::PendActive,
+        }
+    }
+}
+
+impl IrqState {
+    pub fn is_active(self) -> bool {
+        matches!(self, IrqState::Active | IrqState::PendActive)
+    }
+
+    pub fn is_pend(self) -> bool {
+        matches!(self, IrqState::Pend | IrqState::PendActive)
+    }
+
+    pub fn add_pend(self) -> Self {
+        match self {
+            IrqState::Inactive | IrqState::Pend => IrqState::Pend,
+            IrqState::Active | IrqState::PendActive => IrqState::PendActive,
+        }
+    }
+
+    pub fn clear_pend(self) -> Self {
+        match self {
+            IrqState::Inactive | IrqState::Active => self,
+            IrqState::Pend => IrqState::Inactive,
+            IrqState::PendActive => IrqState::Active,
+        }
+    }
+
+    pub fn add_active(self) -> Self {
+        match self {
+            IrqState::Inactive => IrqState::Active,
+            IrqState::Pend => IrqState::PendActive,
+            IrqState::Active | IrqState::PendActive => self,
+        }
+    }
+
+    pub fn clear_active(self) -> Self {
+        match self {
+            IrqState::Inactive | IrqState::Pend => self,
+            IrqState::Active => IrqState::Inactive,
+            IrqState::PendActive => IrqState::Pend,
+        }
+    }
+}
+
+pub struct GicDesc {
+    pub gicd_addr: usize,
+    pub gicc_addr: usize,
+    pub gich_addr: usize,
+    pub gicv_addr: usize,
+    pub maintenance_int_id: usize,
+}
+
+/* Affinity of a MPIDR in the layout of GICD_IROUTER and ICC_SGI1R_EL1.
+ *
+ * @param[in] mpidr: value of MPIDR_EL1 or VMPIDR_EL2.
+ */
+pub fn gic_mpidr_to_affinity(mpidr: usize) -> usize {
+    mpidr & 0xff_00ff_ffff
+}
+
+// Aff3.Aff2.Aff1.Aff0 packed in 32 bits, the layout of GICR_TYPER[63:32]
+pub fn gic_mpidr_to_typer_affinity(mpidr: usize) -> usize {
+    (bit_extract(mpidr, 32, 8) << 24) | bit_extract(mpidr, 0, 24)
+}
+
+fn gic_cpu_affinity(cpu_id: usize) -> usize {
+    gic_mpidr_to_affinity(PLAT_DESC.cpu_desc.core_list[cpu_id].mpidr)
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub GicDistributor {
+        (0x0000 => CTLR: ReadWrite<u32>),   // Distributor Control Register
+        (0x0004 => TYPER: ReadOnly<u32>),   // Interrupt Controller Type Register
+        (0x0008 => IIDR: ReadOnly<u32>),    // Distributor Implementer Identification Register
+        (0x000c => reserve0),
+        (0x0080 => IGROUPR: [ReadWrite<u32>; GIC_INT_REGS_NUM]),    // Interrupt Group Registers
+        (0x0100 => ISENABLER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),  // Interrupt Set-Enable Registers
+        (0x0180 => ICENABLER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),  // Interrupt Clear-Enable Registers
+        (0x0200 => ISPENDR: [ReadWrite<u32>; GIC_INT_REGS_NUM]),    // Interrupt Set-Pending Registers
+        (0x0280 => ICPENDR: [ReadWrite<u32>; GIC_INT_REGS_NUM]),    // Interrupt Clear-Pending Registers
+        (0x0300 => ISACTIVER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),  // Interrupt Set-Active Registers
+        (0x0380 => ICACTIVER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),  // Interrupt Clear-Active Registers
+        (0x0400 => IPRIORITYR: [ReadWrite<u32>; GIC_PRIO_REGS_NUM]),    // Interrupt Priority Registers
+        (0x0800 => reserve1),
+        (0x0c00 => ICFGR: [ReadWrite<u32>; GIC_CONFIG_REGS_NUM]),   // Interrupt Configuration Registers
+        (0x0d00 => IGRPMODR: [ReadWrite<u32>; GIC_INT_REGS_NUM]),   // Interrupt Group Modifier Registers
+        (0x0d80 => reserve2),
+        (0x6000 => IROUTER: [ReadWrite<u64>; GIC_INTS_MAX]),        // Interrupt Routing Registers
+        (0x8000 => reserve3),
+        (0xffe8 => PIDR2: ReadOnly<u32>),   // Peripheral ID2 Register
+        (0xffec => reserve4),
+        (0x10000 => @END),
+    }
+}
+
+unsafe impl Sync for GicDistributor {}
+
+impl GicDistributor {
+    pub fn ctlr(&self) -> u32 {
+        self.CTLR.get()
+    }
+
+    fn wait_rwp(&self) {
+        while self.CTLR.get() & GICD_CTLR_RWP_BIT as u32 != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn global_init(&self) {
+        let int_num = gic_max_spi();
+
+        self.CTLR.set(0);
+        self.wait_rwp();
+
+        for i in GIC_PRIVINT_NUM / 32..int_num / 32 {
+            self.ICENABLER[i].set(u32::MAX);
+            self.ICPENDR[i].set(u32::MAX);
+            self.ICACTIVER[i].set(u32::MAX);
+            // non-secure group 1
+            self.IGROUPR[i].set(u32::MAX);
+            self.IGRPMODR[i].set(0);
+        }
+        self.wait_rwp();
+
+        for i in GIC_PRIVINT_NUM / 4..int_num * 8 / 32 {
+            self.IPRIORITYR[i].set(u32::MAX);
+        }
+
+        let affinity = gic_cpu_affinity(current_cpu().id);
+        for i in GIC_PRIVINT_NUM..int_num {
+            self.IROUTER[i].set(affinity as u64);
+        }
+
+        self.CTLR
+            .set((GICD_CTLR_ARE_NS_BIT | GICD_CTLR_ENABLE_G1A_BIT | GICD_CTLR_ENABLE_G1_BIT) as u32);
+        self.wait_rwp();
+    }
+
+    pub fn send_sgi(&self, cpu_if: usize, sgi_num: usize) {
+        let affinity = gic_cpu_affinity(Platform::cpuif_to_cpuid(cpu_if));
+        let aff0 = bit_extract(affinity, 0, 8);
+        // Aff3 [55:48], Aff2 [39:32], Aff1 [23:16], INTID [27:24], RS [47:44], TargetList [15:0]
+        let sgi1r = (bit_extract(affinity, 32, 8) << 48)
+            | (bit_extract(affinity, 16, 8) << 32)
+            | (bit_extract(affinity, 8, 8) << 16)
+            | ((sgi_num & 0b1111) << 24)
+            | ((aff0 >> 4) << 44)
+            | (1 << (aff0 & 0b1111));
+        msr!(ICC_SGI1R_EL1, sgi1r);
+        isb!();
+    }
+
+    pub fn prio(&self, int_id: usize) -> usize {
+        let idx = (int_id * 8) / 32;
+        let off = (int_id * 8) % 32;
+        if gic_is_priv(int_id) {
+            ((gicr().IPRIORITYR[idx].get() >> off) & 0xff) as usize
+        } else {
+            ((self.IPRIORITYR[idx].get() >> off) & 0xff) as usize
+        }
+    }
+
+    pub fn set_prio(&self, int_id: usize, prio: u8) {
+        let idx = (int_id * 8) / 32;
+        let off = (int_id * 8) % 32;
+        let mask: u32 = 0b11111111 << off;
+
+        let lock = GICD_LOCK.lock();
+        let reg = if gic_is_priv(int_id) {
+            &gicr().IPRIORITYR[idx]
+        } else {
+            &self.IPRIORITYR[idx]
+        };
+        let prev = reg.get();
+        reg.set((prev & !mask) | (((prio as u32) << off) & mask));
+        drop(lock);
+    }
+
+    // the cpu interface mask of an interrupt, for the compatibility with ITARGETSR
+    pub fn trgt(&self, int_id: usize) -> usize {
+        if gic_is_priv(int_id) {
+            return 1 << Platform::cpuid_to_cpuif(current_cpu().id);
+        }
+        let affinity = gic_mpidr_to_affinity(self.IROUTER[int_id].get() as usize);
+        for cpu_id in 0..PLAT_DESC.cpu_desc.num {
+            if gic_cpu_affinity(cpu_id) == affinity {
+                return 1 << Platform::cpuid_to_cpuif(cpu_id);
+            }
+        }
+        0
+    }
+
+    /* Route a SPI to one core, only the lowest cpu interface in the mask is taken, as GICv3 routes a SPI to a
+     * single core or to any core.
+     *
+     * @param[in] int_id: the SPI.
+     * @param[in] trgt: the cpu interface mask in the layout of ITARGETSR.
+     */
+    pub fn set_trgt(&self, int_id: usize, trgt: u8) {
+        if gic_is_priv(int_id) || trgt == 0 {
+            return;
+        }
+        let cpu_id = Platform::cpuif_to_cpuid(trgt.trailing_zeros() as usize);
+        if cpu_id >= PLAT_DESC.cpu_desc.num {
+            return;
+        }
+
+        let lock = GICD_LOCK.lock();
+        self.IROUTER[int_id].set(gic_cpu_affinity(cpu_id) as u64);
+        drop(lock);
+    }
+
+    pub fn set_enable(&self, int_id: usize, en: bool) {
+        let idx = int_id / 32;
+        let bit = 1 << (int_id % 32);
+
+        let lock = GICD_LOCK.lock();
+        if gic_is_priv(int_id) {
+            let gicr = gicr();
+            if en {
+                gicr.ISENABLER0.set(bit);
+            } else {
+                gicr.ICENABLER0.set(bit);
+                gicr.wait_rwp();
+            }
+        } else if en {
+            self.ISENABLER[idx].set(bit);
+        } else {
+            self.ICENABLER[idx].set(bit);
+            self.wait_rwp();
+        }
+        drop(lock);
+    }
+
+    pub fn set_pend(&self, int_id: usize, pend: bool) {
+        let reg_ind = int_id / 32;
+        let mask = 1 << (int_id % 32);
+
+        let lock = GICD_LOCK.lock();
+        if gic_is_priv(int_id) {
+            let gicr = gicr();
+            if pend {
+                gicr.ISPENDR0.set(mask);
+            } else {
+                gicr.ICPENDR0.set(mask);
+            }
+        } else if pend {
+            self.ISPENDR[reg_ind].set(mask);
+        } else {
+            self.ICPENDR[reg_ind].set(mask);
+        }
+        drop(lock);
+    }
+
+    pub fn set_act(&self, int_id: usize, act: bool) {
+        let reg_ind = int_id / 32;
+        let mask = 1 << (int_id % 32);
+
+        let lock = GICD_LOCK.lock();
+        if gic_is_priv(int_id) {
+            let gicr = gicr();
+            if act {
+                gicr.ISACTIVER0.set(mask);
+            } else {
+                gicr.ICACTIVER0.set(mask);
+            }
+        } else if act {
+            self.ISACTIVER[reg_ind].set(mask);
+        } else {
+            self.ICACTIVER[reg_ind].set(mask);
+        }
+        drop(lock);
+    }
+
+    pub fn set_state(&self, int_id: usize, state: IrqState) {
+        self.set_act(int_id, state.is_active());
+        self.set_pend(int_id, state.is_pend());
+    }
+
+    pub fn set_icfgr(&self, int_id: usize, cfg: u8) {
+        let lock = GICD_LOCK.lock();
+        let reg_ind = (int_id * GIC_CONFIG_BITS) / 32;
+        let off = (int_id * GIC_CONFIG_BITS) % 32;
+        let mask = 0b11 << off;
+
+        let reg = if gic_is_priv(int_id) {
+            &gicr().ICFGR[reg_ind]
+        } else {
+            &self.ICFGR[reg_ind]
+        };
+        let icfgr = reg.get();
+        reg.set((icfgr & !mask) | (((cfg as u32) << off) & mask));
+        drop(lock);
+    }
+
+    pub fn typer(&self) -> u32 {
+        self.TYPER.get()
+    }
+
+    pub fn iidr(&self) -> u32 {
+        self.IIDR.get()
+    }
+
+    pub fn state(&self, int_id: usize) -> usize {
+        let reg_ind = int_id / 32;
+        let mask = 1 << (int_id % 32);
+
+        let lock = GICD_LOCK.lock();
+        let (pend, act) = if gic_is_priv(int_id) {
+            let gicr = gicr();
+            (gicr.ISPENDR0.get(), gicr.ISACTIVER0.get())
+        } else {
+            (self.ISPENDR[reg_ind].get(), self.ISACTIVER[reg_ind].get())
+        };
+        drop(lock);
+        usize::from(pend & mask != 0) | (usize::from(act & mask != 0) << 1)
+    }
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub GicRedistributor {
+        // RD_base frame
+        (0x0000 => CTLR: ReadWrite<u32>),   // Redistributor Control Register
+        (0x0004 => IIDR: ReadOnly<u32>),    // Implementer Identification Register
+        (0x0008 => TYPER: ReadOnly<u64>),   // Redistributor Type Register
+        (0x0010 => STATUSR: ReadWrite<u32>),    // Error Reporting Status Register, optional
+        (0x0014 => WAKER: ReadWrite<u32>),  // Redistributor Wake Register
+        (0x0018 => reserve0),
+        (0x0070 => PROPBASER: ReadWrite<u64>),  // Redistributor Properties Base Address Register
+        (0x0078 => PENDBASER: ReadWrite<u64>),  // Redistributor LPI Pending Table Base Address Register
+        (0x0080 => reserve13),
+        (0xffe8 => PIDR2: ReadOnly<u32>),   // Peripheral ID2 Register
+        (0xffec => reserve1),
+        // SGI_base frame
+        (0x10000 => reserve2),
+        (0x10080 => IGROUPR0: ReadWrite<u32>),      // Interrupt Group Register 0
+        (0x10084 => reserve3),
+        (0x10100 => ISENABLER0: ReadWrite<u32>),    // Interrupt Set-Enable Register 0
+        (0x10104 => reserve4),
+        (0x10180 => ICENABLER0: ReadWrite<u32>),    // Interrupt Clear-Enable Register 0
+        (0x10184 => reserve5),
+        (0x10200 => ISPENDR0: ReadWrite<u32>),      // Interrupt Set-Pend Register 0
+        (0x10204 => reserve6),
+        (0x10280 => ICPENDR0: ReadWrite<u32>),      // Interrupt Clear-Pend Register 0
+        (0x10284 => reserve7),
+        (0x10300 => ISACTIVER0: ReadWrite<u32>),    // Interrupt Set-Active Register 0
+        (0x10304 => reserve8),
+        (0x10380 => ICACTIVER0: ReadWrite<u32>),    // Interrupt Clear-Active Register 0
+        (0x10384 => reserve9),
+        (0x10400 => IPRIORITYR: [ReadWrite<u32>; GIC_PRIV_PRIO_REGS_NUM]),  // Interrupt Priority Registers
+        (0x10420 => reserve10),
+        (0x10c00 => ICFGR: [ReadWrite<u32>; GIC_PRIV_CONFIG_REGS_NUM]),     // Interrupt Configuration Registers
+        (0x10c08 => reserve11),
+        (0x10d00 => IGRPMODR0: ReadWrite<u32>),     // Interrupt Group Modifier Register 0
+        (0x10d04 => reserve12),
+        (0x20000 => @END),
+    }
+}
+
+// SAFETY: each core only accesses its own redistributor
+unsafe impl Send for GicRedistributor {}
+unsafe impl Sync for GicRedistributor {}
+
+impl GicRedistributor {
+    fn wait_rwp(&self) {
+        while self.CTLR.get() & GICR_CTLR_RWP_BIT as u32 != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn init(&self) {
+        // wake up the redistributor
+        let waker = self.WAKER.get();
+        self.WAKER.set(waker & !GICR_WAKER_PROCESSORSLEEP_BIT as u32);
+        while self.WAKER.get() & GICR_WAKER_CHILDRENASLEEP_BIT as u32 != 0 {
+            core::hint::spin_loop();
+        }
+
+        /*
+         * Make sure all private interrupts are not enabled, non pending,
+         * non active.
+         */
+        self.ICENABLER0.set(u32::MAX);
+        self.wait_rwp();
+        self.ICPENDR0.set(u32::MAX);
+        self.ICACTIVER0.set(u32::MAX);
+        self.IGROUPR0.set(u32::MAX);
+        self.IGRPMODR0.set(0);
+
+        /* All interrupts have lowest priority possible by default */
+        for i in 0..GIC_PRIV_PRIO_REGS_NUM {
+            self.IPRIORITYR[i].set(u32::MAX);
+        }
+    }
+}
+
+// the redistributor of the current core
+fn gicr() -> &'static GicRedistributor {
+    let addr = GICR_LIST[current_cpu().id].load(Ordering::Relaxed);
+    debug_assert_ne!(addr, 0, "redistributor of core {} is not found", current_cpu().id);
+    // SAFETY: the address is a redistributor found by gic_cpu_init, which lives for the program duration
+    unsafe { &*(addr as *const GicRedistributor) }
+}
+
+/* Find the redistributor of a core by its affinity in GICR_TYPER.
+ *
+ * @param[in] cpu_id: the core.
+ */
+fn gicr_probe(cpu_id: usize) -> Option<usize> {
+    let affinity = gic_mpidr_to_typer_affinity(PLAT_DESC.cpu_desc.core_list[cpu_id].mpidr);
+    let mut addr = Platform::GICR_BASE;
+    if addr == usize::MAX {
+        return None;
+    }
+    loop {
+        // SAFETY: the redistributor region of the platform is mapped
+        let gicr = unsafe { &*(addr as *const GicRedistributor) };
+        let typer = gicr.TYPER.get() as usize;
+        if bit_extract(typer, 32, 32) == affinity {
+            return Some(addr);
+        }
+        if typer & GICR_TYPER_LAST_BIT != 0 {
+            return None;
+        }
+        // GICv4 has two more frames for the virtual LPIs
+        addr += if typer & GICR_TYPER_VLPIS_BIT != 0 {
+            GICR_FRAME_SIZE * 2
+        } else {
+            GICR_FRAME_SIZE
+        };
+    }
+}
+
+// the redistributor of a core supports physical LPIs, which are not enabled yet, e.g. by the firmware
+pub fn gicr_lpi_capable(cpu_id: usize) -> bool {
+    let Some(addr) = gicr_probe(cpu_id) else {
+        return false;
+    };
+    // SAFETY: the address is a redistributor found by gicr_probe
+    let gicr = unsafe { &*(addr as *const GicRedistributor) };
+    gicr.TYPER.get() as usize & GICR_TYPER_PLPIS_BIT != 0 && gicr.CTLR.get() as usize & GICR_CTLR_ENABLE_LPIS_BIT == 0
+}
+
+/* Enable the physical LPIs of the redistributor of a core, which must be checked by gicr_lpi_capable, the tables
+ * can not be changed once LPIs are enabled.
+ *
+ * @param[in] cpu_id: the core.
+ * @param[in] propbaser: value of GICR_PROPBASER, the LPI configuration table shared by all cores.
+ * @param[in] pendbaser: value of GICR_PENDBASER, the LPI pending table of the core.
+ */
+pub fn gicr_enable_lpis(cpu_id: usize, propbaser: u64, pendbaser: u64) {
+    let Some(addr) = gicr_probe(cpu_id) else {
+        return;
+    };
+    // SAFETY: the address is a redistributor found by gicr_probe
+    let gicr = unsafe { &*(addr as *const GicRedistributor) };
+    gicr.PROPBASER.set(propbaser);
+    gicr.PENDBASER.set(pendbaser);
+    dsb!();
+    gicr.CTLR.set(gicr.CTLR.get() | GICR_CTLR_ENABLE_LPIS_BIT as u32);
+    dsb!();
+}
+
+/* The physical address and the processor number of the redistributor of a core, either is the target of ITS
+ * commands depending on GITS_TYPER.PTA.
+ *
+ * @param[in] cpu_id: the core.
+ */
+pub fn gicr_its_target(cpu_id: usize) -> Option<(usize, usize)> {
+    let addr = gicr_probe(cpu_id)?;
+    // SAFETY: the address is a redistributor found by gicr_probe
+    let gicr = unsafe { &*(addr as *const GicRedistributor) };
+    Some((addr, bit_extract(gicr.TYPER.get() as usize, 8, 16)))
+}
+
+// The cpu interface is accessed by the ICC system registers
+pub struct GicCpuInterface;
+
+impl GicCpuInterface {
+    fn init(&self) {
+        for i in 0..gic_lrs() {
+            GICH.set_lr(i, 0);
+        }
+
+        let sre = mrs!(ICC_SRE_EL2) as usize;
+        msr!(ICC_SRE_EL2, sre | ICC_SRE_SRE_BIT | ICC_SRE_ENABLE_BIT);
+        isb!();
+
+        msr!(ICC_PMR_EL1, 0xff_usize);
+        msr!(ICC_BPR1_EL1, 0_usize);
+        let ctlr_prev = mrs!(ICC_CTLR_EL1) as usize;
+        msr!(ICC_CTLR_EL1, ctlr_prev | GICC_CTLR_EOIMODENS_BIT);
+        msr!(ICC_IGRPEN1_EL1, 1_usize);
+        isb!();
+
+        let hcr_prev = GICH.hcr();
+        GICH.set_hcr(hcr_prev | GICH_HCR_LRENPIE_BIT as u32);
+    }
+
+    pub fn ctlr(&self) -> u32 {
+        mrs!(ICC_CTLR_EL1) as u32
+    }
+
+    pub fn set_ctlr(&self, ctlr: u32) {
+        msr!(ICC_CTLR_EL1, ctlr as usize);
+        isb!();
+    }
+
+    pub fn hppir(&self) -> u32 {
+        mrs!(ICC_HPPIR1_EL1) as u32
+    }
+
+    pub fn rpr(&self) -> u32 {
+        mrs!(ICC_RPR_EL1) as u32
+    }
+
+    pub fn bpr(&self) -> u32 {
+        mrs!(ICC_BPR1_EL1) as u32
+    }
+}
+
+macro_rules! ich_lr_access {
+    ($($idx:literal => $reg:ident),* $(,)?) => {
+        fn ich_lr_read(lr_idx: usize) -> u64 {
+            match lr_idx {
+                $($idx => mrs!($reg),)*
+                _ => 0,
+            }
+        }
+
+        fn ich_lr_write(lr_idx: usize, val: u64) {
+            match lr_idx {
+                $($idx => msr!($reg, val),)*
+                _ => {}
+            }
+        }
+    };
+}
+
+ich_lr_access!(
+    0 => ICH_LR0_EL2,
+    1 => ICH_LR1_EL2,
+    2 => ICH_LR2_EL2,
+    3 => ICH_LR3_EL2,
+    4 => ICH_LR4_EL2,
+    5 => ICH_LR5_EL2,
+    6 => ICH_LR6_EL2,
+    7 => ICH_LR7_EL2,
+    8 => ICH_LR8_EL2,
+    9 => ICH_LR9_EL2,
+    10 => ICH_LR10_EL2,
+    11 => ICH_LR11_EL2,
+    12 => ICH_LR12_EL2,
+    13 => ICH_LR13_EL2,
+    14 => ICH_LR14_EL2,
+    15 => ICH_LR15_EL2,
+);
+
+// ICH_LR<n>_EL2 fields
+const ICH_LR_VINTID_LEN: usize = 32;
+const ICH_LR_PINTID_OFF: usize = 32;
+const ICH_LR_EOI_BIT: u64 = 1 << 41;
+const ICH_LR_PRIO_OFF: usize = 48;
+const ICH_LR_GROUP1_BIT: u64 = 1 << 60;
+const ICH_LR_HW_BIT: u64 = 1 << 61;
+const ICH_LR_STATE_OFF: usize = 62;
+
+// GICH_LR fields of GICv2, which the vgic uses
+const GICH_LR_VID_LEN: usize = 10;
+const GICH_LR_PID_OFF: usize = 10;
+const GICH_LR_EOI_BIT: u32 = 1 << 19;
+const GICH_LR_PRIO_OFF: usize = 23;
+const GICH_LR_PRIO_LEN: usize = 5;
+const GICH_LR_STATE_OFF: usize = 28;
+const GICH_LR_HW_BIT: u32 = 1 << 31;
+
+/* Convert a list register from the GICv2 layout, the source cpu of a SGI is dropped as GICv3 has no such field.
+ * All virtual interrupts are group 1, which is the only group the guests enable.
+ *
+ * @param[in] lr: the list register in the GICH_LR layout.
+ */
+fn gich_lr_to_ich_lr(lr: u32) -> u64 {
+    if lr == 0 {
+        return 0;
+    }
+    let lr = lr as usize;
+    let mut ich_lr = bit_extract(lr, 0, GICH_LR_VID_LEN) as u64
+        | ((bit_extract(lr, GICH_LR_PRIO_OFF, GICH_LR_PRIO_LEN) as u64) << (ICH_LR_PRIO_OFF + 3))
+        | ((bit_extract(lr, GICH_LR_STATE_OFF, 2) as u64) << ICH_LR_STATE_OFF)
+        | ICH_LR_GROUP1_BIT;
+    if lr as u32 & GICH_LR_HW_BIT != 0 {
+        ich_lr |= ICH_LR_HW_BIT | ((bit_extract(lr, GICH_LR_PID_OFF, GICH_LR_VID_LEN) as u64) << ICH_LR_PINTID_OFF);
+    } else if lr as u32 & GICH_LR_EOI_BIT != 0 {
+        ich_lr |= ICH_LR_EOI_BIT;
+    }
+    ich_lr
+}
+
+// the reverse of gich_lr_to_ich_lr, LPIs do not fit in the GICv2 layout and read as free
+fn ich_lr_to_gich_lr(val: u64) -> u32 {
+    let ich_lr = val as usize;
+    let state = bit_extract(ich_lr, ICH_LR_STATE_OFF, 2);
+    if state == 0 || bit_extract(ich_lr, 0, ICH_LR_VINTID_LEN) >= GIC_INTS_MAX {
+        return 0;
+    }
+    let mut lr = bit_extract(ich_lr, 0, usize::min(ICH_LR_VINTID_LEN, GICH_LR_VID_LEN))
+        | (bit_extract(ich_lr, ICH_LR_PRIO_OFF + 3, GICH_LR_PRIO_LEN) << GICH_LR_PRIO_OFF)
+        | (state << GICH_LR_STATE_OFF);
+    if val & ICH_LR_HW_BIT != 0 {
+        lr |= GICH_LR_HW_BIT as usize | (bit_extract(ich_lr, ICH_LR_PINTID_OFF, GICH_LR_VID_LEN) << GICH_LR_PID_OFF);
+    } else if val & ICH_LR_EOI_BIT != 0 {
+        lr |= GICH_LR_EOI_BIT as usize;
+    }
+    lr as u32
+}
+
+// The virtual cpu interface control is accessed by the ICH system registers
+pub struct GicHypervisorInterface;
+
+impl GicHypervisorInterface {
+    pub fn hcr(&self) -> u32 {
+        mrs!(ICH_HCR_EL2) as u32
+    }
+
+    pub fn set_hcr(&self, hcr: u32) {
+        msr!(ICH_HCR_EL2, hcr as usize);
+        isb!();
+    }
+
+    pub fn vtr(&self) -> u32 {
+        mrs!(ICH_VTR_EL2) as u32
+    }
+
+    pub fn vmcr(&self) -> u32 {
+        mrs!(ICH_VMCR_EL2) as u32
+    }
+
+    pub fn set_vmcr(&self, vmcr: u32) {
+        msr!(ICH_VMCR_EL2, vmcr as usize);
+    }
+
+    // there are at most 16 list registers, so the second status register is always 0
+    pub fn elrsr(&self, elsr_idx: usize) -> u32 {
+        if elsr_idx == 0 {
+            mrs!(ICH_ELRSR_EL2) as u32
+        } else {
+            0
+        }
+    }
+
+    pub fn eisr(&self, eisr_idx: usize) -> u32 {
+        if eisr_idx == 0 {
+            mrs!(ICH_EISR_EL2) as u32
+        } else {
+            0
+        }
+    }
+
+    // the list register in the GICv2 GICH_LR layout
+    pub fn lr(&self, lr_idx: usize) -> u32 {
+        ich_lr_to_gich_lr(ich_lr_read(lr_idx))
+    }
+
+    pub fn misr(&self) -> u32 {
+        mrs!(ICH_MISR_EL2) as u32
+    }
+
+    pub fn apr(&self) -> u32 {
+        mrs!(ICH_AP1R0_EL2) as u32
+    }
+
+    pub fn set_apr(&self, apr: u32) {
+        msr!(ICH_AP1R0_EL2, apr as usize);
+    }
+
+    pub fn set_lr(&self, lr_idx: usize, val: u32) {
+        ich_lr_write(lr_idx, gich_lr_to_ich_lr(val));
+    }
+
+    /* Put a pending virtual LPI in a list register. LPIs are never hardware interrupts, the physical LPI is
+     * already finished by the hypervisor.
+     *
+     * @param[in] lr_idx: the list register.
+     * @param[in] int_id: the virtual LPI.
+     * @param[in] prio: the priority of the LPI from the guest LPI configuration table.
+     */
+    pub fn set_lr_lpi(&self, lr_idx: usize, int_id: usize, prio: u8) {
+        let lr = int_id as u64
+            | ((prio as u64) << ICH_LR_PRIO_OFF)
+            | ICH_LR_GROUP1_BIT
+            | ((IrqState::Pend as u64) << ICH_LR_STATE_OFF);
+        ich_lr_write(lr_idx, lr);
+    }
+
+    /* Make a virtual LPI pending again in the list register which holds it.
+     *
+     * @param[in] int_id: the virtual LPI.
+     * @return: false if the LPI is not in any list register.
+     */
+    pub fn lr_lpi_pend(&self, int_id: usize) -> bool {
+        let elrsr = self.elrsr(0);
+        for i in (0..gic_lrs()).filter(|i| elrsr & (1 << i) == 0) {
+            let lr = ich_lr_read(i);
+            if bit_extract(lr as usize, 0, ICH_LR_VINTID_LEN) == int_id {
+                ich_lr_write(i, lr | ((IrqState::Pend as u64) << ICH_LR_STATE_OFF));
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct GicState {
+    hcr: u32,
+    vmcr: u32,
+    apr: u32,
+    lr: [u64; GIC_LIST_REGS_NUM],
+    pub ctlr: u32,
+}
+
+impl Default for GicState {
+    fn default() -> Self {
+        Self {
+            hcr: GICH_HCR_LRENPIE_BIT as u32, // List Register Entry Not Present Interrupt Enable.
+            vmcr: 0,
+            apr: 0,
+            lr: [0; GIC_LIST_REGS_NUM],
+            ctlr: 0,
+        }
+    }
+}
+
+impl crate::arch::InterruptContextTriat for GicState {
+    fn save_state(&mut self) {
+        self.hcr = GICH.hcr();
+        self.vmcr = GICH.vmcr();
+        self.apr = GICH.apr();
+        let elrsr = GICH.elrsr(0);
+        for i in 0..gic_lrs() {
+            self.lr[i] = if elrsr & 1 << i == 0 { ich_lr_read(i) } else { 0 };
+        }
+        self.ctlr = GICC.ctlr();
+    }
+
+    fn restore_state(&self) {
+        GICH.set_hcr(self.hcr);
+        GICH.set_vmcr(self.vmcr);
+        GICH.set_apr(self.apr);
+        for i in 0..gic_lrs() {
+            ich_lr_write(i, self.lr[i]);
+        }
+        GICC.set_ctlr(self.ctlr);
+    }
+}
+
+// SAFETY: it is the GICv3 distributor mmio region
+pub(super) static GICD: DeviceRef<GicDistributor> = unsafe { DeviceRef::new(Platform::GICD_BASE as *const _) };
+pub(super) static GICC: GicCpuInterface = GicCpuInterface;
+pub(super) static GICH: GicHypervisorInterface = GicHypervisorInterface;
+
+#[inline(always)]
+fn gic_max_spi() -> usize {
+    let typer = GICD.TYPER.get();
+    let value = typer & GICD_TYPER_ITLINESNUM_MSK;
+    usize::min(32 * (value + 1) as usize, 1020)
+}
+
+pub fn gic_glb_init() {
+    let gich_lrs_num = {
+        let vtr = GICH.vtr();
+        usize::min(((vtr & 0b11111) + 1) as usize, GIC_LIST_REGS_NUM)
+    };
+
+    GIC_LRS_NUM.store(gich_lrs_num, Ordering::Relaxed);
+    GICD.global_init();
+}
+
+pub fn gic_cpu_init() {
+    let cpu_id = current_cpu().id;
+    match gicr_probe(cpu_id) {
+        Some(addr) => GICR_LIST[cpu_id].store(addr, Ordering::Relaxed),
+        None => panic!("gic_cpu_init: no redistributor for core {}", cpu_id),
+    }
+    gicr().init();
+    GICC.init();
+}
+
+pub fn gic_cpu_reset() {
+    GICC.init();
+}
+
+pub fn gic_is_priv(int_id: usize) -> bool {
+    int_id < GIC_PRIVINT_NUM
+}
+
+pub(super) fn gicc_clear_current_irq(for_hypervisor: bool) {
+    let irq = current_cpu().current_irq;
+    if irq == 0 {
+        return;
+    }
+    msr!(ICC_EOIR1_EL1, irq);
+    // LPIs have no active state
+    if for_hypervisor && irq < GIC_LPI_BASE {
+        msr!(ICC_DIR_EL1, irq);
+    }
+    isb!();
+    current_cpu().current_irq = 0;
+}
+
+// GICv3 does not report the source core of a SGI, the source is always 0
+pub(super) fn gicc_get_current_irq() -> Option<(usize, usize)> {
+    let iar = mrs!(ICC_IAR1_EL1) as usize;
+    current_cpu().current_irq = iar;
+    let id = bit_extract(iar, 0, 24);
+    // 1020~1023 are special ids, such as the spurious interrupt
+    if (1020..GIC_LPI_BASE).contains(&id) {
+        None
+    } else {
+        Some((id, 0))
+    }
+}
+
+pub fn gic_lrs() -> usize {
+    GIC_LRS_NUM.load(Ordering::Relaxed)
+}
@@ -14,6 +14,8 @@ pub fn interrupt_arch_init() {
 
     if current_cpu().id == 0 {
         gic_glb_init();
+        #[cfg(feature = "gicv3")]
+        super::vgic_sysreg_init();
     }
 
     gic_cpu_init();
@@ -79,11 +81,11 @@ impl InterruptController for IntCtrl {
     const IRQ_GUEST_TIMER: usize = INTERRUPT_IRQ_GUEST_TIMER;
 
     fn init() {
-        todo!()
+        interrupt_arch_init();
     }
 
-    fn enable(_int_id: usize, _en: bool) {
-        todo!()
+    fn enable(int_id: usize, en: bool) {
+        interrupt_arch_enable(int_id, en);
     }
 
     fn fetch() -> Option<(usize, usize)> {
@@ -91,7 +93,7 @@ impl InterruptController for IntCtrl {
     }
 
     fn finish(_int_id: usize) {
-        todo!()
+        interrupt_arch_deactive_irq(true);
     }
 
     fn irq_priority(int_id: usize) -> usize {
@@ -0,0 +1,585 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use spin::{Mutex, Once};
+use tock_registers::interfaces::*;
+use tock_registers::registers::*;
+use tock_registers::*;
+
+use crate::arch::{Arch, CacheInvalidate, PAGE_SIZE};
+use crate::board::{PlatOperation, Platform, PLAT_DESC};
+use crate::kernel::current_cpu;
+use crate::mm::PageFrame;
+use crate::util::{bit_extract, device_ref::DeviceRef};
+
+use super::gic::{gicr_enable_lpis, gicr_its_target, gicr_lpi_capable, GIC_LPI_BASE};
+
+// The physical ITS of GICv3. It translates the MSIs of the passthrough PCIe devices into LPIs, which are taken by
+// the hypervisor and injected into the VM owning the device as the LPIs the guest mapped on its virtual ITS.
+// There is one collection for each core, whose id is the core id.
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub GicIts {
+        (0x0000 => CTLR: ReadWrite<u32>),       // ITS Control Register
+        (0x0004 => IIDR: ReadOnly<u32>),        // ITS Identification Register
+        (0x0008 => TYPER: ReadOnly<u64>),       // ITS Type Register
+        (0x0010 => reserve0),
+        (0x0080 => CBASER: ReadWrite<u64>),     // ITS Command Queue Descriptor
+        (0x0088 => CWRITER: ReadWrite<u64>),    // ITS Write Register
+        (0x0090 => CREADR: ReadOnly<u64>),      // ITS Read Register
+        (0x0098 => reserve1),
+        (0x0100 => BASER: [ReadWrite<u64>; 8]), // ITS Translation Table Descriptors
+        (0x0140 => reserve2),
+        (0x10000 => @END),
+    }
+}
+
+unsafe impl Sync for GicIts {}
+
+// SAFETY: it is the ITS mmio region, only accessed if the platform has one
+static GITS: DeviceRef<GicIts> = unsafe { DeviceRef::new(Platform::GITS_BASE as *const _) };
+
+static ITS: Once<Option<Mutex<Its>>> = Once::new();
+
+// GITS BITS
+const GITS_CTLR_ENABLED_BIT: u32 = 1 << 0;
+const GITS_CTLR_QUIESCENT_BIT: u32 = 1 << 31;
+const GITS_TYPER_PTA_BIT: usize = 1 << 19;
+const GITS_CREADR_STALLED_BIT: usize = 1 << 0;
+const GITS_CQ_OFFSET_MSK: usize = 0xfffe0;
+const GITS_BASER_VALID_BIT: u64 = 1 << 63;
+const GITS_BASER_INDIRECT_BIT: u64 = 1 << 62;
+const GITS_BASER_TYPE_DEVICE: usize = 1;
+const GITS_BASER_TYPE_COLLECTION: usize = 4;
+// the second 64K frame, which holds GITS_TRANSLATER
+pub const GITS_TRANSLATER_FRAME: usize = 0x10000;
+pub const GITS_TRANSLATER: usize = GITS_TRANSLATER_FRAME + 0x40;
+
+// write-back read-allocate write-allocate and inner shareable, in the layout of GITS_CBASER and GITS_BASER<n>
+const GITS_TABLE_ATTRS: u64 = (0b111 << 59) | (0b01 << 10);
+// same in the layout of GICR_PROPBASER and GICR_PENDBASER
+const GICR_TABLE_ATTRS: u64 = (0b111 << 7) | (0b01 << 10);
+const GICR_PENDBASER_PTZ_BIT: u64 = 1 << 62;
+const GICR_PEND_TABLE_ALIGN: usize = 0x10000;
+
+// 14 bits interrupt ids, the LPIs of the hypervisor are 8192~16383
+const ITS_LPI_ID_BITS: usize = 14;
+const ITS_LPI_MAX: usize = 1 << ITS_LPI_ID_BITS;
+const ITS_LPI_PRIO: u8 = 0xa0;
+const ITS_LPI_ENABLE_BIT: u8 = 1 << 0;
+const ITS_ITT_ALIGN: usize = 0x100;
+// a flat device table is limited to 64K, it is physically contiguous
+const ITS_FLAT_TABLE_PAGES_MAX: usize = 16;
+
+// ITS command ids, the guest commands of the virtual ITS are decoded with them as well
+pub const ITS_CMD_MOVI: usize = 0x01;
+pub const ITS_CMD_INT: usize = 0x03;
+pub const ITS_CMD_CLEAR: usize = 0x04;
+pub const ITS_CMD_SYNC: usize = 0x05;
+pub const ITS_CMD_MAPD: usize = 0x08;
+pub const ITS_CMD_MAPC: usize = 0x09;
+pub const ITS_CMD_MAPTI: usize = 0x0a;
+pub const ITS_CMD_MAPI: usize = 0x0b;
+pub const ITS_CMD_INV: usize = 0x0c;
+pub const ITS_CMD_INVALL: usize = 0x0d;
+pub const ITS_CMD_MOVALL: usize = 0x0e;
+pub const ITS_CMD_DISCARD: usize = 0x0f;
+pub const ITS_CMD_SIZE: usize = 32;
+
+// the virtual LPI that a physical LPI is injected as
+#[derive(Clone, Copy, Debug)]
+pub struct ItsLpiRoute {
+    pub vm_id: usize,
+    pub vcpu_id: usize,
+    pub int_id: usize,
+}
+
+struct ItsDevice {
+    vm_id: usize,
+    // interrupt translation table of the device, allocated when the guest maps the device
+    itt: Option<PageFrame>,
+    // event id to the physical LPI and the core taking it
+    events: BTreeMap<usize, (usize, usize)>,
+}
+
+struct ItsDeviceTable {
+    entry_size: usize,
+    indirect: bool,
+    // number of device ids the table has room for
+    capacity: usize,
+    table: PageFrame,
+    // the second level pages of an indirect table, by the index of the first level entry
+    pages: BTreeMap<usize, PageFrame>,
+}
+
+struct Its {
+    cwriter: usize,
+    cmd_queue: PageFrame,
+    prop_table: PageFrame,
+    dev_table: ItsDeviceTable,
+    // the pending tables and the collection table, owned by the hardware as long as the hypervisor runs
+    _tables: Vec<PageFrame>,
+    // RDbase of the collection of each core
+    targets: Vec<usize>,
+    devices: BTreeMap<usize, ItsDevice>,
+    routes: BTreeMap<usize, ItsLpiRoute>,
+}
+
+/* Allocate the zeroed memory of an ITS table, which is accessed by physical address and must be contiguous.
+ * The hypervisor heap is not contiguous if the hypervisor is colored.
+ *
+ * @param[in] page_num: the size in pages.
+ * @param[in] align: the alignment of the physical address.
+ */
+fn its_table_alloc(page_num: usize, align: usize) -> Option<PageFrame> {
+    let frame = PageFrame::alloc_aligned_pages(page_num, align).ok()?;
+    let contiguous =
+        (1..page_num).all(|i| current_cpu().pt().ipa2pa(frame.hva + i * PAGE_SIZE) == Some(frame.pa + i * PAGE_SIZE));
+    if !contiguous || frame.pa & (align - 1) != 0 {
+        error!(
+            "its_table_alloc: {} pages at pa {:#x} are not contiguous or aligned to {:#x}",
+            page_num, frame.pa, align
+        );
+        return None;
+    }
+    Some(frame)
+}
+
+// make the writes of the hypervisor visible to the ITS and the redistributors
+fn its_table_flush(hva: usize, len: usize) {
+    Arch::dcache_clean_flush(hva, len);
+    dsb!();
+}
+
+/* Program a GITS_BASER<n> with the memory of a table.
+ *
+ * @param[in] idx: the GITS_BASER<n>.
+ * @param[in] table: the memory of the table.
+ * @param[in] indirect: the table is a first level table.
+ * @return: false if the ITS does not take the table as is.
+ */
+fn its_baser_set(idx: usize, table: &PageFrame, indirect: bool) -> bool {
+    let baser = GITS_BASER_VALID_BIT
+        | if indirect { GITS_BASER_INDIRECT_BIT } else { 0 }
+        | GITS_TABLE_ATTRS
+        | table.pa as u64
+        | (table.page_num - 1) as u64;
+    GITS.BASER[idx].set(baser);
+    // Indirect is RAZ/WI without two level tables, Page_Size [9:8] is 0 for 4K pages
+    let val = GITS.BASER[idx].get();
+    if (val & GITS_BASER_INDIRECT_BIT != 0) == indirect && bit_extract(val as usize, 8, 2) == 0 {
+        return true;
+    }
+    GITS.BASER[idx].set(0);
+    false
+}
+
+impl ItsDeviceTable {
+    /* Set up the device table of GITS_BASER<n>, a two level table if the ITS supports it.
+     *
+     * @param[in] idx: the GITS_BASER<n> of the device table.
+     * @param[in] dev_bits: number of DeviceID bits of the ITS.
+     */
+    fn new(idx: usize, dev_bits: usize) -> Option<Self> {
+        let entry_size = bit_extract(GITS.BASER[idx].get() as usize, 48, 5) + 1;
+        let devs_per_page = PAGE_SIZE / entry_size;
+        // the first level table has an 8 bytes entry for each second level page
+        let l1_entries = usize::min((1 << dev_bits) / devs_per_page, PAGE_SIZE / 8).max(1);
+        let table = its_table_alloc(1, PAGE_SIZE)?;
+        if its_baser_set(idx, &table, true) {
+            return Some(Self {
+                entry_size,
+                indirect: true,
+                capacity: usize::min(l1_entries * devs_per_page, 1 << dev_bits),
+                table,
+                pages: BTreeMap::new(),
+            });
+        }
+        let page_num = usize::min(
+            ((1 << dev_bits) * entry_size).div_ceil(PAGE_SIZE),
+            ITS_FLAT_TABLE_PAGES_MAX,
+        );
+        let table = its_table_alloc(page_num, PAGE_SIZE)?;
+        if !its_baser_set(idx, &table, false) {
+            error!("ItsDeviceTable: GITS_BASER{} does not take 4K pages", idx);
+            return None;
+        }
+        Some(Self {
+            entry_size,
+            indirect: false,
+            capacity: usize::min(page_num * PAGE_SIZE / entry_size, 1 << dev_bits),
+            table,
+            pages: BTreeMap::new(),
+        })
+    }
+
+    // make room for a device, the second level page of an indirect table is allocated on demand
+    fn prepare(&mut self, devid: usize) -> bool {
+        if devid >= self.capacity {
+            return false;
+        }
+        if !self.indirect {
+            return true;
+        }
+        let l1_idx = devid / (PAGE_SIZE / self.entry_size);
+        if self.pages.contains_key(&l1_idx) {
+            return true;
+        }
+        let Some(page) = its_table_alloc(1, PAGE_SIZE) else {
+            return false;
+        };
+        let entry = (self.table.hva + l1_idx * 8) as *mut u64;
+        // SAFETY: the entry is inside the first level table
+        unsafe { entry.write_volatile(GITS_BASER_VALID_BIT | page.pa as u64) };
+        its_table_flush(entry as usize, 8);
+        self.pages.insert(l1_idx, page);
+        true
+    }
+}
+
+impl Its {
+    fn probe() -> Option<Self> {
+        if Platform::GITS_BASE == usize::MAX {
+            return None;
+        }
+        let cpu_num = PLAT_DESC.cpu_desc.num;
+        if let Some(cpu_id) = (0..cpu_num).find(|&cpu_id| !gicr_lpi_capable(cpu_id)) {
+            error!("its: redistributor of core {} can not take LPIs", cpu_id);
+            return None;
+        }
+
+        GITS.CTLR.set(GITS.CTLR.get() & !GITS_CTLR_ENABLED_BIT);
+        while GITS.CTLR.get() & GITS_CTLR_QUIESCENT_BIT == 0 {
+            core::hint::spin_loop();
+        }
+        let typer = GITS.TYPER.get() as usize;
+
+        // one page of 128 commands
+        let cmd_queue = its_table_alloc(1, PAGE_SIZE)?;
+        GITS.CBASER
+            .set(GITS_BASER_VALID_BIT | GITS_TABLE_ATTRS | cmd_queue.pa as u64);
+        GITS.CWRITER.set(0);
+
+        let mut tables = Vec::new();
+        let mut dev_table = None;
+        // collections beyond the HCC of the ITS are held in memory
+        let mut collections_in_memory = bit_extract(typer, 24, 8) < cpu_num;
+        for idx in 0..GITS.BASER.len() {
+            match bit_extract(GITS.BASER[idx].get() as usize, 56, 3) {
+                GITS_BASER_TYPE_DEVICE => dev_table = Some(ItsDeviceTable::new(idx, bit_extract(typer, 13, 5) + 1)?),
+                GITS_BASER_TYPE_COLLECTION if collections_in_memory => {
+                    let table = its_table_alloc(1, PAGE_SIZE)?;
+                    if !its_baser_set(idx, &table, false) {
+                        error!("its: GITS_BASER{} does not take 4K pages", idx);
+                        return None;
+                    }
+                    tables.push(table);
+                    collections_in_memory = false;
+                }
+                _ => {}
+            }
+        }
+        let Some(dev_table) = dev_table else {
+            error!("its: no device table");
+            return None;
+        };
+        if collections_in_memory {
+            error!("its: no collection table for {} cores", cpu_num);
+            return None;
+        }
+
+        // the configuration of all LPIs is the same, they are disabled until they are mapped
+        let prop_len = ITS_LPI_MAX - GIC_LPI_BASE;
+        let prop_table = its_table_alloc(prop_len / PAGE_SIZE, PAGE_SIZE)?;
+        // SAFETY: the table is allocated with prop_len bytes
+        unsafe { core::slice::from_raw_parts_mut(prop_table.hva as *mut u8, prop_len) }.fill(ITS_LPI_PRIO);
+        its_table_flush(prop_table.hva, prop_len);
+        let propbaser = GICR_TABLE_ATTRS | prop_table.pa as u64 | (ITS_LPI_ID_BITS - 1) as u64;
+        let mut targets = Vec::with_capacity(cpu_num);
+        let mut pend_tables = Vec::with_capacity(cpu_num);
+        for cpu_id in 0..cpu_num {
+            // one bit for each interrupt id, zeroed by the allocator
+            let pend_table = its_table_alloc(1, GICR_PEND_TABLE_ALIGN)?;
+            its_table_flush(pend_table.hva, PAGE_SIZE);
+            let (addr, processor_number) = gicr_its_target(cpu_id)?;
+            targets.push(if typer & GITS_TYPER_PTA_BIT != 0 {
+                addr >> 16
+            } else {
+                processor_number
+            });
+            pend_tables.push(pend_table);
+        }
+        for (cpu_id, pend_table) in pend_tables.iter().enumerate() {
+            let pendbaser = GICR_PENDBASER_PTZ_BIT | GICR_TABLE_ATTRS | pend_table.pa as u64;
+            gicr_enable_lpis(cpu_id, propbaser, pendbaser);
+        }
+        tables.append(&mut pend_tables);
+
+        GITS.CTLR.set(GITS.CTLR.get() | GITS_CTLR_ENABLED_BIT);
+        let mut its = Self {
+            cwriter: 0,
+            cmd_queue,
+            prop_table,
+            dev_table,
+            _tables: tables,
+            targets,
+            devices: BTreeMap::new(),
+            routes: BTreeMap::new(),
+        };
+        for cpu_id in 0..cpu_num {
+            let target = its.targets[cpu_id];
+            its.send([ITS_CMD_MAPC, 0, (1 << 63) | (target << 16) | cpu_id, 0]);
+            its.sync(cpu_id);
+        }
+        info!(
+            "its: init ok, typer {:#x}, {} device ids",
+            typer, its.dev_table.capacity
+        );
+        Some(its)
+    }
+
+    fn send(&mut self, cmd: [usize; 4]) {
+        let next = (self.cwriter + ITS_CMD_SIZE) % (self.cmd_queue.page_num * PAGE_SIZE);
+        // the queue is full if the next command would reach the read offset
+        while next == GITS.CREADR.get() as usize & GITS_CQ_OFFSET_MSK {
+            core::hint::spin_loop();
+        }
+        let ptr = (self.cmd_queue.hva + self.cwriter) as *mut [u64; 4];
+        // SAFETY: the command is inside the command queue
+        unsafe { ptr.write_volatile(cmd.map(|dw| dw as u64)) };
+        its_table_flush(ptr as usize, ITS_CMD_SIZE);
+        self.cwriter = next;
+        GITS.CWRITER.set(next as u64);
+    }
+
+    // wait until the commands sent before take effect on the redistributor of a core
+    fn sync(&mut self, cpu_id: usize) {
+        self.send([ITS_CMD_SYNC, 0, self.targets[cpu_id] << 16, 0]);
+        loop {
+            let creadr = GITS.CREADR.get() as usize;
+            if creadr & GITS_CREADR_STALLED_BIT != 0 {
+                error!("its: command queue stalled at {:#x}", creadr & GITS_CQ_OFFSET_MSK);
+                return;
+            }
+            if creadr & GITS_CQ_OFFSET_MSK == self.cwriter {
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn set_lpi_config(&self, lpi: usize, config: u8) {
+        let ptr = (self.prop_table.hva + lpi - GIC_LPI_BASE) as *mut u8;
+        // SAFETY: LPIs of the hypervisor are inside the configuration table
+        unsafe { ptr.write_volatile(config) };
+        its_table_flush(ptr as usize, 1);
+    }
+
+    fn device(&mut self, devid: usize, vm_id: usize) -> Option<&mut ItsDevice> {
+        self.devices.get_mut(&devid).filter(|dev| dev.vm_id == vm_id)
+    }
+
+    fn unmap_event(&mut self, devid: usize, eventid: usize, (lpi, cpu_id): (usize, usize)) {
+        self.routes.remove(&lpi);
+        self.send([ITS_CMD_DISCARD | (devid << 32), eventid, 0, 0]);
+        self.set_lpi_config(lpi, ITS_LPI_PRIO);
+        self.sync(cpu_id);
+    }
+
+    fn unmap_device(&mut self, devid: usize) {
+        let Some(dev) = self.devices.get_mut(&devid) else {
+            return;
+        };
+        let events = core::mem::take(&mut dev.events);
+        for (eventid, event) in events {
+            self.unmap_event(devid, eventid, event);
+        }
+        self.send([ITS_CMD_MAPD | (devid << 32), 0, 0, 0]);
+        self.sync(current_cpu().id);
+        // the ITS does not access the table after the sync
+        if let Some(dev) = self.devices.get_mut(&devid) {
+            dev.itt = None;
+        }
+    }
+}
+
+fn its() -> Option<&'static Mutex<Its>> {
+    ITS.get()?.as_ref()
+}
+
+// probe the ITS of the platform, after the hypervisor is colored as the tables must stay where they are
+pub fn its_init() {
+    ITS.call_once(|| Its::probe().map(Mutex::new));
+}
+
+// the GITS_TRANSLATER frame of the physical ITS, which the MSIs of passthrough devices are written to
+pub fn its_translater() -> Option<usize> {
+    its().map(|_| Platform::GITS_BASE + GITS_TRANSLATER_FRAME)
+}
+
+/* Give a passthrough PCIe device to a VM, the MSIs of the device are translated by the physical ITS once the
+ * guest maps the device on its virtual ITS.
+ *
+ * @param[in] devid: the DeviceID of the device.
+ * @param[in] vm_id: the VM.
+ * @return: false if there is no ITS, or the device belongs to another VM.
+ */
+pub fn its_assign_device(devid: usize, vm_id: usize) -> bool {
+    let Some(its) = its() else {
+        error!("its_assign_device: no ITS for device {:#x} of VM {}", devid, vm_id);
+        return false;
+    };
+    let mut its = its.lock();
+    if let Some(dev) = its.devices.get(&devid) {
+        error!(
+            "its_assign_device: device {:#x} of VM {} is taken by VM {}",
+            devid, vm_id, dev.vm_id
+        );
+        return false;
+    }
+    its.devices.insert(
+        devid,
+        ItsDevice {
+            vm_id,
+            itt: None,
+            events: BTreeMap::new(),
+        },
+    );
+    true
+}
+
+// take a passthrough device back from the VM owning it, all of its LPIs are discarded
+pub fn its_release_device(devid: usize, vm_id: usize) {
+    if let Some(its) = its() {
+        let mut its = its.lock();
+        if its.device(devid, vm_id).is_some() {
+            its.unmap_device(devid);
+            its.devices.remove(&devid);
+        }
+    }
+}
+
+/* Map a device of a VM with an interrupt translation table, any previous mapping is dropped.
+ *
+ * @param[in] vm_id: the VM owning the device.
+ * @param[in] devid: the DeviceID of the device.
+ * @param[in] event_bits: number of EventID bits of the device.
+ */
+pub fn its_map_device(vm_id: usize, devid: usize, event_bits: usize) -> bool {
+    let Some(its) = its() else {
+        return false;
+    };
+    let mut its = its.lock();
+    if its.device(devid, vm_id).is_none() {
+        return false;
+    }
+    its.unmap_device(devid);
+    let typer = GITS.TYPER.get() as usize;
+    let event_bits = usize::min(event_bits, bit_extract(typer, 8, 5) + 1).max(1);
+    let itt_size = usize::max((1 << event_bits) * (bit_extract(typer, 4, 4) + 1), ITS_ITT_ALIGN);
+    let Some(itt) = its_table_alloc(itt_size.div_ceil(PAGE_SIZE), PAGE_SIZE) else {
+        return false;
+    };
+    if !its.dev_table.prepare(devid) {
+        error!("its_map_device: no room for device {:#x} in the device table", devid);
+        return false;
+    }
+    its.send([ITS_CMD_MAPD | (devid << 32), event_bits - 1, (1 << 63) | itt.pa, 0]);
+    its.sync(current_cpu().id);
+    if let Some(dev) = its.device(devid, vm_id) {
+        dev.itt = Some(itt);
+    }
+    true
+}
+
+// drop the mapping of a device, its LPIs are discarded
+pub fn its_unmap_device(vm_id: usize, devid: usize) {
+    if let Some(its) = its() {
+        let mut its = its.lock();
+        if its.device(devid, vm_id).is_some() {
+            its.unmap_device(devid);
+        }
+    }
+}
+
+/* Translate an event of a mapped device into a new physical LPI, which is injected as a virtual LPI.
+ *
+ * @param[in] devid: the DeviceID of the device.
+ * @param[in] eventid: the EventID of the MSI.
+ * @param[in] cpu_id: the core that takes the physical LPI, which runs the target vcpu.
+ * @param[in] route: the virtual LPI the physical LPI is injected as.
+ */
+pub fn its_map_event(devid: usize, eventid: usize, cpu_id: usize, route: ItsLpiRoute) -> bool {
+    let Some(its) = its() else {
+        return false;
+    };
+    let mut its = its.lock();
+    let Some(dev) = its.device(devid, route.vm_id) else {
+        return false;
+    };
+    if dev.itt.is_none() {
+        return false;
+    }
+    if let Some(event) = dev.events.remove(&eventid) {
+        its.unmap_event(devid, eventid, event);
+    }
+    let Some(lpi) = (GIC_LPI_BASE..ITS_LPI_MAX).find(|lpi| !its.routes.contains_key(lpi)) else {
+        error!(
+            "its_map_event: out of LPIs for event {} of device {:#x}",
+            eventid, devid
+        );
+        return false;
+    };
+    its.routes.insert(lpi, route);
+    if let Some(dev) = its.device(devid, route.vm_id) {
+        dev.events.insert(eventid, (lpi, cpu_id));
+    }
+    its.set_lpi_config(lpi, ITS_LPI_PRIO | ITS_LPI_ENABLE_BIT);
+    its.send([ITS_CMD_MAPTI | (devid << 32), eventid | (lpi << 32), cpu_id, 0]);
+    its.send([ITS_CMD_INV | (devid << 32), eventid, 0, 0]);
+    its.sync(cpu_id);
+    true
+}
+
+/* Move a mapped event to another vcpu.
+ *
+ * @param[in] vm_id: the VM owning the device.
+ * @param[in] devid: the DeviceID of the device.
+ * @param[in] eventid: the EventID of the MSI.
+ * @param[in] cpu_id: the core running the new target vcpu.
+ * @param[in] vcpu_id: the new target vcpu.
+ * @return: false if the event is not mapped.
+ */
+pub fn its_move_event(vm_id: usize, devid: usize, eventid: usize, cpu_id: usize, vcpu_id: usize) -> bool {
+    let Some(its) = its() else {
+        return false;
+    };
+    let mut its = its.lock();
+    let Some(event) = its.device(devid, vm_id).and_then(|dev| dev.events.get_mut(&eventid)) else {
+        return false;
+    };
+    event.1 = cpu_id;
+    let lpi = event.0;
+    if let Some(route) = its.routes.get_mut(&lpi) {
+        route.vcpu_id = vcpu_id;
+    }
+    its.send([ITS_CMD_MOVI | (devid << 32), eventid, cpu_id, 0]);
+    its.sync(cpu_id);
+    true
+}
+
+// discard the physical LPI of an event
+pub fn its_unmap_event(vm_id: usize, devid: usize, eventid: usize) {
+    if let Some(its) = its() {
+        let mut its = its.lock();
+        if let Some(event) = its.device(devid, vm_id).and_then(|dev| dev.events.remove(&eventid)) {
+            its.unmap_event(devid, eventid, event);
+        }
+    }
+}
+
+// the virtual LPI of a physical LPI
+pub fn its_lpi_route(lpi: usize) -> Option<ItsLpiRoute> {
+    its()?.lock().routes.get(&lpi).copied()
+}
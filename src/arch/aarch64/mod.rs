@@ -3,12 +3,16 @@ pub use self::context_frame::*;
 pub use self::gic::*;
 pub use self::interface::*;
 pub use self::interrupt::*;
+#[cfg(feature = "gicv3")]
+pub use self::its::{its_assign_device, its_init, its_release_device, its_translater};
 pub use self::mmu::PLATFORM_PHYSICAL_LIMIT_GB;
 pub use self::page_table::*;
 pub use self::psci::*;
 #[cfg(feature = "smmuv2")]
 pub use self::smmu::*;
 pub use self::vgic::*;
+#[cfg(feature = "gicv3")]
+pub use self::vits::{emu_vgits_init, vgic_its_lpi_handler, VgicIts};
 pub use pmuv3::arch_pmu_init;
 #[cfg(feature = "memory-reservation")]
 pub use pmuv3::{vcpu_start_pmu, vcpu_stop_pmu, PmuTimerEvent};
@@ -23,9 +27,12 @@ mod cpu;
 #[allow(dead_code)]
 mod exception;
 #[allow(dead_code)]
+#[cfg_attr(feature = "gicv3", path = "./gicv3.rs")]
 mod gic;
 mod interface;
 mod interrupt;
+#[cfg(feature = "gicv3")]
+mod its;
 mod mmu;
 #[allow(dead_code)]
 mod page_table;
@@ -41,6 +48,8 @@ pub mod timer;
 mod tlb;
 mod vcpu;
 mod vgic;
+#[cfg(feature = "gicv3")]
+mod vits;
 mod vm;
 
 pub struct SmmuDesc {
@@ -453,6 +453,50 @@ impl PageTable {
         }
     }
 
+    // find the lvl 3 table entry which maps a 4KB page at ipa, return (lvl 2 entry, lvl 3 index)
+    fn lvl3_entry(&self, ipa: usize) -> Option<(Aarch64PageTableEntry, usize)> {
+        let directory = Aarch64PageTableEntry::from_pa(self.directory_pa);
+        let l1e = directory.entry(pt_lvl1_idx(ipa));
+        if !l1e.valid() || l1e.to_pte() & 0b11 == PTE_BLOCK {
+            return None;
+        }
+        let l2e = l1e.entry(pt_lvl2_idx(ipa));
+        if !l2e.valid() || l2e.to_pte() & 0b11 == PTE_BLOCK {
+            return None;
+        }
+        if l2e.entry(pt_lvl3_idx(ipa)).valid() {
+            Some((l2e, pt_lvl3_idx(ipa)))
+        } else {
+            None
+        }
+    }
+
+    /* Change the stage 2 access permission of a 4KB page.
+     * Block mappings are not supported, and the tlb maintenance is left to the caller.
+     *
+     * @param[in] ipa: the page to be changed.
+     * @param[in] writable: true for RW, false for RO.
+     * @return true if the permission is changed.
+     */
+    pub fn set_page_access(&self, ipa: usize, writable: bool) -> bool {
+        if self.stage != MmuStage::S2 {
+            return false;
+        }
+        match self.lvl3_entry(ipa) {
+            Some((l2e, idx)) => {
+                let pte = l2e.entry(idx).to_pte();
+                let new_pte = (pte & !PTE_S2_FIELD_AP_RW) | if writable { PTE_S2_FIELD_AP_RW } else { PTE_S2_FIELD_AP_RO };
+                if pte != new_pte {
+                    l2e.set_entry(idx, Aarch64PageTableEntry::from_pte(new_pte));
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false,
+        }
+    }
+
     pub fn get_pte(&self, va: usize, lvl: usize) -> Option<usize> {
         if lvl == 1 {
             let directory = Aarch64PageTableEntry::from_pa(self.directory_pa);
@@ -3,7 +3,7 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
 #[cfg(feature = "memory-reservation")]
-use crate::kernel::{current_cpu, Vcpu, VcpuState, WeakVcpu};
+use crate::kernel::{current_cpu, vm_if_get_state, Vcpu, VcpuState, VmState, WeakVcpu};
 
 use super::regs::{PMCCFILTR_EL0, PMCR_EL0, PMUSERENR_EL0};
 
@@ -262,7 +262,10 @@ impl crate::util::timer_list::TimerEvent for PmuTimerEvent {
                         );
                     }
                     vcpu.bw_info().supply_budget();
-                    current_cpu().vcpu_array.wakeup_vcpu(&vcpu);
+                    // the vcpus of a VM paused for migration stay blocked
+                    if matches!(vm_if_get_state(vcpu.vm_id()), VmState::Active) {
+                        current_cpu().vcpu_array.wakeup_vcpu(&vcpu);
+                    }
                 }
                 _ => {}
             }
@@ -3,7 +3,7 @@ use crate::board::PlatOperation;
 use crate::kernel::IpiMessage;
 use crate::kernel::{active_vm, ipi_send_msg, IpiInnerMsg, IpiPowerMessage, IpiType, PowerEvent};
 use crate::kernel::{current_cpu, ipi_intra_broadcast_msg, Vcpu, VcpuState, Vm};
-use crate::vmm::vmm_reboot;
+use crate::vmm::{vmm_guest_sys_off, vmm_reboot};
 
 use super::smc::smc_call;
 use smccc::psci::*;
@@ -58,8 +58,7 @@ fn psci_guest_sys_off() -> usize {
     if vm_id == 0 {
         crate::board::Platform::sys_shutdown();
     } else {
-        info!("VM[{}] system off, please remove it on MVM", vm_id);
-        // vmm_remove_vm(vm_id);
+        vmm_guest_sys_off(vm_id);
     }
     0
 }
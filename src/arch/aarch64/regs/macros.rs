@@ -43,6 +43,13 @@ macro_rules! isb {
     };
 }
 
+// not `nomem`, the memory accesses are not reordered across the barrier by the compiler either
+macro_rules! dsb {
+    () => {
+        unsafe { core::arch::asm!("dsb sy", options(nostack)) }
+    };
+}
+
 macro_rules! sysreg_encode_addr {
     ($op0:expr, $op1:expr, $crn:expr, $crm:expr, $op2:expr) => {
         // (Op0[21..20] + Op2[19..17] + Op1[16..14] + CRn[13..10]) + CRm[4..1]
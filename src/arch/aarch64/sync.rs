@@ -35,21 +35,17 @@ pub fn data_abort_handler() {
 
     if !exception_data_abort_is_translate_fault() {
         if exception_data_abort_is_permission_fault() {
-            // println!(
-            //     "write {}, width {}, reg width {}, addr {:x}, iss {:x}, reg idx {}, reg val {:#x}, esr {:#x}",
-            //     exception_data_abort_access_is_write(),
-            //     emu_ctx.width,
-            //     emu_ctx.reg_width,
-            //     emu_ctx.address,
-            //     exception_iss(),
-            //     emu_ctx.reg,
-            //     current_cpu().get_gpr(emu_ctx.reg),
-            //     exception_esr()
-            // );
-            // no need to rewrite elr
-
-            // let time1 = time_current_us();
-            // println!("migrate_data_abort_handler: {}us", time1 - time0);
+            // write to a page protected by dirty logging, no need to rewrite elr
+            if exception_data_abort_access_is_write() && active_vm().unwrap().dirty_log_fault(emu_ctx.address) {
+                return;
+            }
+            warn!(
+                "Core {} unexpected permission fault, write {}, addr {:#x}, esr {:#x}",
+                current_cpu().id,
+                exception_data_abort_access_is_write(),
+                emu_ctx.address,
+                exception_esr()
+            );
             return;
         } else {
             panic!(
@@ -81,11 +81,14 @@ impl GenericTimerContext {
         *self = Default::default();
     }
 
-    #[cfg(feature = "vtimer")]
     pub fn set_offset(&mut self, vtimer_offset: u64) {
         self.cntvoff_el2 = vtimer_offset;
     }
 
+    pub fn offset(&self) -> u64 {
+        self.cntvoff_el2
+    }
+
     pub fn save(&mut self) {
         // no need to save offset register
         mrs!(self.cntkctl_el1, CNTKCTL_EL1);
@@ -3,6 +3,8 @@ use core::ops::Range;
 use core::sync::atomic::{AtomicU32, Ordering};
 
 use alloc::collections::VecDeque;
+#[cfg(feature = "gicv3")]
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
@@ -10,6 +12,8 @@ use spin::Mutex;
 
 use crate::board::{PlatOperation, Platform};
 use crate::config::VmEmulatedDeviceConfig;
+#[cfg(feature = "gicv3")]
+use crate::device::{emu_register_reg, EmuRegType};
 use crate::device::{EmuContext, EmuDev, EmuDeviceType};
 use crate::kernel::{active_vcpu_id, active_vm, current_cpu};
 use crate::kernel::{ipi_intra_broadcast_msg, ipi_send_msg, IpiInitcMessage, IpiInnerMsg, IpiMessage, IpiType};
@@ -70,6 +74,16 @@ impl VgicInt {
         vgic_int.prio = prio;
     }
 
+    fn set_affinity(&self, affinity: Option<usize>) {
+        let mut vgic_int = self.inner.lock();
+        vgic_int.affinity = affinity;
+    }
+
+    fn set_guest_targets(&self, targets: u8) {
+        let mut vgic_int = self.inner.lock();
+        vgic_int.guest_targets = targets;
+    }
+
     fn clear_lr(&self) {
         let mut vgic_int = self.inner.lock();
         vgic_int.lr = None;
@@ -129,6 +143,16 @@ impl VgicInt {
         vgic_int.targets
     }
 
+    fn affinity(&self) -> Option<usize> {
+        let vgic_int = self.inner.lock();
+        vgic_int.affinity
+    }
+
+    fn guest_targets(&self) -> u8 {
+        let vgic_int = self.inner.lock();
+        vgic_int.guest_targets
+    }
+
     #[inline]
     fn hw(&self) -> bool {
         self.inner_const.hw.get()
@@ -178,6 +202,72 @@ impl VgicInt {
     }
 }
 
+impl VgicInt {
+    fn migrate_save(&self, vm: &Vm) -> VgicIntMigrateState {
+        let inner = self.inner.lock();
+        VgicIntMigrateState {
+            owner: inner.owner.as_ref().map_or(u16::MAX, |owner| owner.id() as u16),
+            lr: inner.lr.unwrap_or(u16::MAX),
+            enabled: inner.enabled,
+            state: inner.state as u8,
+            prio: inner.prio,
+            // physical cpu targets may differ on the receiver, save the vcpu targets
+            targets: vgic_target_translate(vm, inner.targets as u32, false) as u8,
+            cfg: inner.cfg,
+        }
+    }
+
+    fn migrate_restore(&self, vm: &Vm, state: &VgicIntMigrateState) {
+        let mut inner = self.inner.lock();
+        inner.owner = match state.owner {
+            u16::MAX => None,
+            owner => vm.vcpu(owner as usize).cloned(),
+        };
+        inner.lr = match state.lr {
+            u16::MAX => None,
+            lr => Some(lr),
+        };
+        inner.enabled = state.enabled;
+        inner.state = IrqState::from(state.state as u32);
+        inner.prio = state.prio;
+        inner.targets = vgic_target_translate(vm, state.targets as u32, true) as u8;
+        inner.cfg = state.cfg;
+        // pend/act lists are rebuilt by update_int_list
+        inner.in_pend = false;
+        inner.in_act = false;
+    }
+}
+
+/* Migration image of a virtual interrupt.
+ * owner and lr use u16::MAX for none.
+ */
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VgicIntMigrateState {
+    owner: u16,
+    lr: u16,
+    enabled: bool,
+    state: u8,
+    prio: u8,
+    targets: u8,
+    cfg: u8,
+}
+
+// Migration image of the private interrupts and list register bookkeeping of one vcpu
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VgicCpuMigrateState {
+    interrupts: [VgicIntMigrateState; GIC_PRIVINT_NUM],
+    curr_lrs: [u16; GIC_LIST_REGS_NUM],
+    sgis: [Sgis; GIC_SGIS_NUM],
+}
+
+pub struct VgicMigrateState {
+    pub ctlr: u32,
+    pub cpu_priv: Vec<VgicCpuMigrateState>,
+    pub interrupts: Vec<VgicIntMigrateState>,
+}
+
 struct VgicIntInnerMut {
     owner: Option<Vcpu>,
     lr: Option<u16>,
@@ -186,6 +276,10 @@ struct VgicIntInnerMut {
     prio: u8,
     targets: u8,
     cfg: u8,
+    // physical core a passthrough interrupt is pinned to, overrides the targets written by the guest
+    affinity: Option<usize>,
+    // targets last written by the guest, applied again when the pin is released
+    guest_targets: u8,
 
     in_pend: bool,
     in_act: bool,
@@ -201,6 +295,8 @@ impl VgicIntInnerMut {
             prio: 0xff,
             targets: 0,
             cfg: 0,
+            affinity: None,
+            guest_targets: 0,
             in_pend: false,
             in_act: false,
         }
@@ -215,6 +311,8 @@ impl VgicIntInnerMut {
             prio: 0xff,
             targets: targets as u8,
             cfg: 0,
+            affinity: None,
+            guest_targets: targets as u8,
             in_pend: false,
             in_act: false,
         }
@@ -237,8 +335,12 @@ impl Vgicd {
     fn new(cpu_num: usize) -> Self {
         Self {
             ctlr: AtomicU32::new(0),
+            #[cfg(not(feature = "gicv3"))]
             typer: (GICD.typer() & GICD_TYPER_ITLINESNUM_MSK)
                 | (((cpu_num as u32 - 1) << GICD_TYPER_CPUNUM_OFF) & GICD_TYPER_CPUNUM_MSK),
+            // the cpu number is not used with affinity routing
+            #[cfg(feature = "gicv3")]
+            typer: (GICD.typer() & GICD_TYPER_ITLINESNUM_MSK) | VGICD_TYPER_IDBITS,
             iidr: GICD.iidr(),
             interrupts: Vec::new(),
         }
@@ -311,6 +413,12 @@ pub struct Vgic {
     address_range: Range<usize>,
     vgicd: Vgicd,
     cpu_priv: Vec<VgicCpuPriv>,
+    // the LPI part of the redistributor of each vcpu
+    #[cfg(feature = "gicv3")]
+    redist_lpis: Vec<Mutex<VgicRedistLpi>>,
+    // the configuration of the mapped LPIs, loaded from the LPI configuration table of the guest
+    #[cfg(feature = "gicv3")]
+    lpi_config: Mutex<BTreeMap<usize, u8>>,
 }
 
 impl Vgic {
@@ -319,6 +427,10 @@ impl Vgic {
             address_range: base..base + length,
             vgicd: Vgicd::new(cpu_num),
             cpu_priv: Vec::new(),
+            #[cfg(feature = "gicv3")]
+            redist_lpis: (0..cpu_num).map(|_| Mutex::new(VgicRedistLpi::default())).collect(),
+            #[cfg(feature = "gicv3")]
+            lpi_config: Mutex::new(BTreeMap::new()),
         }
     }
 
@@ -380,6 +492,69 @@ impl Vgic {
         self.vgicd.iidr
     }
 
+    /* Save the state of the virtual distributor and all cpu interfaces.
+     * All vcpus of the vm must be paused before calling this function.
+     *
+     * @param[in] vm: the vm which this vgic belongs to.
+     */
+    pub fn migrate_save(&self, vm: &Vm) -> VgicMigrateState {
+        let cpu_priv = self
+            .cpu_priv
+            .iter()
+            .map(|cpu_priv| {
+                let inner = cpu_priv.inner_mut.borrow();
+                let mut interrupts = [cpu_priv.interrupts[0].migrate_save(vm); GIC_PRIVINT_NUM];
+                for (dst, interrupt) in interrupts.iter_mut().zip(cpu_priv.interrupts.iter()) {
+                    *dst = interrupt.migrate_save(vm);
+                }
+                VgicCpuMigrateState {
+                    interrupts,
+                    curr_lrs: inner.curr_lrs,
+                    sgis: inner.sgis,
+                }
+            })
+            .collect();
+        VgicMigrateState {
+            ctlr: self.vgicd_ctlr(),
+            cpu_priv,
+            interrupts: self.vgicd.interrupts.iter().map(|int| int.migrate_save(vm)).collect(),
+        }
+    }
+
+    /* Restore the state saved by migrate_save, before the vcpus of the vm are booted.
+     * Interrupts owned by a vcpu are put back to its pend/act list.
+     *
+     * @param[in] vm: the vm which this vgic belongs to.
+     * @param[in] state: the saved vgic state, must have the same vcpu number.
+     */
+    pub fn migrate_restore(&self, vm: &Vm, state: &VgicMigrateState) -> Result<(), ()> {
+        if state.cpu_priv.len() != self.cpu_priv.len() || state.interrupts.len() != self.vgicd.interrupts.len() {
+            return Err(());
+        }
+        self.set_vgicd_ctlr(state.ctlr);
+        for (vcpu_id, (cpu_priv, cpu_state)) in self.cpu_priv.iter().zip(state.cpu_priv.iter()).enumerate() {
+            {
+                let mut inner = cpu_priv.inner_mut.borrow_mut();
+                inner.curr_lrs = cpu_state.curr_lrs;
+                inner.sgis = cpu_state.sgis;
+                inner.pend_list.clear();
+                inner.act_list.clear();
+            }
+            let vcpu = vm.vcpu(vcpu_id).ok_or(())?;
+            for (interrupt, int_state) in cpu_priv.interrupts.iter().zip(cpu_state.interrupts.iter()) {
+                interrupt.migrate_restore(vm, int_state);
+                self.update_int_list(vcpu, interrupt);
+            }
+        }
+        for (interrupt, int_state) in self.vgicd.interrupts.iter().zip(state.interrupts.iter()) {
+            interrupt.migrate_restore(vm, int_state);
+            if let Some(owner) = interrupt.owner() {
+                self.update_int_list(&owner, interrupt);
+            }
+        }
+        Ok(())
+    }
+
     fn cpu_priv_interrupt(&self, cpu_id: usize, idx: usize) -> Option<&VgicInt> {
         self.cpu_priv[cpu_id].interrupts.get(idx)
     }
@@ -937,6 +1112,7 @@ impl Vgic {
     fn set_trgt(&self, vcpu: &Vcpu, int_id: usize, trgt: u8) {
         if let Some(interrupt) = self.get_int(vcpu, int_id) {
             let interrupt_lock = interrupt.lock.lock();
+            let trgt = interrupt.affinity().map_or(trgt, |cpu_id| (1 << cpu_id) as u8);
             if vgic_int_get_owner(vcpu.clone(), interrupt) {
                 if interrupt.targets() != trgt {
                     interrupt.set_targets(trgt);
@@ -974,13 +1150,61 @@ impl Vgic {
         }
     }
 
+    // the targets written by the guest, kept apart from a pin which overrides them
+    fn guest_set_trgt(&self, vcpu: &Vcpu, int_id: usize, trgt: u8) {
+        if let Some(interrupt) = self.get_int(vcpu, int_id) {
+            interrupt.set_guest_targets(trgt);
+        }
+        self.set_trgt(vcpu, int_id, trgt);
+    }
+
     fn get_trgt(&self, vcpu: &Vcpu, int_id: usize) -> u8 {
         self.get_int(vcpu, int_id).unwrap().targets()
     }
 
+    // pin a passthrough spi to a physical core of the vm, or release it to the guest routing with None
+    pub fn set_hw_int_affinity(&self, vm: &Vm, int_id: usize, cpu_id: Option<usize>) -> bool {
+        if int_id < GIC_PRIVINT_NUM {
+            return false;
+        }
+        let Some(interrupt) = self.get_int(vm.vcpu(0).unwrap(), int_id) else {
+            return false;
+        };
+        if !interrupt.hw() {
+            return false;
+        }
+        let pinned = interrupt.affinity();
+        interrupt.set_affinity(cpu_id);
+        // the owner protocol runs on the newly or previously pinned core, where the vm has a vcpu
+        let (ipi_cpu_id, trgt) = match (cpu_id, pinned) {
+            (Some(cpu_id), _) => (cpu_id, (1 << cpu_id) as u8),
+            (None, Some(pinned)) if interrupt.guest_targets() != 0 => (pinned, interrupt.guest_targets()),
+            // the guest has not written the targets, keep the physical routing of the pin
+            _ => return true,
+        };
+        let m = IpiInitcMessage {
+            event: InitcEvent::SetTrgt,
+            vm_id: vm.id(),
+            int_id: int_id as u16,
+            val: trgt,
+        };
+        if !ipi_send_msg(ipi_cpu_id, IpiType::Intc, IpiInnerMsg::Initc(m)) {
+            error!("set_hw_int_affinity: Failed to send ipi message, target {}", ipi_cpu_id);
+            return false;
+        }
+        true
+    }
+
     pub fn inject(&self, vcpu: &Vcpu, int_id: usize) {
         // println!("Core {} inject int {} to vm{}", current_cpu().id, int_id, vcpu.vm_id());
+        #[cfg(feature = "gicv3")]
+        if int_id >= GIC_LPI_BASE {
+            self.lpi_inject(vcpu, int_id);
+            return;
+        }
         if let Some(interrupt) = self.get_int(vcpu, bit_extract(int_id, 0, 10)) {
+            #[cfg(feature = "irq-latency")]
+            crate::kernel::irq_latency_inject(vcpu, interrupt.id() as usize, interrupt.hw());
             if interrupt.hw() {
                 let interrupt_lock = interrupt.lock.lock();
                 // interrupt.set_owner(vcpu.clone());
@@ -1004,7 +1228,10 @@ impl Vgic {
         if emu_ctx.write {
             let prev_ctlr = self.vgicd_ctlr();
             let idx = emu_ctx.reg;
+            #[cfg(not(feature = "gicv3"))]
             self.set_vgicd_ctlr(current_cpu().get_gpr(idx) as u32 & 0x1);
+            #[cfg(feature = "gicv3")]
+            self.set_vgicd_ctlr(current_cpu().get_gpr(idx) as u32 & VGICD_CTLR_ENABLE_MSK);
             if prev_ctlr ^ self.vgicd_ctlr() != 0 {
                 let enable = self.vgicd_ctlr() != 0;
                 let hcr = GICH.hcr();
@@ -1025,6 +1252,9 @@ impl Vgic {
         } else {
             let idx = emu_ctx.reg;
             let val = self.vgicd_ctlr() as usize;
+            // affinity routing is always enabled
+            #[cfg(feature = "gicv3")]
+            let val = val | VGICD_CTLR_ARE_NS as usize;
             current_cpu().set_gpr(idx, val);
         }
     }
@@ -1032,7 +1262,14 @@ impl Vgic {
     fn emu_typer_access(&self, emu_ctx: &EmuContext) {
         if !emu_ctx.write {
             let idx = emu_ctx.reg;
+            #[cfg(not(feature = "gicv3"))]
             let val = self.vgicd_typer() as usize;
+            // LPIs are only supported with a virtual ITS
+            #[cfg(feature = "gicv3")]
+            let val = match active_vm().unwrap().vits() {
+                Some(_) => (self.vgicd_typer() & !VGICD_TYPER_IDBITS) | VGICD_TYPER_LPI_IDBITS | GICD_TYPER_LPIS_BIT,
+                None => self.vgicd_typer(),
+            } as usize;
             current_cpu().set_gpr(idx, val);
         } else {
             println!("emu_typer_access: can't write to RO reg");
@@ -1345,22 +1582,7 @@ impl Vgic {
                     _ => {}
                 }
 
-                for i in 0..8 {
-                    if trgtlist & (1 << i) != 0 {
-                        let m = IpiInitcMessage {
-                            event: InitcEvent::SetPend,
-                            vm_id: active_vm().unwrap().id(),
-                            int_id: (bit_extract(val, 0, 8) | (active_vcpu_id() << 10)) as u16,
-                            val: true as u8,
-                        };
-                        if !ipi_send_msg(i, IpiType::Intc, IpiInnerMsg::Initc(m)) {
-                            error!(
-                                "emu_sgiregs_access: Failed to send ipi message, target {} type {}",
-                                i, 0
-                            );
-                        }
-                    }
-                }
+                vgic_send_sgi(&vm, bit_extract(val, 0, 8), trgtlist);
             }
         } else {
             // TODO: CPENDSGIR and SPENDSGIR access
@@ -1424,7 +1646,7 @@ impl Vgic {
             // println!("write");
             val = vgic_target_translate(&active_vm().unwrap(), val as u32, true) as usize;
             for i in 0..emu_ctx.width {
-                self.set_trgt(
+                self.guest_set_trgt(
                     current_cpu().active_vcpu.as_ref().unwrap(),
                     first_int + i,
                     bit_extract(val, GIC_TARGET_BITS * i, GIC_TARGET_BITS) as u8,
@@ -1444,6 +1666,66 @@ impl Vgic {
         }
     }
 
+    // GICv3 routes a SPI to the vcpu with the affinity in IROUTER
+    #[cfg(feature = "gicv3")]
+    fn emu_irouter_access(&self, emu_ctx: &EmuContext, offset: usize) {
+        let int_id = (offset - GICD_IROUTER_OFFSET) / 8;
+        // the upper word only has Aff3, which is always 0 for the vcpus
+        if int_id < GIC_PRIVINT_NUM || offset % 8 != 0 {
+            if !emu_ctx.write {
+                current_cpu().set_gpr(emu_ctx.reg, 0);
+            }
+            return;
+        }
+        let vm = active_vm().unwrap();
+        let vcpu = current_cpu().active_vcpu.as_ref().unwrap();
+
+        if emu_ctx.write {
+            if !vm.has_interrupt(int_id) {
+                warn!("emu_irouter_access: vm[{}] does not have interrupt {}", vm.id(), int_id);
+                return;
+            }
+            let val = current_cpu().get_gpr(emu_ctx.reg);
+            let target = if val & GICD_IROUTER_IRM_BIT != 0 {
+                // any vcpu, take the first one
+                vm.vcpu(0)
+            } else {
+                let affinity = gic_mpidr_to_affinity(val);
+                vm.vcpu_list()
+                    .iter()
+                    .find(|vcpu| gic_mpidr_to_affinity(vcpu.vmpidr()) == affinity)
+            };
+            match target {
+                Some(target) => self.guest_set_trgt(vcpu, int_id, (1 << target.phys_id()) as u8),
+                None => warn!("emu_irouter_access: no vcpu with affinity {:#x}", val),
+            }
+        } else {
+            let trgt = self.get_trgt(vcpu, int_id) as usize;
+            let val = (0..GIC_TARGETS_MAX)
+                .find(|cpu_id| trgt & (1 << cpu_id) != 0)
+                .and_then(|cpu_id| vm.pcpuid_to_vcpuid(cpu_id))
+                .and_then(|vcpu_id| vm.vcpu(vcpu_id))
+                .map_or(0, |target| gic_mpidr_to_affinity(target.vmpidr()));
+            current_cpu().set_gpr(emu_ctx.reg, val);
+        }
+    }
+
+    // the GICv3 distributor registers beyond the GICv2 ones
+    #[cfg(feature = "gicv3")]
+    fn emu_gicd_v3_access(&self, emu_ctx: &EmuContext, offset: usize) -> bool {
+        if (GICD_IROUTER_OFFSET..GICD_IROUTER_OFFSET + GIC_INTS_MAX * 8).contains(&offset) {
+            self.emu_irouter_access(emu_ctx, offset);
+        } else if !emu_ctx.write {
+            let val = if offset == GIC_PIDR2_OFFSET {
+                GIC_PIDR2_ARCH_GICV3
+            } else {
+                0
+            };
+            current_cpu().set_gpr(emu_ctx.reg, val as usize);
+        }
+        true
+    }
+
     fn handle_trapped_eoir(&self, vcpu: &Vcpu) {
         // if current_cpu().id == 2 {
         //     for i in 0..4 {
@@ -1463,6 +1745,8 @@ impl Vgic {
         ) {
             let lr_val = GICH.lr(lr_idx) as usize;
             GICH.set_lr(lr_idx, 0);
+            #[cfg(feature = "irq-latency")]
+            crate::kernel::irq_latency_eoi(vcpu, bit_extract(lr_val, 0, 10));
 
             match self.get_int(vcpu, bit_extract(lr_val, 0, 10)) {
                 Some(interrupt) => {
@@ -1539,6 +1823,8 @@ impl Vgic {
                 }
             }
         }
+        #[cfg(feature = "gicv3")]
+        self.lpi_refill(vcpu);
         // println!("end refill lrs");
     }
 
@@ -1546,6 +1832,8 @@ impl Vgic {
         let interrupt = self.int_list_head(vcpu, false);
         if let Some(int) = interrupt {
             vgic_int_get_owner(vcpu.clone(), int);
+            #[cfg(feature = "irq-latency")]
+            crate::kernel::irq_latency_eoi(vcpu, int.id() as usize);
 
             let state = int.state();
             int.set_state(state.clear_active());
@@ -1560,6 +1848,28 @@ impl Vgic {
     }
 }
 
+/* Send a SGI from the current vcpu.
+ *
+ * @param[in] vm: the vm of the current vcpu.
+ * @param[in] sgi: the SGI id.
+ * @param[in] trgtlist: mask of the physical cores running the target vcpus.
+ */
+fn vgic_send_sgi(vm: &Vm, sgi: usize, trgtlist: usize) {
+    for i in 0..8 {
+        if trgtlist & (1 << i) != 0 {
+            let m = IpiInitcMessage {
+                event: InitcEvent::SetPend,
+                vm_id: vm.id(),
+                int_id: (sgi | (active_vcpu_id() << 10)) as u16,
+                val: true as u8,
+            };
+            if !ipi_send_msg(i, IpiType::Intc, IpiInnerMsg::Initc(m)) {
+                error!("vgic_send_sgi: Failed to send ipi message, target {} type {}", i, 0);
+            }
+        }
+    }
+}
+
 fn vgic_target_translate(vm: &Vm, trgt: u32, v2p: bool) -> u32 {
     let from = trgt.to_le_bytes();
 
@@ -1939,6 +2249,13 @@ impl EmuDev for Vgic {
     }
 
     fn handler(&self, emu_ctx: &EmuContext) -> bool {
+        #[cfg(feature = "gicv3")]
+        {
+            let offset = emu_ctx.address - self.address_range.start;
+            if offset >= 0x1000 {
+                return self.emu_gicd_v3_access(emu_ctx, offset);
+            }
+        }
         let offset = emu_ctx.address & 0xfff;
         if emu_ctx.width > 4 {
             return false;
@@ -2051,5 +2368,330 @@ pub fn vgic_set_hw_int(vm: &Vm, int_id: usize) {
         }
     } else if let Some(interrupt) = vgic.get_int(vm.vcpu(0).unwrap(), int_id) {
         interrupt.set_hw(true);
+        if let Some(cpu_id) = vm.config().passthrough_irq_affinity(int_id) {
+            if vm.pcpuid_to_vcpuid(cpu_id).is_some() {
+                interrupt.set_affinity(Some(cpu_id));
+                interrupt.set_targets(1 << cpu_id);
+                GICD.set_trgt(int_id, 1 << Platform::cpuid_to_cpuif(cpu_id));
+            } else {
+                warn!(
+                    "VM[{}] has no vcpu on core {}, irq {} is not pinned",
+                    vm.id(),
+                    cpu_id,
+                    int_id
+                );
+            }
+        }
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "gicv3")] {
+        const VGICR_REG_OFFSET_CTLR: usize = 0x0000;
+        const VGICR_REG_OFFSET_IIDR: usize = 0x0004;
+        const VGICR_REG_OFFSET_TYPER: usize = 0x0008;
+        const VGICR_REG_OFFSET_PROPBASER: usize = 0x0070;
+        const VGICR_REG_OFFSET_PENDBASER: usize = 0x0078;
+        const VGICR_CTLR_ENABLE_LPIS_BIT: usize = 1 << 0;
+
+        // LPI configuration byte of the guest, priority [7:2] and enable [0]
+        const VGIC_LPI_PRIO_MSK: u8 = 0xfc;
+        const VGIC_LPI_ENABLE_BIT: u8 = 1 << 0;
+
+        #[derive(Default)]
+        struct VgicRedistLpi {
+            ctlr: usize,
+            propbaser: usize,
+            pendbaser: usize,
+            // LPIs waiting for a list register
+            pending: BTreeSet<usize>,
+        }
+
+        impl Vgic {
+            // make a LPI pending on the active vcpu, it is dropped if the vcpu has not enabled LPIs
+            fn lpi_inject(&self, vcpu: &Vcpu, int_id: usize) {
+                let mut redist = self.redist_lpis[vcpu.id()].lock();
+                if redist.ctlr & VGICR_CTLR_ENABLE_LPIS_BIT == 0 {
+                    return;
+                }
+                redist.pending.insert(int_id);
+                drop(redist);
+                self.lpi_refill(vcpu);
+            }
+
+            /* Move the enabled pending LPIs of the active vcpu to the list registers by priority. If the list
+             * registers are full, the rest wait for the maintenance interrupt of no pending.
+             *
+             * @param[in] vcpu: the active vcpu.
+             */
+            fn lpi_refill(&self, vcpu: &Vcpu) {
+                let mut redist = self.redist_lpis[vcpu.id()].lock();
+                let config = self.lpi_config.lock();
+                let mut ready: Vec<(u8, usize)> = redist
+                    .pending
+                    .iter()
+                    .filter_map(|int_id| {
+                        let cfg = *config.get(int_id)?;
+                        (cfg & VGIC_LPI_ENABLE_BIT != 0).then_some((cfg & VGIC_LPI_PRIO_MSK, *int_id))
+                    })
+                    .collect();
+                drop(config);
+                ready.sort_unstable();
+                for (prio, int_id) in ready {
+                    if !GICH.lr_lpi_pend(int_id) {
+                        let elrsr = GICH.elrsr(0);
+                        let Some(lr_idx) = (0..gic_lrs()).find(|i| elrsr & (1 << i) != 0) else {
+                            GICH.set_hcr(GICH.hcr() | (1 << 3));
+                            break;
+                        };
+                        GICH.set_lr_lpi(lr_idx, int_id, prio);
+                    }
+                    redist.pending.remove(&int_id);
+                }
+            }
+
+            // the guest LPI configuration table, which all redistributors share
+            fn lpi_propbaser(&self) -> Option<usize> {
+                self.redist_lpis.iter().find_map(|redist| {
+                    let redist = redist.lock();
+                    (redist.ctlr & VGICR_CTLR_ENABLE_LPIS_BIT != 0).then_some(redist.propbaser)
+                })
+            }
+
+            /* Reload the configuration of a LPI from the LPI configuration table of the guest, as the virtual ITS
+             * does on INV, INVALL and mapping the LPI.
+             *
+             * @param[in] vm: the VM of the vgic.
+             * @param[in] int_id: the LPI.
+             * @return: whether the LPI is enabled.
+             */
+            pub fn lpi_load_config(&self, vm: &Vm, int_id: usize) -> bool {
+                let Some(propbaser) = self.lpi_propbaser() else {
+                    return false;
+                };
+                // IDbits [4:0] is the number of interrupt id bits minus one
+                if int_id >= 1 << (bit_extract(propbaser, 0, 5) + 1) {
+                    return false;
+                }
+                let ipa = (bit_extract(propbaser, 12, 40) << 12) + int_id - GIC_LPI_BASE;
+                let hva = vm.ipa2hva(ipa);
+                if hva == 0 {
+                    warn!("lpi_load_config: VM {} illegal LPI configuration at ipa {:#x}", vm.id(), ipa);
+                    return false;
+                }
+                // SAFETY: the address is in the memory of the VM
+                let cfg = unsafe { (hva as *const u8).read_volatile() };
+                self.lpi_config.lock().insert(int_id, cfg);
+                cfg & VGIC_LPI_ENABLE_BIT != 0
+            }
+
+            // forget a LPI which is unmapped, along with its pending state
+            pub fn lpi_drop(&self, int_id: usize) {
+                self.lpi_config.lock().remove(&int_id);
+                for redist in self.redist_lpis.iter() {
+                    redist.lock().pending.remove(&int_id);
+                }
+            }
+
+            // the LPIs are disabled by the reset of the redistributors
+            pub fn lpi_reset(&self) {
+                self.lpi_config.lock().clear();
+                for redist in self.redist_lpis.iter() {
+                    *redist.lock() = VgicRedistLpi::default();
+                }
+            }
+
+            pub fn lpi_is_pending(&self, vcpu_id: usize, int_id: usize) -> bool {
+                self.redist_lpis[vcpu_id].lock().pending.contains(&int_id)
+            }
+
+            pub fn lpi_clear_pending(&self, vcpu_id: usize, int_id: usize) {
+                self.redist_lpis[vcpu_id].lock().pending.remove(&int_id);
+            }
+
+            // take all LPIs waiting for the vcpu, which are moved to another vcpu
+            pub fn lpi_take_pending(&self, vcpu_id: usize) -> BTreeSet<usize> {
+                core::mem::take(&mut self.redist_lpis[vcpu_id].lock().pending)
+            }
+
+            /* Read a LPI register of the redistributor of a vcpu.
+             *
+             * @param[in] vcpu_id: the vcpu.
+             * @param[in] offset: the offset in the RD frame, the upper half of a 64 bits register is at +4.
+             */
+            fn lpi_rd_read(&self, vcpu_id: usize, offset: usize) -> usize {
+                let redist = self.redist_lpis[vcpu_id].lock();
+                let val = match offset & !0x7 {
+                    VGICR_REG_OFFSET_CTLR => redist.ctlr,
+                    VGICR_REG_OFFSET_PROPBASER => redist.propbaser,
+                    VGICR_REG_OFFSET_PENDBASER => redist.pendbaser,
+                    _ => 0,
+                };
+                if offset & 0x4 != 0 {
+                    val >> 32
+                } else {
+                    val
+                }
+            }
+
+            /* Write a LPI register of the redistributor of a vcpu. The tables are fixed once LPIs are enabled.
+             *
+             * @param[in] vcpu_id: the vcpu.
+             * @param[in] offset: the offset in the RD frame, the upper half of a 64 bits register is at +4.
+             * @param[in] width: the access width.
+             * @param[in] val: the written value.
+             */
+            fn lpi_rd_write(&self, vcpu_id: usize, offset: usize, width: usize, val: usize) {
+                let mut redist = self.redist_lpis[vcpu_id].lock();
+                if offset == VGICR_REG_OFFSET_CTLR {
+                    redist.ctlr = val & VGICR_CTLR_ENABLE_LPIS_BIT;
+                    return;
+                }
+                if redist.ctlr & VGICR_CTLR_ENABLE_LPIS_BIT != 0 {
+                    return;
+                }
+                let reg = match offset & !0x7 {
+                    VGICR_REG_OFFSET_PROPBASER => &mut redist.propbaser,
+                    VGICR_REG_OFFSET_PENDBASER => &mut redist.pendbaser,
+                    _ => return,
+                };
+                *reg = match (width, offset & 0x4 != 0) {
+                    (8, _) => val,
+                    (_, false) => (*reg & !0xffff_ffff) | (val & 0xffff_ffff),
+                    (_, true) => (*reg & 0xffff_ffff) | (val << 32),
+                };
+            }
+        }
+
+
+        // The redistributors of a GICv3 guest, one frame for each vcpu. The SGI frame has the same layout as the
+        // private interrupt part of the distributor, so it goes to the vgicd handler.
+        pub struct VgicRedist {
+            address_range: Range<usize>,
+        }
+
+        impl VgicRedist {
+            fn rd_read(&self, vm: &Vm, vcpu: &Vcpu, offset: usize) -> usize {
+                let typer = || {
+                    let last = if vcpu.id() + 1 == vm.cpu_num() { GICR_TYPER_LAST_BIT } else { 0 };
+                    // physical LPIs of the guest are the virtual LPIs translated by the virtual ITS
+                    let plpis = if vm.vits().is_some() { GICR_TYPER_PLPIS_BIT } else { 0 };
+                    (gic_mpidr_to_typer_affinity(vcpu.vmpidr()) << 32) | (vcpu.id() << 8) | last | plpis
+                };
+                match offset {
+                    VGICR_REG_OFFSET_IIDR => vm.vgic().vgicd_iidr() as usize,
+                    VGICR_REG_OFFSET_TYPER => typer(),
+                    0x000c => typer() >> 32,
+                    GIC_PIDR2_OFFSET => GIC_PIDR2_ARCH_GICV3 as usize,
+                    VGICR_REG_OFFSET_CTLR | VGICR_REG_OFFSET_PROPBASER..=0x007f => {
+                        vm.vgic().lpi_rd_read(vcpu.id(), offset)
+                    }
+                    // WAKER of a redistributor which is always awake
+                    _ => 0,
+                }
+            }
+
+            // only the LPI registers are writable, and only with a virtual ITS
+            fn rd_write(&self, vm: &Vm, vcpu: &Vcpu, offset: usize, width: usize, val: usize) {
+                if vm.vits().is_some() {
+                    vm.vgic().lpi_rd_write(vcpu.id(), offset, width, val);
+                }
+            }
+        }
+
+        impl EmuDev for VgicRedist {
+            fn emu_type(&self) -> EmuDeviceType {
+                EmuDeviceType::EmuDeviceTGicr
+            }
+
+            fn address_range(&self) -> Range<usize> {
+                self.address_range.clone()
+            }
+
+            fn handler(&self, emu_ctx: &EmuContext) -> bool {
+                let offset = emu_ctx.address - self.address_range.start;
+                let vm = active_vm().unwrap();
+                let Some(vcpu) = vm.vcpu(offset / GICR_FRAME_SIZE) else {
+                    warn!("vgicr: access to the frame of a nonexistent vcpu, offset {:#x}", offset);
+                    return false;
+                };
+                let frame_offset = offset % GICR_FRAME_SIZE;
+                if frame_offset < GICR_SGI_OFFSET {
+                    if emu_ctx.write {
+                        let val = current_cpu().get_gpr(emu_ctx.reg);
+                        self.rd_write(&vm, vcpu, frame_offset, emu_ctx.width, val);
+                    } else {
+                        let val = self.rd_read(&vm, vcpu, frame_offset);
+                        current_cpu().set_gpr(emu_ctx.reg, val);
+                    }
+                    return true;
+                }
+                // the private interrupts of other vcpus are not accessible
+                if vcpu.id() != active_vcpu_id() {
+                    warn!(
+                        "vgicr: vcpu {} accesses the SGI frame of vcpu {}",
+                        active_vcpu_id(),
+                        vcpu.id()
+                    );
+                    if !emu_ctx.write {
+                        current_cpu().set_gpr(emu_ctx.reg, 0);
+                    }
+                    return true;
+                }
+                let vgic = vm.vgic();
+                let vgicd_ctx = EmuContext {
+                    address: vgic.address_range.start + frame_offset - GICR_SGI_OFFSET,
+                    ..*emu_ctx
+                };
+                vgic.handler(&vgicd_ctx)
+            }
+        }
+
+        pub fn emu_vgicr_init(emu_cfg: &VmEmulatedDeviceConfig, cpu_num: usize) -> Result<Arc<dyn EmuDev>, ()> {
+            if emu_cfg.emu_type != EmuDeviceType::EmuDeviceTGicr || emu_cfg.length < cpu_num * GICR_FRAME_SIZE {
+                error!("emu_vgicr_init: illegal config of {}", emu_cfg.name);
+                return Err(());
+            }
+            Ok(Arc::new(VgicRedist {
+                address_range: emu_cfg.base_ipa..emu_cfg.base_ipa + emu_cfg.length,
+            }))
+        }
+
+        const ICC_SGI1R_IRM_BIT: usize = 1 << 40;
+
+        /* The guest sends SGIs by ICC_SGI1R_EL1, which always traps to EL2 with HCR_EL2.IMO set.
+         * The targets are the vcpus with the affinity Aff3.Aff2.Aff1.<RS * 16 + n> for each bit n of the TargetList,
+         * or all other vcpus if IRM is set.
+         */
+        fn vgic_icc_sgi1r_handler(_id: usize, emu_ctx: &EmuContext) -> bool {
+            if !emu_ctx.write {
+                return false;
+            }
+            let val = current_cpu().get_gpr(emu_ctx.reg);
+            let vm = active_vm().unwrap();
+            let vcpu_id = active_vcpu_id();
+            let targets = vm.vcpu_list().iter().filter(|vcpu| {
+                if val & ICC_SGI1R_IRM_BIT != 0 {
+                    return vcpu.id() != vcpu_id;
+                }
+                let affinity = gic_mpidr_to_affinity(vcpu.vmpidr());
+                let aff0 = bit_extract(affinity, 0, 8);
+                let aff0_base = bit_extract(val, 44, 4) * 16;
+                let upper =
+                    (bit_extract(val, 48, 8) << 32) | (bit_extract(val, 32, 8) << 16) | (bit_extract(val, 16, 8) << 8);
+                affinity & !0xff == upper
+                    && (aff0_base..aff0_base + 16).contains(&aff0)
+                    && bit_get(val, aff0 - aff0_base) != 0
+            });
+            let trgtlist = targets.fold(0, |mask, vcpu| mask | (1 << vcpu.phys_id()));
+            vgic_send_sgi(&vm, bit_extract(val, 24, 4), trgtlist);
+            true
+        }
+
+        // register the system registers trapped for GICv3 guests, called once on the boot core
+        pub fn vgic_sysreg_init() {
+            const ICC_SGI1R_EL1_ADDR: usize = sysreg_encode_addr!(0b11, 0b000, 0b1100, 0b1011, 0b101);
+            emu_register_reg(EmuRegType::SysReg, ICC_SGI1R_EL1_ADDR, vgic_icc_sgi1r_handler);
+        }
     }
 }
@@ -0,0 +1,485 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use spin::Mutex;
+
+use crate::arch::PAGE_SIZE;
+use crate::config::VmEmulatedDeviceConfig;
+use crate::device::{EmuContext, EmuDev, EmuDeviceType};
+use crate::kernel::{active_vm, copy_segment_from_vm, current_cpu, interrupt_vm_inject, ipi_send_msg, vm_by_id};
+use crate::kernel::{IpiInnerMsg, IpiIntInjectMsg, IpiType, Vm};
+use crate::util::bit_extract;
+
+use super::gic::{GIC_LPI_BASE, GIC_PIDR2_ARCH_GICV3, GIC_PIDR2_OFFSET};
+use super::its::*;
+
+// The virtual ITS of a GICv3 guest. The device table, the interrupt translation tables and the collection table
+// are kept by the hypervisor instead of the guest memory, so GITS_BASER<n> are RAZ/WI and all collections are
+// held in the ITS (GITS_TYPER.HCC). The collection target is the processor number of the vcpu (GITS_TYPER.PTA
+// is 0). The events of the passthrough devices are mapped on the physical ITS as well, whose LPIs are injected
+// as the virtual LPIs the guest mapped.
+
+const VGITS_REG_OFFSET_CTLR: usize = 0x0000;
+const VGITS_REG_OFFSET_IIDR: usize = 0x0004;
+const VGITS_REG_OFFSET_TYPER: usize = 0x0008;
+const VGITS_REG_OFFSET_CBASER: usize = 0x0080;
+const VGITS_REG_OFFSET_CWRITER: usize = 0x0088;
+const VGITS_REG_OFFSET_CREADR: usize = 0x0090;
+
+const VGITS_CTLR_ENABLED_BIT: usize = 1 << 0;
+const VGITS_CTLR_QUIESCENT_BIT: usize = 1 << 31;
+const VGITS_CBASER_VALID_BIT: usize = 1 << 63;
+// the implementer of the GIC in the layout of GITS_IIDR, Arm
+const VGITS_IIDR: usize = 0x43b;
+const VGITS_ITT_ENTRY_SIZE: usize = 8;
+const VGITS_EVENT_BITS: usize = 16;
+// the DeviceIDs of the emulated PCIe functions follow those of the passthrough devices, see PCI_MSI_DEVID_BASE
+const VGITS_DEVICE_BITS: usize = 17;
+// the LPI ids of the guest have 16 bits, see VGICD_TYPER_LPI_IDBITS
+const VGITS_LPI_MAX: usize = 1 << 16;
+const VGITS_FRAME_SIZE: usize = 0x20000;
+
+#[derive(Clone, Copy)]
+struct VitsEvent {
+    int_id: usize,
+    icid: usize,
+}
+
+struct VitsDevice {
+    event_bits: usize,
+    // the events of a passthrough device are mapped on the physical ITS as well
+    passthrough: bool,
+    events: BTreeMap<usize, VitsEvent>,
+}
+
+#[derive(Default)]
+struct VgicItsInner {
+    ctlr: usize,
+    cbaser: usize,
+    cwriter: usize,
+    creadr: usize,
+    devices: BTreeMap<usize, VitsDevice>,
+    // collection id to the target vcpu
+    collections: BTreeMap<usize, usize>,
+}
+
+pub struct VgicIts {
+    address_range: Range<usize>,
+    inner: Mutex<VgicItsInner>,
+}
+
+/* Make a virtual LPI pending on a vcpu of a VM, on the core running the vcpu.
+ *
+ * @param[in] vm: the VM.
+ * @param[in] vcpu_id: the target vcpu.
+ * @param[in] int_id: the virtual LPI.
+ */
+fn vgic_its_inject(vm: &Vm, vcpu_id: usize, int_id: usize) {
+    let Some(vcpu) = vm.vcpu(vcpu_id) else {
+        return;
+    };
+    if vcpu.phys_id() == current_cpu().id {
+        interrupt_vm_inject(vm, vcpu, int_id);
+    } else {
+        let m = IpiIntInjectMsg { vm_id: vm.id(), int_id };
+        if !ipi_send_msg(vcpu.phys_id(), IpiType::IntInject, IpiInnerMsg::IntInjectMsg(m)) {
+            error!("vgic_its_inject: failed to send ipi to Core {}", vcpu.phys_id());
+        }
+    }
+}
+
+/* Route an event of a passthrough device on the physical ITS to the core running the vcpu of its collection.
+ * The physical event is unmapped while the collection is not mapped, as the ITS drops the event.
+ *
+ * @param[in] vm: the VM owning the device.
+ * @param[in] devid: the DeviceID of the device.
+ * @param[in] eventid: the EventID.
+ * @param[in] event: the virtual LPI and the collection of the event.
+ * @param[in] collections: the collections of the virtual ITS.
+ */
+fn vgic_its_route_passthrough_event(
+    vm: &Vm,
+    devid: usize,
+    eventid: usize,
+    event: VitsEvent,
+    collections: &BTreeMap<usize, usize>,
+) {
+    let Some(vcpu) = collections.get(&event.icid).and_then(|&vcpu_id| vm.vcpu(vcpu_id)) else {
+        its_unmap_event(vm.id(), devid, eventid);
+        return;
+    };
+    if its_move_event(vm.id(), devid, eventid, vcpu.phys_id(), vcpu.id()) {
+        return;
+    }
+    let route = ItsLpiRoute {
+        vm_id: vm.id(),
+        vcpu_id: vcpu.id(),
+        int_id: event.int_id,
+    };
+    if !its_map_event(devid, eventid, vcpu.phys_id(), route) {
+        warn!(
+            "VM {} vits: failed to map event {} of passthrough device {:#x}",
+            vm.id(),
+            eventid,
+            devid
+        );
+    }
+}
+
+impl VgicIts {
+    fn typer(vm: &Vm) -> usize {
+        // Physical [0], ITT_entry_size [7:4], IDbits [12:8], Devbits [17:13], HCC [31:24]
+        1 | ((VGITS_ITT_ENTRY_SIZE - 1) << 4)
+            | ((VGITS_EVENT_BITS - 1) << 8)
+            | ((VGITS_DEVICE_BITS - 1) << 13)
+            | (vm.cpu_num() << 24)
+    }
+
+    // the target vcpu and the virtual LPI of an event
+    fn translate(inner: &VgicItsInner, devid: usize, eventid: usize) -> Option<(usize, usize)> {
+        let event = inner.devices.get(&devid)?.events.get(&eventid)?;
+        Some((*inner.collections.get(&event.icid)?, event.int_id))
+    }
+
+    // forget a device mapping along with the LPIs of its events
+    fn unmap_device(vm: &Vm, inner: &mut VgicItsInner, devid: usize) {
+        if let Some(dev) = inner.devices.remove(&devid) {
+            for event in dev.events.values() {
+                vm.vgic().lpi_drop(event.int_id);
+            }
+            if dev.passthrough {
+                its_unmap_device(vm.id(), devid);
+            }
+        }
+    }
+
+    fn unmap_event(vm: &Vm, inner: &mut VgicItsInner, devid: usize, eventid: usize) {
+        let Some(dev) = inner.devices.get_mut(&devid) else {
+            return;
+        };
+        if let Some(event) = dev.events.remove(&eventid) {
+            vm.vgic().lpi_drop(event.int_id);
+            if dev.passthrough {
+                its_unmap_event(vm.id(), devid, eventid);
+            }
+        }
+    }
+
+    // reload the configuration of the LPI of an event, a pending LPI is delivered if it becomes enabled
+    fn invalidate(vm: &Vm, inner: &VgicItsInner, devid: usize, eventid: usize) {
+        let Some((vcpu_id, int_id)) = Self::translate(inner, devid, eventid) else {
+            return;
+        };
+        let vgic = vm.vgic();
+        if vgic.lpi_load_config(vm, int_id) && vgic.lpi_is_pending(vcpu_id, int_id) {
+            vgic_its_inject(vm, vcpu_id, int_id);
+        }
+    }
+
+    /* Execute a command of the guest command queue.
+     *
+     * @param[in] vm: the VM of the virtual ITS.
+     * @param[in] inner: the state of the virtual ITS.
+     * @param[in] cmd: the 32 bytes command.
+     */
+    fn command(vm: &Vm, inner: &mut VgicItsInner, cmd: &[usize; 4]) {
+        let devid = bit_extract(cmd[0], 32, 32);
+        let eventid = bit_extract(cmd[1], 0, 32);
+        let icid = bit_extract(cmd[2], 0, 16);
+        let valid = cmd[2] & (1 << 63) != 0;
+        match bit_extract(cmd[0], 0, 8) {
+            ITS_CMD_MAPD => {
+                Self::unmap_device(vm, inner, devid);
+                if !valid {
+                    return;
+                }
+                if devid >= 1 << VGITS_DEVICE_BITS {
+                    warn!("VM {} vits: MAPD of illegal device {:#x}", vm.id(), devid);
+                    return;
+                }
+                let event_bits = usize::min(bit_extract(cmd[1], 0, 5) + 1, VGITS_EVENT_BITS);
+                let passthrough = vm.config().passthrough_device_msi_ids().contains(&devid);
+                if passthrough && !its_map_device(vm.id(), devid, event_bits) {
+                    warn!("VM {} vits: failed to map passthrough device {:#x}", vm.id(), devid);
+                }
+                let dev = VitsDevice {
+                    event_bits,
+                    passthrough,
+                    events: BTreeMap::new(),
+                };
+                inner.devices.insert(devid, dev);
+            }
+            ITS_CMD_MAPC => {
+                // RDbase [50:16] is the processor number of the target redistributor
+                let vcpu_id = bit_extract(cmd[2], 16, 35);
+                if valid && vcpu_id < vm.cpu_num() {
+                    inner.collections.insert(icid, vcpu_id);
+                } else {
+                    inner.collections.remove(&icid);
+                }
+                // the passthrough events follow the collection to the new vcpu
+                for (&devid, dev) in inner.devices.iter().filter(|(_, dev)| dev.passthrough) {
+                    for (&eventid, &event) in dev.events.iter().filter(|(_, event)| event.icid == icid) {
+                        vgic_its_route_passthrough_event(vm, devid, eventid, event, &inner.collections);
+                    }
+                }
+            }
+            cmd_id @ (ITS_CMD_MAPTI | ITS_CMD_MAPI) => {
+                let int_id = if cmd_id == ITS_CMD_MAPTI {
+                    bit_extract(cmd[1], 32, 32)
+                } else {
+                    eventid
+                };
+                Self::unmap_event(vm, inner, devid, eventid);
+                let Some(dev) = inner.devices.get_mut(&devid) else {
+                    warn!(
+                        "VM {} vits: map event {} of unmapped device {:#x}",
+                        vm.id(),
+                        eventid,
+                        devid
+                    );
+                    return;
+                };
+                if eventid >= 1 << dev.event_bits || !(GIC_LPI_BASE..VGITS_LPI_MAX).contains(&int_id) {
+                    warn!(
+                        "VM {} vits: illegal event {} LPI {} of device {:#x}",
+                        vm.id(),
+                        eventid,
+                        int_id,
+                        devid
+                    );
+                    return;
+                }
+                let event = VitsEvent { int_id, icid };
+                dev.events.insert(eventid, event);
+                if dev.passthrough {
+                    vgic_its_route_passthrough_event(vm, devid, eventid, event, &inner.collections);
+                }
+                vm.vgic().lpi_load_config(vm, int_id);
+            }
+            ITS_CMD_DISCARD => Self::unmap_event(vm, inner, devid, eventid),
+            ITS_CMD_MOVI => {
+                let Some((prev_vcpu_id, int_id)) = Self::translate(inner, devid, eventid) else {
+                    return;
+                };
+                let Some(dev) = inner.devices.get_mut(&devid) else {
+                    return;
+                };
+                let Some(event) = dev.events.get_mut(&eventid) else {
+                    return;
+                };
+                event.icid = icid;
+                let event = *event;
+                if dev.passthrough {
+                    vgic_its_route_passthrough_event(vm, devid, eventid, event, &inner.collections);
+                }
+                let Some(&vcpu_id) = inner.collections.get(&icid) else {
+                    return;
+                };
+                let vgic = vm.vgic();
+                if vcpu_id != prev_vcpu_id && vgic.lpi_is_pending(prev_vcpu_id, int_id) {
+                    vgic.lpi_clear_pending(prev_vcpu_id, int_id);
+                    vgic_its_inject(vm, vcpu_id, int_id);
+                }
+            }
+            ITS_CMD_INV => Self::invalidate(vm, inner, devid, eventid),
+            ITS_CMD_INVALL => {
+                for (&devid, dev) in inner.devices.iter() {
+                    for (&eventid, _) in dev.events.iter().filter(|(_, event)| event.icid == icid) {
+                        Self::invalidate(vm, inner, devid, eventid);
+                    }
+                }
+            }
+            ITS_CMD_INT => {
+                if let Some((vcpu_id, int_id)) = Self::translate(inner, devid, eventid) {
+                    vgic_its_inject(vm, vcpu_id, int_id);
+                }
+            }
+            ITS_CMD_CLEAR => {
+                if let Some((vcpu_id, int_id)) = Self::translate(inner, devid, eventid) {
+                    vm.vgic().lpi_clear_pending(vcpu_id, int_id);
+                }
+            }
+            ITS_CMD_MOVALL => {
+                let from = bit_extract(cmd[2], 16, 35);
+                let to = bit_extract(cmd[3], 16, 35);
+                if from < vm.cpu_num() && to < vm.cpu_num() && from != to {
+                    for int_id in vm.vgic().lpi_take_pending(from) {
+                        vgic_its_inject(vm, to, int_id);
+                    }
+                }
+            }
+            // the commands take effect once they are read
+            ITS_CMD_SYNC => {}
+            cmd_id => {
+                warn!("VM {} vits: unsupported command {:#x}", vm.id(), cmd_id);
+            }
+        }
+    }
+
+    // read the commands from GITS_CREADR up to GITS_CWRITER
+    fn process_commands(&self, vm: &Vm, inner: &mut VgicItsInner) {
+        if inner.ctlr & VGITS_CTLR_ENABLED_BIT == 0 || inner.cbaser & VGITS_CBASER_VALID_BIT == 0 {
+            return;
+        }
+        // Physical_Address [51:12], Size [7:0] in 4K pages minus one
+        let base = bit_extract(inner.cbaser, 12, 40) << 12;
+        let size = (bit_extract(inner.cbaser, 0, 8) + 1) * PAGE_SIZE;
+        if inner.cwriter >= size {
+            warn!(
+                "VM {} vits: GITS_CWRITER {:#x} beyond the queue",
+                vm.id(),
+                inner.cwriter
+            );
+            return;
+        }
+        while inner.creadr != inner.cwriter {
+            let mut cmd = [0usize; 4];
+            copy_segment_from_vm(vm, &mut cmd, base + inner.creadr);
+            Self::command(vm, inner, &cmd);
+            inner.creadr = (inner.creadr + ITS_CMD_SIZE) % size;
+        }
+    }
+
+    fn read(&self, vm: &Vm, offset: usize) -> usize {
+        let inner = self.inner.lock();
+        let val = match offset & !0x7 {
+            VGITS_REG_OFFSET_TYPER => Self::typer(vm),
+            VGITS_REG_OFFSET_CBASER => inner.cbaser,
+            VGITS_REG_OFFSET_CWRITER => inner.cwriter,
+            VGITS_REG_OFFSET_CREADR => inner.creadr,
+            _ => {
+                return match offset {
+                    // the commands are done synchronously, the ITS is always quiescent
+                    VGITS_REG_OFFSET_CTLR => inner.ctlr | VGITS_CTLR_QUIESCENT_BIT,
+                    VGITS_REG_OFFSET_IIDR => VGITS_IIDR,
+                    GIC_PIDR2_OFFSET => GIC_PIDR2_ARCH_GICV3 as usize,
+                    _ => 0,
+                };
+            }
+        };
+        if offset & 0x4 != 0 {
+            val >> 32
+        } else {
+            val
+        }
+    }
+
+    fn write(&self, vm: &Vm, offset: usize, width: usize, val: usize) {
+        let mut inner = self.inner.lock();
+        let merge = |reg: usize| match (width, offset & 0x4 != 0) {
+            (8, _) => val,
+            (_, false) => (reg & !0xffff_ffff) | (val & 0xffff_ffff),
+            (_, true) => (reg & 0xffff_ffff) | (val << 32),
+        };
+        if offset == VGITS_REG_OFFSET_CTLR {
+            inner.ctlr = val & VGITS_CTLR_ENABLED_BIT;
+            self.process_commands(vm, &mut inner);
+            return;
+        }
+        match offset & !0x7 {
+            // the command queue is fixed while the ITS is enabled
+            VGITS_REG_OFFSET_CBASER if inner.ctlr & VGITS_CTLR_ENABLED_BIT == 0 => {
+                inner.cbaser = merge(inner.cbaser);
+                inner.creadr = 0;
+            }
+            VGITS_REG_OFFSET_CWRITER => {
+                // Offset [19:5]
+                inner.cwriter = merge(inner.cwriter) & 0xfffe0;
+                self.process_commands(vm, &mut inner);
+            }
+            // GITS_TRANSLATER is written by devices, not by the guest
+            _ => {}
+        }
+    }
+
+    /* Deliver a MSI of an emulated device, as if the device wrote the EventID to GITS_TRANSLATER.
+     *
+     * @param[in] vm: the VM of the virtual ITS.
+     * @param[in] addr: the address the MSI is written to.
+     * @param[in] devid: the DeviceID of the device.
+     * @param[in] eventid: the data of the MSI.
+     * @return: false if the address is not GITS_TRANSLATER.
+     */
+    pub fn msi_write(&self, vm: &Vm, addr: usize, devid: usize, eventid: usize) -> bool {
+        if addr != self.address_range.start + GITS_TRANSLATER {
+            return false;
+        }
+        // the ITS drops a MSI which is not mapped
+        let target = Self::translate(&self.inner.lock(), devid, eventid);
+        if let Some((vcpu_id, int_id)) = target {
+            vgic_its_inject(vm, vcpu_id, int_id);
+        }
+        true
+    }
+
+    // the GITS_TRANSLATER frame, which the passthrough devices write their MSIs to
+    pub fn translater_ipa(&self) -> usize {
+        self.address_range.start + GITS_TRANSLATER_FRAME
+    }
+
+    // drop all mappings when the VM is reset, the guest sets up its ITS again
+    pub fn reset(&self, vm: &Vm) {
+        let mut inner = self.inner.lock();
+        let devids: Vec<usize> = inner.devices.keys().copied().collect();
+        for devid in devids {
+            Self::unmap_device(vm, &mut inner, devid);
+        }
+        *inner = VgicItsInner::default();
+        vm.vgic().lpi_reset();
+    }
+}
+
+impl EmuDev for VgicIts {
+    fn emu_type(&self) -> EmuDeviceType {
+        EmuDeviceType::EmuDeviceTGits
+    }
+
+    fn address_range(&self) -> Range<usize> {
+        self.address_range.clone()
+    }
+
+    fn handler(&self, emu_ctx: &EmuContext) -> bool {
+        let offset = emu_ctx.address - self.address_range.start;
+        if emu_ctx.width < 4 || offset & (emu_ctx.width - 1) != 0 {
+            return false;
+        }
+        let vm = active_vm().unwrap();
+        if emu_ctx.write {
+            let val = current_cpu().get_gpr(emu_ctx.reg);
+            self.write(&vm, offset, emu_ctx.width, val);
+        } else {
+            let val = self.read(&vm, offset);
+            current_cpu().set_gpr(emu_ctx.reg, val);
+        }
+        true
+    }
+}
+
+pub fn emu_vgits_init(emu_cfg: &VmEmulatedDeviceConfig) -> Result<Arc<dyn EmuDev>, ()> {
+    if emu_cfg.emu_type != EmuDeviceType::EmuDeviceTGits || emu_cfg.length < VGITS_FRAME_SIZE {
+        error!("emu_vgits_init: illegal config of {}", emu_cfg.name);
+        return Err(());
+    }
+    Ok(Arc::new(VgicIts {
+        address_range: emu_cfg.base_ipa..emu_cfg.base_ipa + emu_cfg.length,
+        inner: Mutex::new(VgicItsInner::default()),
+    }))
+}
+
+// a physical LPI of a passthrough device, which is injected as the virtual LPI the guest mapped
+pub fn vgic_its_lpi_handler(lpi: usize) {
+    let Some(route) = its_lpi_route(lpi) else {
+        warn!(
+            "vgic_its_lpi_handler: core {} receives unmapped LPI {}",
+            current_cpu().id,
+            lpi
+        );
+        return;
+    };
+    if let Some(vm) = vm_by_id(route.vm_id) {
+        vgic_its_inject(&vm, route.vcpu_id, route.int_id);
+    }
+}
@@ -15,6 +15,7 @@ impl PlatOperation for Platform {
     const UART_1_INT: usize = 32 + 0x79;
 
     const HYPERVISOR_UART_BASE: usize = Self::UART_0_ADDR;
+    const HYPERVISOR_UART_INT: usize = Self::UART_0_INT;
 
     const GICD_BASE: usize = 0xFF841000;
     const GICC_BASE: usize = 0xFF842000;
@@ -48,18 +49,22 @@ pub static PLAT_DESC: PlatformConfig = PlatformConfig {
             PlatCpuCoreConfig {
                 mpidr: 0x80000000,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
             PlatCpuCoreConfig {
                 mpidr: 0x80000001,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
             PlatCpuCoreConfig {
                 mpidr: 0x80000002,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
             PlatCpuCoreConfig {
                 mpidr: 0x80000003,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
         ],
     },
@@ -9,6 +9,23 @@ pub enum SchedRule {
     RoundRobin,
     #[cfg(feature = "rt-sched")]
     RealTime,
+    #[cfg(feature = "rt-sched")]
+    FixedPriority,
+}
+
+impl TryFrom<usize> for SchedRule {
+    type Error = ();
+
+    fn try_from(value: usize) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(SchedRule::RoundRobin),
+            #[cfg(feature = "rt-sched")]
+            1 => Ok(SchedRule::RealTime),
+            #[cfg(feature = "rt-sched")]
+            2 => Ok(SchedRule::FixedPriority),
+            _ => Err(()),
+        }
+    }
 }
 
 pub struct PlatMemoryConfig {
@@ -19,6 +36,8 @@ pub struct PlatMemoryConfig {
 pub struct PlatCpuCoreConfig {
     pub mpidr: usize,
     pub sched: SchedRule,
+    // time slice of the round robin scheduler in milliseconds
+    pub slice: usize,
 }
 
 pub struct PlatCpuConfig {
@@ -49,12 +68,18 @@ pub trait PlatOperation {
     const UART_0_INT: usize = usize::MAX;
     const UART_1_INT: usize = usize::MAX;
     const UART_2_INT: usize = usize::MAX;
+    // receive interrupt of the hypervisor used uart
+    const HYPERVISOR_UART_INT: usize = usize::MAX;
 
     // must offer interrupt controller
     const GICD_BASE: usize;
     const GICC_BASE: usize;
     const GICH_BASE: usize;
     const GICV_BASE: usize;
+    // redistributors of GICv3
+    const GICR_BASE: usize = usize::MAX;
+    // interrupt translation service of GICv3, which turns the MSIs of PCIe devices into LPIs
+    const GITS_BASE: usize = usize::MAX;
 
     fn cpu_on(arch_core_id: usize, entry: usize, ctx: usize) {
         crate::arch::power_arch_cpu_on(arch_core_id, entry, ctx);
@@ -16,11 +16,14 @@ impl PlatOperation for Platform {
     const UART_1_INT: usize = 32 + 0x72;
 
     const HYPERVISOR_UART_BASE: usize = Self::UART_0_ADDR;
+    const HYPERVISOR_UART_INT: usize = 32 + 0x1;
 
     const GICD_BASE: usize = 0x08000000;
     const GICC_BASE: usize = 0x08010000;
     const GICH_BASE: usize = 0x08030000;
     const GICV_BASE: usize = 0x08040000;
+    const GICR_BASE: usize = 0x080a0000;
+    const GITS_BASE: usize = 0x08080000;
 
     fn cpuid_to_cpuif(cpuid: usize) -> usize {
         cpuid
@@ -50,18 +53,22 @@ pub static PLAT_DESC: PlatformConfig = PlatformConfig {
             PlatCpuCoreConfig {
                 mpidr: 0,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
             PlatCpuCoreConfig {
                 mpidr: 1,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
             PlatCpuCoreConfig {
                 mpidr: 2,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
             PlatCpuCoreConfig {
                 mpidr: 3,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
         ],
     },
@@ -16,6 +16,7 @@ impl PlatOperation for Platform {
     const UART_1_INT: usize = 32 + 0x72;
 
     const HYPERVISOR_UART_BASE: usize = Self::UART_1_ADDR;
+    const HYPERVISOR_UART_INT: usize = Self::UART_1_INT;
 
     const GICD_BASE: usize = 0x3881000;
     const GICC_BASE: usize = 0x3882000;
@@ -75,18 +76,22 @@ pub static PLAT_DESC: PlatformConfig = PlatformConfig {
             PlatCpuCoreConfig {
                 mpidr: 0x80000100,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
             PlatCpuCoreConfig {
                 mpidr: 0x80000101,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
             PlatCpuCoreConfig {
                 mpidr: 0x80000102,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
             PlatCpuCoreConfig {
                 mpidr: 0x80000103,
                 sched: SchedRule::RoundRobin,
+                slice: 10,
             },
         ],
     },
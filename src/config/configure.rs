@@ -1,3 +1,4 @@
+use alloc::collections::BTreeMap;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use core::ffi::CStr;
@@ -24,6 +25,10 @@ pub enum DtbDevType {
     Serial = 0,
     Gicd = 1,
     Gicc = 2,
+    // a passthrough PCIe root complex, with its ECAM region and INTA..INTD
+    PciHost = 3,
+    // a memory window of the root complex before it
+    PciMem = 4,
 }
 
 impl From<usize> for DtbDevType {
@@ -32,6 +37,8 @@ impl From<usize> for DtbDevType {
             0 => Self::Serial,
             1 => Self::Gicd,
             2 => Self::Gicc,
+            3 => Self::PciHost,
+            4 => Self::PciMem,
             _ => panic!("Unknown DtbDevType value: {}", value),
         }
     }
@@ -66,6 +73,10 @@ pub struct VmPassthroughDeviceConfig {
     pub regions: Vec<PassthroughRegion>,
     pub irqs: Vec<usize>,
     pub streams_ids: Vec<usize>,
+    // DeviceIDs of the passthrough PCIe devices whose MSIs are translated by the ITS
+    pub msi_ids: Vec<usize>,
+    // physical core that each pinned passthrough irq is routed to
+    pub irq_affinity: BTreeMap<usize, usize>,
 }
 
 #[derive(Clone, Debug)]
@@ -151,6 +162,11 @@ pub struct VmCpuConfig {
     pub num: usize,
     pub allocate_bitmap: usize,
     pub master: Option<usize>,
+    // real-time scheduling reservation of each vcpu in microseconds, 0 for default
+    pub budget: usize,
+    pub period: usize,
+    // fixed-priority scheduling priority of each vcpu, higher is more urgent
+    pub priority: usize,
 }
 
 impl VmCpuConfig {
@@ -177,6 +193,7 @@ impl VmCpuConfig {
             num,
             allocate_bitmap,
             master,
+            ..Default::default()
         }
     }
 }
@@ -297,7 +314,30 @@ impl VmConfigEntry {
     }
 
     fn set_cpu_cfg(&mut self, num: usize, allocate_bitmap: usize, master: usize) {
+        let (budget, period) = (self.cpu.budget, self.cpu.period);
         self.cpu = VmCpuConfig::new(num, allocate_bitmap, master);
+        self.set_cpu_reservation_cfg(budget, period);
+    }
+
+    pub fn cpu_budget(&self) -> usize {
+        self.cpu.budget
+    }
+
+    pub fn cpu_period(&self) -> usize {
+        self.cpu.period
+    }
+
+    fn set_cpu_reservation_cfg(&mut self, budget: usize, period: usize) {
+        self.cpu.budget = budget;
+        self.cpu.period = period;
+    }
+
+    pub fn cpu_priority(&self) -> usize {
+        self.cpu.priority
+    }
+
+    fn set_cpu_priority_cfg(&mut self, priority: usize) {
+        self.cpu.priority = priority;
     }
 
     pub fn emulated_device_list(&self) -> &[VmEmulatedDeviceConfig] {
@@ -328,10 +368,26 @@ impl VmConfigEntry {
         self.vm_pt_dev_confg.irqs.append(irqs);
     }
 
+    pub fn passthrough_irq_affinity(&self, irq: usize) -> Option<usize> {
+        self.vm_pt_dev_confg.irq_affinity.get(&irq).copied()
+    }
+
+    fn set_passthrough_irq_affinity(&mut self, irq: usize, cpu_id: usize) {
+        self.vm_pt_dev_confg.irq_affinity.insert(irq, cpu_id);
+    }
+
     fn add_passthrough_device_streams_ids(&mut self, streams_ids: &mut Vec<usize>) {
         self.vm_pt_dev_confg.streams_ids.append(streams_ids);
     }
 
+    pub fn passthrough_device_msi_ids(&self) -> &[usize] {
+        &self.vm_pt_dev_confg.msi_ids
+    }
+
+    fn add_passthrough_device_msi_ids(&mut self, msi_ids: &mut Vec<usize>) {
+        self.vm_pt_dev_confg.msi_ids.append(msi_ids);
+    }
+
     pub fn dtb_device_list(&self) -> &[VmDtbDevConfig] {
         &self.vm_dtb_devs.dtb_device_list
     }
@@ -534,6 +590,49 @@ pub fn set_cpu(vmid: usize, num: usize, allocate_bitmap: usize, master: usize) -
     })
 }
 
+/* Set VM vcpu budget and period for real-time scheduling, must be set before the VM is initialized.
+ *
+ * @param[in] vmid: target VM id.
+ * @param[in] budget: budget of each vcpu in microseconds, 0 for default.
+ * @param[in] period: period of each vcpu in microseconds, 0 for default.
+ */
+pub fn set_cpu_reservation(vmid: usize, budget: usize, period: usize) -> Result<usize, ()> {
+    if (budget == 0) != (period == 0) || budget > period {
+        error!(
+            "VM[{}] illegal cpu reservation: budget {}us period {}us",
+            vmid, budget, period
+        );
+        return Err(());
+    }
+    vm_cfg_editor(vmid, |vm_cfg| {
+        vm_cfg.set_cpu_reservation_cfg(budget, period);
+        info!(
+            "VM[{}] vm_cfg_set_cpu_reservation: budget {}us period {}us",
+            vmid, budget, period
+        );
+        if !cfg!(feature = "rt-sched") {
+            warn!("VM[{vmid}] cpu reservation only takes effect with feature \"rt-sched\"");
+        }
+        Ok(0)
+    })
+}
+
+/* Set VM vcpu priority for fixed-priority scheduling, must be set before the VM is initialized.
+ *
+ * @param[in] vmid: target VM id.
+ * @param[in] priority: priority of each vcpu, higher is more urgent.
+ */
+pub fn set_cpu_priority(vmid: usize, priority: usize) -> Result<usize, ()> {
+    vm_cfg_editor(vmid, |vm_cfg| {
+        vm_cfg.set_cpu_priority_cfg(priority);
+        info!("VM[{}] vm_cfg_set_cpu_priority: priority {}", vmid, priority);
+        if !cfg!(feature = "rt-sched") {
+            warn!("VM[{vmid}] cpu priority only takes effect with feature \"rt-sched\"");
+        }
+        Ok(0)
+    })
+}
+
 /* Add emulated device config for VM */
 pub fn add_emu_dev(
     vmid: usize,
@@ -643,6 +742,51 @@ pub fn add_passthrough_device_streams_ids(
     })
 }
 
+/* Add the DeviceIDs of passthrough PCIe devices for VM, whose MSIs are translated by the ITS.
+ *
+ * @param[in] vmid: target VM id.
+ * @param[in] msi_ids_base_ipa: ipa of the DeviceID list in MVM.
+ * @param[in] msi_ids_length: number of the DeviceIDs.
+ */
+pub fn add_passthrough_device_msi_ids(
+    vmid: usize,
+    msi_ids_base_ipa: usize,
+    msi_ids_length: usize,
+) -> Result<usize, ()> {
+    let mut msi_ids = vec![0_usize; msi_ids_length];
+    if msi_ids_length > 0 {
+        copy_segment_from_vm(&active_vm().unwrap(), msi_ids.as_mut_slice(), msi_ids_base_ipa);
+    }
+    info!("VM[{}] vm_cfg_add_pt_dev msi ids {:x?}", vmid, msi_ids);
+
+    vm_cfg_editor(vmid, |vm_cfg| {
+        vm_cfg.add_passthrough_device_msi_ids(&mut msi_ids);
+        Ok(0)
+    })
+}
+
+/* Pin a passthrough irq of VM to a physical core, must be set after the cpu and passthrough irqs config.
+ *
+ * @param[in] vmid: target VM id.
+ * @param[in] irq: passthrough irq of the VM.
+ * @param[in] cpu_id: physical core that the irq is routed to, one of the cores of the VM.
+ */
+pub fn set_passthrough_irq_affinity(vmid: usize, irq: usize, cpu_id: usize) -> Result<usize, ()> {
+    vm_cfg_editor(vmid, |vm_cfg| {
+        if !vm_cfg.passthrough_device_irqs().contains(&irq) {
+            error!("VM[{}] irq {} is not a passthrough irq", vmid, irq);
+            return Err(());
+        }
+        if cpu_id >= usize::BITS as usize || vm_cfg.cpu_allocated_bitmap() & (1 << cpu_id) == 0 {
+            error!("VM[{}] does not run on core {}", vmid, cpu_id);
+            return Err(());
+        }
+        vm_cfg.set_passthrough_irq_affinity(irq, cpu_id);
+        info!("VM[{}] vm_cfg_set_irq_affinity: irq {} core {}", vmid, irq, cpu_id);
+        Ok(0)
+    })
+}
+
 /* Add device tree device config for VM */
 pub fn add_dtb_dev(
     vmid: usize,
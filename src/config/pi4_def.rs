@@ -179,6 +179,7 @@ pub fn mvm_config_init() {
             num: 1,
             allocate_bitmap: 0b0001,
             master: Some(0),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList{emu_dev_list: emu_dev_config,},
         vm_pt_dev_confg: pt_dev_config,
@@ -17,12 +17,23 @@ pub fn mvm_config_init() {
         VmEmulatedDeviceConfig {
             name: String::from("vgicd"),
             base_ipa: Platform::GICD_BASE,
-            length: 0x1000,
+            length: if cfg!(feature = "gicv3") { 0x10000 } else { 0x1000 },
             irq_id: 0,
             cfg_list: Vec::new(),
             emu_type: EmuDeviceType::EmuDeviceTGicd,
             mediated: false,
         },
+        // a 128K redistributor frame for each of the 4 vcpus
+        #[cfg(feature = "gicv3")]
+        VmEmulatedDeviceConfig {
+            name: String::from("vgicr"),
+            base_ipa: Platform::GICR_BASE,
+            length: 0x20000 * 4,
+            irq_id: 0,
+            cfg_list: Vec::new(),
+            emu_type: EmuDeviceType::EmuDeviceTGicr,
+            mediated: false,
+        },
         // VmEmulatedDeviceConfig {
         //     name: String::from("virtio-blk0"),
         //     base_ipa: 0xa000000,
@@ -56,6 +67,8 @@ pub fn mvm_config_init() {
     let mut pt_dev_config: VmPassthroughDeviceConfig = VmPassthroughDeviceConfig::default();
     pt_dev_config.regions = vec![
         PassthroughRegion { ipa: Platform::UART_0_ADDR, pa: Platform::UART_0_ADDR, length: 0x1000, dev_property: true },
+        // GICv3 guests use the system register cpu interface
+        #[cfg(not(feature = "gicv3"))]
         PassthroughRegion { ipa: Platform::GICC_BASE, pa: Platform::GICV_BASE, length: 0x2000, dev_property: true },
         // pass-througn virtio blk/net
         PassthroughRegion { ipa: 0x0a003000, pa: 0x0a003000, length: 0x1000, dev_property: true },
@@ -114,6 +127,7 @@ pub fn mvm_config_init() {
             num: 4,
             allocate_bitmap: 0b1111,
             master: None,
+            ..Default::default()
         },
         memory: VmMemoryConfig {
             region: vm_region,
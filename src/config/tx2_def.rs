@@ -1,3 +1,4 @@
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 
@@ -221,7 +222,9 @@ pub fn mvm_config_init() {
             1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 20, 21, 22, 25, 26, 27, 28,
             29, 30, 31, 32, 42, 45, 50, 51, 56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 69, 70,
             71,
-        ]
+        ],
+        msi_ids: vec![],
+        irq_affinity: BTreeMap::new(),
     };
 
     // vm0 vm_region
@@ -261,6 +264,7 @@ pub fn mvm_config_init() {
             num: 1,
             allocate_bitmap: 0b0001,
             master: Some(0),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList { emu_dev_list: emu_dev_config },
         vm_pt_dev_confg: pt_dev_config,
@@ -320,6 +324,8 @@ pub fn unishyper_config_init() {
         ],
         irqs: vec![INTERRUPT_IRQ_GUEST_TIMER, Platform::UART_1_INT],
         streams_ids: vec![],
+        msi_ids: vec![],
+        irq_affinity: BTreeMap::new(),
     };
 
     // vm0 vm_region
@@ -351,6 +357,7 @@ pub fn unishyper_config_init() {
             num: 1,
             allocate_bitmap: 0b0001,
             master: Some(0),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
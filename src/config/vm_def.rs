@@ -82,6 +82,7 @@ pub fn init_tmp_config_for_bma1() {
             num: 1,
             allocate_bitmap: 0b0010,
             master: Some(1),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
@@ -163,6 +164,7 @@ pub fn init_tmp_config_for_bma2() {
             num: 1,
             allocate_bitmap: 0b0100,
             master: Some(2),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
@@ -308,6 +310,7 @@ pub fn init_tmp_config_for_vm1() {
             num: 1,
             allocate_bitmap: 0b0010,
             master: Some(1),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
@@ -443,6 +446,7 @@ pub fn init_tmp_config_for_vm2() {
             num: 1,
             allocate_bitmap: 0b0100,
             master: Some(2),
+            ..Default::default()
         },
         vm_emu_dev_confg: VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
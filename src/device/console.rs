@@ -0,0 +1,228 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::board::{PlatOperation, Platform};
+use crate::driver::uart;
+use crate::kernel::{interrupt_cpu_enable, interrupt_try_reserve_int};
+
+// Multiplexer of the hypervisor UART. The guest consoles connected to it print their output with a "[VM n] "
+// prefix on each line, and the input goes to the one holding the focus. Typing Ctrl-A n moves the focus to the
+// next console (the hypervisor shell comes first with feature "shell"), Ctrl-A Ctrl-A sends a Ctrl-A.
+
+const CONSOLE_ESCAPE: u8 = 0x01;
+const CONSOLE_RX_BATCH: usize = 64;
+
+// A guest console which can be connected to the hypervisor UART
+pub trait ConsoleBackend: Send + Sync {
+    /* Deliver the input to the guest.
+     *
+     * @param[in] data: bytes received from the hypervisor UART.
+     * @return: false if the guest can not take the input now, the input is dropped.
+     */
+    fn console_input(&self, data: &[u8]) -> bool;
+    // id of the VM owning the console, None if the VM is gone
+    fn console_vm_id(&self) -> Option<usize>;
+}
+
+struct ConsolePort {
+    backend: Arc<dyn ConsoleBackend>,
+    // the next output byte starts a new line
+    line_start: bool,
+}
+
+struct ConsoleMux {
+    ports: Vec<ConsolePort>,
+    // id of the VM holding the focus, None for the hypervisor shell
+    focus: Option<usize>,
+    // the last input byte is the escape
+    escape: bool,
+    rx_int_on: bool,
+}
+
+// where a piece of input goes
+enum ConsoleFocus {
+    Hypervisor,
+    Guest(Arc<dyn ConsoleBackend>),
+}
+
+static CONSOLE_MUX: Mutex<ConsoleMux> = Mutex::new(ConsoleMux {
+    ports: Vec::new(),
+    focus: None,
+    escape: false,
+    rx_int_on: false,
+});
+
+impl ConsolePort {
+    fn owned_by(&self, vm_id: Option<usize>) -> bool {
+        vm_id.is_some() && self.backend.console_vm_id() == vm_id
+    }
+}
+
+impl ConsoleMux {
+    // without the hypervisor shell, the input goes to the first console if the focused one is gone
+    fn focus(&self) -> Option<ConsoleFocus> {
+        match self.ports.iter().find(|port| port.owned_by(self.focus)) {
+            Some(port) => Some(ConsoleFocus::Guest(port.backend.clone())),
+            None if cfg!(feature = "shell") => Some(ConsoleFocus::Hypervisor),
+            None => self.ports.first().map(|port| ConsoleFocus::Guest(port.backend.clone())),
+        }
+    }
+
+    // the hypervisor shell comes before the guest consoles
+    fn focus_next(&mut self) {
+        let next = match self.ports.iter().position(|port| port.owned_by(self.focus)) {
+            Some(idx) => idx + 1,
+            None if cfg!(feature = "shell") => 0,
+            None => 1,
+        };
+        self.focus = match self.ports.get(next) {
+            Some(port) => port.backend.console_vm_id(),
+            None if cfg!(feature = "shell") => None,
+            None => self.ports.first().and_then(|port| port.backend.console_vm_id()),
+        };
+        match self.focus {
+            Some(vm_id) => println!("\n[console] input to VM {}", vm_id),
+            None if cfg!(feature = "shell") => println!("\n[console] input to hypervisor shell"),
+            None => println!("\n[console] no console to input"),
+        }
+    }
+
+    fn enable_rx_int(&mut self) {
+        if self.rx_int_on {
+            return;
+        }
+        let int_id = Platform::HYPERVISOR_UART_INT;
+        if int_id == usize::MAX {
+            warn!("console: no hypervisor uart interrupt on this platform");
+            return;
+        }
+        if !interrupt_try_reserve_int(int_id, console_irq_handler) {
+            warn!(
+                "console: hypervisor uart interrupt {} is passed through to a VM",
+                int_id
+            );
+            return;
+        }
+        interrupt_cpu_enable(int_id, true);
+        uart::rx_int_enable(true);
+        self.rx_int_on = true;
+        info!("console: hypervisor uart input on interrupt {}", int_id);
+    }
+}
+
+fn console_dispatch(focus: &Option<ConsoleFocus>, data: &[u8]) {
+    match focus {
+        Some(ConsoleFocus::Guest(backend)) => {
+            backend.console_input(data);
+        }
+        #[cfg(feature = "shell")]
+        Some(ConsoleFocus::Hypervisor) => data.iter().for_each(|&byte| crate::kernel::shell_input(byte)),
+        _ => {}
+    }
+}
+
+/* Route the bytes received from the hypervisor UART to the focused console, escapes are handled here.
+ *
+ * @param[in] data: the received bytes.
+ */
+fn console_input(data: &[u8]) {
+    let mut mux = CONSOLE_MUX.lock();
+    let mut focus = mux.focus();
+    let mut start = 0;
+    for (i, &byte) in data.iter().enumerate() {
+        if mux.escape {
+            mux.escape = false;
+            start = i + 1;
+            match byte {
+                CONSOLE_ESCAPE => {
+                    drop(mux);
+                    console_dispatch(&focus, &[CONSOLE_ESCAPE]);
+                    mux = CONSOLE_MUX.lock();
+                }
+                b'n' | b'N' => {
+                    mux.focus_next();
+                    focus = mux.focus();
+                }
+                _ => {}
+            }
+        } else if byte == CONSOLE_ESCAPE {
+            mux.escape = true;
+            drop(mux);
+            console_dispatch(&focus, &data[start..i]);
+            mux = CONSOLE_MUX.lock();
+        }
+    }
+    let escape = mux.escape;
+    drop(mux);
+    if !escape && start < data.len() {
+        console_dispatch(&focus, &data[start..]);
+    }
+}
+
+fn console_irq_handler() {
+    let mut buf = [0; CONSOLE_RX_BATCH];
+    loop {
+        let mut len = 0;
+        while len < CONSOLE_RX_BATCH {
+            match uart::getc() {
+                Some(byte) => {
+                    buf[len] = byte;
+                    len += 1;
+                }
+                None => break,
+            }
+        }
+        console_input(&buf[..len]);
+        if len < CONSOLE_RX_BATCH {
+            break;
+        }
+    }
+}
+
+// connect a guest console to the hypervisor UART, a VM should have at most one
+pub fn console_register(backend: Arc<dyn ConsoleBackend>) {
+    let mut mux = CONSOLE_MUX.lock();
+    mux.ports.push(ConsolePort {
+        backend,
+        line_start: true,
+    });
+    mux.enable_rx_int();
+}
+
+pub fn remove_console(vm_id: usize) {
+    let mut mux = CONSOLE_MUX.lock();
+    mux.ports.retain(|port| !port.owned_by(Some(vm_id)));
+    if mux.focus == Some(vm_id) {
+        mux.focus = None;
+    }
+}
+
+// take the hypervisor UART input for the hypervisor shell
+#[cfg(feature = "shell")]
+pub fn console_input_init() {
+    CONSOLE_MUX.lock().enable_rx_int();
+}
+
+/* Print the output of a guest console on the hypervisor UART, each line is prefixed with the VM id.
+ *
+ * @param[in] vm_id: id of the VM owning the console.
+ * @param[in] data: the output bytes.
+ */
+pub fn console_output(vm_id: usize, data: &[u8]) {
+    let mut mux = CONSOLE_MUX.lock();
+    let Some(port) = mux.ports.iter_mut().find(|port| port.owned_by(Some(vm_id))) else {
+        return;
+    };
+    let mut line_start = port.line_start;
+    for line in data.split_inclusive(|&byte| byte == b'\n') {
+        if line_start {
+            print!("[VM{}] ", vm_id);
+        }
+        print!("{}", String::from_utf8_lossy(line));
+        line_start = line.last() == Some(&b'\n');
+    }
+    port.line_start = line_start;
+}
@@ -34,6 +34,9 @@ pub enum EmuDeviceType {
     EmuDeviceTVirtioBlkMediated = 7,
     EmuDeviceTIOMMU = 8,
     VirtioBalloon = 9,
+    EmuDeviceTGicr = 10,
+    EmuDeviceTPciHost = 11,
+    EmuDeviceTGits = 12,
 }
 
 impl From<usize> for EmuDeviceType {
@@ -49,6 +52,9 @@ impl From<usize> for EmuDeviceType {
             7 => EmuDeviceType::EmuDeviceTVirtioBlkMediated,
             8 => EmuDeviceType::EmuDeviceTIOMMU,
             9 => EmuDeviceType::VirtioBalloon,
+            10 => EmuDeviceType::EmuDeviceTGicr,
+            11 => EmuDeviceType::EmuDeviceTPciHost,
+            12 => EmuDeviceType::EmuDeviceTGits,
             _ => panic!("Unknown EmuDeviceType value: {}", value),
         }
     }
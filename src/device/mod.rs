@@ -1,5 +1,11 @@
+pub use self::console::*;
 pub use self::emu::*;
+pub use self::pci::*;
+pub use self::pl011::emu_pl011_init;
 pub use self::virtio::*;
 
+mod console;
 mod emu;
+mod pci;
+mod pl011;
 mod virtio;
@@ -0,0 +1,234 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use spin::Mutex;
+
+use crate::config::VmEmulatedDeviceConfig;
+use crate::kernel::current_cpu;
+
+use super::{EmuContext, EmuDev, EmuDeviceType};
+
+// Emulated ECAM PCI host bridge (pci-host-ecam-generic). Only bus 0 is populated, each device has a single
+// function and raises its legacy INTx on its own SPI, which the VM device tree maps with interrupt-map. With a
+// virtual ITS, the functions may raise MSI-X instead, whose DeviceID is given by the msi-map of the host bridge.
+
+pub const PCI_CONFIG_SPACE_SIZE: usize = 0x100;
+pub const PCI_ECAM_BUS_SIZE: usize = 1 << 20;
+pub const PCI_SLOT_MAX: usize = 32;
+// the DeviceIDs of the emulated functions, which are above those of the passthrough devices, 256 per host bridge
+pub const PCI_MSI_DEVID_BASE: usize = 0x10000;
+pub const PCI_MSI_DEVID_NUM: usize = 0x100;
+
+pub const PCI_VENDOR_ID: usize = 0x00;
+pub const PCI_DEVICE_ID: usize = 0x02;
+pub const PCI_COMMAND: usize = 0x04;
+pub const PCI_STATUS: usize = 0x06;
+pub const PCI_REVISION_ID: usize = 0x08;
+pub const PCI_CLASS_PROG: usize = 0x09;
+pub const PCI_BASE_ADDRESS_0: usize = 0x10;
+pub const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+pub const PCI_SUBSYSTEM_ID: usize = 0x2e;
+pub const PCI_CAPABILITY_LIST: usize = 0x34;
+pub const PCI_INTERRUPT_LINE: usize = 0x3c;
+pub const PCI_INTERRUPT_PIN: usize = 0x3d;
+
+pub const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+pub const PCI_COMMAND_MASTER: u16 = 1 << 2;
+pub const PCI_STATUS_INTERRUPT: u16 = 1 << 3;
+pub const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+pub const PCI_BASE_ADDRESS_MEM_TYPE_64: u32 = 0b10 << 1;
+pub const PCI_INTERRUPT_PIN_INTA: u8 = 1;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+// a function on the emulated host bridge
+pub trait PciDevice: Send + Sync {
+    fn config_read(&self, offset: usize, width: usize) -> u32;
+    fn config_write(&self, offset: usize, width: usize, value: u32);
+}
+
+// the type 0 configuration header and capabilities of a function, the bits clear in wmask are read-only
+pub struct PciConfigSpace {
+    data: [u8; PCI_CONFIG_SPACE_SIZE],
+    wmask: [u8; PCI_CONFIG_SPACE_SIZE],
+}
+
+impl PciConfigSpace {
+    pub fn new(vendor_id: u16, device_id: u16, class: u32, revision: u8) -> Self {
+        let mut space = Self {
+            data: [0; PCI_CONFIG_SPACE_SIZE],
+            wmask: [0; PCI_CONFIG_SPACE_SIZE],
+        };
+        space.set_u16(PCI_VENDOR_ID, vendor_id);
+        space.set_u16(PCI_DEVICE_ID, device_id);
+        space.set_u8(PCI_REVISION_ID, revision);
+        space.set_bytes(PCI_CLASS_PROG, &class.to_le_bytes()[..3]);
+        space.set_u16(PCI_SUBSYSTEM_VENDOR_ID, vendor_id);
+        space.set_u16(PCI_SUBSYSTEM_ID, device_id);
+        space.set_wmask(PCI_COMMAND, &(PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER).to_le_bytes());
+        space.set_wmask(PCI_INTERRUPT_LINE, &[0xff]);
+        space
+    }
+
+    pub fn read(&self, offset: usize, width: usize) -> u32 {
+        self.data[offset..offset + width]
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as u32)
+    }
+
+    pub fn write(&mut self, offset: usize, width: usize, value: u32) {
+        for (i, byte) in value.to_le_bytes().iter().take(width).enumerate() {
+            let mask = self.wmask[offset + i];
+            self.data[offset + i] = (self.data[offset + i] & !mask) | (byte & mask);
+        }
+    }
+
+    pub fn set_bytes(&mut self, offset: usize, bytes: &[u8]) {
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn set_u8(&mut self, offset: usize, value: u8) {
+        self.data[offset] = value;
+    }
+
+    pub fn set_u16(&mut self, offset: usize, value: u16) {
+        self.set_bytes(offset, &value.to_le_bytes());
+    }
+
+    pub fn set_u32(&mut self, offset: usize, value: u32) {
+        self.set_bytes(offset, &value.to_le_bytes());
+    }
+
+    pub fn set_wmask(&mut self, offset: usize, mask: &[u8]) {
+        self.wmask[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+
+    // a 64-bit memory BAR of a power of two size, starting at base
+    pub fn set_bar64(&mut self, bar: usize, base: usize, size: usize) {
+        let offset = PCI_BASE_ADDRESS_0 + bar * 4;
+        self.set_u32(offset, base as u32 | PCI_BASE_ADDRESS_MEM_TYPE_64);
+        self.set_u32(offset + 4, (base >> 32) as u32);
+        self.set_wmask(offset, &(!(size as u32 - 1) & !0xf).to_le_bytes());
+        self.set_wmask(offset + 4, &u32::MAX.to_le_bytes());
+    }
+
+    pub fn bar64(&self, bar: usize) -> usize {
+        let offset = PCI_BASE_ADDRESS_0 + bar * 4;
+        let low = self.read(offset, 4) & !0xf;
+        let high = self.read(offset + 4, 4);
+        (high as usize) << 32 | low as usize
+    }
+
+    // append a capability to the capability list, return its offset
+    pub fn add_capability(&mut self, offset: usize, cap: &[u8]) -> usize {
+        let mut next = PCI_CAPABILITY_LIST;
+        while self.data[next] != 0 {
+            next = self.data[next] as usize + 1;
+        }
+        self.data[next] = offset as u8;
+        self.set_bytes(offset, cap);
+        self.data[offset + 1] = 0;
+        let status = self.read(PCI_STATUS, 2) as u16 | PCI_STATUS_CAP_LIST;
+        self.set_u16(PCI_STATUS, status);
+        offset
+    }
+}
+
+pub struct PciHost {
+    // the order of the host bridge in the VM, which picks its DeviceIDs
+    index: usize,
+    ecam: Range<usize>,
+    mem_window: Range<usize>,
+    devs: Mutex<Vec<Arc<dyn PciDevice>>>,
+}
+
+impl PciHost {
+    // the window that the BARs of the devices are placed in
+    pub fn mem_window(&self) -> Range<usize> {
+        self.mem_window.clone()
+    }
+
+    // plug the device into the next free slot of bus 0, return the device number
+    pub fn attach(&self, dev: Arc<dyn PciDevice>) -> Option<usize> {
+        let mut devs = self.devs.lock();
+        if devs.len() >= PCI_SLOT_MAX {
+            return None;
+        }
+        devs.push(dev);
+        Some(devs.len() - 1)
+    }
+
+    pub fn has_free_slot(&self) -> bool {
+        self.devs.lock().len() < PCI_SLOT_MAX
+    }
+
+    // the DeviceID of the MSIs of the function in the slot, the requester id is slot << 3 on bus 0
+    pub fn msi_devid(&self, slot: usize) -> usize {
+        PCI_MSI_DEVID_BASE + self.index * PCI_MSI_DEVID_NUM + (slot << 3)
+    }
+
+    fn device(&self, bus: usize, slot: usize, func: usize) -> Option<Arc<dyn PciDevice>> {
+        if bus != 0 || func != 0 {
+            return None;
+        }
+        self.devs.lock().get(slot).cloned()
+    }
+}
+
+impl EmuDev for PciHost {
+    fn emu_type(&self) -> EmuDeviceType {
+        EmuDeviceType::EmuDeviceTPciHost
+    }
+
+    fn address_range(&self) -> Range<usize> {
+        self.ecam.clone()
+    }
+
+    fn handler(&self, emu_ctx: &EmuContext) -> bool {
+        let offset = emu_ctx.address - self.ecam.start;
+        let width = emu_ctx.width;
+        let reg = offset & 0xfff;
+        if width > 4 || reg % width != 0 {
+            warn!(
+                "emu_pci_host: illegal config access offset {:#x} width {}",
+                offset, width
+            );
+            return false;
+        }
+        let dev = self.device(offset >> 20, (offset >> 15) & 0x1f, (offset >> 12) & 0x7);
+        if emu_ctx.write {
+            let value = current_cpu().get_gpr(emu_ctx.reg) as u32;
+            if let Some(dev) = dev.filter(|_| reg < PCI_CONFIG_SPACE_SIZE) {
+                dev.config_write(reg, width, value);
+            }
+        } else {
+            let value = match dev {
+                Some(dev) if reg < PCI_CONFIG_SPACE_SIZE => dev.config_read(reg, width),
+                // the extended configuration space of a conventional PCI device
+                Some(_) => 0,
+                // no device answers, the read completes with all ones
+                None => (u64::MAX >> (64 - width * 8)) as u32,
+            };
+            current_cpu().set_gpr(emu_ctx.reg, value as usize);
+        }
+        true
+    }
+}
+
+// cfg_list[0] and cfg_list[1] are the base and size of the memory window. The virtio devices in the window
+// become its functions, so they must come after the host bridge in the device list. The index is the number of
+// host bridges before it.
+pub fn emu_pci_host_init(emu_cfg: &VmEmulatedDeviceConfig, index: usize) -> Result<Arc<dyn EmuDev>, ()> {
+    if emu_cfg.length < PCI_ECAM_BUS_SIZE || emu_cfg.cfg_list.len() < 2 || emu_cfg.cfg_list[1] == 0 {
+        error!("emu_pci_host_init: illegal config of {}", emu_cfg.name);
+        return Err(());
+    }
+    let host = Arc::new(PciHost {
+        index,
+        ecam: emu_cfg.base_ipa..emu_cfg.base_ipa + emu_cfg.length,
+        mem_window: emu_cfg.cfg_list[0]..emu_cfg.cfg_list[0] + emu_cfg.cfg_list[1],
+        devs: Mutex::new(Vec::new()),
+    });
+    Ok(host)
+}
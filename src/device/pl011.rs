@@ -0,0 +1,274 @@
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use core::ops::Range;
+
+use spin::Mutex;
+
+use crate::config::VmEmulatedDeviceConfig;
+use crate::kernel::{current_cpu, interrupt_vm_inject, ipi_send_msg, IpiInnerMsg, IpiIntInjectMsg, IpiType, Vm};
+
+use super::{console_output, console_register, ConsoleBackend, EmuContext, EmuDev, EmuDeviceType};
+
+// Emulated PL011 UART, enough for the Linux amba-pl011 driver and earlycon. The output goes to the hypervisor
+// UART and the input comes from it while the VM holds the console focus, the transmitter is never busy.
+
+const UART_DR: usize = 0x000;
+const UART_RSR: usize = 0x004;
+const UART_FR: usize = 0x018;
+const UART_ILPR: usize = 0x020;
+const UART_IBRD: usize = 0x024;
+const UART_FBRD: usize = 0x028;
+const UART_LCR_H: usize = 0x02c;
+const UART_CR: usize = 0x030;
+const UART_IFLS: usize = 0x034;
+const UART_IMSC: usize = 0x038;
+const UART_RIS: usize = 0x03c;
+const UART_MIS: usize = 0x040;
+const UART_ICR: usize = 0x044;
+const UART_DMACR: usize = 0x048;
+// peripheral and PrimeCell identification registers
+const UART_PERIPH_ID0: usize = 0xfe0;
+const UART_PCELL_ID3: usize = 0xffc;
+const UART_ID_END: usize = 0x1000;
+
+const UART_PL011_ID: [u32; 8] = [0x11, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+const UART_FR_RXFE: u32 = 1 << 4;
+const UART_FR_RXFF: u32 = 1 << 6;
+const UART_FR_TXFE: u32 = 1 << 7;
+
+const UART_INT_RX: u32 = 1 << 4;
+const UART_INT_TX: u32 = 1 << 5;
+const UART_INT_RT: u32 = 1 << 6;
+const UART_INT_ALL: u32 = 0x7ff;
+
+const UART_LCR_H_FEN: u32 = 1 << 4;
+const UART_CR_UARTEN: u32 = 1 << 0;
+const UART_CR_TXE: u32 = 1 << 8;
+const UART_CR_RXE: u32 = 1 << 9;
+
+const UART_FIFO_DEPTH: usize = 32;
+
+pub struct EmuPl011 {
+    vm: Weak<Vm>,
+    address_range: Range<usize>,
+    int_id: usize,
+    inner: Mutex<EmuPl011Inner>,
+}
+
+struct EmuPl011Inner {
+    rx_fifo: VecDeque<u8>,
+    ilpr: u32,
+    ibrd: u32,
+    fbrd: u32,
+    lcr_h: u32,
+    cr: u32,
+    ifls: u32,
+    imsc: u32,
+    // raw interrupt status except the receive ones, which follow the receive FIFO
+    int_level: u32,
+    dmacr: u32,
+    // the interrupt line is high
+    irq_level: bool,
+}
+
+impl EmuPl011Inner {
+    fn fifo_depth(&self) -> usize {
+        if self.lcr_h & UART_LCR_H_FEN != 0 {
+            UART_FIFO_DEPTH
+        } else {
+            1
+        }
+    }
+
+    fn flag(&self) -> u32 {
+        let mut flag = UART_FR_TXFE;
+        if self.rx_fifo.is_empty() {
+            flag |= UART_FR_RXFE;
+        }
+        if self.rx_fifo.len() >= self.fifo_depth() {
+            flag |= UART_FR_RXFF;
+        }
+        flag
+    }
+
+    fn raw_int(&self) -> u32 {
+        if self.rx_fifo.is_empty() {
+            self.int_level
+        } else {
+            self.int_level | UART_INT_RX | UART_INT_RT
+        }
+    }
+
+    // update the interrupt line, return true if it rises
+    fn update_irq(&mut self) -> bool {
+        let level = self.raw_int() & self.imsc != 0;
+        let rise = level && !self.irq_level;
+        self.irq_level = level;
+        rise
+    }
+}
+
+impl EmuPl011 {
+    fn inject(&self) {
+        let Some(vm) = self.vm.upgrade() else {
+            return;
+        };
+        let Some(target_vcpu) = vm.vcpu(0) else {
+            return;
+        };
+        if target_vcpu.phys_id() == current_cpu().id {
+            interrupt_vm_inject(&vm, target_vcpu, self.int_id);
+        } else {
+            let m = IpiIntInjectMsg {
+                vm_id: vm.id(),
+                int_id: self.int_id,
+            };
+            if !ipi_send_msg(target_vcpu.phys_id(), IpiType::IntInject, IpiInnerMsg::IntInjectMsg(m)) {
+                error!("emu_pl011: failed to send ipi to Core {}", target_vcpu.phys_id());
+            }
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        let mut inner = self.inner.lock();
+        match offset {
+            UART_DR => {
+                let value = inner.rx_fifo.pop_front().unwrap_or(0) as u32;
+                inner.update_irq();
+                value
+            }
+            UART_RSR => 0,
+            UART_FR => inner.flag(),
+            UART_ILPR => inner.ilpr,
+            UART_IBRD => inner.ibrd,
+            UART_FBRD => inner.fbrd,
+            UART_LCR_H => inner.lcr_h,
+            UART_CR => inner.cr,
+            UART_IFLS => inner.ifls,
+            UART_IMSC => inner.imsc,
+            UART_RIS => inner.raw_int(),
+            UART_MIS => inner.raw_int() & inner.imsc,
+            UART_DMACR => inner.dmacr,
+            UART_PERIPH_ID0..=UART_PCELL_ID3 => UART_PL011_ID[(offset - UART_PERIPH_ID0) / 4],
+            _ => {
+                warn!("emu_pl011: read unknown register {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        let mut inner = self.inner.lock();
+        match offset {
+            UART_DR => {
+                let enabled = UART_CR_UARTEN | UART_CR_TXE;
+                if inner.cr & enabled == enabled {
+                    if let Some(vm) = self.vm.upgrade() {
+                        console_output(vm.id(), &[value as u8]);
+                    }
+                }
+                // the character is sent at once, so the transmit FIFO is always below the trigger level
+                inner.int_level |= UART_INT_TX;
+            }
+            UART_RSR => {}
+            UART_ILPR => inner.ilpr = value,
+            UART_IBRD => inner.ibrd = value,
+            UART_FBRD => inner.fbrd = value,
+            UART_LCR_H => {
+                // switching the FIFO mode flushes the receive FIFO
+                if (inner.lcr_h ^ value) & UART_LCR_H_FEN != 0 {
+                    inner.rx_fifo.clear();
+                }
+                inner.lcr_h = value;
+            }
+            UART_CR => inner.cr = value,
+            UART_IFLS => inner.ifls = value,
+            UART_IMSC => inner.imsc = value & UART_INT_ALL,
+            UART_ICR => inner.int_level &= !value,
+            UART_DMACR => inner.dmacr = value,
+            _ => {
+                warn!("emu_pl011: write unknown register {:#x} value {:#x}", offset, value);
+            }
+        }
+        if inner.update_irq() {
+            drop(inner);
+            self.inject();
+        }
+    }
+}
+
+impl EmuDev for EmuPl011 {
+    fn emu_type(&self) -> EmuDeviceType {
+        EmuDeviceType::EmuDeviceTConsole
+    }
+
+    fn address_range(&self) -> Range<usize> {
+        self.address_range.clone()
+    }
+
+    fn handler(&self, emu_ctx: &EmuContext) -> bool {
+        let offset = emu_ctx.address - self.address_range.start;
+        if offset >= UART_ID_END || offset % 4 != 0 {
+            warn!("emu_pl011: illegal access offset {:#x}", offset);
+            return false;
+        }
+        if emu_ctx.write {
+            let value = current_cpu().get_gpr(emu_ctx.reg) as u32;
+            self.write(offset, value);
+        } else {
+            let value = self.read(offset);
+            current_cpu().set_gpr(emu_ctx.reg, value as usize);
+        }
+        true
+    }
+}
+
+impl ConsoleBackend for EmuPl011 {
+    fn console_input(&self, data: &[u8]) -> bool {
+        let mut inner = self.inner.lock();
+        let enabled = UART_CR_UARTEN | UART_CR_RXE;
+        if inner.cr & enabled != enabled {
+            return false;
+        }
+        let room = inner.fifo_depth().saturating_sub(inner.rx_fifo.len());
+        inner.rx_fifo.extend(data.iter().take(room));
+        if inner.update_irq() {
+            drop(inner);
+            self.inject();
+        }
+        data.len() <= room
+    }
+
+    fn console_vm_id(&self) -> Option<usize> {
+        self.vm.upgrade().map(|vm| vm.id())
+    }
+}
+
+pub fn emu_pl011_init(vm: Weak<Vm>, emu_cfg: &VmEmulatedDeviceConfig) -> Result<Arc<dyn EmuDev>, ()> {
+    if emu_cfg.emu_type != EmuDeviceType::EmuDeviceTConsole || emu_cfg.length < UART_ID_END {
+        error!("emu_pl011_init: illegal config of {}", emu_cfg.name);
+        return Err(());
+    }
+    let pl011 = Arc::new(EmuPl011 {
+        vm,
+        address_range: emu_cfg.base_ipa..emu_cfg.base_ipa + emu_cfg.length,
+        int_id: emu_cfg.irq_id,
+        inner: Mutex::new(EmuPl011Inner {
+            rx_fifo: VecDeque::with_capacity(UART_FIFO_DEPTH),
+            ilpr: 0,
+            ibrd: 0,
+            fbrd: 0,
+            lcr_h: 0,
+            // enabled at reset, so that earlycon works without any setup
+            cr: UART_CR_UARTEN | UART_CR_TXE | UART_CR_RXE,
+            ifls: 0x12,
+            imsc: 0,
+            int_level: 0,
+            dmacr: 0,
+            irq_level: false,
+        }),
+    });
+    console_register(pl011.clone());
+    Ok(pl011)
+}
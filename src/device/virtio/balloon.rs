@@ -114,7 +114,7 @@ pub fn virtio_balloon_notify_handler(vq: Arc<Virtq>, balloon: Arc<VirtioMmio>, v
             return false;
         }
     }
-    balloon.notify();
+    balloon.notify(vq.vq_indx());
     true
 }
 
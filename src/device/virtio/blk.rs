@@ -4,7 +4,9 @@ use alloc::vec::Vec;
 use spin::Mutex;
 
 use crate::arch::PAGE_SIZE;
-use crate::device::{mediated_blk_list_get, EmuContext, ReadAsyncMsg, UsedInfo, VirtioMmio, Virtq, WriteAsyncMsg};
+use crate::device::{
+    mediated_blk_list_get, EmuContext, FlushAsyncMsg, ReadAsyncMsg, UsedInfo, VirtioMmio, Virtq, WriteAsyncMsg,
+};
 use crate::kernel::{async_blk_io_req, async_ipi_req, AsyncTask, IpiMediatedMsg, Vm, EXECUTOR};
 use crate::util::memcpy_safe;
 
@@ -15,6 +17,7 @@ pub const VIRTQUEUE_BLK_MAX_SIZE: usize = 256;
 /* VIRTIO_BLK_FEATURES*/
 const VIRTIO_BLK_F_SIZE_MAX: usize = 1 << 1;
 const VIRTIO_BLK_F_SEG_MAX: usize = 1 << 2;
+const VIRTIO_BLK_F_FLUSH: usize = 1 << 9;
 
 /* BLOCK PARAMETERS*/
 pub const SECTOR_BSIZE: usize = 512;
@@ -33,7 +36,7 @@ pub const VIRTIO_BLK_S_OK: usize = 0;
 pub const VIRTIO_BLK_S_UNSUPP: usize = 2;
 
 pub fn blk_features() -> usize {
-    VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX
+    VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_SIZE_MAX | VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_FLUSH
 }
 
 #[repr(C)]
@@ -121,10 +124,15 @@ struct BlkReqRegion {
     pub size: usize,
 }
 
+// cfg_list of a blk device: [ start sector ][ size in sectors ][ pa of the disk image, non-mediated only ]
+// A mediated blk is a region of the disk of MVM, a non-mediated one is a disk image which the bootloader places in
+// the host memory outside of the memory managed by the hypervisor.
 #[repr(C)]
 pub struct VirtioBlkReq {
     region: BlkReqRegion,
     mediated: bool,
+    // hva of the disk image of a non-mediated blk
+    image: usize,
 }
 
 impl VirtioBlkReq {
@@ -132,9 +140,18 @@ impl VirtioBlkReq {
         VirtioBlkReq {
             region: BlkReqRegion { start: 0, size: 0 },
             mediated: false,
+            image: 0,
         }
     }
 
+    pub fn set_image(&mut self, image: usize) {
+        self.image = image;
+    }
+
+    pub fn image(&self) -> usize {
+        self.image
+    }
+
     pub fn set_start(&mut self, start: usize) {
         self.region.start = start;
     }
@@ -198,10 +215,9 @@ fn generate_blk_req(
 ) {
     let region_start = req.region_start();
     let region_size = req.region_size();
-    let mut cache_ptr = cache;
     for req_node in req_node_list {
         let sector = req_node.sector;
-        if sector + req_node.iov_sum_up / SECTOR_BSIZE > region_start + region_size {
+        if sector + req_node.iov_sum_up / SECTOR_BSIZE > region_size {
             println!(
                 "blk_req_handler: {} out of vm range",
                 if req_node.req_type == VIRTIO_BLK_T_IN as u32 {
@@ -236,6 +252,7 @@ fn generate_blk_req(
                     );
                     EXECUTOR.add_task(task, false);
                 } else {
+                    let mut cache_ptr = cache + (sector + region_start) * SECTOR_BSIZE;
                     for iov in req_node.iov.iter() {
                         let data_bg = iov.data_bg;
                         let len = iov.len as usize;
@@ -245,6 +262,7 @@ fn generate_blk_req(
                             continue;
                         }
                         memcpy_safe(data_bg as *mut u8, cache_ptr as *mut u8, len);
+                        vm.mark_dirty(vm.hva2ipa(data_bg), len);
                         cache_ptr += len;
                     }
                 }
@@ -278,11 +296,12 @@ fn generate_blk_req(
                     );
                     EXECUTOR.add_task(task, false);
                 } else {
+                    let mut cache_ptr = cache + (sector + region_start) * SECTOR_BSIZE;
                     for iov in req_node.iov.iter() {
                         let data_bg = iov.data_bg;
                         let len = iov.len as usize;
                         if len < SECTOR_BSIZE {
-                            println!("blk_req_handler: read len < SECTOR_BSIZE");
+                            println!("blk_req_handler: write len < SECTOR_BSIZE");
                             continue;
                         }
                         memcpy_safe(cache_ptr as *mut u8, data_bg as *mut u8, len);
@@ -291,7 +310,25 @@ fn generate_blk_req(
                 }
             }
             VIRTIO_BLK_T_FLUSH => {
-                todo!();
+                if req.mediated() {
+                    // mediated blk flush, complete after MVM flushes its disk
+                    let task = AsyncTask::new(
+                        FlushAsyncMsg {
+                            src_vm: vm.clone(),
+                            vq: vq.clone(),
+                            dev: dev.clone(),
+                            blk_id: vm.med_blk_id(),
+                            used_info: UsedInfo {
+                                desc_chain_head_idx: req_node.desc_chain_head_idx,
+                                used_len: req_node.iov_total as u32,
+                            },
+                        },
+                        vm.id(),
+                        async_blk_io_req(),
+                    );
+                    EXECUTOR.add_task(task, false);
+                }
+                // the disk image of a non-mediated blk is in memory, the flush completes at once
             }
             VIRTIO_BLK_T_GET_ID => {
                 let name = CString::new("virtio-blk").unwrap();
@@ -299,10 +336,13 @@ fn generate_blk_req(
                 let data_bg =
                     unsafe { core::slice::from_raw_parts_mut(req_node.iov[0].data_bg as *mut u8, cstr.len()) };
                 data_bg.copy_from_slice(cstr);
-                if !vq.update_used_ring(req_node.iov_total as u32, req_node.desc_chain_head_idx) {
-                    println!("blk_req_handler: fail to update used ring");
+                vm.mark_dirty(vm.hva2ipa(req_node.iov[0].data_bg), cstr.len());
+                if req.mediated() {
+                    if !vq.update_used_ring(req_node.iov_total as u32, req_node.desc_chain_head_idx) {
+                        println!("blk_req_handler: fail to update used ring");
+                    }
+                    dev.notify(vq.vq_indx());
                 }
-                dev.notify();
             }
             _ => {
                 println!("Wrong block request type {} ", req_node.req_type);
@@ -310,12 +350,9 @@ fn generate_blk_req(
             }
         }
 
-        // update used ring
-        if !req.mediated() {
-            todo!("reset num to vq size");
-            // if !vq.update_used_ring(req_node.iov_total as u32, req_node.desc_chain_head_idx as u32) {
-            //     println!("blk_req_handler: fail to update used ring");
-            // }
+        // update used ring, the caller notifies the guest after all requests
+        if !req.mediated() && !vq.update_used_ring(req_node.iov_total as u32, req_node.desc_chain_head_idx) {
+            println!("blk_req_handler: fail to update used ring");
         }
     }
 }
@@ -381,7 +418,7 @@ pub fn virtio_blk_notify_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<V
                             next_desc_idx,
                             vq.desc_flags(next_desc_idx)
                         );
-                        blk.notify();
+                        blk.notify(vq.vq_indx());
                         return false;
                     }
                     head = false;
@@ -402,7 +439,7 @@ pub fn virtio_blk_notify_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<V
                             req_node.req_type,
                             vq.desc_flags(next_desc_idx)
                         );
-                        blk.notify();
+                        blk.notify(vq.vq_indx());
                         return false;
                     }
                     let data_bg = vm.ipa2hva(vq.desc_addr(next_desc_idx));
@@ -422,7 +459,7 @@ pub fn virtio_blk_notify_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<V
                 /*state handler*/
                 if !vq.desc_is_writable(next_desc_idx) {
                     println!("Failed to get virt blk queue desc status, idx = {}", next_desc_idx);
-                    blk.notify();
+                    blk.notify(vq.vq_indx());
                     return false;
                 }
                 let vstatus_addr = vm.ipa2hva(vq.desc_addr(next_desc_idx));
@@ -431,11 +468,15 @@ pub fn virtio_blk_notify_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<V
                     return false;
                 }
                 let vstatus = unsafe { &mut *(vstatus_addr as *mut u8) };
-                if req_node.req_type > 1 && req_node.req_type != VIRTIO_BLK_T_GET_ID as u32 {
+                if req_node.req_type > 1
+                    && req_node.req_type != VIRTIO_BLK_T_GET_ID as u32
+                    && req_node.req_type != VIRTIO_BLK_T_FLUSH as u32
+                {
                     *vstatus = VIRTIO_BLK_S_UNSUPP as u8;
                 } else {
                     *vstatus = VIRTIO_BLK_S_OK as u8;
                 }
+                vm.mark_dirty(vq.desc_addr(next_desc_idx), 1);
                 break;
             }
             next_desc_idx = vq.desc_next(next_desc_idx) as usize;
@@ -447,20 +488,17 @@ pub fn virtio_blk_notify_handler(vq: Arc<Virtq>, blk: Arc<VirtioMmio>, vm: Arc<V
         process_count += 1;
     }
 
-    if !req.mediated() {
-        // generate_blk_req(&req, &vq, &blk, dev.cache(), &vm);
-        unimplemented!("!req.mediated()");
+    let cache = if req.mediated() {
+        mediated_blk_list_get(vm.med_blk_id()).cache_pa()
     } else {
-        let mediated_blk = mediated_blk_list_get(vm.med_blk_id());
-        let cache = mediated_blk.cache_pa();
-        generate_blk_req(req, vq.clone(), blk.clone(), cache, vm, req_node_list);
+        req.image()
     };
+    generate_blk_req(req, vq.clone(), blk.clone(), cache, vm, req_node_list);
 
     // let time1 = time_current_us();
 
     if vq.avail_flags() == 0 && process_count > 0 && !req.mediated() {
-        println!("virtio blk notify");
-        blk.notify();
+        blk.notify(vq.vq_indx());
     }
 
     // let end = time_current_us();
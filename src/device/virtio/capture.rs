@@ -0,0 +1,190 @@
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut, copy_nonoverlapping, read_volatile, write_volatile};
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::kernel::{active_vm, timer};
+use crate::util::round_up;
+
+// Mirror port of the virtio-net switch: frames sent by or to the selected VMs are copied into a ring registered by
+// MVM, which may write them out as a pcap file.
+//
+// The ring is [ NetCaptureRingHdr ][ data - size ], the data area is a byte stream of records, each one is
+// [ NetCaptureRecord ][ frame - caplen ] padded to 8 bytes. A record may wrap around the end of the data area.
+
+// the most bytes of a frame captured
+const NET_CAPTURE_SNAPLEN: usize = 0x10000;
+
+static NET_CAPTURE: Mutex<Option<NetCapture>> = Mutex::new(None);
+static NET_CAPTURE_ON: AtomicBool = AtomicBool::new(false);
+
+#[repr(C)]
+struct NetCaptureRingHdr {
+    // bytes of the data area following the header
+    size: u64,
+    // bytes produced by the hypervisor, only written by the hypervisor
+    head: u64,
+    // bytes consumed by MVM, only written by MVM
+    tail: u64,
+    // records lost as the ring is full
+    dropped: u64,
+}
+
+#[repr(C)]
+struct NetCaptureRecord {
+    // bytes of the record including this header and the padding
+    len: u32,
+    // bytes of the frame in the record
+    caplen: u32,
+    // bytes of the frame on the switch
+    orig_len: u32,
+    src_vm: u16,
+    vlan: u16,
+    // ns since the hypervisor boots
+    timestamp: u64,
+}
+
+struct NetCapture {
+    // hva of the ring header
+    ring: usize,
+    size: usize,
+    // VMs whose frames are captured
+    vm_bitmap: usize,
+}
+
+impl NetCapture {
+    fn hdr(&self) -> *mut NetCaptureRingHdr {
+        self.ring as *mut NetCaptureRingHdr
+    }
+
+    fn data(&self) -> usize {
+        self.ring + size_of::<NetCaptureRingHdr>()
+    }
+
+    // copy `len` bytes to the data area at the stream position `pos`
+    fn write(&self, pos: u64, src: *const u8, len: usize) {
+        let offset = (pos % self.size as u64) as usize;
+        let first = usize::min(len, self.size - offset);
+        unsafe {
+            copy_nonoverlapping(src, (self.data() + offset) as *mut u8, first);
+            copy_nonoverlapping(src.add(first), self.data() as *mut u8, len - first);
+        }
+    }
+
+    fn push(&self, record: &NetCaptureRecord, frame: &[u8]) {
+        let hdr = self.hdr();
+        let head = unsafe { read_volatile(addr_of!((*hdr).head)) };
+        let tail = unsafe { read_volatile(addr_of!((*hdr).tail)) };
+        let used = head.wrapping_sub(tail) as usize;
+        if used > self.size || self.size - used < record.len as usize {
+            unsafe { write_volatile(addr_of_mut!((*hdr).dropped), (*hdr).dropped + 1) };
+            return;
+        }
+        self.write(head, record as *const _ as *const u8, size_of::<NetCaptureRecord>());
+        self.write(
+            head + size_of::<NetCaptureRecord>() as u64,
+            frame.as_ptr(),
+            record.caplen as usize,
+        );
+        // the record must be visible to MVM before the head moves
+        fence(Ordering::Release);
+        unsafe { write_volatile(addr_of_mut!((*hdr).head), head + record.len as u64) };
+    }
+}
+
+#[inline]
+fn capture_selected(vm_bitmap: usize, vm_id: usize) -> bool {
+    vm_id < usize::BITS as usize && vm_bitmap & (1 << vm_id) != 0
+}
+
+/* Copy a frame into the capture ring if the sender or any receiver is selected.
+ *
+ * @param[in] read_frame: reads the ethernet frame without the virtio-net header, only called for a captured frame.
+ * @param[in] src_vm: id of the sender VM.
+ * @param[in] vlan: VLAN of the sender port.
+ * @param[in] dst_vms: ids of the VMs the frame is delivered to.
+ */
+pub fn net_capture<F, I>(read_frame: F, src_vm: usize, vlan: u16, mut dst_vms: I)
+where
+    F: FnOnce() -> Vec<u8>,
+    I: Iterator<Item = usize>,
+{
+    let capture = NET_CAPTURE.lock();
+    let Some(capture) = capture.as_ref() else {
+        return;
+    };
+    if !capture_selected(capture.vm_bitmap, src_vm) && !dst_vms.any(|id| capture_selected(capture.vm_bitmap, id)) {
+        return;
+    }
+    let frame = read_frame();
+    let caplen = usize::min(frame.len(), NET_CAPTURE_SNAPLEN);
+    let record = NetCaptureRecord {
+        len: round_up(size_of::<NetCaptureRecord>() + caplen, 8) as u32,
+        caplen: caplen as u32,
+        orig_len: frame.len() as u32,
+        src_vm: src_vm as u16,
+        vlan,
+        timestamp: timer::now().as_nanos() as u64,
+    };
+    capture.push(&record, &frame[..caplen]);
+}
+
+#[inline]
+pub fn net_capture_enabled() -> bool {
+    NET_CAPTURE_ON.load(Ordering::Relaxed)
+}
+
+/* Register the capture ring of MVM, or stop capturing.
+ *
+ * @param[in] ring_ipa: ipa of the ring in MVM, 0 to stop capturing.
+ * @param[in] ring_len: bytes of the ring including its header.
+ * @param[in] vm_bitmap: bitmap of the VMs whose frames are captured.
+ */
+pub fn virtio_net_capture(ring_ipa: usize, ring_len: usize, vm_bitmap: usize) -> Result<usize, ()> {
+    let mut capture = NET_CAPTURE.lock();
+    if ring_ipa == 0 {
+        *capture = None;
+        NET_CAPTURE_ON.store(false, Ordering::Relaxed);
+        info!("virtio net capture stopped");
+        return Ok(0);
+    }
+    if ring_len <= size_of::<NetCaptureRingHdr>() + size_of::<NetCaptureRecord>() || ring_ipa % 8 != 0 {
+        warn!(
+            "virtio_net_capture: illegal ring ipa {:#x} len {:#x}",
+            ring_ipa, ring_len
+        );
+        return Err(());
+    }
+    let vm = active_vm().unwrap();
+    let ring = vm.ipa2hva(ring_ipa);
+    // the ring must be contiguous in the hypervisor
+    if ring == 0 || vm.ipa2hva(ring_ipa + ring_len - 1) != ring + ring_len - 1 {
+        warn!(
+            "virtio_net_capture: illegal ring ipa {:#x} len {:#x}",
+            ring_ipa, ring_len
+        );
+        return Err(());
+    }
+    let size = ring_len - size_of::<NetCaptureRingHdr>();
+    unsafe {
+        write_volatile(
+            ring as *mut NetCaptureRingHdr,
+            NetCaptureRingHdr {
+                size: size as u64,
+                head: 0,
+                tail: 0,
+                dropped: 0,
+            },
+        );
+    }
+    *capture = Some(NetCapture { ring, size, vm_bitmap });
+    NET_CAPTURE_ON.store(true, Ordering::Relaxed);
+    info!(
+        "virtio net capture: ring ipa {:#x} size {:#x}, VM bitmap {:#x}",
+        ring_ipa, size, vm_bitmap
+    );
+    Ok(0)
+}
@@ -2,11 +2,9 @@ use alloc::sync::Arc;
 
 use spin::Mutex;
 
-use crate::arch::PAGE_SIZE;
-use crate::device::{EmuContext, VirtioMmio, Virtq};
+use crate::device::{ConsoleBackend, EmuContext, VirtioMmio, Virtq};
 use crate::kernel::vm_by_id;
 use crate::kernel::Vm;
-use crate::util::round_down;
 
 use super::dev::DevDesc;
 use super::iov::VirtioIov;
@@ -29,6 +27,9 @@ const VIRTIO_CONSOLE_RESIZE: usize = 5;
 const VIRTIO_CONSOLE_PORT_OPEN: usize = 6;
 const VIRTIO_CONSOLE_PORT_NAME: usize = 7;
 
+// the other end of a console configured with this VM id is the hypervisor UART
+pub const VIRTIO_CONSOLE_HOST_VMID: u16 = 0xffff;
+
 pub struct ConsoleDesc {
     inner: Mutex<ConsoleDescInner>,
 }
@@ -65,6 +66,10 @@ impl ConsoleDesc {
         let inner = self.inner.lock();
         (inner.oppo_end_vmid, inner.oppo_end_ipa)
     }
+
+    pub fn host_console(&self) -> bool {
+        self.inner.lock().oppo_end_vmid == VIRTIO_CONSOLE_HOST_VMID
+    }
 }
 
 #[repr(C)]
@@ -137,7 +142,9 @@ pub fn virtio_console_notify_handler(vq: Arc<Virtq>, console: Arc<VirtioMmio>, v
             idx = vq.desc_next(idx) as usize;
         }
 
-        if !virtio_console_recv(trgt_vmid, trgt_console_ipa, tx_iov, len) {
+        if trgt_vmid == VIRTIO_CONSOLE_HOST_VMID {
+            virtio_console_output(&vm, &tx_iov, len);
+        } else if !virtio_console_recv(trgt_vmid, trgt_console_ipa, tx_iov, len) {
             println!("virtio_console_notify_handler: failed send");
             // return false;
         }
@@ -151,7 +158,7 @@ pub fn virtio_console_notify_handler(vq: Arc<Virtq>, console: Arc<VirtioMmio>, v
         return false;
     }
 
-    console.notify();
+    console.notify(vq.vq_indx());
 
     true
 }
@@ -167,7 +174,7 @@ fn virtio_console_recv(trgt_vmid: u16, trgt_console_ipa: u64, tx_iov: VirtioIov,
 
     let console = match trgt_vm
         .find_emu_dev(trgt_console_ipa as usize)
-        .and_then(|dev| dev.into_any_arc().downcast::<VirtioMmio>().ok())
+        .and_then(super::pci::virtio_dev_downcast)
     {
         Some(x) => x,
         _ => {
@@ -176,6 +183,12 @@ fn virtio_console_recv(trgt_vmid: u16, trgt_console_ipa: u64, tx_iov: VirtioIov,
         }
     };
 
+    virtio_console_deliver(&trgt_vm, &console, tx_iov, len)
+}
+
+// copy the data in `tx_iov` to a receive buffer of the console of `trgt_vm`
+fn virtio_console_deliver(trgt_vm: &Vm, console: &VirtioMmio, tx_iov: VirtioIov, len: usize) -> bool {
+    let trgt_vmid = trgt_vm.id();
     if !console.dev().activated() {
         println!(
             "virtio_console_recv: trgt_vm[{}] virtio console dev is not ready",
@@ -219,13 +232,6 @@ fn virtio_console_recv(trgt_vmid: u16, trgt_console_ipa: u64, tx_iov: VirtioIov,
             return false;
         }
         let desc_len = rx_vq.desc_len(desc_idx) as usize;
-        // dirty pages
-        if trgt_vmid != 0 {
-            let mut ipa_addr = round_down(rx_vq.desc_addr(desc_idx), PAGE_SIZE);
-            while ipa_addr <= round_down(rx_vq.desc_addr(desc_idx) + desc_len, PAGE_SIZE) {
-                ipa_addr += PAGE_SIZE;
-            }
-        }
         rx_iov.push_data(dst, desc_len);
         rx_len += desc_len;
         if rx_len >= len {
@@ -253,6 +259,7 @@ fn virtio_console_recv(trgt_vmid: u16, trgt_console_ipa: u64, tx_iov: VirtioIov,
         );
         return false;
     }
+    rx_iov.mark_dirty(trgt_vm);
 
     if !rx_vq.update_used_ring(len as u32, desc_idx_header as u32) {
         println!(
@@ -263,6 +270,29 @@ fn virtio_console_recv(trgt_vmid: u16, trgt_console_ipa: u64, tx_iov: VirtioIov,
         return false;
     }
 
-    console.notify();
+    console.notify(rx_vq.vq_indx());
     true
 }
+
+// print the output of a console connected to the hypervisor UART
+fn virtio_console_output(vm: &Vm, tx_iov: &VirtioIov, len: usize) {
+    let mut buf = vec![0; len];
+    tx_iov.copy_to_buf(buf.as_mut_ptr() as usize, len);
+    crate::device::console_output(vm.id(), &buf);
+}
+
+impl ConsoleBackend for VirtioMmio {
+    fn console_input(&self, data: &[u8]) -> bool {
+        let Some(vm) = self.upper_vm() else {
+            return false;
+        };
+        let mut tx_iov = VirtioIov::default();
+        tx_iov.push_data(data.as_ptr() as usize, data.len());
+        // the input is dropped if the guest has no receive buffer
+        virtio_console_deliver(&vm, self, tx_iov, data.len())
+    }
+
+    fn console_vm_id(&self) -> Option<usize> {
+        self.upper_vm().map(|vm| vm.id())
+    }
+}
@@ -1,5 +1,6 @@
 use spin::Mutex;
 
+use crate::arch::Address;
 use crate::config::VmEmulatedDeviceConfig;
 
 #[cfg(feature = "balloon")]
@@ -43,19 +44,22 @@ impl VirtDev {
             VirtioDeviceType::Block => {
                 let desc = DevDesc::Blk(BlkDesc::new(config.cfg_list[1]));
 
-                // TODO: blk_features_init & cache init
                 let features = blk_features();
 
                 let mut blk_req = VirtioBlkReq::default();
                 blk_req.set_start(config.cfg_list[0]);
                 blk_req.set_mediated(config.mediated);
                 blk_req.set_size(config.cfg_list[1]);
+                if !config.mediated {
+                    blk_req.set_image(config.cfg_list[2].pa2hva());
+                }
                 (desc, features, Some(blk_req))
             }
             VirtioDeviceType::Net => {
-                let desc = DevDesc::Net(NetDesc::new(&config.cfg_list));
+                let net_desc = NetDesc::new(&config.cfg_list);
 
-                let features = net_features();
+                let features = net_features(net_desc.max_queue_pairs());
+                let desc = DevDesc::Net(net_desc);
 
                 (desc, features, None)
             }
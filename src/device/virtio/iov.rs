@@ -1,6 +1,7 @@
 use alloc::vec::Vec;
 use core::slice::from_raw_parts;
 
+use crate::kernel::Vm;
 use crate::util::memcpy_safe;
 
 pub(super) struct VirtioIov {
@@ -58,6 +59,13 @@ impl VirtioIov {
         }
     }
 
+    // the buffers are written by the hypervisor, mark them dirty for the migration of the vm
+    pub fn mark_dirty(&self, vm: &Vm) {
+        for iov_data in self.vector.iter() {
+            vm.mark_dirty(vm.hva2ipa(iov_data.buf), iov_data.len);
+        }
+    }
+
     pub fn num(&self) -> usize {
         self.vector.len()
     }
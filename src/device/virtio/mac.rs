@@ -1,15 +1,36 @@
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
 
 use spin::Mutex;
 
+use crate::kernel::timer::now;
+use crate::util::timer_list::TimerValue;
+
 use super::VirtioMmio;
 
-static MAC2NIC_INFO: Mutex<BTreeMap<MacAddress, Arc<VirtioMmio>>> = Mutex::new(BTreeMap::new());
+// the most MACs learned on a port, the least recently seen one is evicted for a new one
+const MAC_ENTRY_PER_PORT_MAX: usize = 64;
+// a learned MAC not seen for this long is forgotten, its frames are flooded again
+const MAC_AGEING_TIME: Duration = Duration::from_secs(300);
+
+// ports of the inter-VM switch
+static NIC_LIST: Mutex<Vec<Arc<VirtioMmio>>> = Mutex::new(Vec::new());
+// learned forwarding table, keyed by (VLAN, MAC)
+static MAC2NIC_INFO: Mutex<BTreeMap<(u16, MacAddress), MacEntry>> = Mutex::new(BTreeMap::new());
+// denied VM pairs, stored as (smaller id, larger id)
+static NET_ACL: Mutex<BTreeSet<(usize, usize)>> = Mutex::new(BTreeSet::new());
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct MacAddress([u8; 6]);
 
+struct MacEntry {
+    nic: Arc<VirtioMmio>,
+    // when a frame from the MAC was last seen on the port
+    last_seen: TimerValue,
+}
+
 impl MacAddress {
     fn new(mac: &[u8]) -> Self {
         let mut this = Self([0; 6]);
@@ -18,12 +39,53 @@ impl MacAddress {
     }
 }
 
-pub fn set_mac_info(mac: &[u8], nic: Arc<VirtioMmio>) {
-    MAC2NIC_INFO.lock().insert(MacAddress::new(mac), nic);
+pub fn add_virtio_nic(nic: Arc<VirtioMmio>) {
+    NIC_LIST.lock().push(nic);
+}
+
+// Learn the source MAC of a frame sent from the port
+pub fn learn_mac(vlan: u16, mac: &[u8], nic: &Arc<VirtioMmio>) {
+    // multicast source address is invalid
+    if mac[0] & 1 != 0 {
+        return;
+    }
+    let key = (vlan, MacAddress::new(mac));
+    let now = now();
+    let mut table = MAC2NIC_INFO.lock();
+    match table.get_mut(&key) {
+        Some(entry) if Arc::ptr_eq(&entry.nic, nic) => entry.last_seen = now,
+        _ => {
+            // the entry of a MAC moving to another port is replaced, so only the port's own entries are counted
+            table.remove(&key);
+            let port_entries = table.iter().filter(|(_, entry)| Arc::ptr_eq(&entry.nic, nic));
+            if port_entries.clone().count() >= MAC_ENTRY_PER_PORT_MAX {
+                let oldest = port_entries
+                    .min_by_key(|(_, entry)| entry.last_seen)
+                    .map(|(&key, _)| key);
+                if let Some(oldest) = oldest {
+                    table.remove(&oldest);
+                }
+            }
+            table.insert(
+                key,
+                MacEntry {
+                    nic: nic.clone(),
+                    last_seen: now,
+                },
+            );
+        }
+    }
 }
 
-pub fn mac_to_nic(mac: &[u8]) -> Option<Arc<VirtioMmio>> {
-    MAC2NIC_INFO.lock().get(&MacAddress::new(mac)).cloned()
+pub fn mac_to_nic(vlan: u16, mac: &[u8]) -> Option<Arc<VirtioMmio>> {
+    let key = (vlan, MacAddress::new(mac));
+    let mut table = MAC2NIC_INFO.lock();
+    let entry = table.get(&key)?;
+    if now().saturating_sub(entry.last_seen) > MAC_AGEING_TIME {
+        table.remove(&key);
+        return None;
+    }
+    Some(entry.nic.clone())
 }
 
 #[inline]
@@ -31,17 +93,50 @@ pub fn virtio_nic_list_walker<F>(mut f: F)
 where
     F: FnMut(&Arc<VirtioMmio>),
 {
-    for nic in MAC2NIC_INFO.lock().values() {
+    for nic in NIC_LIST.lock().iter() {
         f(nic);
     }
 }
 
 pub fn remove_virtio_nic(vmid: usize) {
-    MAC2NIC_INFO.lock().retain(|_mac, nic| {
-        if let Some(vm) = nic.upper_vm() {
-            vm.id() != vmid
-        } else {
-            false // if the vm is gone, the nic should be removed
-        }
-    });
+    // if the vm is gone, the nic should be removed
+    let keep = |nic: &Arc<VirtioMmio>| nic.upper_vm().is_some_and(|vm| vm.id() != vmid);
+    NIC_LIST.lock().retain(keep);
+    MAC2NIC_INFO.lock().retain(|_, entry| keep(&entry.nic));
+    NET_ACL.lock().retain(|&(a, b)| a != vmid && b != vmid);
+}
+
+#[inline]
+fn acl_key(vmid_a: usize, vmid_b: usize) -> (usize, usize) {
+    (usize::min(vmid_a, vmid_b), usize::max(vmid_a, vmid_b))
+}
+
+pub fn net_acl_allow(vmid_a: usize, vmid_b: usize) -> bool {
+    !NET_ACL.lock().contains(&acl_key(vmid_a, vmid_b))
+}
+
+/* Deny or allow the traffic between two VMs on the virtual network.
+ *
+ * @param[in] vmid_a: id of a VM.
+ * @param[in] vmid_b: id of the other VM.
+ * @param[in] deny: 1 to deny the traffic, 0 to allow it.
+ */
+pub fn virtio_net_set_acl(vmid_a: usize, vmid_b: usize, deny: usize) -> Result<usize, ()> {
+    if vmid_a == vmid_b {
+        warn!("virtio_net_set_acl: illegal VM pair {} {}", vmid_a, vmid_b);
+        return Err(());
+    }
+    let mut acl = NET_ACL.lock();
+    if deny != 0 {
+        acl.insert(acl_key(vmid_a, vmid_b));
+    } else {
+        acl.remove(&acl_key(vmid_a, vmid_b));
+    }
+    info!(
+        "virtio net acl: {} traffic between VM {} and VM {}",
+        if deny != 0 { "deny" } else { "allow" },
+        vmid_a,
+        vmid_b
+    );
+    Ok(0)
 }
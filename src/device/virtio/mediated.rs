@@ -3,7 +3,7 @@ use alloc::vec::Vec;
 
 use spin::Mutex;
 
-use crate::device::{virtio_blk_notify_handler, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT};
+use crate::device::{virtio_blk_notify_handler, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT};
 use crate::kernel::IpiMessage;
 use crate::kernel::{
     active_vm, hvc_send_msg_to_vm, vm_list_walker, AsyncTaskState, HvcDefaultMsg, HvcGuestMsg, IpiInnerMsg, Vm,
@@ -221,6 +221,24 @@ pub fn mediated_blk_write(blk_idx: usize, sector: usize, count: usize) {
     }
 }
 
+pub fn mediated_blk_flush(blk_idx: usize) {
+    let mediated_blk = mediated_blk_list_get(blk_idx);
+    let nreq = mediated_blk.nreq();
+    mediated_blk.set_nreq(nreq + 1);
+    mediated_blk.set_type(VIRTIO_BLK_T_FLUSH);
+    mediated_blk.set_sector(0);
+    mediated_blk.set_count(0);
+
+    let med_msg = HvcDefaultMsg {
+        fid: HVC_MEDIATED,
+        event: HVC_MEDIATED_DRV_NOTIFY,
+    };
+
+    if !hvc_send_msg_to_vm(0, &HvcGuestMsg::Default(med_msg)) {
+        println!("mediated_blk_flush: failed to notify VM 0");
+    }
+}
+
 pub struct UsedInfo {
     pub desc_chain_head_idx: u32,
     pub used_len: u32,
@@ -249,3 +267,11 @@ pub struct WriteAsyncMsg {
     pub buffer: Arc<Mutex<Vec<u8>>>,
     pub used_info: UsedInfo,
 }
+
+pub struct FlushAsyncMsg {
+    pub src_vm: Arc<Vm>,
+    pub vq: Arc<Virtq>,
+    pub dev: Arc<VirtioMmio>,
+    pub blk_id: usize,
+    pub used_info: UsedInfo,
+}
@@ -1,7 +1,8 @@
 use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
-use spin::Mutex;
+use spin::{Mutex, Once};
 
+use crate::arch::PAGE_SIZE;
 use crate::config::VmEmulatedDeviceConfig;
 use crate::device::EmuContext;
 use crate::device::Virtq;
@@ -14,7 +15,8 @@ use super::blk::{virtio_blk_notify_handler, virtio_mediated_blk_notify_handler,
 use super::console::{virtio_console_notify_handler, VIRTQUEUE_CONSOLE_MAX_SIZE};
 use super::dev::{VirtDev, VirtioDeviceType};
 use super::net::{virtio_net_handle_ctrl, virtio_net_notify_handler, VIRTQUEUE_NET_MAX_SIZE};
-use super::queue::VIRTQ_READY;
+use super::pci::VirtioPci;
+use super::queue::{VirtqMigrateState, VIRTQ_READY};
 
 pub const VIRTIO_F_VERSION_1: usize = 1 << 32;
 pub const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
@@ -94,6 +96,29 @@ impl VirtMmioRegs {
     }
 }
 
+// state of a virtio device carried over by migration, the state of each of its queues follows it
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VirtioMigrateState {
+    base: usize,
+    vq_num: usize,
+    driver_features: usize,
+    activated: bool,
+    net_queue_pairs: usize,
+    net_status: u16,
+    regs: VirtMmioRegs,
+}
+
+impl VirtioMigrateState {
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn vq_num(&self) -> usize {
+        self.vq_num
+    }
+}
+
 struct VirtioInnerConst {
     base: usize,
     length: usize,
@@ -101,6 +126,8 @@ struct VirtioInnerConst {
     vq: Vec<Arc<Virtq>>,
     dev: VirtDev,
     vm: Weak<Vm>,
+    // the PCI transport of the device, which may raise MSI-X instead of the interrupt of the device
+    pci: Once<Weak<VirtioPci>>,
 }
 
 pub struct VirtioMmio {
@@ -118,6 +145,7 @@ impl VirtioMmio {
                 vq: vec![],
                 dev: VirtDev::new(dev_type, config),
                 vm,
+                pci: Once::new(),
             },
             inner: Mutex::new(VirtioMmioInnerMut::new()),
         }
@@ -146,12 +174,17 @@ impl VirtioMmio {
             }
             VirtioDeviceType::Net => {
                 self.set_q_num_max(VIRTQUEUE_NET_MAX_SIZE as u32);
-                // Not support feature VIRTIO_NET_F_CTRL_VQ (no control queue)
-                for i in 0..2 {
+                let queue_pairs = match self.inner_const.dev.desc() {
+                    super::dev::DevDesc::Net(desc) => desc.max_queue_pairs(),
+                    _ => unreachable!(),
+                };
+                // rx and tx queue of each queue pair, then the control queue, queue 2 serves as the control queue
+                // of a driver without VIRTIO_NET_F_MQ
+                for i in 0..2 * queue_pairs {
                     let queue = Virtq::new(i, weak.clone(), virtio_net_notify_handler);
                     self.inner_const.vq.push(queue);
                 }
-                let queue = Virtq::new(2, weak.clone(), virtio_net_handle_ctrl);
+                let queue = Virtq::new(2 * queue_pairs, weak.clone(), virtio_net_handle_ctrl);
                 self.inner_const.vq.push(queue);
             }
             VirtioDeviceType::Console => {
@@ -179,10 +212,26 @@ impl VirtioMmio {
         self.inner_const.vm.upgrade()
     }
 
+    pub(super) fn set_pci(&self, pci: Weak<VirtioPci>) {
+        self.inner_const.pci.call_once(|| pci);
+    }
+
+    // the MSI-X vector of the queue or of the configuration change if the PCI transport enables MSI-X
+    fn msix_notify(&self, vq_idx: Option<usize>) -> bool {
+        self.inner_const
+            .pci
+            .get()
+            .and_then(|pci| pci.upgrade())
+            .is_some_and(|pci| pci.msix_notify(vq_idx))
+    }
+
     pub fn notify_config(&self) {
         let mut inner = self.inner.lock();
         inner.regs.irt_stat |= VIRTIO_MMIO_INT_CONFIG;
         drop(inner);
+        if self.msix_notify(None) {
+            return;
+        }
         let vm = self.upper_vm().unwrap();
         let int_id = self.dev().int_id();
         let target_vcpu = vm.vcpu(0).unwrap();
@@ -196,10 +245,14 @@ impl VirtioMmio {
         }
     }
 
-    pub fn notify(&self) {
+    // raise the interrupt of the used ring of the queue
+    pub fn notify(&self, vq_idx: usize) {
         let mut inner = self.inner.lock();
         inner.regs.irt_stat |= VIRTIO_MMIO_INT_VRING;
         drop(inner);
+        if self.msix_notify(Some(vq_idx)) {
+            return;
+        }
         let vm = self.upper_vm().unwrap();
         let int_id = self.dev().int_id();
         let target_vcpu = vm.vcpu(0).unwrap();
@@ -218,12 +271,16 @@ impl VirtioMmio {
         let mut inner = self.inner.lock();
         inner.regs.dev_stat = 0;
         inner.regs.irt_stat = 0;
+        inner.driver_features = 0;
         let idx = inner.regs.q_sel as usize;
         let vq = &self.inner_const.vq;
         vq[idx].set_ready(0);
         for virtq in vq.iter() {
             virtq.reset();
         }
+        if let super::dev::DevDesc::Net(desc) = self.dev().desc() {
+            desc.set_queue_pairs(1);
+        }
         self.dev().set_activated(false);
     }
 
@@ -247,6 +304,26 @@ impl VirtioMmio {
         inner.regs.dev_stat = dev_stat;
     }
 
+    // the driver writes the device status, 0 resets the device
+    pub fn write_dev_stat(&self, dev_stat: u32) {
+        self.set_dev_stat(dev_stat);
+        if dev_stat == 0 {
+            self.dev_reset();
+            info!(
+                "VM {} virtio device {:x} is reset",
+                active_vm().unwrap().id(),
+                self.base()
+            );
+        } else if dev_stat == 0xf {
+            self.dev().set_activated(true);
+            info!(
+                "VM {} virtio device {:x} init ok",
+                active_vm().unwrap().id(),
+                self.base()
+            );
+        }
+    }
+
     pub fn set_dev_feature(&self, dev_feature: u32) {
         let mut inner = self.inner.lock();
         inner.regs.dev_feature = dev_feature;
@@ -264,7 +341,7 @@ impl VirtioMmio {
 
     pub fn set_drv_feature_sel(&self, drv_feature_sel: u32) {
         let mut inner = self.inner.lock();
-        inner.regs.drv_feature = drv_feature_sel;
+        inner.regs.drv_feature_sel = drv_feature_sel;
     }
 
     pub fn or_driver_feature(&self, driver_features: usize) {
@@ -272,6 +349,12 @@ impl VirtioMmio {
         inner.driver_features |= driver_features;
     }
 
+    // features negotiated by the driver
+    pub fn driver_features(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.driver_features
+    }
+
     pub(super) fn dev(&self) -> &VirtDev {
         &self.inner_const.dev
     }
@@ -333,10 +416,76 @@ impl VirtioMmio {
         }
     }
 
+    // run the notify handler of the queue, false if there is no such queue or the request fails
+    pub fn queue_notify(&self, idx: usize) -> bool {
+        self.inner_const.vq.get(idx).is_some_and(|vq| vq.call_notify_handler())
+    }
+
+    pub fn vq_num(&self) -> usize {
+        self.inner_const.vq.len()
+    }
+
     #[inline]
     pub fn base(&self) -> usize {
         self.inner_const.base
     }
+
+    pub fn migrate_save(&self) -> (VirtioMigrateState, Vec<VirtqMigrateState>) {
+        let (net_queue_pairs, net_status) = match self.dev().desc() {
+            super::dev::DevDesc::Net(desc) => (desc.queue_pairs(), desc.status()),
+            _ => (0, 0),
+        };
+        let inner = self.inner.lock();
+        let state = VirtioMigrateState {
+            base: self.base(),
+            vq_num: self.vq_num(),
+            driver_features: inner.driver_features,
+            activated: self.dev().activated(),
+            net_queue_pairs,
+            net_status,
+            regs: inner.regs,
+        };
+        drop(inner);
+        (state, self.inner_const.vq.iter().map(|vq| vq.migrate_save()).collect())
+    }
+
+    /* Restore the state of the device and its queues on the receiver VM.
+     *
+     * @param[in] vm: the VM which owns the device.
+     * @param[in] state: the device state saved by the sender.
+     * @param[in] vq_states: the queue states saved by the sender.
+     */
+    pub fn migrate_restore(
+        &self,
+        vm: &Vm,
+        state: &VirtioMigrateState,
+        vq_states: &[VirtqMigrateState],
+    ) -> Result<(), ()> {
+        if vq_states.len() != self.vq_num() {
+            error!(
+                "virtio device {:x} migrate restore: {} queues saved, {} expected",
+                self.base(),
+                vq_states.len(),
+                self.vq_num()
+            );
+            return Err(());
+        }
+        for (vq, vq_state) in self.inner_const.vq.iter().zip(vq_states) {
+            vq.migrate_restore(vm, vq_state)?;
+        }
+        if let super::dev::DevDesc::Net(desc) = self.dev().desc() {
+            if !desc.set_queue_pairs(state.net_queue_pairs) {
+                return Err(());
+            }
+            desc.set_status(state.net_status);
+        }
+        let mut inner = self.inner.lock();
+        inner.driver_features = state.driver_features;
+        inner.regs = state.regs;
+        drop(inner);
+        self.dev().set_activated(state.activated);
+        Ok(())
+    }
 }
 
 #[allow(dead_code)]
@@ -395,24 +544,7 @@ fn virtio_mmio_prologue_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset:
                 }
             }
             VIRTIO_MMIO_GUEST_FEATURES_SEL => mmio.set_drv_feature_sel(value),
-            VIRTIO_MMIO_STATUS => {
-                mmio.set_dev_stat(value);
-                if mmio.dev_stat() == 0 {
-                    mmio.dev_reset();
-                    info!(
-                        "VM {} virtio device {:x} is reset",
-                        active_vm().unwrap().id(),
-                        mmio.base()
-                    );
-                } else if mmio.dev_stat() == 0xf {
-                    mmio.dev().set_activated(true);
-                    info!(
-                        "VM {} virtio device {:x} init ok",
-                        active_vm().unwrap().id(),
-                        mmio.base()
-                    );
-                }
-            }
+            VIRTIO_MMIO_STATUS => mmio.write_dev_stat(value),
             _ => {
                 error!("virtio_mmio_prologue_access: wrong reg write {:#x}", emu_ctx.address);
             }
@@ -519,16 +651,10 @@ fn virtio_mmio_cfg_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset: usize
     if !write {
         let value = match offset {
             VIRTIO_MMIO_CONFIG_GENERATION => mmio.dev().generation() as u64,
-            VIRTIO_MMIO_CONFIG..=0x1ff => match mmio.dev().desc() {
-                super::dev::DevDesc::Blk(blk_desc) => blk_desc.offset_data(emu_ctx, offset - VIRTIO_MMIO_CONFIG),
-                super::dev::DevDesc::Net(net_desc) => net_desc.offset_data(emu_ctx, offset - VIRTIO_MMIO_CONFIG),
-                #[cfg(feature = "balloon")]
-                super::dev::DevDesc::Balloon(config) => config.read_config(emu_ctx, offset - VIRTIO_MMIO_CONFIG),
-                _ => {
-                    error!("unknow desc type");
-                    return;
-                }
-            },
+            VIRTIO_MMIO_CONFIG..=0x1ff => {
+                virtio_dev_config_access(mmio, emu_ctx, offset - VIRTIO_MMIO_CONFIG, write);
+                return;
+            }
             _ => {
                 error!("virtio_mmio_cfg_access: wrong reg write {:#x}", emu_ctx.address);
                 return;
@@ -537,12 +663,31 @@ fn virtio_mmio_cfg_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset: usize
         let idx = emu_ctx.reg;
         let val = value as usize;
         current_cpu().set_gpr(idx, val);
+    } else if (VIRTIO_MMIO_CONFIG..=0x1ff).contains(&offset) {
+        virtio_dev_config_access(mmio, emu_ctx, offset - VIRTIO_MMIO_CONFIG, write);
+    }
+}
+
+// access the device specific configuration, shared by the MMIO and PCI transports
+pub(super) fn virtio_dev_config_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset: usize, write: bool) {
+    if !write {
+        let value = match mmio.dev().desc() {
+            super::dev::DevDesc::Blk(blk_desc) => blk_desc.offset_data(emu_ctx, offset),
+            super::dev::DevDesc::Net(net_desc) => net_desc.offset_data(emu_ctx, offset),
+            #[cfg(feature = "balloon")]
+            super::dev::DevDesc::Balloon(config) => config.read_config(emu_ctx, offset),
+            _ => {
+                error!("unknow desc type");
+                return;
+            }
+        };
+        current_cpu().set_gpr(emu_ctx.reg, value as usize);
     } else {
         #[cfg(feature = "balloon")]
-        if (VIRTIO_MMIO_CONFIG..=0x1ff).contains(&offset) {
+        {
             let val = current_cpu().get_gpr(emu_ctx.reg) as u64;
             match mmio.dev().desc() {
-                super::dev::DevDesc::Balloon(config) => config.write_config(emu_ctx, offset - VIRTIO_MMIO_CONFIG, val),
+                super::dev::DevDesc::Balloon(config) => config.write_config(emu_ctx, offset, val),
                 _ => {
                     error!("unknow desc type");
                 }
@@ -552,6 +697,12 @@ fn virtio_mmio_cfg_access(mmio: &VirtioMmio, emu_ctx: &EmuContext, offset: usize
 }
 
 pub fn emu_virtio_mmio_init(vm: Weak<Vm>, emu_cfg: &VmEmulatedDeviceConfig) -> Result<Arc<dyn EmuDev>, ()> {
+    let mmio = virtio_dev_init(vm, emu_cfg)?;
+    Ok(mmio)
+}
+
+// create the device and its queues, the transport decides how the driver reaches them
+pub(super) fn virtio_dev_init(vm: Weak<Vm>, emu_cfg: &VmEmulatedDeviceConfig) -> Result<Arc<VirtioMmio>, ()> {
     let virt_dev_type = match emu_cfg.emu_type {
         EmuDeviceType::EmuDeviceTVirtioBlk => VirtioDeviceType::Block,
         EmuDeviceType::EmuDeviceTVirtioNet => VirtioDeviceType::Net,
@@ -563,6 +714,14 @@ pub fn emu_virtio_mmio_init(vm: Weak<Vm>, emu_cfg: &VmEmulatedDeviceConfig) -> R
             return Err(());
         }
     };
+    // a non-mediated blk needs the disk image in the host memory
+    if emu_cfg.emu_type == EmuDeviceType::EmuDeviceTVirtioBlk
+        && !emu_cfg.mediated
+        && !matches!(emu_cfg.cfg_list[..], [_, _, image_pa, ..] if image_pa != 0 && image_pa % PAGE_SIZE == 0)
+    {
+        error!("virtio_dev_init: non-mediated {} has no disk image", emu_cfg.name);
+        return Err(());
+    }
     let mmio = Arc::new_cyclic(|weak| {
         let mut mmio = VirtioMmio::new(vm, virt_dev_type, emu_cfg);
         mmio.init(virt_dev_type);
@@ -570,9 +729,12 @@ pub fn emu_virtio_mmio_init(vm: Weak<Vm>, emu_cfg: &VmEmulatedDeviceConfig) -> R
         mmio
     });
     if emu_cfg.emu_type == EmuDeviceType::EmuDeviceTVirtioNet {
-        let nic = mmio.clone();
-        let mac = emu_cfg.cfg_list.iter().take(6).map(|&x| x as u8).collect::<Vec<_>>();
-        super::mac::set_mac_info(&mac, nic);
+        super::mac::add_virtio_nic(mmio.clone());
+    }
+    if let super::dev::DevDesc::Console(desc) = mmio.dev().desc() {
+        if desc.host_console() {
+            crate::device::console_register(mmio.clone());
+        }
     }
     Ok(mmio)
 }
@@ -595,7 +757,7 @@ impl EmuDev for VirtioMmio {
             self.set_irt_stat(VIRTIO_MMIO_INT_VRING);
             trace!("in VIRTIO_MMIO_QUEUE_NOTIFY");
             let idx = current_cpu().get_gpr(emu_ctx.reg);
-            if !self.inner_const.vq[idx].call_notify_handler() {
+            if !self.queue_notify(idx) {
                 error!("Failed to handle virtio mmio request!");
             }
         } else if offset == VIRTIO_MMIO_INTERRUPT_STATUS && !write {
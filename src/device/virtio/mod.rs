@@ -1,13 +1,16 @@
-pub use blk::{virtio_blk_notify_handler, BlkIov, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT};
-pub use mac::remove_virtio_nic;
+pub use blk::{virtio_blk_notify_handler, BlkIov, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT};
+pub use capture::virtio_net_capture;
+pub use mac::{remove_virtio_nic, virtio_net_set_acl};
 pub use mediated::*;
-pub use mmio::{emu_virtio_mmio_init, VirtioMmio};
-pub use net::{ethernet_ipi_rev_handler, virtio_net_announce};
-pub use queue::Virtq;
+pub use mmio::{emu_virtio_mmio_init, VirtioMigrateState, VirtioMmio};
+pub use net::{ethernet_ipi_rev_handler, virtio_net_announce, virtio_net_get_stat};
+pub use pci::emu_virtio_pci_init;
+pub use queue::{Virtq, VirtqMigrateState};
 
 #[cfg(feature = "balloon")]
 mod balloon;
 mod blk;
+mod capture;
 #[allow(dead_code)]
 mod console;
 mod dev;
@@ -17,4 +20,7 @@ mod mediated;
 mod mmio;
 #[allow(dead_code)]
 mod net;
+mod offload;
+mod pci;
 mod queue;
+mod ratelimit;
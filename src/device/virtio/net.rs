@@ -1,18 +1,25 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
 
 use crate::device::{EmuContext, VirtioMmio, Virtq};
 use crate::kernel::IpiMessage;
 use crate::kernel::Vm;
-use crate::kernel::{current_cpu, vm_if_get_cpu_id};
+use crate::kernel::{active_vm, current_cpu, vm_by_id};
 use crate::kernel::{ipi_send_msg, IpiEthernetMsg, IpiInnerMsg, IpiType};
 
+use super::capture::{net_capture, net_capture_enabled};
 use super::dev::DevDesc;
 use super::iov::VirtioIov;
+use super::mac::{learn_mac, mac_to_nic, net_acl_allow, virtio_nic_list_walker};
 use super::mmio::VIRTIO_F_VERSION_1;
+use super::offload::{
+    self, VIRTIO_NET_HDR_GSO_ECN, VIRTIO_NET_HDR_GSO_TCPV4, VIRTIO_NET_HDR_GSO_TCPV6, VIRTIO_NET_HDR_GSO_UDP,
+};
 use super::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+use super::ratelimit::NetRateLimiter;
 
 pub const VIRTQUEUE_NET_MAX_SIZE: usize = 256;
 
@@ -51,7 +58,9 @@ const VIRTIO_NET_F_CTRL_RX: usize = 1 << 18;
 const VIRTIO_NET_F_CTRL_VLAN: usize = 1 << 19;
 // control channel VLAN filtering
 const VIRTIO_NET_F_GUEST_ANNOUNCE: usize = 1 << 21; // guest can send gratuitous pkts
+const VIRTIO_NET_F_MQ: usize = 1 << 22; // device supports multiqueue with automatic receive steering
 
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: usize = 1;
 const VIRTIO_NET_HDR_F_DATA_VALID: usize = 2;
 
 const VIRTIO_NET_HDR_GSO_NONE: usize = 0;
@@ -67,19 +76,85 @@ struct VirtioNetHdr {
     pub num_buffers: u16,
 }
 
+// cfg_list of a net device: [ MAC - 6 ][ VLAN ID ][ queue pairs ][ bandwidth (bytes/s) ][ burst (bytes) ]
+const NET_CFG_VLAN: usize = 6;
+const NET_CFG_QUEUE_PAIRS: usize = 7;
+const NET_CFG_BANDWIDTH: usize = 8;
+const NET_CFG_BURST: usize = 9;
+
 pub struct NetDesc {
     inner: Mutex<NetDescInner>,
+    vlan: u16,
+    // queue pairs in use, set by the driver with VIRTIO_NET_CTRL_MQ
+    queue_pairs: AtomicUsize,
+    // limits the traffic sent from the port, none if the bandwidth is unlimited
+    rate_limiter: Option<Arc<NetRateLimiter>>,
+    stat: Mutex<VirtioNetStat>,
 }
 
 impl NetDesc {
-    pub fn new(mac: &[usize]) -> NetDesc {
+    pub fn new(cfg_list: &[usize]) -> NetDesc {
         let mut desc = NetDescInner::default();
-        for (i, item) in mac.iter().enumerate().take(6) {
+        for (i, item) in cfg_list.iter().enumerate().take(6) {
             desc.mac[i] = *item as u8;
         }
+        let max_queue_pairs = cfg_list
+            .get(NET_CFG_QUEUE_PAIRS)
+            .copied()
+            .unwrap_or(0)
+            .clamp(VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX);
+        desc.max_virtqueue_pairs = max_queue_pairs as u16;
+        let bandwidth = cfg_list.get(NET_CFG_BANDWIDTH).copied().unwrap_or(0);
+        let burst = cfg_list.get(NET_CFG_BURST).copied().unwrap_or(0);
         NetDesc {
             inner: Mutex::new(desc),
+            vlan: cfg_list.get(NET_CFG_VLAN).copied().unwrap_or(0) as u16,
+            queue_pairs: AtomicUsize::new(VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN),
+            rate_limiter: if bandwidth != 0 {
+                Some(NetRateLimiter::new(bandwidth, burst))
+            } else {
+                None
+            },
+            stat: Mutex::new(VirtioNetStat::default()),
+        }
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        let inner = self.inner.lock();
+        inner.mac
+    }
+
+    pub fn stat(&self) -> VirtioNetStat {
+        *self.stat.lock()
+    }
+
+    fn update_stat<F: FnOnce(&mut VirtioNetStat)>(&self, f: F) {
+        f(&mut *self.stat.lock());
+    }
+
+    pub fn rate_limiter(&self) -> Option<&Arc<NetRateLimiter>> {
+        self.rate_limiter.as_ref()
+    }
+
+    pub fn vlan(&self) -> u16 {
+        self.vlan
+    }
+
+    pub fn max_queue_pairs(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.max_virtqueue_pairs as usize
+    }
+
+    pub fn queue_pairs(&self) -> usize {
+        self.queue_pairs.load(Ordering::Relaxed)
+    }
+
+    pub fn set_queue_pairs(&self, queue_pairs: usize) -> bool {
+        if !(VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN..=self.max_queue_pairs()).contains(&queue_pairs) {
+            return false;
         }
+        self.queue_pairs.store(queue_pairs, Ordering::Relaxed);
+        true
     }
 
     pub fn set_status(&self, status: u16) {
@@ -105,6 +180,67 @@ impl NetDesc {
     }
 }
 
+// reasons for the switch to drop a frame
+#[derive(Clone, Copy, Debug)]
+enum NetDrop {
+    // the frame is malformed
+    Malformed,
+    // the traffic between the VMs is denied
+    Acl,
+    // the sender exceeds its bandwidth
+    RateLimit,
+    // the receiver is not activated
+    Inactive,
+    // the receiver has no available rx descriptor
+    NoDesc,
+    // the rx descriptors of the receiver are invalid
+    DescErr,
+    // the rx buffers are smaller than the frame
+    ShortBuf,
+    // failed to copy the frame into the rx buffers
+    Copy,
+    // failed to checksum or segment the frame for the receiver
+    Offload,
+}
+
+// counters of a virtio-net port, malformed, denied and rate limited frames are counted on the sender
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct VirtioNetStat {
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    // broadcast and multicast frames sent
+    pub tx_broadcasts: u64,
+    pub drop_malformed: u64,
+    pub drop_acl: u64,
+    pub drop_rate_limit: u64,
+    pub drop_inactive: u64,
+    pub drop_no_desc: u64,
+    pub drop_desc_err: u64,
+    pub drop_short_buf: u64,
+    pub drop_copy: u64,
+    pub drop_offload: u64,
+}
+
+impl VirtioNetStat {
+    fn count_drop(&mut self, reason: NetDrop) {
+        let counter = match reason {
+            NetDrop::Malformed => &mut self.drop_malformed,
+            NetDrop::Acl => &mut self.drop_acl,
+            NetDrop::RateLimit => &mut self.drop_rate_limit,
+            NetDrop::Inactive => &mut self.drop_inactive,
+            NetDrop::NoDesc => &mut self.drop_no_desc,
+            NetDrop::DescErr => &mut self.drop_desc_err,
+            NetDrop::ShortBuf => &mut self.drop_short_buf,
+            NetDrop::Copy => &mut self.drop_copy,
+            NetDrop::Offload => &mut self.drop_offload,
+        };
+        *counter += 1;
+    }
+}
+
 pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
 pub const VIRTIO_NET_S_ANNOUNCE: u16 = 2;
 
@@ -113,6 +249,7 @@ pub const VIRTIO_NET_S_ANNOUNCE: u16 = 2;
 struct NetDescInner {
     mac: [u8; 6],
     status: u16,
+    max_virtqueue_pairs: u16,
 }
 
 impl NetDescInner {
@@ -120,6 +257,7 @@ impl NetDescInner {
         NetDescInner {
             mac: [0; 6],
             status: VIRTIO_NET_S_LINK_UP,
+            max_virtqueue_pairs: 1,
         }
     }
 }
@@ -131,8 +269,16 @@ struct VirtioNetCtrlHdr {
     command: u8,
 }
 
-pub fn net_features() -> usize {
-    VIRTIO_F_VERSION_1
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct VirtioNetCtrlMq {
+    hdr: VirtioNetCtrlHdr,
+    virtqueue_pairs: u16,
+}
+
+pub fn net_features(max_queue_pairs: usize) -> usize {
+    let mq = if max_queue_pairs > 1 { VIRTIO_NET_F_MQ } else { 0 };
+    mq | VIRTIO_F_VERSION_1
         | VIRTIO_NET_F_GUEST_CSUM
         | VIRTIO_NET_F_MAC
         | VIRTIO_NET_F_CSUM
@@ -146,11 +292,22 @@ pub fn net_features() -> usize {
         | VIRTIO_NET_F_CTRL_VQ
         | VIRTIO_NET_F_GUEST_ANNOUNCE
         | VIRTIO_NET_F_STATUS
+        | VIRTIO_NET_F_MRG_RXBUF
 }
 
 const VIRTIO_NET_CTRL_ANNOUNCE: u8 = 3;
 const VIRTIO_NET_CTRL_ANNOUNCE_ACK: u8 = 0;
 
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN: usize = 1;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX: usize = 0x8000;
+
+// Queue pair N consists of rx queue 2N and tx queue 2N + 1, the control queue follows the last pair, or is queue 2
+// if the driver does not negotiate VIRTIO_NET_F_MQ.
+// On PCI with MSI-X, each queue raises its own vector, which the guest routes to the vCPU serving the pair
+// through the virtual ITS. On MMIO all queues share the single interrupt of the device, raised on vCPU 0.
+
 pub fn virtio_net_handle_ctrl(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: Arc<Vm>) -> bool {
     if vq.ready() == 0 {
         println!("virtio net control queue is not ready!");
@@ -199,6 +356,22 @@ pub fn virtio_net_handle_ctrl(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: Arc<Vm>)
                 };
                 in_iov.copy_from_buf(&status as *const _ as usize, size_of::<u8>());
             }
+            VIRTIO_NET_CTRL_MQ => {
+                let mut mq = VirtioNetCtrlMq::default();
+                out_iov.copy_to_buf(&mut mq as *mut _ as usize, size_of::<VirtioNetCtrlMq>());
+                let status: u8 = match nic.dev().desc() {
+                    DevDesc::Net(desc)
+                        if ctrl.command == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET
+                            && nic.driver_features() & VIRTIO_NET_F_MQ != 0
+                            && desc.set_queue_pairs(mq.virtqueue_pairs as usize) =>
+                    {
+                        info!("VM {} virtio net uses {} queue pairs", vm.id(), mq.virtqueue_pairs);
+                        VIRTIO_NET_OK
+                    }
+                    _ => VIRTIO_NET_ERR,
+                };
+                in_iov.copy_from_buf(&status as *const _ as usize, size_of::<u8>());
+            }
             _ => {
                 println!("Control queue header class can't match {}", ctrl.class);
             }
@@ -209,7 +382,7 @@ pub fn virtio_net_handle_ctrl(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: Arc<Vm>)
             return false;
         }
     }
-    nic.notify();
+    nic.notify(vq.vq_indx());
     true
 }
 
@@ -219,7 +392,12 @@ pub fn virtio_net_notify_handler(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: alloc
         return false;
     }
 
-    if vq.vq_indx() != 1 {
+    // without VIRTIO_NET_F_MQ the driver only has one queue pair, and takes queue 2 as the control queue
+    if vq.vq_indx() == 2 && nic.driver_features() & VIRTIO_NET_F_MQ == 0 {
+        return virtio_net_handle_ctrl(vq, nic, vm);
+    }
+
+    if vq.vq_indx() % 2 == 0 {
         // println!("net rx queue notified!");
         return true;
     }
@@ -246,8 +424,14 @@ pub fn virtio_net_notify_handler(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: alloc
             idx = vq.desc_next(idx) as usize;
         }
 
-        if let Some(list) = ethernet_transmit(tx_iov, len, &vm) {
-            nics_to_notify.extend(list);
+        net_desc(&nic).update_stat(|stat| {
+            stat.tx_packets += 1;
+            stat.tx_bytes += len.saturating_sub(size_of::<VirtioNetHdr>()) as u64;
+        });
+        if !ethernet_rate_limit(&nic, len - size_of::<VirtioNetHdr>()) {
+            if let Some(list) = ethernet_transmit(tx_iov, len, &nic) {
+                nics_to_notify.extend(list);
+            }
         }
 
         if !vq.update_used_ring((len - size_of::<VirtioNetHdr>()) as u32, head_idx as u32) {
@@ -260,27 +444,18 @@ pub fn virtio_net_notify_handler(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: alloc
         return false;
     }
 
-    nic.notify();
-    for nic in nics_to_notify {
+    nic.notify(vq.vq_indx());
+    for (nic, rx_vq_idx) in nics_to_notify {
         let trgt_vm = nic.upper_vm().unwrap();
         let vcpu = trgt_vm.vcpu(0).unwrap();
         if vcpu.phys_id() == current_cpu().id {
-            let rx_vq = match nic.vq(0) {
-                Ok(x) => x,
-                Err(_) => {
-                    println!(
-                        "virtio_net_notify_handler: vm[{}] failed to get virtio net rx virt queue",
-                        vm.id()
-                    );
-                    return false;
-                }
-            };
-            if rx_vq.ready() != 0 && rx_vq.avail_flags() == 0 {
-                nic.notify();
-            }
+            ethernet_rx_notify(&nic, rx_vq_idx);
         } else {
-            let msg = IpiEthernetMsg { trgt_nic: nic };
-            let cpu_trgt = vm_if_get_cpu_id(trgt_vm.id()).unwrap();
+            let msg = IpiEthernetMsg {
+                trgt_nic: nic,
+                rx_vq: rx_vq_idx,
+            };
+            let cpu_trgt = vcpu.phys_id();
             if !ipi_send_msg(cpu_trgt, IpiType::EthernetMsg, IpiInnerMsg::EnternetMsg(msg)) {
                 error!(
                     "virtio_net_notify_handler: failed to send ipi message, target {}",
@@ -292,25 +467,29 @@ pub fn virtio_net_notify_handler(vq: Arc<Virtq>, nic: Arc<VirtioMmio>, vm: alloc
     true
 }
 
+fn ethernet_rx_notify(nic: &VirtioMmio, rx_vq_idx: usize) {
+    let vm = nic.upper_vm().unwrap();
+    let rx_vq = match nic.vq(rx_vq_idx) {
+        Ok(x) => x,
+        Err(_) => {
+            println!(
+                "ethernet_rx_notify: vm[{}] failed to get virtio net rx virt queue {}",
+                vm.id(),
+                rx_vq_idx
+            );
+            return;
+        }
+    };
+
+    if rx_vq.ready() != 0 && rx_vq.avail_flags() == 0 {
+        nic.notify(rx_vq_idx);
+    }
+}
+
 pub fn ethernet_ipi_rev_handler(msg: IpiMessage) {
     match msg.ipi_message {
         IpiInnerMsg::EnternetMsg(ethernet_msg) => {
-            let nic = ethernet_msg.trgt_nic;
-            let vm = nic.upper_vm().unwrap();
-            let rx_vq = match nic.vq(0) {
-                Ok(x) => x,
-                Err(_) => {
-                    println!(
-                        "ethernet_ipi_rev_handler: vm[{}] failed to get virtio net rx virt queue",
-                        vm.id()
-                    );
-                    return;
-                }
-            };
-
-            if rx_vq.ready() != 0 && rx_vq.avail_flags() == 0 {
-                nic.notify();
-            }
+            ethernet_rx_notify(&ethernet_msg.trgt_nic, ethernet_msg.rx_vq);
         }
         _ => {
             panic!("illegal ipi message type in ethernet_ipi_rev_handler");
@@ -318,7 +497,79 @@ pub fn ethernet_ipi_rev_handler(msg: IpiMessage) {
     }
 }
 
-fn ethernet_transmit(tx_iov: VirtioIov, len: usize, vm: &Vm) -> Option<Vec<Arc<VirtioMmio>>> {
+fn net_desc(nic: &VirtioMmio) -> &NetDesc {
+    match nic.dev().desc() {
+        DevDesc::Net(desc) => desc,
+        _ => {
+            panic!("illegal dev type for nic");
+        }
+    }
+}
+
+fn nic_vlan(nic: &VirtioMmio) -> u16 {
+    net_desc(nic).vlan()
+}
+
+fn ethernet_count_drop(nic: &VirtioMmio, reason: NetDrop) {
+    net_desc(nic).update_stat(|stat| stat.count_drop(reason));
+}
+
+// Whether the frame sent from the port exceeds its bandwidth and is dropped
+fn ethernet_rate_limit(nic: &VirtioMmio, frame_len: usize) -> bool {
+    match net_desc(nic).rate_limiter() {
+        Some(limiter) if !limiter.consume(frame_len) => {
+            ethernet_count_drop(nic, NetDrop::RateLimit);
+            debug!(
+                "VM {} virtio net port {:#x} exceeds its bandwidth",
+                nic.upper_vm().map_or(0, |vm| vm.id()),
+                nic.base()
+            );
+            true
+        }
+        _ => false,
+    }
+}
+
+// whether the frame from `src_vm` in `vlan` may be forwarded to the port
+fn ethernet_port_allow(vlan: u16, src_vm: &Vm, nic: &VirtioMmio) -> bool {
+    match nic.upper_vm() {
+        Some(vm) => vm.id() != src_vm.id() && nic_vlan(nic) == vlan && net_acl_allow(src_vm.id(), vm.id()),
+        None => false,
+    }
+}
+
+fn ethernet_transmit(
+    tx_iov: VirtioIov,
+    len: usize,
+    src_nic: &Arc<VirtioMmio>,
+) -> Option<Vec<(Arc<VirtioMmio>, usize)>> {
+    let nic_list = ethernet_forward(&tx_iov, len, src_nic);
+
+    let hdr_size = size_of::<VirtioNetHdr>();
+    if net_capture_enabled() && len > hdr_size {
+        if let Some(src_vm) = src_nic.upper_vm() {
+            let read_frame = || {
+                let mut pkt = vec![0_u8; len];
+                tx_iov.copy_to_buf(pkt.as_mut_ptr() as usize, len);
+                pkt.drain(..hdr_size);
+                pkt
+            };
+            let dst_vms = nic_list
+                .iter()
+                .flatten()
+                .filter_map(|(nic, _)| nic.upper_vm().map(|vm| vm.id()));
+            net_capture(read_frame, src_vm.id(), nic_vlan(src_nic), dst_vms);
+        }
+    }
+
+    nic_list
+}
+
+fn ethernet_forward(
+    tx_iov: &VirtioIov,
+    len: usize,
+    src_nic: &Arc<VirtioMmio>,
+) -> Option<Vec<(Arc<VirtioMmio>, usize)>> {
     // [ destination MAC - 6 ][ source MAC - 6 ][ EtherType - 2 ][ Payload ]
     if len < size_of::<VirtioNetHdr>() || len - size_of::<VirtioNetHdr>() < 6 + 6 + 2 {
         println!(
@@ -326,45 +577,46 @@ fn ethernet_transmit(tx_iov: VirtioIov, len: usize, vm: &Vm) -> Option<Vec<Arc<V
             len,
             size_of::<VirtioNetHdr>()
         );
+        ethernet_count_drop(src_nic, NetDrop::Malformed);
         return None;
     }
 
+    let src_vm = src_nic.upper_vm()?;
+    let vlan = nic_vlan(src_nic);
     let frame: &[u8] = tx_iov.get_ptr(size_of::<VirtioNetHdr>());
-    if frame[0..6] == [0xff, 0xff, 0xff, 0xff, 0xff, 0xff] {
-        if ethernet_is_arp(frame) {
-            return ethernet_broadcast(&tx_iov, len, vm);
-        } else {
-            return None;
-        }
-    }
+    learn_mac(vlan, &frame[6..12], src_nic);
 
-    if frame[0] == 0x33 && frame[1] == 0x33 {
-        if !(frame[12] == 0x86 && frame[13] == 0xdd) {
-            // Only IPV6 multicast packet is allowed to be broadcast
-            return None;
-        }
-        return ethernet_broadcast(&tx_iov, len, vm);
+    // broadcast and multicast frames are flooded in the VLAN
+    if frame[0] & 1 != 0 {
+        net_desc(src_nic).update_stat(|stat| stat.tx_broadcasts += 1);
+        return ethernet_broadcast(tx_iov, len, vlan, &src_vm);
     }
 
-    match ethernet_mac_to_nic(frame) {
-        Ok(nic) => {
-            let vm = nic.upper_vm().unwrap();
-            if ethernet_send_to(&vm, &nic, &tx_iov, len) {
-                Some(vec![nic])
-            } else {
-                None
+    match mac_to_nic(vlan, &frame[0..6]) {
+        Some(nic) => {
+            if !ethernet_port_allow(vlan, &src_vm, &nic) {
+                if nic.upper_vm().is_some_and(|vm| vm.id() != src_vm.id()) {
+                    ethernet_count_drop(src_nic, NetDrop::Acl);
+                }
+                return None;
             }
+            let vm = nic.upper_vm().unwrap();
+            let rx_vq_idx = ethernet_send_to(&vm, &nic, tx_iov, len)?;
+            Some(vec![(nic, rx_vq_idx)])
         }
-        Err(_) => None,
+        // unknown unicast is flooded until the destination is learned
+        None => ethernet_broadcast(tx_iov, len, vlan, &src_vm),
     }
 }
 
-fn ethernet_broadcast(tx_iov: &VirtioIov, len: usize, cur_vm: &Vm) -> Option<Vec<Arc<VirtioMmio>>> {
+fn ethernet_broadcast(tx_iov: &VirtioIov, len: usize, vlan: u16, src_vm: &Vm) -> Option<Vec<(Arc<VirtioMmio>, usize)>> {
     let mut nic_list = vec![];
-    super::mac::virtio_nic_list_walker(|nic| {
-        let vm = nic.upper_vm().unwrap();
-        if vm.id() != cur_vm.id() && ethernet_send_to(&vm, nic, tx_iov, len) {
-            nic_list.push(nic.clone());
+    virtio_nic_list_walker(|nic| {
+        if ethernet_port_allow(vlan, src_vm, nic) {
+            let vm = nic.upper_vm().unwrap();
+            if let Some(rx_vq_idx) = ethernet_send_to(&vm, nic, tx_iov, len) {
+                nic_list.push((nic.clone(), rx_vq_idx));
+            }
         }
     });
     if nic_list.is_empty() {
@@ -374,73 +626,215 @@ fn ethernet_broadcast(tx_iov: &VirtioIov, len: usize, cur_vm: &Vm) -> Option<Vec
     }
 }
 
-fn ethernet_send_to(vm: &Vm, nic: &VirtioMmio, tx_iov: &VirtioIov, len: usize) -> bool {
+// how a frame is handed to a receiver according to its negotiated features
+enum RxOffload {
+    Pass,
+    Csum,
+    Segment,
+}
+
+fn ethernet_rx_offload(header: &VirtioNetHdr, features: usize) -> RxOffload {
+    if header.gso_type as usize != VIRTIO_NET_HDR_GSO_NONE {
+        let guest_feature = match header.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+            VIRTIO_NET_HDR_GSO_TCPV4 => VIRTIO_NET_F_GUEST_TSO4,
+            VIRTIO_NET_HDR_GSO_TCPV6 => VIRTIO_NET_F_GUEST_TSO6,
+            VIRTIO_NET_HDR_GSO_UDP => VIRTIO_NET_F_GUEST_UFO,
+            _ => 0,
+        };
+        let ecn = header.gso_type & VIRTIO_NET_HDR_GSO_ECN == 0 || features & VIRTIO_NET_F_GUEST_ECN != 0;
+        if guest_feature != 0 && features & guest_feature != 0 && ecn {
+            RxOffload::Pass
+        } else {
+            RxOffload::Segment
+        }
+    } else if header.flags as usize & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 && features & VIRTIO_NET_F_GUEST_CSUM == 0 {
+        RxOffload::Csum
+    } else {
+        RxOffload::Pass
+    }
+}
+
+// bytes of a frame inspected to steer it to a rx queue
+const NET_STEER_PEEK: usize = 128;
+
+// Pick the rx queue of the receiver by the flow of the frame
+fn ethernet_rx_queue(nic: &VirtioMmio, tx_iov: &VirtioIov, len: usize) -> usize {
+    let queue_pairs = net_desc(nic).queue_pairs();
+    if queue_pairs == 1 {
+        return 0;
+    }
+    let hdr_size = size_of::<VirtioNetHdr>();
+    let mut buf = [0_u8; size_of::<VirtioNetHdr>() + NET_STEER_PEEK];
+    let peek_len = usize::min(len, buf.len());
+    tx_iov.copy_to_buf(buf.as_mut_ptr() as usize, peek_len);
+    let hash = offload::flow_hash(&buf[hdr_size..peek_len]);
+    2 * (hash as usize % queue_pairs)
+}
+
+// Send the frame to the port, return the index of the rx queue it is put in
+fn ethernet_send_to(vm: &Vm, nic: &VirtioMmio, tx_iov: &VirtioIov, len: usize) -> Option<usize> {
     if !nic.dev().activated() {
         // println!("ethernet_send_to: vm[{}] nic dev is not activate", vmid);
-        return false;
+        ethernet_count_drop(nic, NetDrop::Inactive);
+        return None;
     }
 
-    let rx_vq = match nic.vq(0) {
-        Ok(x) => x,
-        Err(_) => {
-            println!(
-                "ethernet_send_to: vm[{}] failed to get virtio net rx virt queue",
-                vm.id()
-            );
-            return false;
+    if tx_iov.get_buf(0) < 0x1000 {
+        panic!("illegal header addr {}", tx_iov.get_buf(0));
+    }
+    let header = unsafe { &*(tx_iov.get_buf(0) as *const VirtioNetHdr) };
+    let hdr_size = size_of::<VirtioNetHdr>();
+    let rx_vq_idx = ethernet_rx_queue(nic, tx_iov, len);
+
+    let sent = match ethernet_rx_offload(header, nic.driver_features()) {
+        RxOffload::Pass => ethernet_rx_account(nic, ethernet_rx_deliver(vm, nic, rx_vq_idx, tx_iov, len), len),
+        RxOffload::Csum => {
+            let mut pkt = vec![0_u8; len];
+            tx_iov.copy_to_buf(pkt.as_mut_ptr() as usize, len);
+            if !offload::fill_csum(
+                &mut pkt[hdr_size..],
+                header.csum_start as usize,
+                header.csum_offset as usize,
+            ) {
+                println!("ethernet_send_to: illegal csum_start {}", header.csum_start);
+                ethernet_count_drop(nic, NetDrop::Offload);
+                return None;
+            }
+            // the checksum is complete, clear the flags of the header
+            pkt[0] = 0;
+            ethernet_rx_account(nic, ethernet_rx_deliver_buf(vm, nic, rx_vq_idx, &pkt), len)
+        }
+        RxOffload::Segment => {
+            let mut frame = vec![0_u8; len];
+            tx_iov.copy_to_buf(frame.as_mut_ptr() as usize, len);
+            match offload::segment(&frame[hdr_size..], header.gso_type, header.gso_size as usize) {
+                Some(segs) => {
+                    let mut sent = false;
+                    for seg in segs {
+                        // an empty header: no checksum needed and no GSO
+                        let mut pkt = vec![0_u8; hdr_size];
+                        pkt.extend_from_slice(&seg);
+                        let result = ethernet_rx_deliver_buf(vm, nic, rx_vq_idx, &pkt);
+                        sent |= ethernet_rx_account(nic, result, pkt.len());
+                    }
+                    sent
+                }
+                None => {
+                    println!("ethernet_send_to: failed to segment gso type {}", header.gso_type);
+                    ethernet_count_drop(nic, NetDrop::Offload);
+                    false
+                }
+            }
         }
     };
+    if sent {
+        Some(rx_vq_idx)
+    } else {
+        None
+    }
+}
 
-    let desc_header_idx_opt = rx_vq.pop_avail_desc_idx(rx_vq.avail_idx());
-    if !rx_vq.avail_is_avail() {
-        println!("ethernet_send_to: receive invalid avail desc idx");
-        return false;
-    } else if desc_header_idx_opt.is_none() {
-        // println!("ethernet_send_to: desc_header_idx_opt is none");
-        return false;
+// Count the frame of `len` bytes (with the header) delivered to the port or dropped
+fn ethernet_rx_account(nic: &VirtioMmio, result: Result<(), NetDrop>, len: usize) -> bool {
+    match result {
+        Ok(()) => {
+            net_desc(nic).update_stat(|stat| {
+                stat.rx_packets += 1;
+                stat.rx_bytes += (len - size_of::<VirtioNetHdr>()) as u64;
+            });
+            true
+        }
+        Err(reason) => {
+            ethernet_count_drop(nic, reason);
+            false
+        }
     }
+}
 
-    let desc_idx_header = desc_header_idx_opt.unwrap();
-    let mut desc_idx = desc_header_idx_opt.unwrap() as usize;
-    let mut rx_iov = VirtioIov::default();
-    let mut rx_len = 0;
+fn ethernet_rx_deliver_buf(vm: &Vm, nic: &VirtioMmio, rx_vq_idx: usize, pkt: &[u8]) -> Result<(), NetDrop> {
+    let mut iov = VirtioIov::default();
+    iov.push_data(pkt.as_ptr() as usize, pkt.len());
+    ethernet_rx_deliver(vm, nic, rx_vq_idx, &iov, pkt.len())
+}
 
-    loop {
-        let dst = vm.ipa2hva(rx_vq.desc_addr(desc_idx));
-        if dst == 0 {
+// Copy the packet into the rx queue, it spans several descriptor chains with VIRTIO_NET_F_MRG_RXBUF
+fn ethernet_rx_deliver(
+    vm: &Vm,
+    nic: &VirtioMmio,
+    rx_vq_idx: usize,
+    tx_iov: &VirtioIov,
+    len: usize,
+) -> Result<(), NetDrop> {
+    let rx_vq = match nic.vq(rx_vq_idx) {
+        Ok(x) => x,
+        Err(_) => {
             println!(
-                "rx_vq desc base table addr {:#x}, idx {}, avail table addr {:#x}, avail last idx {}",
-                rx_vq.desc_table_addr(),
-                desc_idx,
-                rx_vq.avail_addr(),
-                rx_vq.avail_idx()
+                "ethernet_send_to: vm[{}] failed to get virtio net rx virt queue {}",
+                vm.id(),
+                rx_vq_idx
             );
-            println!("ethernet_send_to: failed to get dst {}", vm.id());
-            return false;
+            return Err(NetDrop::DescErr);
         }
-        let desc_len = rx_vq.desc_len(desc_idx) as usize;
+    };
+    let mergeable = nic.driver_features() & VIRTIO_NET_F_MRG_RXBUF != 0;
 
-        rx_iov.push_data(dst, desc_len);
-        rx_len += desc_len;
-        if rx_len >= len {
-            break;
+    let mut rx_iov = VirtioIov::default();
+    let mut rx_len = 0;
+    // (head index, length) of each used descriptor chain
+    let mut chain_list: Vec<(u16, usize)> = vec![];
+
+    while rx_len < len && (mergeable || chain_list.is_empty()) {
+        let desc_header_idx_opt = rx_vq.pop_avail_desc_idx(rx_vq.avail_idx());
+        if !rx_vq.avail_is_avail() {
+            println!("ethernet_send_to: receive invalid avail desc idx");
+            return Err(NetDrop::DescErr);
         }
-        if rx_vq.desc_flags(desc_idx) & 0x1 == 0 {
-            break;
+        let desc_idx_header = match desc_header_idx_opt {
+            Some(idx) => idx,
+            // println!("ethernet_send_to: desc_header_idx_opt is none");
+            None => break,
+        };
+        chain_list.push((desc_idx_header, 0));
+
+        let mut desc_idx = desc_idx_header as usize;
+        loop {
+            let dst = vm.ipa2hva(rx_vq.desc_addr(desc_idx));
+            if dst == 0 {
+                println!(
+                    "rx_vq desc base table addr {:#x}, idx {}, avail table addr {:#x}, avail last idx {}",
+                    rx_vq.desc_table_addr(),
+                    desc_idx,
+                    rx_vq.avail_addr(),
+                    rx_vq.avail_idx()
+                );
+                println!("ethernet_send_to: failed to get dst {}", vm.id());
+                return Err(NetDrop::DescErr);
+            }
+            let desc_len = rx_vq.desc_len(desc_idx) as usize;
+
+            rx_iov.push_data(dst, desc_len);
+            rx_len += desc_len;
+            chain_list.last_mut().unwrap().1 += desc_len;
+            if rx_len >= len {
+                break;
+            }
+            if rx_vq.desc_flags(desc_idx) & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            desc_idx = rx_vq.desc_next(desc_idx) as usize;
         }
-        desc_idx = rx_vq.desc_next(desc_idx) as usize;
     }
 
     if rx_len < len {
-        rx_vq.put_back_avail_desc_idx();
+        for _ in chain_list.iter() {
+            rx_vq.put_back_avail_desc_idx();
+        }
+        if chain_list.is_empty() {
+            return Err(NetDrop::NoDesc);
+        }
         println!("ethernet_send_to: rx_len smaller than tx_len");
-        return false;
-    }
-    if tx_iov.get_buf(0) < 0x1000 {
-        panic!("illegal header addr {}", tx_iov.get_buf(0));
+        return Err(NetDrop::ShortBuf);
     }
-    let header = unsafe { &mut *(tx_iov.get_buf(0) as *mut VirtioNetHdr) };
-    header.num_buffers = 1;
 
     if tx_iov.write_through_iov(&rx_iov, len) > 0 {
         println!(
@@ -450,27 +844,79 @@ fn ethernet_send_to(vm: &Vm, nic: &VirtioMmio, tx_iov: &VirtioIov, len: usize) -
             rx_len,
             len
         );
-        return false;
+        return Err(NetDrop::Copy);
     }
 
-    if !rx_vq.update_used_ring(len as u32, desc_idx_header as u32) {
-        return false;
+    // set the number of merged buffers in the header of the receiver
+    let num_buffers = rx_iov.get_buf(0) + core::mem::offset_of!(VirtioNetHdr, num_buffers);
+    unsafe { (num_buffers as *mut u16).write_unaligned(chain_list.len() as u16) };
+    rx_iov.mark_dirty(vm);
+
+    let mut remain = len;
+    for (desc_idx_header, chain_len) in chain_list {
+        let used_len = usize::min(chain_len, remain);
+        remain -= used_len;
+        if !rx_vq.update_used_ring(used_len as u32, desc_idx_header as u32) {
+            return Err(NetDrop::DescErr);
+        }
     }
 
-    true
+    Ok(())
 }
 
-fn ethernet_is_arp(frame: &[u8]) -> bool {
-    frame[12] == 0x8 && frame[13] == 0x6
+// the most ports of a VM reported to MVM
+const NET_STAT_PORT_MAX: usize = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VirtioNetPortStat {
+    base_ipa: u64,
+    mac: [u8; 6],
+    vlan: u16,
+    stat: VirtioNetStat,
+}
+
+#[repr(C)]
+struct VmNetStatInfo {
+    port_num: u32,
+    port_stat: [VirtioNetPortStat; NET_STAT_PORT_MAX],
 }
 
-fn ethernet_mac_to_nic(frame: &[u8]) -> Result<Arc<VirtioMmio>, ()> {
-    let frame_mac = &frame[0..6];
-    super::mac::mac_to_nic(frame_mac).ok_or(())
+/* Get the counters of each virtio-net port of a VM.
+ *
+ * @param[in] vm_id : target VM id.
+ * @param[in] stat_ipa : ipa of the VmNetStatInfo buffer in MVM.
+ */
+pub fn virtio_net_get_stat(vm_id: usize, stat_ipa: usize) -> Result<usize, ()> {
+    if vm_by_id(vm_id).is_none() {
+        error!("virtio_net_get_stat: VM [{}] does not exist", vm_id);
+        return Err(());
+    }
+    let stat_pa = active_vm().unwrap().ipa2hva(stat_ipa);
+    if stat_pa == 0 {
+        error!("illegal stat_ipa {:x}", stat_ipa);
+        return Err(());
+    }
+    let stat_info = unsafe { &mut *(stat_pa as *mut VmNetStatInfo) };
+    let mut port_num = 0;
+    virtio_nic_list_walker(|nic| {
+        if port_num < NET_STAT_PORT_MAX && nic.upper_vm().is_some_and(|vm| vm.id() == vm_id) {
+            let desc = net_desc(nic);
+            stat_info.port_stat[port_num] = VirtioNetPortStat {
+                base_ipa: nic.base() as u64,
+                mac: desc.mac(),
+                vlan: desc.vlan(),
+                stat: desc.stat(),
+            };
+            port_num += 1;
+        }
+    });
+    stat_info.port_num = port_num as u32;
+    Ok(0)
 }
 
 pub fn virtio_net_announce(vm: Arc<Vm>) {
-    super::mac::virtio_nic_list_walker(|nic| {
+    virtio_nic_list_walker(|nic| {
         if let Some(nic_vm) = nic.upper_vm() {
             if Arc::ptr_eq(&nic_vm, &vm) {
                 nic.notify_config();
@@ -0,0 +1,249 @@
+use alloc::vec::Vec;
+
+// Software checksum and segmentation offload for receivers without the matching features.
+// All the frames here start with the ethernet header.
+
+const ETH_HLEN: usize = 14;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const ETH_P_8021Q: u16 = 0x8100;
+const IPV6_HLEN: usize = 40;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+const TCP_CSUM_OFFSET: usize = 16;
+const UDP_CSUM_OFFSET: usize = 6;
+
+const TCP_FLAG_FIN: u8 = 1 << 0;
+const TCP_FLAG_PSH: u8 = 1 << 3;
+const TCP_FLAG_CWR: u8 = 1 << 7;
+
+const IP_MF: u16 = 0x2000;
+const IP_FRAG_OFFSET: u16 = 0x1fff;
+
+pub const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+pub const VIRTIO_NET_HDR_GSO_UDP: u8 = 3;
+pub const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+pub const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+#[inline]
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+#[inline]
+fn put_be16(buf: &mut [u8], offset: usize, val: u16) {
+    buf[offset..offset + 2].copy_from_slice(&val.to_be_bytes());
+}
+
+// one's complement sum of 16-bit words, `data` is padded with zero if its length is odd
+fn csum_add(mut sum: u64, data: &[u8]) -> u64 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u64;
+    }
+    if let [byte] = chunks.remainder() {
+        sum += (*byte as u64) << 8;
+    }
+    sum
+}
+
+fn csum_fold(mut sum: u64) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+struct L3Info {
+    l3: usize,
+    ipv6: bool,
+    proto: u8,
+    l4: usize,
+}
+
+fn parse(frame: &[u8]) -> Option<L3Info> {
+    if frame.len() < ETH_HLEN {
+        return None;
+    }
+    let (ether_type, l3) = match be16(frame, 12) {
+        ETH_P_8021Q if frame.len() >= ETH_HLEN + 4 => (be16(frame, 16), ETH_HLEN + 4),
+        ether_type => (ether_type, ETH_HLEN),
+    };
+    let info = match ether_type {
+        ETH_P_IP if frame.len() >= l3 + 20 => L3Info {
+            l3,
+            ipv6: false,
+            proto: frame[l3 + 9],
+            l4: l3 + (frame[l3] & 0xf) as usize * 4,
+        },
+        ETH_P_IPV6 if frame.len() >= l3 + IPV6_HLEN => L3Info {
+            l3,
+            ipv6: true,
+            proto: frame[l3 + 6],
+            l4: l3 + IPV6_HLEN,
+        },
+        _ => return None,
+    };
+    if info.l4 > frame.len() {
+        return None;
+    }
+    Some(info)
+}
+
+// Hash of the addresses and ports of a frame, frames of one flow get the same hash
+pub fn flow_hash(frame: &[u8]) -> u32 {
+    // FNV-1a
+    let hash = |h: u32, data: &[u8]| data.iter().fold(h, |h, b| (h ^ *b as u32).wrapping_mul(0x0100_0193));
+    let Some(info) = parse(frame) else {
+        return hash(0x811c_9dc5, &frame[..usize::min(frame.len(), 12)]);
+    };
+    let mut h = if info.ipv6 {
+        hash(0x811c_9dc5, &frame[info.l3 + 8..info.l3 + IPV6_HLEN])
+    } else {
+        hash(0x811c_9dc5, &frame[info.l3 + 12..info.l3 + 20])
+    };
+    h = hash(h, &[info.proto]);
+    // fragments except the first one carry no ports, keep all the fragments on one queue
+    let fragment = !info.ipv6 && be16(frame, info.l3 + 6) & (IP_MF | IP_FRAG_OFFSET) != 0;
+    if (info.proto == IPPROTO_TCP || info.proto == IPPROTO_UDP) && !fragment && frame.len() >= info.l4 + 4 {
+        h = hash(h, &frame[info.l4..info.l4 + 4]);
+    }
+    h
+}
+
+// Complete a partial checksum: sum from `csum_start` to the end and store it at `csum_start + csum_offset`
+pub fn fill_csum(frame: &mut [u8], csum_start: usize, csum_offset: usize) -> bool {
+    let offset = csum_start + csum_offset;
+    if offset + 2 > frame.len() {
+        return false;
+    }
+    let csum = csum_fold(csum_add(0, &frame[csum_start..]));
+    put_be16(frame, offset, csum);
+    true
+}
+
+fn ipv4_csum(frame: &mut [u8], info: &L3Info) {
+    put_be16(frame, info.l3 + 10, 0);
+    let csum = csum_fold(csum_add(0, &frame[info.l3..info.l4]));
+    put_be16(frame, info.l3 + 10, csum);
+}
+
+// Update the length field of the IP header after the frame is cut
+fn ip_set_len(frame: &mut [u8], info: &L3Info) {
+    if info.ipv6 {
+        put_be16(frame, info.l3 + 4, (frame.len() - info.l4) as u16);
+    } else {
+        put_be16(frame, info.l3 + 2, (frame.len() - info.l3) as u16);
+    }
+}
+
+// Full TCP/UDP checksum with the pseudo header
+fn l4_csum(frame: &mut [u8], info: &L3Info, csum_offset: usize) {
+    put_be16(frame, info.l4 + csum_offset, 0);
+    let l4_len = frame.len() - info.l4;
+    let addr = if info.ipv6 {
+        &frame[info.l3 + 8..info.l3 + IPV6_HLEN]
+    } else {
+        &frame[info.l3 + 12..info.l3 + 20]
+    };
+    let sum = csum_add(0, addr) + info.proto as u64 + l4_len as u64;
+    let mut csum = csum_fold(csum_add(sum, &frame[info.l4..]));
+    if csum == 0 && info.proto == IPPROTO_UDP {
+        csum = 0xffff;
+    }
+    put_be16(frame, info.l4 + csum_offset, csum);
+}
+
+fn tcp_segment(frame: &[u8], info: &L3Info, mss: usize) -> Option<Vec<Vec<u8>>> {
+    if info.proto != IPPROTO_TCP || frame.len() < info.l4 + 20 {
+        return None;
+    }
+    let hlen = info.l4 + (frame[info.l4 + 12] >> 4) as usize * 4;
+    if hlen > frame.len() {
+        return None;
+    }
+    let payload = &frame[hlen..];
+    let chunks: Vec<&[u8]> = if payload.is_empty() {
+        vec![payload]
+    } else {
+        payload.chunks(mss).collect()
+    };
+    let seq = u32::from_be_bytes(frame[info.l4 + 4..info.l4 + 8].try_into().unwrap());
+    let flags = frame[info.l4 + 13];
+    let ip_id = if info.ipv6 { 0 } else { be16(frame, info.l3 + 4) };
+
+    let num = chunks.len();
+    let mut segs = Vec::with_capacity(num);
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let mut seg = Vec::with_capacity(hlen + chunk.len());
+        seg.extend_from_slice(&frame[..hlen]);
+        seg.extend_from_slice(chunk);
+
+        let seg_seq = seq.wrapping_add((i * mss) as u32);
+        seg[info.l4 + 4..info.l4 + 8].copy_from_slice(&seg_seq.to_be_bytes());
+        // FIN and PSH only belong to the last segment, CWR only to the first one
+        let mut seg_flags = flags;
+        if i != num - 1 {
+            seg_flags &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+        }
+        if i != 0 {
+            seg_flags &= !TCP_FLAG_CWR;
+        }
+        seg[info.l4 + 13] = seg_flags;
+
+        ip_set_len(&mut seg, info);
+        if !info.ipv6 {
+            put_be16(&mut seg, info.l3 + 4, ip_id.wrapping_add(i as u16));
+            ipv4_csum(&mut seg, info);
+        }
+        l4_csum(&mut seg, info, TCP_CSUM_OFFSET);
+        segs.push(seg);
+    }
+    Some(segs)
+}
+
+// UDP fragmentation offload, only IPv4 is supported
+fn udp_fragment(frame: &[u8], info: &L3Info, size: usize) -> Option<Vec<Vec<u8>>> {
+    if info.proto != IPPROTO_UDP || info.ipv6 || frame.len() < info.l4 + 8 {
+        return None;
+    }
+    // fragment payload must be a multiple of 8 bytes
+    let frag_size = size & !7;
+    if frag_size == 0 {
+        return None;
+    }
+    // the UDP checksum covers the whole datagram
+    let mut datagram = frame.to_vec();
+    l4_csum(&mut datagram, info, UDP_CSUM_OFFSET);
+
+    let chunks: Vec<&[u8]> = datagram[info.l4..].chunks(frag_size).collect();
+    let num = chunks.len();
+    let mut frags = Vec::with_capacity(num);
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let mut frag = Vec::with_capacity(info.l4 + chunk.len());
+        frag.extend_from_slice(&datagram[..info.l4]);
+        frag.extend_from_slice(chunk);
+
+        let mf = if i != num - 1 { IP_MF } else { 0 };
+        put_be16(&mut frag, info.l3 + 6, mf | ((i * frag_size) / 8) as u16);
+        ip_set_len(&mut frag, info);
+        ipv4_csum(&mut frag, info);
+        frags.push(frag);
+    }
+    Some(frags)
+}
+
+// Split a GSO frame into frames with complete checksums
+pub fn segment(frame: &[u8], gso_type: u8, gso_size: usize) -> Option<Vec<Vec<u8>>> {
+    if gso_size == 0 {
+        return None;
+    }
+    let info = parse(frame)?;
+    match gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => tcp_segment(frame, &info, gso_size),
+        VIRTIO_NET_HDR_GSO_UDP => udp_fragment(frame, &info, gso_size),
+        _ => None,
+    }
+}
@@ -0,0 +1,591 @@
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use spin::Mutex;
+
+use crate::config::VmEmulatedDeviceConfig;
+use crate::device::{
+    EmuContext, EmuDev, EmuDeviceType, PciConfigSpace, PciDevice, PciHost, PCI_CAP_ID_MSIX, PCI_COMMAND,
+    PCI_COMMAND_MEMORY, PCI_INTERRUPT_PIN, PCI_INTERRUPT_PIN_INTA, PCI_STATUS, PCI_STATUS_INTERRUPT,
+};
+use crate::kernel::{active_vm, current_cpu, Vm};
+
+use super::dev::VirtioDeviceType;
+use super::mmio::{virtio_dev_config_access, virtio_dev_init};
+use super::VirtioMmio;
+
+// Virtio over PCI (virtio 1.0 modern interface). The interrupt is INTx and the ISR status tells the vring and
+// configuration interrupts apart, until the driver enables MSI-X. Then each queue and the configuration change
+// have their own vector, whose MSI is translated by the virtual ITS of the VM, so the driver can spread the queues
+// over the vCPUs. The device itself is shared with the MMIO transport, so the backends don't care about how the
+// driver reaches them.
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+const VIRTIO_PCI_REVISION: u8 = 1;
+
+// all the structures live in BAR 0
+const VIRTIO_PCI_BAR_SIZE: usize = 0x1000;
+const VIRTIO_PCI_COMMON_CFG: usize = 0x000;
+const VIRTIO_PCI_COMMON_CFG_LEN: usize = 0x38;
+const VIRTIO_PCI_ISR_CFG: usize = 0x100;
+const VIRTIO_PCI_ISR_CFG_LEN: usize = 0x1;
+const VIRTIO_PCI_DEVICE_CFG: usize = 0x200;
+const VIRTIO_PCI_DEVICE_CFG_LEN: usize = 0x100;
+// every queue shares the notification address and the driver writes the queue index to it
+const VIRTIO_PCI_NOTIFY_CFG: usize = 0x300;
+const VIRTIO_PCI_NOTIFY_CFG_LEN: usize = 0x2;
+// a vector for each queue and one for the configuration change, at most
+const VIRTIO_PCI_MSIX_TABLE: usize = 0x400;
+const VIRTIO_PCI_MSIX_VECTORS_MAX: usize = 32;
+const VIRTIO_PCI_MSIX_PBA: usize = 0x600;
+const VIRTIO_PCI_MSIX_PBA_LEN: usize = VIRTIO_PCI_MSIX_VECTORS_MAX / 8;
+
+const VIRTIO_PCI_CAP_VNDR: u8 = 0x09;
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+const VIRTIO_PCI_CAP_OFFSET: usize = 0x40;
+const VIRTIO_PCI_CAP_LEN: usize = 0x10;
+
+// struct virtio_pci_common_cfg
+const VIRTIO_PCI_COMMON_DFSELECT: usize = 0x00;
+const VIRTIO_PCI_COMMON_DF: usize = 0x04;
+const VIRTIO_PCI_COMMON_GFSELECT: usize = 0x08;
+const VIRTIO_PCI_COMMON_GF: usize = 0x0c;
+const VIRTIO_PCI_COMMON_MSIX: usize = 0x10;
+const VIRTIO_PCI_COMMON_NUMQ: usize = 0x12;
+const VIRTIO_PCI_COMMON_STATUS: usize = 0x14;
+const VIRTIO_PCI_COMMON_CFGGENERATION: usize = 0x15;
+const VIRTIO_PCI_COMMON_Q_SELECT: usize = 0x16;
+const VIRTIO_PCI_COMMON_Q_SIZE: usize = 0x18;
+const VIRTIO_PCI_COMMON_Q_MSIX: usize = 0x1a;
+const VIRTIO_PCI_COMMON_Q_ENABLE: usize = 0x1c;
+const VIRTIO_PCI_COMMON_Q_NOFF: usize = 0x1e;
+const VIRTIO_PCI_COMMON_Q_DESCLO: usize = 0x20;
+const VIRTIO_PCI_COMMON_Q_DESCHI: usize = 0x24;
+const VIRTIO_PCI_COMMON_Q_AVAILLO: usize = 0x28;
+const VIRTIO_PCI_COMMON_Q_AVAILHI: usize = 0x2c;
+const VIRTIO_PCI_COMMON_Q_USEDLO: usize = 0x30;
+const VIRTIO_PCI_COMMON_Q_USEDHI: usize = 0x34;
+
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+// the MSI-X capability and table entry
+const PCI_MSIX_CAP_LEN: usize = 12;
+const PCI_MSIX_FLAGS: usize = 2;
+const PCI_MSIX_FLAGS_MASKALL: u16 = 1 << 14;
+const PCI_MSIX_FLAGS_ENABLE: u16 = 1 << 15;
+const PCI_MSIX_ENTRY_SIZE: usize = 16;
+const PCI_MSIX_ENTRY_LOWER_ADDR: usize = 0x0;
+const PCI_MSIX_ENTRY_UPPER_ADDR: usize = 0x4;
+const PCI_MSIX_ENTRY_DATA: usize = 0x8;
+const PCI_MSIX_ENTRY_VECTOR_CTRL: usize = 0xc;
+const PCI_MSIX_ENTRY_CTRL_MASKBIT: u32 = 1 << 0;
+
+#[derive(Clone, Copy)]
+struct MsixEntry {
+    addr: usize,
+    data: u32,
+    ctrl: u32,
+}
+
+impl Default for MsixEntry {
+    fn default() -> Self {
+        Self {
+            addr: 0,
+            data: 0,
+            ctrl: PCI_MSIX_ENTRY_CTRL_MASKBIT,
+        }
+    }
+}
+
+struct VirtioPciMsix {
+    // the DeviceID of the MSIs, given by the slot on the host bridge
+    devid: usize,
+    config_vector: u16,
+    queue_vectors: Vec<u16>,
+    table: Vec<MsixEntry>,
+    // the vectors raised while masked
+    pending: u32,
+}
+
+impl VirtioPciMsix {
+    fn new(queue_num: usize) -> Self {
+        Self {
+            devid: 0,
+            config_vector: VIRTIO_MSI_NO_VECTOR,
+            queue_vectors: vec![VIRTIO_MSI_NO_VECTOR; queue_num],
+            table: vec![MsixEntry::default(); usize::min(queue_num + 1, VIRTIO_PCI_MSIX_VECTORS_MAX)],
+            pending: 0,
+        }
+    }
+
+    // a vector beyond the table is refused, and reads back as no vector
+    fn vector(&self, vector: usize) -> u16 {
+        if vector < self.table.len() {
+            vector as u16
+        } else {
+            VIRTIO_MSI_NO_VECTOR
+        }
+    }
+}
+
+pub struct VirtioPci {
+    dev: Arc<VirtioMmio>,
+    emu_type: EmuDeviceType,
+    config: Mutex<PciConfigSpace>,
+    msix_cap: usize,
+    msix: Mutex<VirtioPciMsix>,
+}
+
+impl VirtioPci {
+    fn new(dev: Arc<VirtioMmio>, dev_type: VirtioDeviceType, emu_cfg: &VmEmulatedDeviceConfig) -> Self {
+        let class = match dev_type {
+            VirtioDeviceType::Net => 0x02_00_00,
+            VirtioDeviceType::Block => 0x01_80_00,
+            VirtioDeviceType::Console => 0x07_80_00,
+            _ => 0xff_00_00,
+        };
+        let mut config = PciConfigSpace::new(
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + dev_type as u16,
+            class,
+            VIRTIO_PCI_REVISION,
+        );
+        config.set_u16(PCI_COMMAND, PCI_COMMAND_MEMORY);
+        config.set_u8(PCI_INTERRUPT_PIN, PCI_INTERRUPT_PIN_INTA);
+        config.set_bar64(0, emu_cfg.base_ipa, VIRTIO_PCI_BAR_SIZE);
+        let caps = [
+            (
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                VIRTIO_PCI_COMMON_CFG,
+                VIRTIO_PCI_COMMON_CFG_LEN,
+            ),
+            (VIRTIO_PCI_CAP_ISR_CFG, VIRTIO_PCI_ISR_CFG, VIRTIO_PCI_ISR_CFG_LEN),
+            (
+                VIRTIO_PCI_CAP_DEVICE_CFG,
+                VIRTIO_PCI_DEVICE_CFG,
+                VIRTIO_PCI_DEVICE_CFG_LEN,
+            ),
+            (
+                VIRTIO_PCI_CAP_NOTIFY_CFG,
+                VIRTIO_PCI_NOTIFY_CFG,
+                VIRTIO_PCI_NOTIFY_CFG_LEN,
+            ),
+        ];
+        let mut cap_offset = VIRTIO_PCI_CAP_OFFSET;
+        for (cfg_type, offset, length) in caps {
+            // struct virtio_pci_cap, followed by notify_off_multiplier 0 for the notification capability
+            let mut cap = [0_u8; VIRTIO_PCI_CAP_LEN + 4];
+            let cap_len = if cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG {
+                VIRTIO_PCI_CAP_LEN + 4
+            } else {
+                VIRTIO_PCI_CAP_LEN
+            };
+            cap[0] = VIRTIO_PCI_CAP_VNDR;
+            cap[2] = cap_len as u8;
+            cap[3] = cfg_type;
+            cap[8..12].copy_from_slice(&(offset as u32).to_le_bytes());
+            cap[12..16].copy_from_slice(&(length as u32).to_le_bytes());
+            config.add_capability(cap_offset, &cap[..cap_len]);
+            cap_offset += cap_len;
+        }
+        // the table and the pending bit array are in BAR 0 as well
+        let msix = VirtioPciMsix::new(dev.vq_num());
+        let mut cap = [0_u8; PCI_MSIX_CAP_LEN];
+        cap[0] = PCI_CAP_ID_MSIX;
+        cap[2..4].copy_from_slice(&(msix.table.len() as u16 - 1).to_le_bytes());
+        cap[4..8].copy_from_slice(&(VIRTIO_PCI_MSIX_TABLE as u32).to_le_bytes());
+        cap[8..12].copy_from_slice(&(VIRTIO_PCI_MSIX_PBA as u32).to_le_bytes());
+        let msix_cap = config.add_capability(cap_offset, &cap);
+        let flags_mask = PCI_MSIX_FLAGS_ENABLE | PCI_MSIX_FLAGS_MASKALL;
+        config.set_wmask(msix_cap + PCI_MSIX_FLAGS, &flags_mask.to_le_bytes());
+        Self {
+            dev,
+            emu_type: emu_cfg.emu_type,
+            config: Mutex::new(config),
+            msix_cap,
+            msix: Mutex::new(msix),
+        }
+    }
+
+    fn msix_flags(&self) -> u16 {
+        self.config.lock().read(self.msix_cap + PCI_MSIX_FLAGS, 2) as u16
+    }
+
+    /* Raise the MSI-X vector of a queue or of the configuration change.
+     *
+     * @param[in] vq_idx: the queue, or None for the configuration change.
+     * @return: false if MSI-X is disabled, and the device raises INTx instead.
+     */
+    pub(super) fn msix_notify(&self, vq_idx: Option<usize>) -> bool {
+        let flags = self.msix_flags();
+        if flags & PCI_MSIX_FLAGS_ENABLE == 0 {
+            return false;
+        }
+        let mut msix = self.msix.lock();
+        let vector = match vq_idx {
+            Some(idx) => msix.queue_vectors.get(idx).copied().unwrap_or(VIRTIO_MSI_NO_VECTOR),
+            None => msix.config_vector,
+        } as usize;
+        // there is no interrupt without a vector
+        let Some(entry) = msix.table.get(vector).copied() else {
+            return true;
+        };
+        if flags & PCI_MSIX_FLAGS_MASKALL != 0 || entry.ctrl & PCI_MSIX_ENTRY_CTRL_MASKBIT != 0 {
+            msix.pending |= 1 << vector;
+        } else {
+            let devid = msix.devid;
+            drop(msix);
+            self.msi_send(devid, entry);
+        }
+        true
+    }
+
+    // send the pending vectors which are unmasked
+    fn msix_flush_pending(&self) {
+        let flags = self.msix_flags();
+        if flags & PCI_MSIX_FLAGS_ENABLE == 0 || flags & PCI_MSIX_FLAGS_MASKALL != 0 {
+            return;
+        }
+        let mut msix = self.msix.lock();
+        let mut entries = Vec::new();
+        for vector in 0..msix.table.len() {
+            let entry = msix.table[vector];
+            if msix.pending & (1 << vector) != 0 && entry.ctrl & PCI_MSIX_ENTRY_CTRL_MASKBIT == 0 {
+                msix.pending &= !(1 << vector);
+                entries.push(entry);
+            }
+        }
+        let devid = msix.devid;
+        drop(msix);
+        for entry in entries {
+            self.msi_send(devid, entry);
+        }
+    }
+
+    // the MSI is translated by the virtual ITS of the VM, the one written elsewhere is dropped
+    fn msi_send(&self, devid: usize, entry: MsixEntry) {
+        let vm = self.dev.upper_vm().unwrap();
+        #[cfg(feature = "gicv3")]
+        if vm
+            .vits()
+            .is_some_and(|vits| vits.msi_write(&vm, entry.addr, devid, entry.data as usize))
+        {
+            return;
+        }
+        warn!(
+            "VM {} virtio-pci device {:x} drops MSI {:#x} of DeviceID {:#x} to {:#x}",
+            vm.id(),
+            self.dev.base(),
+            entry.data,
+            devid,
+            entry.addr
+        );
+    }
+
+    fn msix_table_access(&self, emu_ctx: &EmuContext, offset: usize, write: bool) {
+        let mut msix = self.msix.lock();
+        let reg = offset % PCI_MSIX_ENTRY_SIZE;
+        let Some(entry) = msix.table.get_mut(offset / PCI_MSIX_ENTRY_SIZE) else {
+            // the entries beyond the table size are reserved
+            if !write {
+                current_cpu().set_gpr(emu_ctx.reg, 0);
+            }
+            return;
+        };
+        if !write {
+            let value = match reg {
+                PCI_MSIX_ENTRY_LOWER_ADDR => entry.addr,
+                PCI_MSIX_ENTRY_UPPER_ADDR => entry.addr >> 32,
+                PCI_MSIX_ENTRY_DATA => entry.data as usize | (entry.ctrl as usize) << 32,
+                _ => entry.ctrl as usize,
+            };
+            current_cpu().set_gpr(emu_ctx.reg, value & (usize::MAX >> (64 - emu_ctx.width * 8)));
+            return;
+        }
+        let value = current_cpu().get_gpr(emu_ctx.reg);
+        match (reg, emu_ctx.width) {
+            (PCI_MSIX_ENTRY_LOWER_ADDR, 8) => entry.addr = value,
+            (PCI_MSIX_ENTRY_LOWER_ADDR, _) => entry.addr = (entry.addr & !0xffff_ffff) | (value & 0xffff_ffff),
+            (PCI_MSIX_ENTRY_UPPER_ADDR, _) => entry.addr = (entry.addr & 0xffff_ffff) | (value << 32),
+            (PCI_MSIX_ENTRY_DATA, 8) => {
+                entry.data = value as u32;
+                entry.ctrl = (value >> 32) as u32 & PCI_MSIX_ENTRY_CTRL_MASKBIT;
+            }
+            (PCI_MSIX_ENTRY_DATA, _) => entry.data = value as u32,
+            _ => entry.ctrl = value as u32 & PCI_MSIX_ENTRY_CTRL_MASKBIT,
+        }
+        drop(msix);
+        self.msix_flush_pending();
+    }
+
+    fn common_cfg_read(&self, offset: usize) -> u32 {
+        let dev = &self.dev;
+        match offset {
+            VIRTIO_PCI_COMMON_DFSELECT => dev.dev_feature_sel(),
+            VIRTIO_PCI_COMMON_DF => match dev.dev_feature_sel() {
+                0 => dev.dev().features() as u32,
+                1 => (dev.dev().features() >> 32) as u32,
+                _ => 0,
+            },
+            VIRTIO_PCI_COMMON_GFSELECT => dev.drv_feature_sel(),
+            VIRTIO_PCI_COMMON_MSIX => self.msix.lock().config_vector as u32,
+            VIRTIO_PCI_COMMON_Q_MSIX => {
+                let msix = self.msix.lock();
+                msix.queue_vectors
+                    .get(dev.q_sel() as usize)
+                    .copied()
+                    .unwrap_or(VIRTIO_MSI_NO_VECTOR) as u32
+            }
+            VIRTIO_PCI_COMMON_NUMQ => dev.vq_num() as u32,
+            VIRTIO_PCI_COMMON_STATUS => dev.dev_stat(),
+            VIRTIO_PCI_COMMON_CFGGENERATION => dev.dev().generation() as u8 as u32,
+            VIRTIO_PCI_COMMON_Q_SELECT => dev.q_sel(),
+            VIRTIO_PCI_COMMON_Q_NOFF => 0,
+            VIRTIO_PCI_COMMON_Q_SIZE | VIRTIO_PCI_COMMON_Q_ENABLE => {
+                let Ok(virtq) = dev.vq(dev.q_sel() as usize) else {
+                    // a queue that doesn't exist has size 0
+                    return 0;
+                };
+                match offset {
+                    // the size starts at the maximum and the driver may shrink it
+                    VIRTIO_PCI_COMMON_Q_SIZE if virtq.num() == 0 => dev.q_num_max(),
+                    VIRTIO_PCI_COMMON_Q_SIZE => virtq.num() as u32,
+                    _ => virtq.ready() as u32,
+                }
+            }
+            VIRTIO_PCI_COMMON_Q_DESCLO..=VIRTIO_PCI_COMMON_Q_USEDHI => {
+                let Ok(virtq) = dev.vq(dev.q_sel() as usize) else {
+                    return 0;
+                };
+                let addr = if offset < VIRTIO_PCI_COMMON_Q_AVAILLO {
+                    virtq.desc_table_addr()
+                } else if offset < VIRTIO_PCI_COMMON_Q_USEDLO {
+                    virtq.avail_addr()
+                } else {
+                    virtq.used_addr()
+                };
+                (addr >> ((offset & 0x4) * 8)) as u32
+            }
+            _ => 0,
+        }
+    }
+
+    fn common_cfg_write(&self, offset: usize, width: usize, value: usize) {
+        let dev = &self.dev;
+        match offset {
+            VIRTIO_PCI_COMMON_DFSELECT => dev.set_dev_feature_sel(value as u32),
+            VIRTIO_PCI_COMMON_GFSELECT => dev.set_drv_feature_sel(value as u32),
+            VIRTIO_PCI_COMMON_GF => {
+                dev.set_drv_feature(value as u32);
+                match dev.drv_feature_sel() {
+                    0 => dev.or_driver_feature(value),
+                    1 => dev.or_driver_feature(value << 32),
+                    _ => {}
+                }
+            }
+            VIRTIO_PCI_COMMON_MSIX => {
+                let mut msix = self.msix.lock();
+                msix.config_vector = msix.vector(value);
+            }
+            VIRTIO_PCI_COMMON_Q_MSIX => {
+                let q_sel = dev.q_sel() as usize;
+                let mut msix = self.msix.lock();
+                let vector = msix.vector(value);
+                if let Some(queue_vector) = msix.queue_vectors.get_mut(q_sel) {
+                    *queue_vector = vector;
+                }
+            }
+            VIRTIO_PCI_COMMON_STATUS => {
+                dev.write_dev_stat(value as u32);
+                // the reset unbinds the vectors from the queues
+                if value == 0 {
+                    let mut msix = self.msix.lock();
+                    msix.config_vector = VIRTIO_MSI_NO_VECTOR;
+                    msix.queue_vectors.fill(VIRTIO_MSI_NO_VECTOR);
+                }
+            }
+            VIRTIO_PCI_COMMON_Q_SELECT => dev.set_q_sel(value as u32),
+            VIRTIO_PCI_COMMON_Q_SIZE
+            | VIRTIO_PCI_COMMON_Q_ENABLE
+            | VIRTIO_PCI_COMMON_Q_DESCLO..=VIRTIO_PCI_COMMON_Q_USEDHI => {
+                let q_sel = dev.q_sel() as usize;
+                let Ok(virtq) = dev.vq(q_sel) else {
+                    error!("virtio_pci_common_cfg_write: wrong q_sel {:x}", q_sel);
+                    return;
+                };
+                match offset {
+                    VIRTIO_PCI_COMMON_Q_SIZE => virtq.set_num(value),
+                    VIRTIO_PCI_COMMON_Q_ENABLE => {
+                        virtq.set_ready(value);
+                        info!(
+                            "VM {} virtio-pci device {:x} queue {} ready",
+                            active_vm().unwrap().id(),
+                            dev.base(),
+                            q_sel
+                        );
+                    }
+                    _ => {
+                        // a 64-bit address is written at once or as the low half then the high half
+                        let high = matches!(
+                            offset,
+                            VIRTIO_PCI_COMMON_Q_DESCHI | VIRTIO_PCI_COMMON_Q_AVAILHI | VIRTIO_PCI_COMMON_Q_USEDHI
+                        );
+                        let part = if high { value << 32 } else { value };
+                        let complete = high || width == 8;
+                        let vm = active_vm().unwrap();
+                        if offset < VIRTIO_PCI_COMMON_Q_AVAILLO {
+                            virtq.or_desc_table_addr(part);
+                            if complete {
+                                match vm.ipa2hva(virtq.desc_table_addr()) {
+                                    0 => error!("virtio_pci_common_cfg_write: invalid desc_table_addr"),
+                                    hva => virtq.set_desc_table(hva),
+                                }
+                            }
+                        } else if offset < VIRTIO_PCI_COMMON_Q_USEDLO {
+                            virtq.or_avail_addr(part);
+                            if complete {
+                                match vm.ipa2hva(virtq.avail_addr()) {
+                                    0 => error!("virtio_pci_common_cfg_write: invalid avail_addr"),
+                                    hva => virtq.set_avail(hva),
+                                }
+                            }
+                        } else {
+                            virtq.or_used_addr(part);
+                            if complete {
+                                match vm.ipa2hva(virtq.used_addr()) {
+                                    0 => error!("virtio_pci_common_cfg_write: invalid used_addr"),
+                                    hva => virtq.set_used(hva),
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            _ => warn!("virtio_pci_common_cfg_write: write read-only register {:#x}", offset),
+        }
+    }
+}
+
+impl PciDevice for VirtioPci {
+    fn config_read(&self, offset: usize, width: usize) -> u32 {
+        let mut value = self.config.lock().read(offset, width);
+        if (offset..offset + width).contains(&PCI_STATUS) && self.dev.irt_stat() != 0 {
+            value |= (PCI_STATUS_INTERRUPT as u32) << ((PCI_STATUS - offset) * 8);
+        }
+        value
+    }
+
+    fn config_write(&self, offset: usize, width: usize, value: u32) {
+        self.config.lock().write(offset, width, value);
+        // enabling or unmasking MSI-X sends the pending vectors
+        if (offset..offset + width).contains(&(self.msix_cap + PCI_MSIX_FLAGS + 1)) {
+            self.msix_flush_pending();
+        }
+    }
+}
+
+impl EmuDev for VirtioPci {
+    fn emu_type(&self) -> EmuDeviceType {
+        self.emu_type
+    }
+
+    // the BAR moves wherever the driver places it, and decodes nothing while memory space is disabled
+    fn address_range(&self) -> Range<usize> {
+        let config = self.config.lock();
+        if config.read(PCI_COMMAND, 2) as u16 & PCI_COMMAND_MEMORY == 0 {
+            return 0..0;
+        }
+        let base = config.bar64(0);
+        base..base.saturating_add(VIRTIO_PCI_BAR_SIZE)
+    }
+
+    fn handler(&self, emu_ctx: &EmuContext) -> bool {
+        let offset = emu_ctx.address - self.address_range().start;
+        let write = emu_ctx.write;
+
+        if (VIRTIO_PCI_COMMON_CFG..VIRTIO_PCI_COMMON_CFG + VIRTIO_PCI_COMMON_CFG_LEN).contains(&offset) {
+            let offset = offset - VIRTIO_PCI_COMMON_CFG;
+            if write {
+                let value = current_cpu().get_gpr(emu_ctx.reg) & (usize::MAX >> (64 - emu_ctx.width * 8));
+                self.common_cfg_write(offset, emu_ctx.width, value);
+            } else {
+                let value = self.common_cfg_read(offset);
+                current_cpu().set_gpr(emu_ctx.reg, value as usize);
+            }
+        } else if offset == VIRTIO_PCI_ISR_CFG && !write {
+            // reading the ISR status acknowledges the interrupt
+            let value = self.dev.irt_stat();
+            self.dev.set_irt_stat(0);
+            current_cpu().set_gpr(emu_ctx.reg, value as usize);
+        } else if (VIRTIO_PCI_DEVICE_CFG..VIRTIO_PCI_DEVICE_CFG + VIRTIO_PCI_DEVICE_CFG_LEN).contains(&offset) {
+            virtio_dev_config_access(&self.dev, emu_ctx, offset - VIRTIO_PCI_DEVICE_CFG, write);
+        } else if (VIRTIO_PCI_MSIX_TABLE..VIRTIO_PCI_MSIX_PBA).contains(&offset) {
+            self.msix_table_access(emu_ctx, offset - VIRTIO_PCI_MSIX_TABLE, write);
+        } else if (VIRTIO_PCI_MSIX_PBA..VIRTIO_PCI_MSIX_PBA + VIRTIO_PCI_MSIX_PBA_LEN).contains(&offset) && !write {
+            let pending = self.msix.lock().pending as usize >> ((offset - VIRTIO_PCI_MSIX_PBA) * 8);
+            current_cpu().set_gpr(emu_ctx.reg, pending);
+        } else if offset == VIRTIO_PCI_NOTIFY_CFG && write {
+            let idx = current_cpu().get_gpr(emu_ctx.reg) & 0xffff;
+            if !self.dev.queue_notify(idx) {
+                error!("Failed to handle virtio pci request of queue {}!", idx);
+            }
+        } else {
+            error!(
+                "emu_virtio_pci_handler: regs wrong {}, address {:#x}, offset {:#x}",
+                if write { "write" } else { "read" },
+                emu_ctx.address,
+                offset
+            );
+            return false;
+        }
+        true
+    }
+}
+
+// the virtio device behind an emulated device of either transport
+pub(super) fn virtio_dev_downcast(dev: Arc<dyn EmuDev>) -> Option<Arc<VirtioMmio>> {
+    match dev.into_any_arc().downcast::<VirtioMmio>() {
+        Ok(mmio) => Some(mmio),
+        Err(dev) => dev.downcast::<VirtioPci>().ok().map(|pci| pci.dev.clone()),
+    }
+}
+
+// plug the virtio device into the host bridge, its BAR starts at base_ipa
+pub fn emu_virtio_pci_init(
+    vm: Weak<Vm>,
+    emu_cfg: &VmEmulatedDeviceConfig,
+    host: &PciHost,
+) -> Result<Arc<dyn EmuDev>, ()> {
+    let bar = emu_cfg.base_ipa..emu_cfg.base_ipa + VIRTIO_PCI_BAR_SIZE;
+    if bar.start % VIRTIO_PCI_BAR_SIZE != 0
+        || !host.mem_window().contains(&bar.start)
+        || !host.mem_window().contains(&(bar.end - 1))
+        || !host.has_free_slot()
+    {
+        error!("emu_virtio_pci_init: illegal config of {}", emu_cfg.name);
+        return Err(());
+    }
+    let dev_type = match emu_cfg.emu_type {
+        EmuDeviceType::EmuDeviceTVirtioBlk => VirtioDeviceType::Block,
+        EmuDeviceType::EmuDeviceTVirtioNet => VirtioDeviceType::Net,
+        EmuDeviceType::EmuDeviceTVirtioConsole => VirtioDeviceType::Console,
+        #[cfg(feature = "balloon")]
+        EmuDeviceType::VirtioBalloon => VirtioDeviceType::Balloon,
+        _ => {
+            error!("emu_virtio_pci_init: unknown emulated device type");
+            return Err(());
+        }
+    };
+    let dev = virtio_dev_init(vm, emu_cfg)?;
+    let pci = Arc::new(VirtioPci::new(dev.clone(), dev_type, emu_cfg));
+    let slot = host.attach(pci.clone()).ok_or(())?;
+    pci.msix.lock().devid = host.msi_devid(slot);
+    dev.set_pci(Arc::downgrade(&pci));
+    info!("virtio-pci device {} is at slot {}", emu_cfg.name, slot);
+    Ok(pci)
+}
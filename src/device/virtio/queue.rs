@@ -1,4 +1,5 @@
 use alloc::sync::{Arc, Weak};
+use core::mem::size_of;
 use core::slice;
 
 use spin::Mutex;
@@ -55,6 +56,20 @@ struct VringUsed {
     ring: [VringUsedElem; DESC_QUEUE_SIZE],
 }
 
+// state of a queue carried over by migration, the rings themselves are in the guest memory
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VirtqMigrateState {
+    ready: usize,
+    num: usize,
+    last_avail_idx: u16,
+    last_used_idx: u16,
+    used_flags: u16,
+    desc_table_addr: usize,
+    avail_addr: usize,
+    used_addr: usize,
+}
+
 pub struct Virtq {
     vq_index: usize,
     notify_handler: fn(Arc<Self>, Arc<VirtioMmio>, Arc<Vm>) -> bool,
@@ -81,6 +96,61 @@ impl Virtq {
         inner.reset();
     }
 
+    pub fn migrate_save(&self) -> VirtqMigrateState {
+        let inner = self.inner.lock();
+        VirtqMigrateState {
+            ready: inner.ready,
+            num: inner.num,
+            last_avail_idx: inner.last_avail_idx,
+            last_used_idx: inner.last_used_idx,
+            used_flags: inner.used_flags,
+            desc_table_addr: inner.desc_table_addr,
+            avail_addr: inner.avail_addr,
+            used_addr: inner.used_addr,
+        }
+    }
+
+    /* Restore the state of the queue on the receiver VM, and map its rings.
+     *
+     * @param[in] vm: the VM which owns the queue.
+     * @param[in] state: the state saved by the sender.
+     */
+    pub fn migrate_restore(&self, vm: &Vm, state: &VirtqMigrateState) -> Result<(), ()> {
+        // a ring is mapped once the driver has written its address, which may not have happened yet
+        let ring_hva = |ipa: usize| match ipa {
+            0 => Ok(0),
+            ipa => match vm.ipa2hva(ipa) {
+                0 => Err(()),
+                hva => Ok(hva),
+            },
+        };
+        let desc_table = ring_hva(state.desc_table_addr)?;
+        let avail = ring_hva(state.avail_addr)?;
+        let used = ring_hva(state.used_addr)?;
+
+        let mut inner = self.inner.lock();
+        inner.reset();
+        inner.ready = state.ready;
+        inner.num = state.num;
+        inner.last_avail_idx = state.last_avail_idx;
+        inner.last_used_idx = state.last_used_idx;
+        inner.used_flags = state.used_flags;
+        inner.desc_table_addr = state.desc_table_addr;
+        inner.avail_addr = state.avail_addr;
+        inner.used_addr = state.used_addr;
+        drop(inner);
+        if desc_table != 0 {
+            self.set_desc_table(desc_table);
+        }
+        if avail != 0 {
+            self.set_avail(avail);
+        }
+        if used != 0 {
+            self.set_used(used);
+        }
+        Ok(())
+    }
+
     pub fn pop_avail_desc_idx(&self, avail_idx: u16) -> Option<u16> {
         let mut inner = self.inner.lock();
         match &inner.avail {
@@ -160,6 +230,11 @@ impl Virtq {
                 used.ring[used.idx as usize % num].id = desc_chain_head_idx;
                 used.ring[used.idx as usize % num].len = len;
                 used.idx = used.idx.wrapping_add(1);
+                let used_addr = inner.used_addr;
+                drop(inner);
+                if let Some(vm) = self.mmio.upgrade().and_then(|mmio| mmio.upper_vm()) {
+                    vm.mark_dirty(used_addr, size_of::<VringUsedElem>() * num + 6);
+                }
                 true
             }
             None => {
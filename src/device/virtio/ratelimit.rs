@@ -0,0 +1,61 @@
+use alloc::sync::Arc;
+
+use spin::Mutex;
+
+use crate::kernel::timer::now;
+use crate::util::timer_list::TimerValue;
+
+// the burst of a bucket is at least one full-sized ethernet frame
+const NET_RATE_MIN_BURST: usize = 1514;
+
+// Token bucket limiting the traffic sent from a virtio-net port, one token is one byte. The bucket is refilled
+// by the time elapsed since the last refill whenever a frame is sent, so it needs no timer on the core.
+pub struct NetRateLimiter {
+    // bytes per second
+    rate: usize,
+    // capacity of the bucket in bytes
+    burst: usize,
+    inner: Mutex<NetRateLimiterInner>,
+}
+
+struct NetRateLimiterInner {
+    // may drop below zero, a frame is sent as long as any token is left
+    tokens: isize,
+    last_refill: TimerValue,
+}
+
+impl NetRateLimiter {
+    pub fn new(rate: usize, burst: usize) -> Arc<Self> {
+        let burst = usize::max(burst, NET_RATE_MIN_BURST);
+        Arc::new(Self {
+            rate,
+            burst,
+            inner: Mutex::new(NetRateLimiterInner {
+                tokens: burst as isize,
+                last_refill: now(),
+            }),
+        })
+    }
+
+    /* Take tokens for a frame from the bucket.
+     *
+     * @param[in] len: length of the frame in bytes.
+     * @return: false if the bucket is empty and the frame should be dropped.
+     */
+    pub fn consume(&self, len: usize) -> bool {
+        let mut inner = self.inner.lock();
+        let now = now();
+        let elapsed = now.saturating_sub(inner.last_refill);
+        let refill = (self.rate as u128 * elapsed.as_nanos() / 1_000_000_000) as isize;
+        // tokens of the elapsed time that round down to zero are kept for the next refill
+        if refill > 0 {
+            inner.tokens = isize::min(inner.tokens.saturating_add(refill), self.burst as isize);
+            inner.last_refill = now;
+        }
+        if inner.tokens <= 0 {
+            return false;
+        }
+        inner.tokens -= len as isize;
+        true
+    }
+}
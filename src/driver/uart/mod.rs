@@ -14,6 +14,10 @@ use pl011::Pl011Mmio as Uart;
 trait UartOperation {
     fn init(&self);
     fn send(&self, byte: u8);
+    // read a received byte, None if the receive FIFO is empty
+    fn recv(&self) -> Option<u8>;
+    // enable or disable the receive interrupts
+    fn set_rx_int(&self, en: bool);
 }
 
 use crate::board::{PlatOperation, Platform};
@@ -29,6 +33,14 @@ pub fn putc(byte: u8) {
     UART.send(byte);
 }
 
+pub fn getc() -> Option<u8> {
+    UART.recv()
+}
+
+pub fn rx_int_enable(en: bool) {
+    UART.set_rx_int(en);
+}
+
 pub(super) fn init() {
     UART.init();
 }
@@ -317,4 +317,22 @@ impl super::UartOperation for Ns16550Mmio32 {
         }
         self.RHR_THR_DLL.set(byte);
     }
+
+    #[inline]
+    fn recv(&self) -> Option<u8> {
+        if self.LSR.is_set(LSR::RDR) {
+            Some(self.RHR_THR_DLL.read(RHR_THR_DLL::RHR))
+        } else {
+            None
+        }
+    }
+
+    fn set_rx_int(&self, en: bool) {
+        if en {
+            self.IER_DLM.modify(IER_DLM::IE_RHR::SET + IER_DLM::IE_RX_TIMEOUT::SET);
+        } else {
+            self.IER_DLM
+                .modify(IER_DLM::IE_RHR::CLEAR + IER_DLM::IE_RX_TIMEOUT::CLEAR);
+        }
+    }
 }
@@ -2,8 +2,13 @@ use tock_registers::interfaces::*;
 use tock_registers::register_structs;
 use tock_registers::registers::*;
 
-const UART_FR_RXFF: u32 = 1 << 4;
+const UART_FR_RXFE: u32 = 1 << 4;
 const UART_FR_TXFF: u32 = 1 << 5;
+const UART_FR_RXFF: u32 = 1 << 6;
+
+// receive and receive timeout interrupts
+const UART_INT_RX: u32 = 1 << 4;
+const UART_INT_RT: u32 = 1 << 6;
 
 register_structs! {
   #[allow(non_snake_case)]
@@ -40,4 +45,23 @@ impl super::UartOperation for Pl011Mmio {
         }
         self.Data.set(byte as u32);
     }
+
+    #[inline]
+    fn recv(&self) -> Option<u8> {
+        if self.Flag.get() & UART_FR_RXFE != 0 {
+            None
+        } else {
+            Some(self.Data.get() as u8)
+        }
+    }
+
+    fn set_rx_int(&self, en: bool) {
+        let mask = self.IntMaskSetClr.get();
+        if en {
+            self.IntClear.set(UART_INT_RX | UART_INT_RT);
+            self.IntMaskSetClr.set(mask | UART_INT_RX | UART_INT_RT);
+        } else {
+            self.IntMaskSetClr.set(mask & !(UART_INT_RX | UART_INT_RT));
+        }
+    }
 }
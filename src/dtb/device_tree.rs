@@ -1,15 +1,19 @@
+use alloc::string::String;
 use alloc::vec::Vec;
 
 use vm_fdt::{Error, FdtWriter, FdtWriterResult};
 
 use crate::board::{PlatOperation, Platform};
-use crate::config::VmConfigEntry;
 use crate::config::{DtbDevType, VmDtbDevConfig};
-use crate::device::EmuDeviceType;
+use crate::config::{VmConfigEntry, VmEmulatedDeviceConfig};
+use crate::device::{EmuDeviceType, PCI_ECAM_BUS_SIZE, PCI_MSI_DEVID_BASE, PCI_MSI_DEVID_NUM};
 use crate::vmm::CPIO_RAMDISK;
 
 pub static SYSTEM_FDT: spin::Once<alloc::vec::Vec<u8>> = spin::Once::new();
 
+// phandle of the virtual ITS, the MSI controller of the PCIe devices
+const GITS_PHANDLE: u32 = 0x8002;
+
 pub unsafe fn setup_fdt_vm0(config: &VmConfigEntry, dtb: *mut core::ffi::c_void) -> usize {
     use fdt::*;
     let mut mr = Vec::new();
@@ -36,6 +40,9 @@ pub unsafe fn setup_fdt_vm0(config: &VmConfigEntry, dtb: *mut core::ffi::c_void)
 
     for emu_cfg in config.emulated_device_list() {
         match emu_cfg.emu_type {
+            // the GICv3 node from the bootloader already has the distributor and redistributors at the same address
+            EmuDeviceType::EmuDeviceTGicd if cfg!(feature = "gicv3") => {}
+            EmuDeviceType::EmuDeviceTGicr => {}
             EmuDeviceType::EmuDeviceTGicd => {
                 #[cfg(any(feature = "tx2", feature = "qemu"))]
                 fdt_setup_gic(
@@ -55,6 +62,13 @@ pub unsafe fn setup_fdt_vm0(config: &VmConfigEntry, dtb: *mut core::ffi::c_void)
             EmuDeviceType::EmuDeviceTVirtioNet
             | EmuDeviceType::EmuDeviceTVirtioConsole
             | EmuDeviceType::VirtioBalloon => {
+                let on_pci = config.emulated_device_list().iter().any(|host| {
+                    host.emu_type == EmuDeviceType::EmuDeviceTPciHost
+                        && pci_mem_window(host).contains(&emu_cfg.base_ipa)
+                });
+                if on_pci {
+                    continue;
+                }
                 #[cfg(any(feature = "tx2", feature = "qemu"))]
                 fdt_add_virtio(
                     dtb,
@@ -76,6 +90,10 @@ pub unsafe fn setup_fdt_vm0(config: &VmConfigEntry, dtb: *mut core::ffi::c_void)
                 #[cfg(feature = "tx2")]
                 trace!("EmuDeviceTIOMMU");
             }
+            EmuDeviceType::EmuDeviceTConsole | EmuDeviceType::EmuDeviceTPciHost | EmuDeviceType::EmuDeviceTGits => {
+                // the MVM device tree from the bootloader should describe the emulated uart, PCI host bridge and ITS
+                warn!("setup_fdt_vm0: {} is not added to the device tree", emu_cfg.name);
+            }
             _ => {
                 todo!();
             }
@@ -218,7 +236,11 @@ pub fn init_vm0_dtb(dtb: *mut core::ffi::c_void) {
         // pass through the only one uart on qemu-system-aarch64
         // assert_eq!(fdt_remove_node(dtb, "/pl011@9000000\0".as_ptr()), 0);
 
+        // the MSI controller of the removed PCIe host bridge
+        #[cfg(not(feature = "gicv3"))]
         assert_eq!(fdt_remove_node(dtb, "/intc@8000000/v2m@8020000\0".as_ptr()), 0);
+        #[cfg(feature = "gicv3")]
+        assert_eq!(fdt_remove_node(dtb, "/intc@8000000/its@8080000\0".as_ptr()), 0);
         assert_eq!(fdt_remove_node(dtb, "/flash@0\0".as_ptr()), 0);
 
         cfg_if::cfg_if! {
@@ -261,16 +283,35 @@ pub fn create_fdt(config: &VmConfigEntry) -> Result<Vec<u8>, Error> {
             create_serial_node(&mut fdt, dev)?;
         }
     }
+    #[cfg(not(feature = "gicv3"))]
     create_gic_node(&mut fdt, config.gicc_addr(), config.gicd_addr())?;
+    #[cfg(feature = "gicv3")]
+    create_gicv3_node(&mut fdt, config)?;
 
+    // the PCI host bridges and their devices in slot order, as the VM plugs them
+    let mut pci_hosts: Vec<(&VmEmulatedDeviceConfig, Vec<&VmEmulatedDeviceConfig>)> = Vec::new();
     for emu_cfg in config.emulated_device_list() {
         match emu_cfg.emu_type {
+            EmuDeviceType::EmuDeviceTPciHost => pci_hosts.push((emu_cfg, Vec::new())),
+            // every virtio device the VM may attach to a host takes a slot
             EmuDeviceType::EmuDeviceTVirtioBlk
             | EmuDeviceType::EmuDeviceTVirtioNet
-            | EmuDeviceType::EmuDeviceTVirtioConsole => {
+            | EmuDeviceType::EmuDeviceTVirtioConsole
+            | EmuDeviceType::VirtioBalloon => {
+                if let Some((_, devs)) = pci_hosts
+                    .iter_mut()
+                    .find(|(host, _)| pci_mem_window(host).contains(&emu_cfg.base_ipa))
+                {
+                    devs.push(emu_cfg);
+                    continue;
+                }
                 debug!("virtio fdt node init {} {:x}", emu_cfg.name, emu_cfg.base_ipa);
                 create_virtio_node(&mut fdt, &emu_cfg.name, emu_cfg.irq_id, emu_cfg.base_ipa)?;
             }
+            EmuDeviceType::EmuDeviceTConsole => {
+                debug!("pl011 fdt node init {} {:x}", emu_cfg.name, emu_cfg.base_ipa);
+                create_pl011_node(&mut fdt, &emu_cfg.name, emu_cfg.irq_id, emu_cfg.base_ipa)?;
+            }
             EmuDeviceType::EmuDeviceTShyper => {
                 debug!("shyper fdt node init {:x}", emu_cfg.base_ipa);
                 create_shyper_node(
@@ -284,6 +325,31 @@ pub fn create_fdt(config: &VmConfigEntry) -> Result<Vec<u8>, Error> {
             _ => {}
         }
     }
+    // the MSIs of the PCIe devices are translated by the virtual ITS
+    let has_gits = config
+        .emulated_device_list()
+        .iter()
+        .any(|emu_cfg| emu_cfg.emu_type == EmuDeviceType::EmuDeviceTGits);
+    for (index, (host, devs)) in pci_hosts.iter().enumerate() {
+        debug!("pci host fdt node init {} {:x}", host.name, host.base_ipa);
+        let msi_devid = has_gits.then_some(PCI_MSI_DEVID_BASE + index * PCI_MSI_DEVID_NUM);
+        create_pci_host_node(&mut fdt, host, devs, msi_devid)?;
+    }
+    // the passthrough root complexes, each followed by its memory windows
+    let dtb_devs = config.dtb_device_list();
+    for (idx, dev) in dtb_devs.iter().enumerate() {
+        if dev.dev_type == DtbDevType::PciHost {
+            let windows: Vec<&VmDtbDevConfig> = dtb_devs[idx + 1..]
+                .iter()
+                .take_while(|dev| dev.dev_type == DtbDevType::PciMem)
+                .collect();
+            debug!(
+                "passthrough pci host fdt node init {} {:x}",
+                dev.name, dev.addr_region.ipa_start
+            );
+            create_pci_passthrough_node(&mut fdt, dev, &windows, has_gits)?;
+        }
+    }
 
     fdt.end_node(root_node)?;
     fdt.finish()
@@ -367,6 +433,30 @@ fn create_serial_node(fdt: &mut FdtWriter, dev: &VmDtbDevConfig) -> FdtWriterRes
     Ok(())
 }
 
+// the emulated uart has no real clock, the fixed clock only satisfies the amba bus
+fn create_pl011_node(fdt: &mut FdtWriter, name: &str, irq: usize, address: usize) -> FdtWriterResult<()> {
+    let clock_phandle = 0x8100 + (address >> 12) as u32;
+    let clock = fdt.begin_node(&format!("apb-pclk@{:x}", address))?;
+    fdt.property_string("compatible", "fixed-clock")?;
+    fdt.property_u32("#clock-cells", 0)?;
+    fdt.property_u32("clock-frequency", 24000000)?;
+    fdt.property_u32("phandle", clock_phandle)?;
+    fdt.end_node(clock)?;
+
+    let pl011 = fdt.begin_node(name)?;
+    fdt.property_string_list(
+        "compatible",
+        vec![String::from("arm,pl011"), String::from("arm,primecell")],
+    )?;
+    fdt.property_array_u64("reg", &[address as u64, 0x1000])?;
+    fdt.property_array_u32("interrupts", &[0, irq as u32 - 32, 0x4])?;
+    fdt.property_array_u32("clocks", &[clock_phandle, clock_phandle])?;
+    fdt.property_string_list("clock-names", vec![String::from("uartclk"), String::from("apb_pclk")])?;
+    fdt.end_node(pl011)?;
+
+    Ok(())
+}
+
 fn create_chosen_node(fdt: &mut FdtWriter, cmdline: &str, ipa: usize, size: usize) -> FdtWriterResult<()> {
     let chosen = fdt.begin_node("chosen")?;
     fdt.property_string("bootargs", cmdline)?;
@@ -376,6 +466,7 @@ fn create_chosen_node(fdt: &mut FdtWriter, cmdline: &str, ipa: usize, size: usiz
     Ok(())
 }
 
+#[cfg(not(feature = "gicv3"))]
 fn create_gic_node(fdt: &mut FdtWriter, gicc_addr: usize, gicd_addr: usize) -> FdtWriterResult<()> {
     let gic_name = format!("interrupt-controller@{:x}", gicd_addr);
     let gic = fdt.begin_node(&gic_name)?;
@@ -390,6 +481,46 @@ fn create_gic_node(fdt: &mut FdtWriter, gicc_addr: usize, gicd_addr: usize) -> F
     Ok(())
 }
 
+// the distributor, redistributors and ITS are the emulated devices of the VM
+#[cfg(feature = "gicv3")]
+fn create_gicv3_node(fdt: &mut FdtWriter, config: &VmConfigEntry) -> FdtWriterResult<()> {
+    let region = |emu_type| {
+        config
+            .emulated_device_list()
+            .iter()
+            .find(|emu_cfg| emu_cfg.emu_type == emu_type)
+            .map_or((0, 0), |emu_cfg| (emu_cfg.base_ipa as u64, emu_cfg.length as u64))
+    };
+    let (gicd_addr, gicd_len) = region(EmuDeviceType::EmuDeviceTGicd);
+    let (gicr_addr, gicr_len) = region(EmuDeviceType::EmuDeviceTGicr);
+    let (gits_addr, gits_len) = region(EmuDeviceType::EmuDeviceTGits);
+
+    let gic_name = format!("interrupt-controller@{:x}", gicd_addr);
+    let gic = fdt.begin_node(&gic_name)?;
+
+    fdt.property_u32("phandle", 0x8001)?;
+    fdt.property_array_u64("reg", &[gicd_addr, gicd_len, gicr_addr, gicr_len])?;
+    fdt.property_string("compatible", "arm,gic-v3")?;
+    fdt.property_u32("#interrupt-cells", 0x03)?;
+    fdt.property_u32("#redistributor-regions", 0x01)?;
+    fdt.property_null("interrupt-controller")?;
+    if gits_len != 0 {
+        fdt.property_u32("#address-cells", 0x2)?;
+        fdt.property_u32("#size-cells", 0x2)?;
+        fdt.property_null("ranges")?;
+        let its = fdt.begin_node(&format!("msi-controller@{:x}", gits_addr))?;
+        fdt.property_u32("phandle", GITS_PHANDLE)?;
+        fdt.property_string("compatible", "arm,gic-v3-its")?;
+        fdt.property_array_u64("reg", &[gits_addr, gits_len])?;
+        fdt.property_u32("#msi-cells", 0x1)?;
+        fdt.property_null("msi-controller")?;
+        fdt.end_node(its)?;
+    }
+    fdt.end_node(gic)?;
+
+    Ok(())
+}
+
 fn create_virtio_node(fdt: &mut FdtWriter, name: &str, irq: usize, address: usize) -> FdtWriterResult<()> {
     let virtio = fdt.begin_node(name)?;
     fdt.property_null("dma-coherent")?;
@@ -401,6 +532,130 @@ fn create_virtio_node(fdt: &mut FdtWriter, name: &str, irq: usize, address: usiz
     Ok(())
 }
 
+fn pci_mem_window(host: &VmEmulatedDeviceConfig) -> core::ops::Range<usize> {
+    match host.cfg_list[..] {
+        [base, size, ..] => base..base + size,
+        _ => 0..0,
+    }
+}
+
+// pci-host-ecam-generic, the INTA of every device is mapped to the SPI of its config, and the requester ids of
+// bus 0 are mapped to the DeviceIDs from msi_devid
+fn create_pci_host_node(
+    fdt: &mut FdtWriter,
+    host: &VmEmulatedDeviceConfig,
+    devs: &[&VmEmulatedDeviceConfig],
+    msi_devid: Option<usize>,
+) -> FdtWriterResult<()> {
+    let window = pci_mem_window(host);
+    let size = window.len() as u64;
+    // 64-bit memory space if the window is above 4GB
+    let space = if window.end > 1 << 32 { 0x0300_0000 } else { 0x0200_0000 };
+    let mut interrupt_map = Vec::new();
+    for (slot, dev) in devs.iter().enumerate() {
+        interrupt_map.extend_from_slice(&[(slot << 11) as u32, 0, 0, 1, 0x8001, 0, dev.irq_id as u32 - 32, 0x1]);
+    }
+
+    let pci = fdt.begin_node(&format!("pcie@{:x}", host.base_ipa))?;
+    fdt.property_string("compatible", "pci-host-ecam-generic")?;
+    fdt.property_string("device_type", "pci")?;
+    fdt.property_u32("#address-cells", 0x3)?;
+    fdt.property_u32("#size-cells", 0x2)?;
+    fdt.property_u32("#interrupt-cells", 0x1)?;
+    fdt.property_array_u64("reg", &[host.base_ipa as u64, host.length as u64])?;
+    fdt.property_array_u32("bus-range", &[0, (host.length / PCI_ECAM_BUS_SIZE) as u32 - 1])?;
+    fdt.property_array_u32(
+        "ranges",
+        &[
+            space,
+            (window.start >> 32) as u32,
+            window.start as u32,
+            (window.start >> 32) as u32,
+            window.start as u32,
+            (size >> 32) as u32,
+            size as u32,
+        ],
+    )?;
+    fdt.property_array_u32("interrupt-map-mask", &[0xf800, 0, 0, 0x7])?;
+    fdt.property_array_u32("interrupt-map", &interrupt_map)?;
+    if let Some(devid) = msi_devid {
+        fdt.property_array_u32("msi-map", &[0, GITS_PHANDLE, devid as u32, PCI_MSI_DEVID_NUM as u32])?;
+    }
+    fdt.property_null("dma-coherent")?;
+    fdt.end_node(pci)?;
+
+    Ok(())
+}
+
+// pci-host-ecam-generic of the physical root complex, INTA..INTD are swizzled by the slot of the device
+fn create_pci_passthrough_node(
+    fdt: &mut FdtWriter,
+    host: &VmDtbDevConfig,
+    windows: &[&VmDtbDevConfig],
+    has_gits: bool,
+) -> FdtWriterResult<()> {
+    let ecam = &host.addr_region;
+    let mut ranges = Vec::new();
+    for window in windows.iter().map(|dev| &dev.addr_region) {
+        let (start, size) = (window.ipa_start, window.length);
+        // 64-bit memory space if the window is above 4GB
+        let space = if start + size > 1 << 32 {
+            0x0300_0000
+        } else {
+            0x0200_0000
+        };
+        ranges.extend_from_slice(&[
+            space,
+            (start >> 32) as u32,
+            start as u32,
+            (start >> 32) as u32,
+            start as u32,
+            (size >> 32) as u32,
+            size as u32,
+        ]);
+    }
+    let mut interrupt_map = Vec::new();
+    if !host.irqs.is_empty() {
+        for slot in 0..4 {
+            for pin in 0..4 {
+                let irq = host.irqs[(slot + pin) % host.irqs.len()];
+                interrupt_map.extend_from_slice(&[
+                    (slot << 11) as u32,
+                    0,
+                    0,
+                    pin as u32 + 1,
+                    0x8001,
+                    0,
+                    irq as u32 - 32,
+                    0x4,
+                ]);
+            }
+        }
+    }
+
+    let pci = fdt.begin_node(&format!("pcie@{:x}", ecam.ipa_start))?;
+    fdt.property_string("compatible", "pci-host-ecam-generic")?;
+    fdt.property_string("device_type", "pci")?;
+    fdt.property_u32("#address-cells", 0x3)?;
+    fdt.property_u32("#size-cells", 0x2)?;
+    fdt.property_u32("#interrupt-cells", 0x1)?;
+    fdt.property_array_u64("reg", &[ecam.ipa_start as u64, ecam.length as u64])?;
+    fdt.property_array_u32("bus-range", &[0, (ecam.length / PCI_ECAM_BUS_SIZE) as u32 - 1])?;
+    fdt.property_array_u32("ranges", &ranges)?;
+    if !interrupt_map.is_empty() {
+        fdt.property_array_u32("interrupt-map-mask", &[0x1800, 0, 0, 0x7])?;
+        fdt.property_array_u32("interrupt-map", &interrupt_map)?;
+    }
+    // the requester id is the DeviceID of the MSIs
+    if has_gits {
+        fdt.property_array_u32("msi-map", &[0, GITS_PHANDLE, 0, PCI_MSI_DEVID_BASE as u32])?;
+    }
+    fdt.property_null("dma-coherent")?;
+    fdt.end_node(pci)?;
+
+    Ok(())
+}
+
 fn create_shyper_node(fdt: &mut FdtWriter, name: &str, irq: usize, address: usize, len: usize) -> FdtWriterResult<()> {
     let shyper = fdt.begin_node(name)?;
     fdt.property_string("compatible", "shyper")?;
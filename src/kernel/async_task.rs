@@ -9,7 +9,10 @@ use alloc::sync::Arc;
 use alloc::task::Wake;
 use spin::mutex::Mutex;
 
-use crate::device::{mediated_blk_read, mediated_blk_write, virtio_blk_notify_handler, ReadAsyncMsg, WriteAsyncMsg};
+use crate::device::{
+    mediated_blk_flush, mediated_blk_read, mediated_blk_write, virtio_blk_notify_handler, FlushAsyncMsg, ReadAsyncMsg,
+    WriteAsyncMsg,
+};
 use crate::kernel::{active_vm, ipi_send_msg, IpiInnerMsg, IpiMediatedMsg, IpiType};
 use crate::util::{memcpy_safe, sleep};
 
@@ -241,13 +244,14 @@ impl AsyncCallback for ReadAsyncMsg {
             let data_bg = iov.data_bg;
             let len = iov.len as usize;
             memcpy_safe(data_bg as *mut u8, cache_ptr as *mut u8, len);
+            self.src_vm.mark_dirty(self.src_vm.hva2ipa(data_bg), len);
             // sum |= check_sum(data_bg, len);
             cache_ptr += len;
         }
         // println!("read check_sum is {:x}", sum);
         let info = &self.used_info;
         self.vq.update_used_ring(info.used_len, info.desc_chain_head_idx);
-        self.dev.notify();
+        self.dev.notify(self.vq.vq_indx());
     }
 }
 
@@ -261,7 +265,21 @@ impl AsyncCallback for WriteAsyncMsg {
         buffer.clear();
         let info = &self.used_info;
         self.vq.update_used_ring(info.used_len, info.desc_chain_head_idx);
-        self.dev.notify();
+        self.dev.notify(self.vq.vq_indx());
+    }
+}
+
+impl AsyncCallback for FlushAsyncMsg {
+    #[inline]
+    fn preprocess(&self) {
+        mediated_blk_flush(self.blk_id);
+    }
+
+    #[inline]
+    fn finish(&self) {
+        let info = &self.used_info;
+        self.vq.update_used_ring(info.used_len, info.desc_chain_head_idx);
+        self.dev.notify(self.vq.vq_indx());
     }
 }
 
@@ -360,3 +378,10 @@ pub fn remove_vm_async_task(vm_id: usize) {
     io_list.remove(vm_id);
     ipi_list.extract_if(|x| x.src_vmid == vm_id).for_each(drop);
 }
+
+// whether the VM has blk requests which are not completed yet
+pub fn vm_async_task_pending(vm_id: usize) -> bool {
+    let io_list = EXECUTOR.io_task_list.lock();
+    let ipi_list = EXECUTOR.ipi_task_list.lock();
+    io_list.map.contains_key(&vm_id) || ipi_list.iter().any(|x| x.src_vmid == vm_id)
+}
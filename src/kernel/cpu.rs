@@ -11,7 +11,7 @@ use crate::board::{static_config, PLAT_DESC};
 use crate::kernel::{Vcpu, Vm};
 use crate::util::timer_list::TimerList;
 
-use super::sched::get_scheduler;
+use super::sched::{get_scheduler, sched_set_slice};
 use super::vcpu_array::VcpuArray;
 
 pub const CPU_MASTER: usize = 0;
@@ -184,9 +184,10 @@ fn cpu_init_pt() {
     cpu.init_pt(directory);
 }
 
-// TODO: add config for base slice
 fn cpu_sched_init() {
-    let rule = PLAT_DESC.cpu_desc.core_list[current_cpu().id].sched;
+    let core_cfg = &PLAT_DESC.cpu_desc.core_list[current_cpu().id];
+    let rule = core_cfg.sched;
+    let _ = sched_set_slice(current_cpu().id, core_cfg.slice);
     trace!("cpu[{}] init {rule:?} Scheduler", current_cpu().id);
     current_cpu().vcpu_array.sched.call_once(|| {
         let mut scheduler = get_scheduler(rule);
@@ -222,6 +223,11 @@ pub fn cpu_init() {
 
 static mut CPU_LIST: [Cpu; static_config::CORE_NUM] = [const { Cpu::default() }; static_config::CORE_NUM];
 
+// per-core data of another core, only for inspection as the core may be modifying it
+pub(super) fn cpu_by_id(cpu_id: usize) -> Option<&'static Cpu> {
+    unsafe { CPU_LIST.get(cpu_id) }
+}
+
 pub fn cpu_map_self(cpu_id: usize) -> usize {
     let cpu = unsafe { &mut CPU_LIST[cpu_id] };
     cpu.id = cpu_id;
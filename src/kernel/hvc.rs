@@ -7,7 +7,12 @@ use crate::kernel::{
     vm_if_ivc_arg, vm_if_ivc_arg_ptr, vm_if_set_ivc_arg_ptr, IpiHvcMsg, IpiInnerMsg, IpiMessage, IpiType,
 };
 use crate::util::memcpy_safe;
-use crate::vmm::{get_vm_id, vmm_boot_vm, vmm_list_vm, vmm_reboot_vm, vmm_remove_vm};
+use crate::vmm::{
+    get_vm_id, vmm_boot_vm, vmm_dirty_log_fetch, vmm_dirty_log_start, vmm_dirty_log_stop, vmm_get_vcpu_stat,
+    vmm_get_vm_state, vmm_list_vm, vmm_migrate_boot_percore, vmm_migrate_cancel, vmm_migrate_finish,
+    vmm_migrate_init_vm, vmm_migrate_memcpy, vmm_migrate_pause_percore, vmm_migrate_ready, vmm_migrate_start,
+    vmm_migrate_vm_boot, vmm_reboot_vm, vmm_remove_vm, vmm_set_irq_affinity, vmm_shutdown_vm,
+};
 
 use shyper::VM_NUM_MAX;
 
@@ -30,6 +35,10 @@ pub const HVC_SYS_REBOOT: usize = 0;
 pub const HVC_SYS_SHUTDOWN: usize = 1;
 pub const HVC_SYS_UPDATE: usize = 3;
 pub const HVC_SYS_TEST: usize = 4;
+pub const HVC_SYS_SET_SCHED: usize = 5;
+pub const HVC_SYS_SET_SCHED_SLICE: usize = 6;
+pub const HVC_SYS_SCHED_MISS_NOTIFY: usize = 7;
+pub const HVC_SYS_SET_LOG_LEVEL: usize = 8;
 
 // hvc_vmm_event
 pub const HVC_VMM_LIST_VM: usize = 0;
@@ -52,6 +61,20 @@ pub const HVC_VMM_MIGRATE_FINISH: usize = 13;
 pub const HVC_VMM_MIGRATE_INIT_VM: usize = 14;
 pub const HVC_VMM_MIGRATE_VM_BOOT: usize = 15;
 pub const HVC_VMM_VM_REMOVE: usize = 16;
+// dirty page logging for snapshot and checkpoint
+pub const HVC_VMM_DIRTY_LOG_START: usize = 17;
+pub const HVC_VMM_DIRTY_LOG_FETCH: usize = 18;
+pub const HVC_VMM_DIRTY_LOG_STOP: usize = 19;
+pub const HVC_VMM_GET_VCPU_STAT: usize = 20;
+// notification to MVM, a real-time vcpu of the VM missed its deadline
+pub const HVC_VMM_DEADLINE_MISS: usize = 21;
+pub const HVC_VMM_GET_NET_STAT: usize = 22;
+pub const HVC_VMM_NET_CAPTURE: usize = 23;
+pub const HVC_VMM_SET_IRQ_AFFINITY: usize = 24;
+pub const HVC_VMM_GET_IRQ_LATENCY: usize = 25;
+pub const HVC_VMM_RESET_IRQ_LATENCY: usize = 26;
+// for sender: stop the migration and resume the VM
+pub const HVC_VMM_MIGRATE_CANCEL: usize = 27;
 
 // hvc_ivc_event
 pub const HVC_IVC_UPDATE_MQ: usize = 0;
@@ -99,6 +122,11 @@ pub const HVC_CONFIG_PASSTHROUGH_DEVICE_STREAMS_IDS: usize = 7;
 pub const HVC_CONFIG_DTB_DEVICE: usize = 8;
 pub const HVC_CONFIG_UPLOAD_KERNEL_IMAGE: usize = 9;
 pub const HVC_CONFIG_MEMORY_COLOR_BUDGET: usize = 10;
+pub const HVC_CONFIG_CPU_RESERVATION: usize = 11;
+pub const HVC_CONFIG_CPU_PRIORITY: usize = 12;
+pub const HVC_CONFIG_NET_ACL: usize = 13;
+pub const HVC_CONFIG_PASSTHROUGH_IRQ_AFFINITY: usize = 14;
+pub const HVC_CONFIG_PASSTHROUGH_DEVICE_MSI_IDS: usize = 15;
 
 #[cfg(feature = "tx2")]
 pub const HVC_IRQ: usize = 32 + 0x20;
@@ -165,8 +193,8 @@ pub fn hvc_guest_handler(
     x6: usize,
 ) -> Result<usize, ()> {
     match hvc_type {
-        HVC_SYS => hvc_sys_handler(event, x0),
-        HVC_VMM => hvc_vmm_handler(event, x0, x1),
+        HVC_SYS => hvc_sys_handler(event, x0, x1),
+        HVC_VMM => hvc_vmm_handler(event, x0, x1, x2),
         HVC_IVC => hvc_ivc_handler(event, x0, x1),
         HVC_MEDIATED => hvc_mediated_handler(event, x0, x1),
         HVC_CONFIG => hvc_config_handler(event, x0, x1, x2, x3, x4, x5, x6),
@@ -202,6 +230,11 @@ fn hvc_config_handler(
         HVC_CONFIG_DTB_DEVICE => config::add_dtb_dev(x0, x1, x2, x3, x4, x5, x6),
         HVC_CONFIG_UPLOAD_KERNEL_IMAGE => config::upload_kernel_image(x0, x1, x2, x3, x4),
         HVC_CONFIG_MEMORY_COLOR_BUDGET => config::set_memory_color_budget(x0, x1, x2, x3),
+        HVC_CONFIG_CPU_RESERVATION => config::set_cpu_reservation(x0, x1, x2),
+        HVC_CONFIG_CPU_PRIORITY => config::set_cpu_priority(x0, x1),
+        HVC_CONFIG_NET_ACL => crate::device::virtio_net_set_acl(x0, x1, x2),
+        HVC_CONFIG_PASSTHROUGH_IRQ_AFFINITY => config::set_passthrough_irq_affinity(x0, x1, x2),
+        HVC_CONFIG_PASSTHROUGH_DEVICE_MSI_IDS => config::add_passthrough_device_msi_ids(x0, x1, x2),
         _ => {
             println!("hvc_config_handler unknown event {}", event);
             Err(())
@@ -209,7 +242,7 @@ fn hvc_config_handler(
     }
 }
 
-fn hvc_sys_handler(event: usize, _x0: usize) -> Result<usize, ()> {
+fn hvc_sys_handler(event: usize, x0: usize, x1: usize) -> Result<usize, ()> {
     match event {
         HVC_SYS_UPDATE => {
             todo!()
@@ -219,25 +252,23 @@ fn hvc_sys_handler(event: usize, _x0: usize) -> Result<usize, ()> {
             crate::device::virtio_net_announce(vm);
             Ok(0)
         }
+        HVC_SYS_SET_SCHED => super::sched::sched_set_rule(x0, x1),
+        HVC_SYS_SET_SCHED_SLICE => super::sched::sched_set_slice(x0, x1),
+        HVC_SYS_SCHED_MISS_NOTIFY => super::sched::sched_set_miss_notify(x0),
+        HVC_SYS_SET_LOG_LEVEL => crate::util::logger::log_set_level(x0, x1),
         _ => Err(()),
     }
 }
 
-fn hvc_vmm_handler(event: usize, x0: usize, _x1: usize) -> Result<usize, ()> {
+fn hvc_vmm_handler(event: usize, x0: usize, x1: usize, x2: usize) -> Result<usize, ()> {
     match event {
         HVC_VMM_LIST_VM => vmm_list_vm(x0),
-        HVC_VMM_GET_VM_STATE => {
-            error!("unimplemented");
-            Err(())
-        }
+        HVC_VMM_GET_VM_STATE => vmm_get_vm_state(x0, x1),
         HVC_VMM_BOOT_VM => {
             vmm_boot_vm(x0);
             Ok(HVC_FINISH)
         }
-        HVC_VMM_SHUTDOWN_VM => {
-            error!("unimplemented");
-            Err(())
-        }
+        HVC_VMM_SHUTDOWN_VM => vmm_shutdown_vm(x0, x1),
         HVC_VMM_REBOOT_VM => {
             vmm_reboot_vm(x0);
             Ok(HVC_FINISH)
@@ -246,19 +277,28 @@ fn hvc_vmm_handler(event: usize, x0: usize, _x1: usize) -> Result<usize, ()> {
             get_vm_id(x0);
             Ok(HVC_FINISH)
         }
-        HVC_VMM_MIGRATE_START
-        | HVC_VMM_MIGRATE_READY
-        | HVC_VMM_MIGRATE_MEMCPY
-        | HVC_VMM_MIGRATE_INIT_VM
-        | HVC_VMM_MIGRATE_VM_BOOT
-        | HVC_VMM_MIGRATE_FINISH => {
-            error!("unimplemented");
-            Ok(HVC_FINISH)
-        }
+        HVC_VMM_MIGRATE_START => vmm_migrate_start(x0),
+        HVC_VMM_MIGRATE_READY => vmm_migrate_ready(x0, x1),
+        HVC_VMM_MIGRATE_MEMCPY => vmm_migrate_memcpy(x0, x1, x2),
+        HVC_VMM_MIGRATE_FINISH => vmm_migrate_finish(x0, x1, x2),
+        HVC_VMM_MIGRATE_INIT_VM => vmm_migrate_init_vm(x0, x1),
+        HVC_VMM_MIGRATE_VM_BOOT => vmm_migrate_vm_boot(x0, x1, x2),
+        HVC_VMM_MIGRATE_CANCEL => vmm_migrate_cancel(x0),
         HVC_VMM_VM_REMOVE => {
             vmm_remove_vm(x0);
             Ok(HVC_FINISH)
         }
+        HVC_VMM_DIRTY_LOG_START => vmm_dirty_log_start(x0),
+        HVC_VMM_DIRTY_LOG_FETCH => vmm_dirty_log_fetch(x0, x1, x2),
+        HVC_VMM_DIRTY_LOG_STOP => vmm_dirty_log_stop(x0),
+        HVC_VMM_GET_VCPU_STAT => vmm_get_vcpu_stat(x0, x1),
+        HVC_VMM_GET_NET_STAT => crate::device::virtio_net_get_stat(x0, x1),
+        HVC_VMM_NET_CAPTURE => crate::device::virtio_net_capture(x0, x1, x2),
+        HVC_VMM_SET_IRQ_AFFINITY => vmm_set_irq_affinity(x0, x1, x2),
+        #[cfg(feature = "irq-latency")]
+        HVC_VMM_GET_IRQ_LATENCY => crate::kernel::irq_latency_get_stat(x0, x1),
+        #[cfg(feature = "irq-latency")]
+        HVC_VMM_RESET_IRQ_LATENCY => crate::kernel::irq_latency_reset(x0),
         _ => {
             println!("hvc_vmm unknown event {}", event);
             Err(())
@@ -379,6 +419,9 @@ pub fn hvc_send_msg_to_vm(vm_id: usize, guest_msg: &HvcGuestMsg) -> bool {
             (msg.fid, msg.event)
         }
     };
+    if let Some(vm) = vm_by_id(vm_id) {
+        vm.mark_dirty(vm.hva2ipa(target_addr), PAGE_SIZE / VM_NUM_MAX);
+    }
 
     let cpu_trgt = vm_if_get_cpu_id(vm_id).unwrap();
     if cpu_trgt != current_cpu().id {
@@ -441,11 +484,17 @@ pub fn hvc_ipi_handler(msg: IpiMessage) {
                         // in mvm
                         hvc_guest_notify(msg.trgt_vmid);
                     }
+                    HVC_VMM_SHUTDOWN_VM => {
+                        // in gvm, ask the guest to power off
+                        hvc_guest_notify(msg.trgt_vmid);
+                    }
                     HVC_VMM_MIGRATE_FINISH => {
-                        error!("unimplemented");
+                        // in sender vm, pause the vcpu
+                        vmm_migrate_pause_percore(msg.trgt_vmid);
                     }
                     HVC_VMM_MIGRATE_VM_BOOT => {
-                        error!("unimplemented");
+                        // in receiver vm, wake up the vcpu
+                        vmm_migrate_boot_percore(msg.trgt_vmid);
                     }
                     _ => {}
                 },
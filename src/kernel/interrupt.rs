@@ -23,6 +23,25 @@ pub fn interrupt_reserve_int(int_id: usize, handler: fn()) {
     }
 }
 
+/* Reserve an interrupt for the hypervisor unless it is already taken by the hypervisor or a VM.
+ *
+ * @param[in] int_id: the interrupt to reserve.
+ * @param[in] handler: handler of the interrupt.
+ * @return: false if the interrupt is taken.
+ */
+pub fn interrupt_try_reserve_int(int_id: usize, handler: fn()) -> bool {
+    if int_id >= INTERRUPT_NUM_MAX {
+        return false;
+    }
+    let mut glb_bitmap_lock = INTERRUPT_GLB_BITMAP.lock();
+    if glb_bitmap_lock.get(int_id) != 0 {
+        return false;
+    }
+    INTERRUPT_HANDLERS.lock().insert(int_id, handler);
+    glb_bitmap_lock.set(int_id);
+    true
+}
+
 pub fn interrupt_cpu_enable(int_id: usize, en: bool) {
     use crate::arch::interrupt_arch_enable;
     interrupt_arch_enable(int_id, en);
@@ -82,6 +101,16 @@ pub fn interrupt_handler(int_id: usize) -> bool {
         return true;
     }
 
+    // the LPIs are the MSIs of the passthrough PCIe devices, translated by the physical ITS
+    #[cfg(feature = "gicv3")]
+    if int_id >= crate::arch::GIC_LPI_BASE {
+        crate::arch::vgic_its_lpi_handler(int_id);
+        return true;
+    }
+
+    #[cfg(feature = "irq-latency")]
+    crate::kernel::irq_latency_receive(int_id);
+
     if (16..GIC_PRIVINT_NUM).contains(&int_id) {
         if let Some(vcpu) = &current_cpu().active_vcpu {
             if let Some(active_vm) = vcpu.vm() {
@@ -5,7 +5,7 @@ use spin::Mutex;
 
 use crate::arch::INTERRUPT_IRQ_IPI;
 use crate::board::static_config;
-use crate::board::PLAT_DESC;
+use crate::board::{SchedRule, PLAT_DESC};
 use crate::device::{VirtioMmio, Virtq};
 use crate::kernel::{current_cpu, interrupt_cpu_ipi_send};
 use crate::kernel::{interrupt_reserve_int, interrupt_vm_inject};
@@ -61,6 +61,7 @@ pub struct IpiPowerMessage {
 #[derive(Clone)]
 pub struct IpiEthernetMsg {
     pub trgt_nic: Arc<VirtioMmio>,
+    pub rx_vq: usize,
 }
 
 #[derive(Clone)]
@@ -102,6 +103,11 @@ pub struct IpiIntInjectMsg {
     pub int_id: usize,
 }
 
+#[derive(Clone)]
+pub struct IpiSchedMsg {
+    pub rule: SchedRule,
+}
+
 declare_enum_with_handler! {
     #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     #[repr(usize)]
@@ -113,6 +119,7 @@ declare_enum_with_handler! {
         Vmm => crate::vmm::vmm_ipi_handler,
         MediatedDev => crate::device::mediated_ipi_handler,
         IntInject => interrupt_inject_ipi_handler,
+        Sched => super::sched::sched_ipi_handler,
     }
 }
 
@@ -136,6 +143,8 @@ pub enum IpiInnerMsg {
     HvcMsg(IpiHvcMsg),
     // IpiTIntInject
     IntInjectMsg(IpiIntInjectMsg),
+    // IpiTSched
+    SchedMsg(IpiSchedMsg),
 }
 
 pub struct IpiMessage {
@@ -0,0 +1,159 @@
+use alloc::collections::BTreeMap;
+
+use spin::Mutex;
+
+use crate::arch::GIC_PRIVINT_NUM;
+use crate::board::static_config::CORE_NUM;
+use crate::kernel::{active_vm, current_cpu, timer, vm_by_id, Vcpu};
+
+// Latency of the interrupts of the VMs, from the physical IRQ arriving at the hypervisor to its injection into
+// the vgic, and from the injection to the guest completing it. The completion is only seen when it traps, i.e.
+// for virtual interrupts and for passthrough interrupts spilled out of the list registers. The EOI of a
+// passthrough interrupt held in a list register deactivates the physical IRQ directly.
+
+// bucket 0 counts the latencies below 1us, bucket i those in [2^(i-1), 2^i) us, the last one all the rest
+const IRQ_LATENCY_BUCKETS: usize = 16;
+// the most irqs of a VM reported to MVM
+const IRQ_LATENCY_STAT_MAX: usize = 32;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct IrqLatencyHist {
+    count: u64,
+    total_ns: u64,
+    max_ns: u64,
+    buckets: [u64; IRQ_LATENCY_BUCKETS],
+}
+
+impl IrqLatencyHist {
+    fn record(&mut self, ns: u64) {
+        let us = ns / 1000;
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        self.count += 1;
+        self.total_ns += ns;
+        self.max_ns = self.max_ns.max(ns);
+        self.buckets[bucket.min(IRQ_LATENCY_BUCKETS - 1)] += 1;
+    }
+}
+
+#[derive(Default)]
+struct IrqTrace {
+    // counter value of the last injection, for a private interrupt on each vcpu
+    injected: [usize; CORE_NUM],
+    inject: IrqLatencyHist,
+    eoi: IrqLatencyHist,
+}
+
+// counter value of the IRQs taken by each core, not yet injected
+static IRQ_RECEIVED: [Mutex<BTreeMap<usize, usize>>; CORE_NUM] = [const { Mutex::new(BTreeMap::new()) }; CORE_NUM];
+// traces of the interrupts of the VMs, by vm id and interrupt id
+static IRQ_TRACE: Mutex<BTreeMap<(usize, usize), IrqTrace>> = Mutex::new(BTreeMap::new());
+
+fn counter_to_ns(ticks: usize) -> u64 {
+    (ticks as u128 * 1_000_000_000 / crate::arch::timer::timer_arch_get_frequency() as u128) as u64
+}
+
+fn vcpu_slot(vcpu: &Vcpu, int_id: usize) -> usize {
+    if int_id < GIC_PRIVINT_NUM {
+        vcpu.id()
+    } else {
+        0
+    }
+}
+
+pub fn irq_latency_receive(int_id: usize) {
+    IRQ_RECEIVED[current_cpu().id]
+        .lock()
+        .insert(int_id, timer::get_counter());
+}
+
+/* Record the injection of an interrupt into the vgic of a vcpu.
+ *
+ * @param[in] vcpu: the vcpu the interrupt is injected to.
+ * @param[in] int_id: the interrupt id.
+ * @param[in] hw: whether the interrupt is a passthrough one, which is received on the current core first.
+ */
+pub fn irq_latency_inject(vcpu: &Vcpu, int_id: usize, hw: bool) {
+    let now = timer::get_counter();
+    let received = if hw {
+        IRQ_RECEIVED[current_cpu().id].lock().remove(&int_id)
+    } else {
+        None
+    };
+    let mut trace_lock = IRQ_TRACE.lock();
+    let trace = trace_lock.entry((vcpu.vm_id(), int_id)).or_default();
+    if let Some(received) = received {
+        trace.inject.record(counter_to_ns(now.saturating_sub(received)));
+    }
+    trace.injected[vcpu_slot(vcpu, int_id)] = now;
+}
+
+/* Record the completion of an interrupt by the guest.
+ *
+ * @param[in] vcpu: the vcpu which completes the interrupt.
+ * @param[in] int_id: the interrupt id.
+ */
+pub fn irq_latency_eoi(vcpu: &Vcpu, int_id: usize) {
+    let now = timer::get_counter();
+    let mut trace_lock = IRQ_TRACE.lock();
+    if let Some(trace) = trace_lock.get_mut(&(vcpu.vm_id(), int_id)) {
+        let injected = core::mem::take(&mut trace.injected[vcpu_slot(vcpu, int_id)]);
+        if injected != 0 {
+            trace.eoi.record(counter_to_ns(now.saturating_sub(injected)));
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IrqLatencyStat {
+    int_id: u32,
+    inject: IrqLatencyHist,
+    eoi: IrqLatencyHist,
+}
+
+#[repr(C)]
+struct VmIrqLatencyInfo {
+    irq_num: u32,
+    irq_stat: [IrqLatencyStat; IRQ_LATENCY_STAT_MAX],
+}
+
+/* Get the latency histograms of each traced interrupt of a VM.
+ *
+ * @param[in] vm_id : target VM id.
+ * @param[in] stat_ipa : ipa of the VmIrqLatencyInfo buffer in MVM.
+ */
+pub fn irq_latency_get_stat(vm_id: usize, stat_ipa: usize) -> Result<usize, ()> {
+    if vm_by_id(vm_id).is_none() {
+        error!("irq_latency_get_stat: VM [{}] does not exist", vm_id);
+        return Err(());
+    }
+    let stat_pa = active_vm().unwrap().ipa2hva(stat_ipa);
+    if stat_pa == 0 {
+        error!("illegal stat_ipa {:x}", stat_ipa);
+        return Err(());
+    }
+    let stat_info = unsafe { &mut *(stat_pa as *mut VmIrqLatencyInfo) };
+    let trace_lock = IRQ_TRACE.lock();
+    let traces = trace_lock.range((vm_id, 0)..(vm_id + 1, 0));
+    let mut irq_num = 0;
+    for (dst, (&(_, int_id), trace)) in stat_info.irq_stat.iter_mut().zip(traces) {
+        *dst = IrqLatencyStat {
+            int_id: int_id as u32,
+            inject: trace.inject,
+            eoi: trace.eoi,
+        };
+        irq_num += 1;
+    }
+    stat_info.irq_num = irq_num;
+    Ok(0)
+}
+
+/* Clear the latency histograms of a VM, also when the VM is removed.
+ *
+ * @param[in] vm_id : target VM id.
+ */
+pub fn irq_latency_reset(vm_id: usize) -> Result<usize, ()> {
+    IRQ_TRACE.lock().retain(|&(id, _), _| id != vm_id);
+    Ok(0)
+}
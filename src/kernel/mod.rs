@@ -4,8 +4,12 @@ pub use self::hvc::*;
 pub use self::interrupt::*;
 pub use self::iommu::*;
 pub use self::ipi::*;
+#[cfg(feature = "irq-latency")]
+pub use self::irq_latency::*;
 pub use self::ivc::*;
 pub use self::mem::*;
+#[cfg(feature = "shell")]
+pub use self::shell::{shell_init, shell_input};
 pub use self::timer::timer_init;
 pub use self::vcpu::*;
 pub use self::vm::*;
@@ -21,9 +25,13 @@ mod interrupt;
 mod iommu;
 #[allow(dead_code)]
 mod ipi;
+#[cfg(feature = "irq-latency")]
+mod irq_latency;
 mod ivc;
 mod mem;
 mod sched;
+#[cfg(feature = "shell")]
+mod shell;
 pub mod timer;
 mod vcpu;
 mod vcpu_array;
@@ -32,4 +40,6 @@ mod vm;
 pub fn subinit() {
     #[cfg(feature = "memory-reservation")]
     bwres::init();
+    #[cfg(feature = "gicv3")]
+    crate::arch::its_init();
 }
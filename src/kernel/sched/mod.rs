@@ -1,12 +1,15 @@
+#[cfg(feature = "rt-sched")]
+mod sched_fp;
 mod sched_rr;
 #[cfg(feature = "rt-sched")]
 mod sched_rt;
 
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use crate::board::SchedRule;
+use crate::board::{static_config, SchedRule, PLAT_DESC};
 
-use super::Vcpu;
+use super::{current_cpu, ipi_send_msg, IpiInnerMsg, IpiMessage, IpiSchedMsg, IpiType, Vcpu};
 
 pub trait Scheduler {
     type SchedItem;
@@ -20,13 +23,110 @@ pub trait Scheduler {
     fn remove(&mut self, item: &Self::SchedItem);
     /* put a new item into the scheduler */
     fn put(&mut self, item: Self::SchedItem);
+    /* whether the queued items should preempt the current running item */
+    fn preempt(&self, _current: &Self::SchedItem) -> bool {
+        false
+    }
+}
+
+// round robin time slice of each core in milliseconds
+static SCHED_SLICE_LIST: [AtomicUsize; static_config::CORE_NUM] =
+    [const { AtomicUsize::new(0) }; static_config::CORE_NUM];
+
+// time slice of the current core
+fn sched_slice() -> usize {
+    SCHED_SLICE_LIST[current_cpu().id].load(Ordering::Relaxed)
+}
+
+/* Set the round robin time slice of a physical core, it takes effect from the next slice.
+ *
+ * @param[in] cpu_id: target physical core.
+ * @param[in] slice: time slice in milliseconds, 0 for switching at every timer tick.
+ */
+pub fn sched_set_slice(cpu_id: usize, slice: usize) -> Result<usize, ()> {
+    match SCHED_SLICE_LIST.get(cpu_id) {
+        Some(cpu_slice) if cpu_id < PLAT_DESC.cpu_desc.num => {
+            cpu_slice.store(slice, Ordering::Relaxed);
+            info!("core {} set sched slice {}ms", cpu_id, slice);
+            Ok(0)
+        }
+        _ => {
+            warn!("sched_set_slice: core {} not exist", cpu_id);
+            Err(())
+        }
+    }
+}
+
+// whether to notify MVM of real-time deadline misses
+static SCHED_MISS_NOTIFY: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "rt-sched")]
+fn sched_miss_notify() -> bool {
+    SCHED_MISS_NOTIFY.load(Ordering::Relaxed)
+}
+
+/* Enable or disable the deadline miss notification to MVM.
+ *
+ * @param[in] enable: 0 to disable, otherwise enable.
+ */
+pub fn sched_set_miss_notify(enable: usize) -> Result<usize, ()> {
+    SCHED_MISS_NOTIFY.store(enable != 0, Ordering::Relaxed);
+    info!("sched deadline miss notify {}", enable != 0);
+    if !cfg!(feature = "rt-sched") {
+        warn!("deadline miss notify only takes effect with feature \"rt-sched\"");
+    }
+    Ok(0)
 }
 
 // factory mode
 pub fn get_scheduler(rule: SchedRule) -> Box<dyn Scheduler<SchedItem = Vcpu>> {
     match rule {
-        SchedRule::RoundRobin => Box::new(sched_rr::SchedulerRR::new(1)),
+        SchedRule::RoundRobin => Box::new(sched_rr::SchedulerRR::new()),
+        #[cfg(feature = "rt-sched")]
+        SchedRule::RealTime => sched_rt::SchedulerRT::new(),
         #[cfg(feature = "rt-sched")]
-        SchedRule::RealTime => Box::new(sched_rt::SchedulerRT::new()),
+        SchedRule::FixedPriority => Box::new(sched_fp::SchedulerFP::new()),
+    }
+}
+
+/* Replace the scheduler of the current core, the queued vcpus are moved into the new one.
+ *
+ * @param[in] rule: scheduling rule of the new scheduler.
+ */
+fn sched_switch_percore(rule: SchedRule) {
+    let mut scheduler = get_scheduler(rule);
+    info!("core {} switch to {} scheduler", current_cpu().id, scheduler.name());
+    scheduler.init();
+    current_cpu().vcpu_array.switch_scheduler(scheduler);
+}
+
+/* Switch the scheduler of a physical core at runtime.
+ *
+ * @param[in] cpu_id: target physical core.
+ * @param[in] rule_id: 0 for round robin, 1 for real-time, 2 for fixed-priority (rt-sched only).
+ */
+pub fn sched_set_rule(cpu_id: usize, rule_id: usize) -> Result<usize, ()> {
+    if cpu_id >= PLAT_DESC.cpu_desc.num {
+        warn!("sched_set_rule: core {} not exist", cpu_id);
+        return Err(());
+    }
+    let rule = SchedRule::try_from(rule_id).map_err(|_| {
+        warn!("sched_set_rule: illegal sched rule {}", rule_id);
+    })?;
+    if cpu_id == current_cpu().id {
+        sched_switch_percore(rule);
+    } else if !ipi_send_msg(cpu_id, IpiType::Sched, IpiInnerMsg::SchedMsg(IpiSchedMsg { rule })) {
+        error!("sched_set_rule: failed to send ipi to core {}", cpu_id);
+        return Err(());
+    }
+    Ok(0)
+}
+
+pub fn sched_ipi_handler(msg: IpiMessage) {
+    match msg.ipi_message {
+        IpiInnerMsg::SchedMsg(sched_msg) => sched_switch_percore(sched_msg.rule),
+        _ => {
+            error!("sched_ipi_handler: illegal ipi type");
+        }
     }
 }
@@ -0,0 +1,85 @@
+use alloc::collections::VecDeque;
+
+use crate::kernel::{Vcpu, VcpuState};
+
+use super::Scheduler;
+
+/*
+ * Static-priority preemptive scheduler, the priority of each vcpu comes from its VM config.
+ * Items with the same priority are scheduled in round robin.
+ */
+#[derive(Default)]
+pub struct SchedulerFP {
+    // sorted by priority in descending order, FIFO for the same priority
+    queue: VecDeque<(usize, Vcpu)>,
+    current: Option<(usize, Vcpu)>,
+}
+
+fn priority(item: &Vcpu) -> usize {
+    item.vm().map_or(0, |vm| vm.config().cpu_priority())
+}
+
+impl SchedulerFP {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn current_priority(&self, item: &Vcpu) -> usize {
+        match &self.current {
+            Some((prio, current)) if current == item => *prio,
+            _ => priority(item),
+        }
+    }
+}
+
+impl Scheduler for SchedulerFP {
+    type SchedItem = Vcpu;
+
+    fn name(&self) -> &'static str {
+        "Fixed Priority"
+    }
+
+    fn init(&mut self) {}
+
+    fn next(&mut self) -> Option<Self::SchedItem> {
+        let (top, _) = self.queue.front()?;
+        // keep running the current item unless an item with no lower priority is queued
+        if let Some((prio, current)) = &self.current {
+            if current.state() == VcpuState::Running && prio > top {
+                return None;
+            }
+        }
+        let (prio, item) = self.queue.pop_front()?;
+        self.current = Some((prio, item.clone()));
+        Some(item)
+    }
+
+    fn remove(&mut self, item: &Self::SchedItem) {
+        self.queue.retain(|(_, x)| x != item);
+        if matches!(&self.current, Some((_, current)) if current == item) {
+            self.current = None;
+        }
+    }
+
+    fn put(&mut self, item: Self::SchedItem) {
+        let prio = priority(&item);
+        // the running item is put back when it is switched out
+        if item.state() == VcpuState::Running {
+            self.current = Some((prio, item));
+            return;
+        }
+        let idx = self
+            .queue
+            .iter()
+            .position(|(p, _)| *p < prio)
+            .unwrap_or(self.queue.len());
+        self.queue.insert(idx, (prio, item));
+    }
+
+    fn preempt(&self, current: &Self::SchedItem) -> bool {
+        match self.queue.front() {
+            Some((top, _)) => *top > self.current_priority(current),
+            None => false,
+        }
+    }
+}
@@ -1,21 +1,61 @@
-use crate::kernel::Vcpu;
 use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use super::Scheduler;
+use crate::kernel::timer::{remove_timer_event, start_timer_event};
+use crate::kernel::{Vcpu, VcpuState};
+use crate::util::timer_list::{TimerEvent, TimerValue};
+
+use super::{sched_slice, Scheduler};
+
+// expiry flag of the time slice, set by the timer
+#[derive(Default)]
+struct SliceTimerEvent(AtomicBool);
+
+impl TimerEvent for SliceTimerEvent {
+    fn callback(self: Arc<Self>, _now: TimerValue) {
+        // the timer irq handler does the rescheduling after all events
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
 
 #[derive(Default)]
 pub struct SchedulerRR {
     queue: VecDeque<Vcpu>,
-    #[allow(unused)]
-    base_slice: usize,
+    current: Option<Vcpu>,
+    slice_event: Arc<SliceTimerEvent>,
 }
 
 impl SchedulerRR {
-    pub fn new(slice: usize) -> Self {
-        Self {
-            queue: VecDeque::new(),
-            base_slice: slice,
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn remove_slice_timer(&self) {
+        remove_timer_event(|event| {
+            if let Some(event) = event.as_any().downcast_ref::<SliceTimerEvent>() {
+                core::ptr::eq(event, Arc::as_ptr(&self.slice_event))
+            } else {
+                false
+            }
+        });
+    }
+
+    // start a new time slice for the dispatched item, 0 slice for switching at every tick
+    fn start_slice(&mut self, item: Vcpu) {
+        self.remove_slice_timer();
+        let slice = sched_slice();
+        self.slice_event.0.store(slice == 0, Ordering::Relaxed);
+        if slice != 0 {
+            start_timer_event(TimerValue::from_millis(slice as u64), self.slice_event.clone());
         }
+        self.current = Some(item);
+    }
+}
+
+impl Drop for SchedulerRR {
+    fn drop(&mut self) {
+        self.remove_slice_timer();
     }
 }
 
@@ -29,16 +69,32 @@ impl Scheduler for SchedulerRR {
     fn init(&mut self) {}
 
     fn next(&mut self) -> Option<Self::SchedItem> {
-        self.queue.pop_front()
+        // keep running the current item until its slice expires
+        if let Some(current) = &self.current {
+            if current.state() == VcpuState::Running && !self.slice_event.0.load(Ordering::Relaxed) {
+                return None;
+            }
+        }
+        let item = self.queue.pop_front()?;
+        self.start_slice(item.clone());
+        Some(item)
     }
 
     fn remove(&mut self, item: &Self::SchedItem) {
         if let Some(idx) = self.queue.iter().position(|x| x.eq(item)) {
             self.queue.remove(idx);
         }
+        if self.current.as_ref() == Some(item) {
+            self.current = None;
+        }
     }
 
     fn put(&mut self, item: Self::SchedItem) {
-        self.queue.push_back(item);
+        // the running item is put back when it is switched out
+        if item.state() != VcpuState::Running {
+            self.queue.push_back(item);
+        } else {
+            self.start_slice(item);
+        }
     }
 }
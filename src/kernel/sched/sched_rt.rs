@@ -9,15 +9,18 @@ use alloc::{
 };
 use core::{cell::Cell, ptr::NonNull};
 
+use alloc::vec::Vec;
+
 use crate::{
     kernel::{
+        hvc_send_msg_to_vm,
         timer::{now, remove_timer_event, start_timer_event},
-        Vcpu, VcpuState,
+        HvcGuestMsg, HvcManageMsg, Vcpu, VcpuState, HVC_VMM, HVC_VMM_DEADLINE_MISS,
     },
     util::timer_list::{TimerEvent, TimerValue},
 };
 
-use super::Scheduler;
+use super::{sched_miss_notify, Scheduler};
 
 pub struct SchedulerRT {
     run_queue: BinaryHeap<Arc<SchedUnit>>,      /* ordered list of runnable units */
@@ -25,6 +28,8 @@ pub struct SchedulerRT {
 
     replenishment_queue: BinaryHeap<Arc<SchedUnit>>, /* units that need replenishment */
 
+    units: Vec<Arc<SchedUnit>>, /* units of all items, keep the deadline across put and remove */
+
     self_ref: SchedulerRTRef,
 }
 
@@ -68,10 +73,18 @@ unsafe impl Send for SchedUnit {}
 
 impl SchedUnit {
     fn new(item: SchedItemInner) -> Self {
+        // reservation from the VM config, 0 for default
+        let (budget, period) = match item.vm() {
+            Some(vm) if vm.config().cpu_period() != 0 => (
+                TimerValue::from_micros(vm.config().cpu_budget() as u64),
+                TimerValue::from_micros(vm.config().cpu_period() as u64),
+            ),
+            _ => (DEFAULT_BUDGET, DEFAULT_PERIOD),
+        };
         Self {
             item,
-            budget: DEFAULT_BUDGET,
-            period: DEFAULT_PERIOD,
+            budget,
+            period,
 
             priority: Cell::new(0),
             current_budget: Cell::new(TimerValue::ZERO),
@@ -82,19 +95,31 @@ impl SchedUnit {
 }
 
 impl SchedUnit {
-    fn update_deadline(&self, now: TimerValue) {
+    /*
+     * Move the deadline forward and replenish the budget.
+     * Return the number of missed deadlines: if the unit was runnable, the current
+     * deadline is missed when budget is left, and every skipped period is missed.
+     */
+    fn update_deadline(&self, now: TimerValue, runnable: bool) -> usize {
         debug_assert!(!self.period.is_zero());
         assert!(now >= self.current_deadline.get());
 
         let count = ((now.as_micros() - self.current_deadline.get().as_micros()) as usize
             / self.period.as_micros() as usize
             + 1) as u32;
+        // the first deadline is not a real one
+        let missed = if runnable && !self.current_deadline.get().is_zero() {
+            (count - 1) as usize + (self.current_budget.get() > TimerValue::ZERO) as usize
+        } else {
+            0
+        };
         self.current_deadline
             .set(self.current_deadline.get() + count * self.period);
 
         self.current_budget.set(self.budget);
         self.last_start.set(now);
         self.priority.set(0);
+        missed
     }
 }
 
@@ -114,17 +139,25 @@ impl Ord for SchedUnit {
 }
 
 impl SchedulerRT {
-    pub fn new() -> Self {
+    pub fn new() -> Box<Self> {
         // Use Box::new() to point object on heap, otherwise the data is
         // on stack and the raw pointer in SchedulerRTRef is not correct.
         let mut this = Box::new(Self {
             run_queue: Default::default(),
             depleted_queue: Default::default(),
             replenishment_queue: Default::default(),
+            units: Default::default(),
             self_ref: SchedulerRTRef(NonNull::dangling()),
         });
         this.self_ref = SchedulerRTRef(NonNull::new(&mut *this).unwrap());
-        *this
+        this
+    }
+}
+
+impl Drop for SchedulerRT {
+    fn drop(&mut self) {
+        // the replenishment timer holds a raw pointer to this scheduler
+        self.remove_timer();
     }
 }
 
@@ -150,19 +183,29 @@ impl Scheduler for SchedulerRT {
         self.run_queue.retain(|unit| &unit.item != item);
         self.depleted_queue.extract_if(|unit| &unit.item == item).for_each(drop);
         self.replenishment_queue_remove(item);
+        // the item is removed from this core
+        if item.state() == VcpuState::Inv {
+            self.units.retain(|unit| &unit.item != item);
+        }
     }
 
     fn put(&mut self, item: Self::SchedItem) {
         let item_state = item.state();
-        let unit = SchedUnit::new(item);
+        let unit = self.unit(item);
+        // the unit is still active if it is put back after running
+        let active = self.replenishment_queue.iter().any(|x| Arc::ptr_eq(x, &unit));
 
         let now = now();
         if now >= unit.current_deadline.get() {
-            unit.update_deadline(now);
+            if active {
+                self.replenishment_queue_remove(&unit.item);
+            }
+            let missed = unit.update_deadline(now, active);
+            self.deadline_miss(&unit, missed);
+            self.replenishment_queue_insert(unit.clone());
+        } else if !active {
+            self.replenishment_queue_insert(unit.clone());
         }
-
-        let unit = Arc::new(unit);
-        self.replenishment_queue_insert(unit.clone());
         if item_state != VcpuState::Running {
             self.run_queue_push(unit);
         }
@@ -170,6 +213,44 @@ impl Scheduler for SchedulerRT {
 }
 
 impl SchedulerRT {
+    fn unit(&mut self, item: SchedItemInner) -> Arc<SchedUnit> {
+        match self.units.iter().find(|unit| unit.item == item) {
+            Some(unit) => unit.clone(),
+            None => {
+                let unit = Arc::new(SchedUnit::new(item));
+                self.units.push(unit.clone());
+                unit
+            }
+        }
+    }
+
+    fn deadline_miss(&self, unit: &SchedUnit, missed: usize) {
+        if missed == 0 {
+            return;
+        }
+        let item = &unit.item;
+        warn!(
+            "VM {} vcpu {} missed {} deadline(s), now deadline {:?}",
+            item.vm_id(),
+            item.id(),
+            missed,
+            unit.current_deadline.get()
+        );
+        for _ in 0..missed {
+            item.stat_deadline_miss();
+        }
+        if sched_miss_notify() {
+            let msg = HvcManageMsg {
+                fid: HVC_VMM,
+                event: HVC_VMM_DEADLINE_MISS,
+                vm_id: item.vm_id(),
+            };
+            if !hvc_send_msg_to_vm(0, &HvcGuestMsg::Manage(msg)) {
+                error!("deadline_miss: failed to notify VM 0");
+            }
+        }
+    }
+
     fn remove_timer(&self) {
         remove_timer_event(|event| {
             if let Some(event) = event.as_any().downcast_ref::<SchedulerRTRef>() {
@@ -246,13 +327,16 @@ impl SchedulerRT {
          */
         while let Some(unit) = self.replenishment_queue.pop() {
             if now < unit.current_deadline.get() {
+                self.replenishment_queue.push(unit);
                 break;
             }
-            unit.update_deadline(now);
-            self.queue_remove(&unit);
+            let on_queue = self.on_queue(&unit);
+            let runnable = on_queue || unit.item.state() == VcpuState::Running;
+            let missed = unit.update_deadline(now, runnable);
+            self.deadline_miss(&unit, missed);
             tmp_queue.push_back(unit.clone());
 
-            if self.on_queue(&unit) {
+            if on_queue {
                 self.queue_remove(&unit);
                 self.run_queue_push(unit);
             }
@@ -0,0 +1,158 @@
+use alloc::format;
+use alloc::string::String;
+
+use spin::Mutex;
+
+use crate::board::PLAT_DESC;
+use crate::util::logger::{log_set_spec, log_spec};
+
+use super::{cpu_by_id, vm_by_id, vm_if_get_state, vm_list_walker};
+
+// A line based debug shell on the hypervisor UART, it has the input while holding the console focus.
+// It only reads the hypervisor data structures, so it still works when MVM is hung.
+
+const SHELL_PROMPT: &str = "shyper> ";
+const SHELL_LINE_MAX: usize = 128;
+
+static SHELL_LINE: Mutex<String> = Mutex::new(String::new());
+
+const SHELL_HELP: &str = "\
+help                 show this message
+vm                   list VMs
+vcpu [vm_id]         show vcpu states
+sched                show the scheduler and the vcpus of each core
+pt <vm_id> <ipa>     dump the stage-2 mapping of an ipa
+log [spec]           show or set the log level, e.g. info,vgic=debug (off/error/warn/info/debug/trace)";
+
+fn shell_vm() {
+    println!("{:<4}{:<16}{:<10}{:<6}", "ID", "NAME", "STATE", "VCPUS");
+    vm_list_walker(|vm| {
+        println!(
+            "{:<4}{:<16}{:<10}{:<6}",
+            vm.id(),
+            vm.config().name,
+            format!("{:?}", vm_if_get_state(vm.id())),
+            vm.cpu_num()
+        );
+    });
+}
+
+fn shell_vcpu(vm_id: Option<usize>) {
+    println!(
+        "{:<4}{:<6}{:<6}{:<10}{:>14}{:>10}{:>10}",
+        "VM", "VCPU", "CORE", "STATE", "RUNTIME(us)", "SWITCHES", "WAKEUPS"
+    );
+    vm_list_walker(|vm| {
+        if vm_id.is_some_and(|id| id != vm.id()) {
+            return;
+        }
+        for vcpu in vm.vcpu_list() {
+            let stat = vcpu.sched_stat();
+            println!(
+                "{:<4}{:<6}{:<6}{:<10}{:>14}{:>10}{:>10}",
+                vm.id(),
+                vcpu.id(),
+                vcpu.phys_id(),
+                format!("{:?}", vcpu.state()),
+                stat.runtime_us,
+                stat.switches,
+                stat.wakeups
+            );
+        }
+    });
+}
+
+fn shell_sched() {
+    for cpu_id in 0..PLAT_DESC.cpu_desc.num {
+        let Some(cpu) = cpu_by_id(cpu_id) else {
+            continue;
+        };
+        let sched = cpu.vcpu_array.sched.get().map_or("none", |sched| sched.name());
+        println!("core {} {:?} scheduler {}", cpu_id, cpu.cpu_state, sched);
+        vm_list_walker(|vm| {
+            for vcpu in vm.vcpu_list().iter().filter(|vcpu| vcpu.phys_id() == cpu_id) {
+                println!("    VM {} vcpu {} {:?}", vm.id(), vcpu.id(), vcpu.state());
+            }
+        });
+    }
+}
+
+fn shell_pt(vm_id: usize, ipa: usize) {
+    match vm_by_id(vm_id) {
+        Some(vm) => vm.show_pagetable(ipa),
+        None => println!("VM {} not exist", vm_id),
+    }
+}
+
+fn shell_log(spec: Option<&str>) {
+    if let Some(spec) = spec {
+        if log_set_spec(spec).is_err() {
+            println!("illegal log spec {}", spec);
+            return;
+        }
+    }
+    println!("log level {}", log_spec());
+}
+
+fn parse_usize(arg: &str) -> Option<usize> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+fn shell_exec(line: &str) {
+    let mut args = line.split_whitespace();
+    let Some(cmd) = args.next() else {
+        return;
+    };
+    match cmd {
+        "help" => println!("{}", SHELL_HELP),
+        "vm" => shell_vm(),
+        "vcpu" => shell_vcpu(args.next().and_then(parse_usize)),
+        "sched" => shell_sched(),
+        "pt" => match (args.next().and_then(parse_usize), args.next().and_then(parse_usize)) {
+            (Some(vm_id), Some(ipa)) => shell_pt(vm_id, ipa),
+            _ => println!("usage: pt <vm_id> <ipa>"),
+        },
+        "log" => shell_log(args.next()),
+        _ => println!("unknown command {}, try help", cmd),
+    }
+}
+
+/* Feed a byte received from the hypervisor UART to the shell.
+ *
+ * @param[in] byte: the received byte.
+ */
+pub fn shell_input(byte: u8) {
+    let mut line = SHELL_LINE.lock();
+    match byte {
+        b'\r' | b'\n' => {
+            println!();
+            let cmd = core::mem::take(&mut *line);
+            drop(line);
+            shell_exec(&cmd);
+            print!("{}", SHELL_PROMPT);
+        }
+        // backspace and delete
+        0x08 | 0x7f => {
+            if line.pop().is_some() {
+                print!("\x08 \x08");
+            }
+        }
+        0x20..=0x7e if line.len() < SHELL_LINE_MAX => {
+            line.push(byte as char);
+            print!("{}", byte as char);
+        }
+        _ => {}
+    }
+}
+
+/* Start the shell, must be called on core 0 after the VMs are initialized. The input comes from the hypervisor
+ * UART multiplexer, so the shell shares the UART with the guest consoles.
+ */
+pub fn shell_init() {
+    crate::device::console_input_init();
+    info!("hypervisor shell started, type help for commands");
+    print!("{}", SHELL_PROMPT);
+}
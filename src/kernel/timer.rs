@@ -4,6 +4,8 @@ use crate::arch::INTERRUPT_IRQ_HYPERVISOR_TIMER;
 use crate::kernel::current_cpu;
 use crate::util::timer_list::{TimerEvent, TimerValue};
 
+const TIMER_TICK_MS: usize = 10;
+
 pub fn timer_init() {
     crate::arch::timer::timer_arch_init();
     timer_enable(false);
@@ -55,7 +57,15 @@ pub fn timer_irq_handler() {
 
     current_cpu().vcpu_array.resched();
 
-    timer_notify_after(10);
+    // fire at the earliest timer event, at most one tick later
+    let ms = match current_cpu().timer_list.peek_timeout() {
+        Some(timeout) => timeout
+            .saturating_sub(now())
+            .as_millis()
+            .clamp(1, TIMER_TICK_MS as u128) as usize,
+        None => TIMER_TICK_MS,
+    };
+    timer_notify_after(ms);
 }
 
 #[allow(dead_code)]
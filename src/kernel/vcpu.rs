@@ -5,6 +5,7 @@ use spin::{Lazy, Mutex};
 use crate::arch::{ContextFrame, ContextFrameTrait, InterruptContext, InterruptContextTriat, VmContext};
 use crate::config::VmConfigEntry;
 use crate::kernel::{current_cpu, interrupt_vm_inject};
+use crate::util::timer_list::TimerValue;
 
 #[cfg(feature = "memory-reservation")]
 use super::bwres::membwres::MemoryBandwidth;
@@ -226,9 +227,8 @@ impl Vcpu {
         self.vm().unwrap().pt_dir()
     }
 
-    fn reset_context(&self) {
-        let mut inner = self.0.inner_mut.lock();
-
+    // the MPIDR_EL1 seen by the guest
+    pub fn vmpidr(&self) -> usize {
         let mut vmpidr = 1 << 31;
 
         #[cfg(feature = "tx2")]
@@ -237,7 +237,12 @@ impl Vcpu {
             vmpidr |= 0x100;
         }
 
-        vmpidr |= self.id();
+        vmpidr | self.id()
+    }
+
+    fn reset_context(&self) {
+        let vmpidr = self.vmpidr();
+        let mut inner = self.0.inner_mut.lock();
         inner.vm_ctx.vmpidr_el2 = vmpidr as u64;
         // if self.vm().vm_type() == VmType::VmTBma {
         //     info!("vm {} bma ctx restore", self.vm_id());
@@ -275,15 +280,113 @@ impl Vcpu {
         }
     }
 
+    // Save the context of a paused vcpu for migration
+    pub fn migrate_save(&self) -> VcpuMigrateState {
+        let inner = self.0.inner_mut.lock();
+        VcpuMigrateState {
+            powered_on: inner.state != VcpuState::Inv,
+            vcpu_ctx: inner.vcpu_ctx,
+            vm_ctx: inner.vm_ctx,
+            intc_ctx: inner.intc_ctx,
+        }
+    }
+
+    // Restore the migrated context before the vcpu is woken up
+    pub fn migrate_restore(&self, state: &VcpuMigrateState) {
+        let mut inner = self.0.inner_mut.lock();
+        inner.vcpu_ctx = state.vcpu_ctx;
+        inner.vm_ctx = state.vm_ctx;
+        inner.intc_ctx = state.intc_ctx;
+    }
+
+    // Account the start of a run on the physical core
+    pub(super) fn stat_run_start(&self, now: TimerValue) {
+        let mut inner = self.0.inner_mut.lock();
+        inner.run_start = now;
+        inner.sched_stat.switches += 1;
+    }
+
+    // Account the end of a run, `preempted` if it is switched out while still runnable
+    pub(super) fn stat_run_stop(&self, now: TimerValue, preempted: bool) {
+        let mut inner = self.0.inner_mut.lock();
+        inner.sched_stat.runtime_us += now.saturating_sub(inner.run_start).as_micros() as u64;
+        inner.run_start = now;
+        if preempted {
+            inner.sched_stat.preemptions += 1;
+        }
+    }
+
+    pub(super) fn stat_wakeup(&self) {
+        let mut inner = self.0.inner_mut.lock();
+        inner.sched_stat.wakeups += 1;
+    }
+
+    #[cfg(feature = "rt-sched")]
+    pub(super) fn stat_deadline_miss(&self) {
+        let mut inner = self.0.inner_mut.lock();
+        inner.sched_stat.deadline_misses += 1;
+    }
+
+    // Snapshot of the scheduling counters, including the ongoing run
+    pub fn sched_stat(&self) -> VcpuSchedStat {
+        let inner = self.0.inner_mut.lock();
+        let mut stat = inner.sched_stat;
+        if inner.state == VcpuState::Running {
+            stat.runtime_us += super::timer::now().saturating_sub(inner.run_start).as_micros() as u64;
+        }
+        stat
+    }
+
     #[cfg(feature = "memory-reservation")]
     pub fn bw_info(&self) -> &MemoryBandwidth {
         &self.0.reservation
     }
 }
 
+/* Migration image of a vcpu.
+ * powered_on: whether the vcpu should be woken up on the receiver.
+ */
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VcpuMigrateState {
+    pub powered_on: bool,
+    vcpu_ctx: ContextFrame,
+    vm_ctx: VmContext,
+    intc_ctx: InterruptContext,
+}
+
+impl VcpuMigrateState {
+    pub fn vtimer_offset(&self) -> usize {
+        self.vm_ctx.generic_timer.offset() as usize
+    }
+
+    pub fn set_vtimer_offset(&mut self, offset: usize) {
+        self.vm_ctx.generic_timer.set_offset(offset as u64);
+    }
+}
+
+/* Scheduling counters of a vcpu, exported to MVM.
+ * runtime_us: total time running on the physical core in microseconds.
+ * switches: times the vcpu is switched in.
+ * wakeups: times the vcpu is woken up.
+ * preemptions: times the vcpu is switched out while still runnable.
+ * deadline_misses: deadline misses detected by the real-time scheduler.
+ */
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct VcpuSchedStat {
+    pub runtime_us: u64,
+    pub switches: u64,
+    pub wakeups: u64,
+    pub preemptions: u64,
+    pub deadline_misses: u64,
+}
+
 pub struct VcpuInnerMut {
     state: VcpuState,
     int_list: Vec<usize>,
+    sched_stat: VcpuSchedStat,
+    run_start: TimerValue,
     // regs: ArchVcpuRegs
     vcpu_ctx: ContextFrame,
     pub vm_ctx: VmContext,
@@ -295,6 +398,8 @@ impl VcpuInnerMut {
         Self {
             state: VcpuState::Inv,
             int_list: vec![],
+            sched_stat: VcpuSchedStat::default(),
+            run_start: TimerValue::ZERO,
             vcpu_ctx: ContextFrame::default(),
             vm_ctx: VmContext::new(),
             intc_ctx: InterruptContext::default(),
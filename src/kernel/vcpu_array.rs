@@ -8,7 +8,11 @@ use alloc::{
 };
 use spin::Once;
 
-use super::{sched::Scheduler, timer::timer_enable, VcpuState};
+use super::{
+    sched::Scheduler,
+    timer::{now, timer_enable},
+    VcpuState,
+};
 
 pub struct VcpuArray {
     array: [Option<Vcpu>; CONFIG_VM_NUM_MAX],
@@ -99,6 +103,7 @@ impl VcpuArray {
             current_cpu().cpu_state = CpuState::Run;
             // set vcpu state
             vcpu.set_state(VcpuState::Runnable);
+            vcpu.stat_wakeup();
             // determine the timer
             self.active += 1;
             if !self.timer_on && self.active >= ENABLE_TIMER_ACTIVE_NUM {
@@ -107,8 +112,11 @@ impl VcpuArray {
             }
             // do scheduling
             self.scheduler().put(vcpu);
-            if current_cpu().active_vcpu.is_none() {
-                self.resched();
+            match current_cpu().active_vcpu.clone() {
+                None => self.resched(),
+                // a more urgent vcpu preempts the current one right away
+                Some(active_vcpu) if self.scheduler().preempt(&active_vcpu) => self.resched(),
+                _ => {}
             }
         }
     }
@@ -120,10 +128,40 @@ impl VcpuArray {
         }
     }
 
+    /* Replace the scheduler of this core, the runnable and running vcpus are put into the new one.
+     *
+     * @param[in] scheduler: the initialized new scheduler.
+     */
+    pub(super) fn switch_scheduler(&mut self, mut scheduler: Box<dyn Scheduler<SchedItem = Vcpu>>) {
+        for vcpu in self.array.iter().flatten() {
+            if matches!(vcpu.state(), VcpuState::Runnable | VcpuState::Running) {
+                scheduler.put(vcpu.clone());
+            }
+        }
+        match self.sched.get_mut() {
+            // the old scheduler is dropped here
+            Some(sched) => *sched = scheduler,
+            None => {
+                self.sched.call_once(|| scheduler);
+            }
+        }
+    }
+
+    // Turn on the timer of this core for a timer event, it is off while the core serves a single vcpu
+    pub fn enable_timer(&mut self) {
+        if !self.timer_on {
+            self.timer_on = true;
+            timer_enable(true);
+        }
+    }
+
     pub fn remove_vcpu(&mut self, vm_id: usize) -> Option<Vcpu> {
         match self.array.get_mut(vm_id) {
             Some(x) => x.take().map(|vcpu| {
                 self.len -= 1;
+                if vcpu.state() == VcpuState::Running {
+                    vcpu.stat_run_stop(now(), false);
+                }
                 if vcpu.state() != VcpuState::Inv {
                     self.active -= 1;
                     assert_ne!(self.active, usize::MAX);
@@ -179,6 +217,7 @@ impl VcpuArray {
                     prev_vcpu.id()
                 );
                 prev_vcpu.context_vm_store();
+                prev_vcpu.stat_run_stop(now(), true);
                 prev_vcpu.set_state(VcpuState::Runnable);
                 // put the prev_vcpu to scheduler
                 self.scheduler().put(prev_vcpu);
@@ -189,6 +228,7 @@ impl VcpuArray {
         // NOTE: Must set active first and then restore context!!!
         //      because context restore while inject pending interrupt for VM
         //      and will judge if current active vcpu
+        next_vcpu.stat_run_start(now());
         next_vcpu.set_state(VcpuState::Running);
         current_cpu().set_active_vcpu(Some(next_vcpu.clone()));
         next_vcpu.context_vm_restore();
@@ -200,12 +240,31 @@ impl VcpuArray {
         if let Some(vcpu) = current_cpu().active_vcpu.take() {
             trace!("core {} VM {} vcpu {} block", current_cpu().id, vcpu.vm_id(), vcpu.id());
             vcpu.context_vm_store();
+            vcpu.stat_run_stop(now(), false);
             vcpu.set_state(VcpuState::Blocked);
             self.scheduler().remove(&vcpu);
             self.resched();
         }
     }
 
+    /* Block the vcpu of the given VM on this core, an active vcpu will store its context first.
+     *
+     * @param[in] vm_id: id of the VM whose vcpu is blocked.
+     */
+    pub fn block_vcpu(&mut self, vm_id: usize) {
+        let vcpu = match self.pop_vcpu_through_vmid(vm_id) {
+            Some(vcpu) => vcpu.clone(),
+            None => return,
+        };
+        if current_cpu().active_vcpu.as_ref() == Some(&vcpu) {
+            self.block_current();
+        } else if vcpu.state() != VcpuState::Inv {
+            trace!("core {} VM {} vcpu {} block", current_cpu().id, vm_id, vcpu.id());
+            vcpu.set_state(VcpuState::Blocked);
+            self.scheduler().remove(&vcpu);
+        }
+    }
+
     pub fn iter(&self) -> Iter<'_, Option<Vcpu>> {
         self.array.iter()
     }
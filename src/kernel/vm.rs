@@ -6,9 +6,10 @@ use spin::{Mutex, Once};
 
 use crate::arch::PageTable;
 use crate::arch::Vgic;
+use crate::arch::PAGE_SIZE;
 use crate::arch::{emu_intc_init, HYP_VA_SIZE, VM_IPA_SIZE};
 use crate::config::VmConfigEntry;
-use crate::device::{emu_virtio_mmio_init, EmuDev};
+use crate::device::{emu_pci_host_init, emu_virtio_mmio_init, emu_virtio_pci_init, EmuDev, PciHost, VirtioMmio};
 use crate::kernel::{mem_color_region_free, shyper_init};
 use crate::util::*;
 
@@ -88,7 +89,7 @@ pub fn vm_if_ivc_arg_ptr(vm_id: usize) -> usize {
 // End vm interface func implementation
 
 #[allow(dead_code)]
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Debug, Default)]
 pub enum VmState {
     #[default]
     Inv = 0,
@@ -235,8 +236,24 @@ impl VmInnerConst {
                     self.intc_type = IntCtrlType::Passthrough;
                     crate::arch::partial_passthrough_intc_init(emu_cfg)
                 }
+                #[cfg(feature = "gicv3")]
+                EmuDeviceTGicr => crate::arch::emu_vgicr_init(emu_cfg, self.vcpu_list.len()),
+                #[cfg(feature = "gicv3")]
+                EmuDeviceTGits => crate::arch::emu_vgits_init(emu_cfg),
+                EmuDeviceTConsole => crate::device::emu_pl011_init(vm.clone(), emu_cfg),
+                EmuDeviceTPciHost => {
+                    let index = self
+                        .emu_devs
+                        .iter()
+                        .filter(|dev| dev.emu_type() == EmuDeviceTPciHost)
+                        .count();
+                    emu_pci_host_init(emu_cfg, index)
+                }
                 EmuDeviceTVirtioBlk | EmuDeviceTVirtioConsole | EmuDeviceTVirtioNet | VirtioBalloon => {
-                    emu_virtio_mmio_init(vm.clone(), emu_cfg)
+                    match self.pci_host_of(emu_cfg.base_ipa) {
+                        Some(host) => emu_virtio_pci_init(vm.clone(), emu_cfg, &host),
+                        None => emu_virtio_mmio_init(vm.clone(), emu_cfg),
+                    }
                 }
                 #[cfg(feature = "iommu")]
                 EmuDeviceTIOMMU => crate::kernel::emu_iommu_init(emu_cfg), // Do IOMMU init later, after add VM to global list
@@ -281,6 +298,14 @@ impl VmInnerConst {
         }
         true
     }
+
+    // the PCI host bridge whose memory window holds the ipa, its devices come after it in the list
+    fn pci_host_of(&self, ipa: usize) -> Option<Arc<PciHost>> {
+        self.emu_devs
+            .iter()
+            .filter_map(|dev| dev.clone().into_any_arc().downcast::<PciHost>().ok())
+            .find(|host| host.mem_window().contains(&ipa))
+    }
 }
 
 impl Vm {
@@ -340,6 +365,24 @@ impl Vm {
             .cloned()
     }
 
+    // the virtio devices of the VM on the MMIO bus
+    pub fn virtio_mmio_devs(&self) -> Vec<Arc<VirtioMmio>> {
+        self.inner_const
+            .emu_devs
+            .iter()
+            .filter_map(|dev| dev.clone().into_any_arc().downcast::<VirtioMmio>().ok())
+            .collect()
+    }
+
+    // the virtual ITS of the VM, which translates the MSIs of its PCIe devices
+    #[cfg(feature = "gicv3")]
+    pub fn vits(&self) -> Option<Arc<crate::arch::VgicIts>> {
+        self.inner_const
+            .emu_devs
+            .iter()
+            .find_map(|dev| dev.clone().into_any_arc().downcast::<crate::arch::VgicIts>().ok())
+    }
+
     pub fn pt_map_range(&self, ipa: usize, len: usize, pa: usize, pte: usize, map_block: bool) {
         let vm_inner = self.inner_mut.lock();
         vm_inner.pt.pt_map_range(ipa, len, pa, pte, map_block);
@@ -454,6 +497,183 @@ impl Vm {
         vm_inner.pt.show_pt(ipa);
     }
 
+    // total 4KB page number of the VM normal memory regions
+    pub fn mem_page_num(&self) -> usize {
+        self.config()
+            .memory_region()
+            .iter()
+            .map(|region| region.length / PAGE_SIZE)
+            .sum()
+    }
+
+    // page index of ipa in the VM normal memory regions, regions are counted in config order
+    fn mem_page_idx(&self, ipa: usize) -> Option<usize> {
+        let mut base = 0;
+        for region in self.config().memory_region() {
+            if region.as_range().contains(&ipa) {
+                return Some(base + (ipa - region.ipa_start) / PAGE_SIZE);
+            }
+            base += region.length / PAGE_SIZE;
+        }
+        None
+    }
+
+    // ipa of the page_idx-th page in the VM normal memory regions
+    pub fn mem_page_ipa(&self, page_idx: usize) -> Option<usize> {
+        let mut base = 0;
+        for region in self.config().memory_region() {
+            let num = region.length / PAGE_SIZE;
+            if page_idx < base + num {
+                return Some(region.ipa_start + (page_idx - base) * PAGE_SIZE);
+            }
+            base += num;
+        }
+        None
+    }
+
+    // Invalidate the stage 2 tlb of this VM, the VM may not be the one running on current core.
+    fn invalid_guest_tlb(&self, pt_dir: usize) {
+        use crate::arch::{Arch, ArchTrait, TlbInvalidate};
+        match super::active_vm() {
+            Some(vm) if vm.id() == self.id() => Arch::invalid_guest_all(),
+            active => {
+                Arch::install_vm_page_table(pt_dir, self.id());
+                Arch::invalid_guest_all();
+                if let Some(vm) = active {
+                    Arch::install_vm_page_table(vm.pt_dir(), vm.id());
+                }
+            }
+        }
+    }
+
+    /* Start dirty page logging of the VM normal memory.
+     * All pages are write protected and marked as dirty at first.
+     *
+     * @return the page number covered by the dirty bitmap.
+     */
+    pub fn start_dirty_log(&self) -> usize {
+        let page_num = self.mem_page_num();
+        let mut vm_inner = self.inner_mut.lock();
+        let mut bitmap = FlexBitmap::new(page_num);
+        bitmap.init_dirty();
+        for idx in 0..page_num {
+            let ipa = self.mem_page_ipa(idx).unwrap();
+            vm_inner.pt.set_page_access(ipa, false);
+        }
+        vm_inner.dirty_log = Some(bitmap);
+        let pt_dir = vm_inner.pt.base_pa();
+        drop(vm_inner);
+        self.invalid_guest_tlb(pt_dir);
+        info!("VM[{}] start dirty log, page num {}", self.id(), page_num);
+        page_num
+    }
+
+    // Stop dirty page logging and give the write permission back to the VM.
+    pub fn stop_dirty_log(&self) {
+        let mut vm_inner = self.inner_mut.lock();
+        if vm_inner.dirty_log.take().is_none() {
+            return;
+        }
+        for idx in 0..self.mem_page_num() {
+            let ipa = self.mem_page_ipa(idx).unwrap();
+            vm_inner.pt.set_page_access(ipa, true);
+        }
+        let pt_dir = vm_inner.pt.base_pa();
+        drop(vm_inner);
+        self.invalid_guest_tlb(pt_dir);
+        info!("VM[{}] stop dirty log", self.id());
+    }
+
+    pub fn dirty_log_enabled(&self) -> bool {
+        let vm_inner = self.inner_mut.lock();
+        vm_inner.dirty_log.is_some()
+    }
+
+    /* Handle the stage 2 permission fault caused by dirty logging.
+     * Must be called on the core where the VM is running.
+     *
+     * @param[in] ipa: fault address.
+     * @return true if the fault is caused by dirty logging.
+     */
+    pub fn dirty_log_fault(&self, ipa: usize) -> bool {
+        use crate::arch::{Arch, TlbInvalidate};
+        let idx = match self.mem_page_idx(ipa) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        let mut vm_inner = self.inner_mut.lock();
+        let inner = &mut *vm_inner;
+        match inner.dirty_log.as_mut() {
+            Some(bitmap) => {
+                bitmap.set(idx, true);
+                if inner.pt.set_page_access(round_down(ipa, PAGE_SIZE), true) {
+                    Arch::invalid_guest_ipa(ipa);
+                } else {
+                    // the page is already writable, drop the stale tlb entries
+                    Arch::invalid_guest_all();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /* Mark the normal memory written by the hypervisor on behalf of the VM as dirty, e.g. by the emulated
+     * devices. These writes do not go through stage 2 and are not caught by the write protection.
+     *
+     * @param[in] ipa: start of the written range.
+     * @param[in] len: length of the written range in bytes.
+     */
+    pub fn mark_dirty(&self, ipa: usize, len: usize) {
+        let mut vm_inner = self.inner_mut.lock();
+        if let Some(bitmap) = vm_inner.dirty_log.as_mut() {
+            let mut page = round_down(ipa, PAGE_SIZE);
+            while page < ipa + len {
+                if let Some(idx) = self.mem_page_idx(page) {
+                    bitmap.set(idx, true);
+                }
+                page += PAGE_SIZE;
+            }
+        }
+    }
+
+    /* Fetch the dirty bitmap and clear it, the dirty pages are write protected again.
+     *
+     * @param[out] dst: buffer to receive the bitmap, 1 bit per page.
+     * @return the dirty page number.
+     */
+    pub fn fetch_dirty_log(&self, dst: &mut [usize]) -> Result<usize, ()> {
+        let mut vm_inner = self.inner_mut.lock();
+        let inner = &mut *vm_inner;
+        let bitmap = match inner.dirty_log.as_mut() {
+            Some(bitmap) => bitmap,
+            None => {
+                error!("VM[{}] dirty log is not started", self.id());
+                return Err(());
+            }
+        };
+        if dst.len() < bitmap.slice().len() {
+            error!(
+                "fetch_dirty_log: buffer len {} is smaller than bitmap len {}",
+                dst.len(),
+                bitmap.slice().len()
+            );
+            return Err(());
+        }
+        let dirty = bitmap.sum();
+        dst[..bitmap.slice().len()].copy_from_slice(bitmap.slice());
+        for idx in 0..bitmap.len() {
+            if bitmap.get(idx) != 0 {
+                inner.pt.set_page_access(self.mem_page_ipa(idx).unwrap(), false);
+            }
+        }
+        bitmap.clear();
+        let pt_dir = inner.pt.base_pa();
+        drop(vm_inner);
+        self.invalid_guest_tlb(pt_dir);
+        Ok(dirty)
+    }
+
     // Formula: Virtual Count = Physical Count - <offset>
     //          (from ARM: Learn the architecture - Generic Timer)
     // So, <offset> = Physical Count - Virtual Count
@@ -484,6 +704,18 @@ impl Vm {
         inner.vtimer_offset
     }
 
+    // Virtual count recorded when all vcpus are stopped, used by migration
+    #[cfg(feature = "vtimer")]
+    pub fn vtimer(&self) -> usize {
+        self.inner_mut.lock().vtimer
+    }
+
+    // Only used before the vcpus are restored, e.g. by migration
+    #[cfg(feature = "vtimer")]
+    pub fn set_vtimer(&self, vtimer: usize) {
+        self.inner_mut.lock().vtimer = vtimer;
+    }
+
     pub fn ipa2hva(&self, ipa: usize) -> usize {
         let mask = (1 << (HYP_VA_SIZE - VM_IPA_SIZE)) - 1;
         let prefix = mask << VM_IPA_SIZE;
@@ -495,9 +727,12 @@ impl Vm {
         prefix | ipa
     }
 
+    pub fn hva2ipa(&self, hva: usize) -> usize {
+        hva & ((1 << VM_IPA_SIZE) - 1)
+    }
+
     #[cfg(feature = "balloon")]
     pub fn inflate_balloon(&self, guest_addr: usize, len: usize) {
-        use crate::arch::PAGE_SIZE;
         if len != PAGE_SIZE {
             error!("len {:#x} not handable", len);
             return;
@@ -545,6 +780,9 @@ struct VmInnerMut {
     #[cfg(feature = "balloon")]
     balloon: Vec<usize>,
 
+    // dirty page bitmap of normal memory, Some if dirty logging is enabled
+    dirty_log: Option<FlexBitmap>,
+
     // VM timer
     #[cfg(feature = "vtimer")]
     running: usize,
@@ -567,6 +805,7 @@ impl VmInnerMut {
             iommu_ctx_id: None,
             #[cfg(feature = "balloon")]
             balloon: vec![],
+            dirty_log: None,
             #[cfg(feature = "vtimer")]
             running: 0,
             #[cfg(feature = "vtimer")]
@@ -81,6 +81,8 @@ pub fn init(cpu_id: usize, dtb: *mut core::ffi::c_void) -> ! {
     if cpu_id == 0 {
         kernel::subinit();
         vmm::vm_init();
+        #[cfg(feature = "shell")]
+        kernel::shell_init();
         info!(
             "{} Hypervisor init ok\n\nStart booting Monitor VM ...",
             env!("CARGO_PKG_NAME")
@@ -24,13 +24,18 @@ impl PageFrame {
     }
 
     pub fn alloc_pages(page_num: usize) -> Result<Self, AllocError> {
+        Self::alloc_aligned_pages(page_num, PAGE_SIZE)
+    }
+
+    // zeroed pages whose start is aligned to `align`, which is a power of two not smaller than PAGE_SIZE
+    pub fn alloc_aligned_pages(page_num: usize, align: usize) -> Result<Self, AllocError> {
         if page_num == 0 {
             return Err(AllocError::AllocZeroPage);
         }
-        match Layout::from_size_align(page_num * PAGE_SIZE, PAGE_SIZE) {
+        match Layout::from_size_align(page_num * PAGE_SIZE, align) {
             Ok(layout) => {
                 let hva = unsafe { alloc::alloc_zeroed(layout) };
-                if hva.is_null() || hva as usize & (PAGE_SIZE - 1) != 0 {
+                if hva.is_null() || hva as usize & (align - 1) != 0 {
                     panic!("alloc_pages: get wrong ptr {hva:#p}, layout = {:?}", layout);
                 }
                 let hva = hva as usize;
@@ -128,6 +128,12 @@ impl FlexBitmap {
 
     pub fn init_dirty(&mut self) {
         self.map.fill(usize::MAX);
+        // bits beyond len must stay clear, or sum() will count them
+        if self.len % 64 != 0 {
+            if let Some(last) = self.map.last_mut() {
+                *last = (1 << (self.len % 64)) - 1;
+            }
+        }
     }
 
     pub fn clear(&mut self) {
@@ -1,7 +1,56 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::str::FromStr;
+
 use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
+use spin::RwLock;
 
 struct SimpleLogger;
 
+// the most bytes of a log spec from MVM
+const LOG_SPEC_MAX: usize = 0x400;
+
+// Runtime log levels, the compile-time cap is chosen by the "log-max-*" features.
+struct LogConfig {
+    // level of the targets without a filter
+    level: LevelFilter,
+    // per-target levels, a filter matches a target if it is one or more whole components of the module path,
+    // e.g. "vgic" or "kernel::sched"
+    filters: Vec<(String, LevelFilter)>,
+}
+
+static LOG_CONFIG: RwLock<LogConfig> = RwLock::new(LogConfig {
+    level: LevelFilter::Trace,
+    filters: Vec::new(),
+});
+
+fn target_match(target: &str, filter: &str) -> bool {
+    target.match_indices(filter).any(|(pos, _)| {
+        let before = &target[..pos];
+        let after = &target[pos + filter.len()..];
+        (before.is_empty() || before.ends_with("::")) && (after.is_empty() || after.starts_with("::"))
+    })
+}
+
+impl LogConfig {
+    // the longest matching filter wins
+    fn level_of(&self, target: &str) -> LevelFilter {
+        self.filters
+            .iter()
+            .filter(|(filter, _)| target_match(target, filter))
+            .max_by_key(|(filter, _)| filter.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.filters
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.level, LevelFilter::max)
+    }
+}
+
 fn level2color(level: Level) -> u8 {
     match level {
         Level::Error => 31, // 31 Red
@@ -20,8 +69,9 @@ macro_rules! with_color {
 }
 
 impl log::Log for SimpleLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let config = LOG_CONFIG.read();
+        config.filters.is_empty() || metadata.level() <= config.level_of(metadata.target())
     }
 
     fn log(&self, record: &Record) {
@@ -59,3 +109,76 @@ static LOGGER: SimpleLogger = SimpleLogger;
 pub fn logger_init() -> Result<(), SetLoggerError> {
     log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Trace))
 }
+
+/* Set the runtime log levels.
+ *
+ * @param[in] spec: comma separated list of a default level and per-target levels, such as
+ *                  "info,vgic=debug,sched=trace". The levels are off, error, warn, info, debug and trace.
+ *                  The filters are replaced, the default level is kept if not given.
+ */
+pub fn log_set_spec(spec: &str) -> Result<(), ()> {
+    let mut level = LOG_CONFIG.read().level;
+    let mut filters = Vec::new();
+    for item in spec.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        match item.split_once('=') {
+            None => level = LevelFilter::from_str(item).map_err(|_| ())?,
+            Some((target, filter_level)) => {
+                let filter_level = LevelFilter::from_str(filter_level.trim()).map_err(|_| ())?;
+                filters.push((target.trim().to_string(), filter_level));
+            }
+        }
+    }
+    let mut config = LOG_CONFIG.write();
+    config.level = level;
+    config.filters = filters;
+    log::set_max_level(config.max_level());
+    Ok(())
+}
+
+// the current log levels in the format of `log_set_spec`
+pub fn log_spec() -> String {
+    let config = LOG_CONFIG.read();
+    let mut spec = config.level.as_str().to_lowercase();
+    for (target, level) in config.filters.iter() {
+        let _ = write!(spec, ",{}={}", target, level.as_str().to_lowercase());
+    }
+    spec
+}
+
+/* Set the runtime log levels from MVM.
+ *
+ * @param[in] spec_ipa: ipa of the log spec string in MVM, see `log_set_spec`.
+ * @param[in] spec_len: bytes of the string.
+ */
+pub fn log_set_level(spec_ipa: usize, spec_len: usize) -> Result<usize, ()> {
+    if spec_len > LOG_SPEC_MAX {
+        warn!("log_set_level: log spec too long ({} bytes)", spec_len);
+        return Err(());
+    }
+    let vm = crate::kernel::active_vm().unwrap();
+    let mut buf = Vec::with_capacity(spec_len);
+    // the string may cross a page boundary
+    for ipa in spec_ipa..spec_ipa + spec_len {
+        match vm.ipa2hva(ipa) {
+            0 => {
+                warn!("log_set_level: illegal log spec ipa {:#x}", ipa);
+                return Err(());
+            }
+            hva => buf.push(unsafe { *(hva as *const u8) }),
+        }
+    }
+    let Ok(spec) = core::str::from_utf8(&buf) else {
+        warn!("log_set_level: log spec is not utf-8");
+        return Err(());
+    };
+    match log_set_spec(spec) {
+        Ok(()) => {
+            info!("log level set to {}", log_spec());
+            Ok(0)
+        }
+        Err(()) => {
+            warn!("log_set_level: illegal log spec {}", spec);
+            Err(())
+        }
+    }
+}
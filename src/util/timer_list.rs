@@ -63,6 +63,10 @@ impl TimerList {
         None
     }
 
+    pub fn peek_timeout(&self) -> Option<TimerValue> {
+        self.events.peek().map(|e| e.0.timeout)
+    }
+
     pub fn remove_all<F>(&mut self, condition: F)
     where
         F: Fn(&Arc<dyn TimerEvent>) -> bool,
@@ -0,0 +1,67 @@
+use core::mem::size_of;
+
+use crate::arch::{Arch, CacheInvalidate};
+use crate::kernel::{active_vm, vm_by_id};
+
+/* Start dirty page logging of a VM, all pages are reported as dirty in the first fetch.
+ *
+ * @param[in] vm_id: target VM id.
+ * @return the page number covered by the dirty bitmap.
+ */
+pub fn vmm_dirty_log_start(vm_id: usize) -> Result<usize, ()> {
+    match vm_by_id(vm_id) {
+        Some(vm) if vm_id != 0 => {
+            if vm.dirty_log_enabled() {
+                warn!("VM[{}] dirty log is already started", vm_id);
+                return Err(());
+            }
+            Ok(vm.start_dirty_log())
+        }
+        _ => {
+            error!("vmm_dirty_log_start: illegal VM[{}]", vm_id);
+            Err(())
+        }
+    }
+}
+
+pub fn vmm_dirty_log_stop(vm_id: usize) -> Result<usize, ()> {
+    match vm_by_id(vm_id) {
+        Some(vm) => {
+            vm.stop_dirty_log();
+            Ok(0)
+        }
+        None => {
+            error!("vmm_dirty_log_stop: VM[{}] does not exist", vm_id);
+            Err(())
+        }
+    }
+}
+
+/* Copy the dirty bitmap of a VM to the caller's buffer and clear it.
+ * Bit n stands for the n-th 4KB page of the VM memory regions, in config order.
+ *
+ * @param[in] vm_id: target VM id.
+ * @param[in] bitmap_ipa: the buffer in caller VM to receive the bitmap, aligned to 8 bytes.
+ * @param[in] len: buffer length in bytes.
+ * @return the dirty page number.
+ */
+pub fn vmm_dirty_log_fetch(vm_id: usize, bitmap_ipa: usize, len: usize) -> Result<usize, ()> {
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) => vm,
+        None => {
+            error!("vmm_dirty_log_fetch: VM[{}] does not exist", vm_id);
+            return Err(());
+        }
+    };
+    let hva = active_vm().unwrap().ipa2hva(bitmap_ipa);
+    if hva == 0 || hva % size_of::<usize>() != 0 {
+        error!("vmm_dirty_log_fetch: illegal bitmap ipa {:#x}", bitmap_ipa);
+        return Err(());
+    }
+    // SAFETY: the buffer is in the caller's normal memory, which is mapped in the hypervisor
+    let dst = unsafe { core::slice::from_raw_parts_mut(hva as *mut usize, len / size_of::<usize>()) };
+    let dirty = vm.fetch_dirty_log(dst)?;
+    Arch::dcache_flush(hva, len);
+    debug!("VM[{}] fetch dirty log, dirty pages {}", vm_id, dirty);
+    Ok(dirty)
+}
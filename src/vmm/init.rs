@@ -190,6 +190,24 @@ fn vmm_init_hardware(vm: &Vm) -> bool {
             return false;
         }
     }
+    // init the MSIs of passthrough PCIe devices, which are translated by the physical ITS
+    #[cfg(feature = "gicv3")]
+    if !vm.config().passthrough_device_msi_ids().is_empty() {
+        let (Some(vits), Some(translater)) = (vm.vits(), crate::arch::its_translater()) else {
+            error!("VM {} has passthrough msi ids but no virtual or physical ITS", vm.id());
+            return false;
+        };
+        for devid in vm.config().passthrough_device_msi_ids() {
+            // the DeviceIDs from PCI_MSI_DEVID_BASE belong to the emulated PCIe devices
+            if *devid >= crate::device::PCI_MSI_DEVID_BASE || !crate::arch::its_assign_device(*devid, vm.id()) {
+                error!("VM {} failed to assign msi device {:#x}", vm.id(), devid);
+                return false;
+            }
+        }
+        // the devices write the GITS_TRANSLATER of the virtual ITS through the stage 2 translation (or 1:1 if
+        // there is no IOMMU), so the frame is backed by the physical one, which is written by the guest as well
+        vm.pt_map_range(vits.translater_ipa(), PAGE_SIZE, translater, PTE_S2_DEVICE, false);
+    }
     true
 }
 
@@ -1,4 +1,8 @@
 use alloc::ffi::CString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
 
 use crate::arch::interrupt_arch_deactive_irq;
 use crate::arch::power_arch_vm_shutdown_secondary_cores;
@@ -7,14 +11,16 @@ use crate::kernel::HVC_CONFIG;
 use crate::kernel::HVC_CONFIG_UPLOAD_KERNEL_IMAGE;
 use crate::kernel::HVC_VMM;
 use crate::kernel::HVC_VMM_REBOOT_VM;
+use crate::kernel::HVC_VMM_SHUTDOWN_VM;
 use crate::kernel::{
-    active_vcpu_id, active_vm, current_cpu, push_vm, vm_if_get_state, vm_if_set_ivc_arg, vm_if_set_ivc_arg_ptr,
-    vm_list_walker, Vm,
+    active_vcpu_id, active_vm, current_cpu, push_vm, timer, vm_by_id, vm_if_get_state, vm_if_set_ivc_arg,
+    vm_if_set_ivc_arg_ptr, vm_if_set_state, vm_list_walker, VcpuSchedStat, Vm, VmState,
 };
 use crate::kernel::{hvc_send_msg_to_vm, HvcGuestMsg, HvcManageMsg};
 use crate::kernel::{ipi_send_msg, vm_if_get_cpu_id, IpiInnerMsg, IpiMessage, IpiType, IpiVmmMsg};
 use crate::util::bit_extract;
-use crate::vmm::{vmm_assign_vcpu_percore, vmm_init_image, vmm_remove_vcpu_percore, vmm_setup_config};
+use crate::util::timer_list::{TimerEvent, TimerValue};
+use crate::vmm::{vmm_assign_vcpu_percore, vmm_init_image, vmm_remove_vcpu_percore, vmm_remove_vm, vmm_setup_config};
 
 use shyper::{VMInfo, VM_NUM_MAX};
 
@@ -22,7 +28,6 @@ use shyper::{VMInfo, VM_NUM_MAX};
 pub enum VmmEvent {
     Boot,
     Reboot,
-    #[allow(dead_code)]
     Shutdown,
 }
 
@@ -156,6 +161,229 @@ pub fn vmm_reboot_vm(arg: usize) {
     }
 }
 
+// default and longest time to wait for the guest powering off itself
+const VMM_SHUTDOWN_TIMEOUT_MS: usize = 3000;
+const VMM_SHUTDOWN_TIMEOUT_MAX_MS: usize = 60000;
+
+// VMs which are asked to power off, with the deadline to remove them
+static SHUTDOWN_PENDING_LIST: Mutex<Vec<(usize, TimerValue)>> = Mutex::new(Vec::new());
+
+// removes the VMs whose shutdown is still pending at the deadline
+struct ShutdownTimeoutEvent;
+
+impl TimerEvent for ShutdownTimeoutEvent {
+    fn callback(self: Arc<Self>, now: TimerValue) {
+        let mut pending_list = SHUTDOWN_PENDING_LIST.lock();
+        let expired: Vec<usize> = pending_list
+            .iter()
+            .filter(|(_, deadline)| *deadline <= now)
+            .map(|&(vm_id, _)| vm_id)
+            .collect();
+        pending_list.retain(|(_, deadline)| *deadline > now);
+        drop(pending_list);
+        for vm_id in expired {
+            warn!("vmm_shutdown_vm: VM [{}] shutdown timeout, remove it forcibly", vm_id);
+            if vm_by_id(vm_id).is_some() {
+                vmm_remove_vm(vm_id);
+            }
+        }
+    }
+}
+
+fn vmm_shutdown_timeout(timeout_ms: usize) -> TimerValue {
+    let timeout_ms = match timeout_ms {
+        0 => VMM_SHUTDOWN_TIMEOUT_MS,
+        ms => usize::min(ms, VMM_SHUTDOWN_TIMEOUT_MAX_MS),
+    };
+    TimerValue::from_millis(timeout_ms as u64)
+}
+
+/**
+ * Shutdown target vm according to arguments
+ * Soft shutdown asks the guest to power off and returns at once, the VM is removed when it powers off, or forcibly
+ * when the timeout expires.
+ *
+ * @param arg force ~ (31, 16) ~ [soft shutdown or hard shutdown]
+ *            vmid ~ (15, 0) ~ [target vm id]
+ * @param timeout_ms time to wait for the guest in soft shutdown, 0 for default, at most 60s
+ */
+pub fn vmm_shutdown_vm(arg: usize, timeout_ms: usize) -> Result<usize, ()> {
+    let vm_id = bit_extract(arg, 0, 16);
+    let force = bit_extract(arg, 16, 16) != 0;
+    if vm_by_id(vm_id).is_none() || vm_id == 0 {
+        error!("vmm_shutdown_vm: illegal VM [{}]", vm_id);
+        return Err(());
+    }
+
+    info!("vmm_shutdown VM [{}] force:{}", vm_id, force);
+
+    if !force && matches!(vm_if_get_state(vm_id), VmState::Active) {
+        let msg = HvcManageMsg {
+            fid: HVC_VMM,
+            event: HVC_VMM_SHUTDOWN_VM,
+            vm_id,
+        };
+        if hvc_send_msg_to_vm(vm_id, &HvcGuestMsg::Manage(msg)) {
+            info!("vmm_shutdown_vm: VM [{}] will be removed after system off", vm_id);
+            let timeout = vmm_shutdown_timeout(timeout_ms);
+            let mut pending_list = SHUTDOWN_PENDING_LIST.lock();
+            pending_list.retain(|&(id, _)| id != vm_id);
+            pending_list.push((vm_id, timer::now() + timeout));
+            drop(pending_list);
+            current_cpu().vcpu_array.enable_timer();
+            timer::start_timer_event(timeout, Arc::new(ShutdownTimeoutEvent));
+            return Ok(0);
+        }
+        warn!("vmm_shutdown_vm: failed to notify VM [{}], remove it forcibly", vm_id);
+    }
+
+    vmm_remove_vm(vm_id);
+    Ok(0)
+}
+
+/* Handle the PSCI system off from a Guest VM.
+ * The VM can not be removed in its own context, let MVM core do it if a shutdown is pending.
+ *
+ * @param[in] vm_id: the VM which powers off.
+ */
+pub fn vmm_guest_sys_off(vm_id: usize) {
+    vm_if_set_state(vm_id, VmState::Pending);
+
+    let mut pending_list = SHUTDOWN_PENDING_LIST.lock();
+    if let Some(idx) = pending_list.iter().position(|&(id, _)| id == vm_id) {
+        pending_list.remove(idx);
+        drop(pending_list);
+        let m = IpiVmmMsg {
+            vmid: vm_id,
+            event: VmmEvent::Shutdown,
+        };
+        let cpu_trgt = vm_if_get_cpu_id(0).unwrap();
+        if !ipi_send_msg(cpu_trgt, IpiType::Vmm, IpiInnerMsg::VmmMsg(m)) {
+            error!("vmm_guest_sys_off: failed to send ipi to Core {}", cpu_trgt);
+        }
+    } else {
+        info!("VM[{}] system off, please remove it on MVM", vm_id);
+    }
+}
+
+// Drop the pending shutdown request when the VM is removed, on the MVM core
+pub(super) fn vmm_shutdown_clear(vm_id: usize) {
+    let mut pending_list = SHUTDOWN_PENDING_LIST.lock();
+    pending_list.retain(|&(id, _)| id != vm_id);
+    // removing the vcpus may turn off the timer, which the other pending requests still wait on
+    if !pending_list.is_empty() {
+        current_cpu().vcpu_array.enable_timer();
+    }
+}
+
+#[repr(C)]
+struct VmStateInfo {
+    pub vm_state: u32,
+    pub vcpu_num: u32,
+    pub vcpu_state: [u32; crate::board::static_config::CORE_NUM],
+}
+
+/* Get VM state and its vcpu states.
+ *
+ * @param[in] vm_id : target VM id.
+ * @param[in] state_ipa : vm state info ipa, 0 if only the VM state is needed.
+ * @return the VM state.
+ */
+pub fn vmm_get_vm_state(vm_id: usize, state_ipa: usize) -> Result<usize, ()> {
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) => vm,
+        None => {
+            error!("vmm_get_vm_state: VM [{}] does not exist", vm_id);
+            return Err(());
+        }
+    };
+    let vm_state = vm_if_get_state(vm_id);
+    if state_ipa != 0 {
+        let state_pa = active_vm().unwrap().ipa2hva(state_ipa);
+        if state_pa == 0 {
+            error!("illegal state_ipa {:x}", state_ipa);
+            return Err(());
+        }
+        let state_info = unsafe { &mut *(state_pa as *mut VmStateInfo) };
+        state_info.vm_state = vm_state as u32;
+        state_info.vcpu_num = vm.vcpu_list().len() as u32;
+        for (dst, vcpu) in state_info.vcpu_state.iter_mut().zip(vm.vcpu_list()) {
+            *dst = vcpu.state() as u32;
+        }
+    }
+    Ok(vm_state as usize)
+}
+
+#[repr(C)]
+struct VmSchedStatInfo {
+    pub vcpu_num: u32,
+    pub vcpu_stat: [VcpuSchedStat; crate::board::static_config::CORE_NUM],
+}
+
+/* Get the scheduling counters of each vcpu of a VM.
+ *
+ * @param[in] vm_id : target VM id.
+ * @param[in] stat_ipa : ipa of the VmSchedStatInfo buffer in MVM.
+ */
+pub fn vmm_get_vcpu_stat(vm_id: usize, stat_ipa: usize) -> Result<usize, ()> {
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) => vm,
+        None => {
+            error!("vmm_get_vcpu_stat: VM [{}] does not exist", vm_id);
+            return Err(());
+        }
+    };
+    let stat_pa = active_vm().unwrap().ipa2hva(stat_ipa);
+    if stat_pa == 0 {
+        error!("illegal stat_ipa {:x}", stat_ipa);
+        return Err(());
+    }
+    let stat_info = unsafe { &mut *(stat_pa as *mut VmSchedStatInfo) };
+    stat_info.vcpu_num = vm.vcpu_list().len() as u32;
+    for (dst, vcpu) in stat_info.vcpu_stat.iter_mut().zip(vm.vcpu_list()) {
+        *dst = vcpu.sched_stat();
+    }
+    Ok(0)
+}
+
+/* Pin a passthrough irq of a running VM to a physical core, the guest can no longer route it elsewhere.
+ *
+ * @param[in] vm_id : target VM id.
+ * @param[in] irq : passthrough irq of the VM.
+ * @param[in] cpu_id : physical core that runs a vcpu of the VM, or any id >= CORE_NUM to unpin the irq and route
+ *                    it to the targets written by the guest again.
+ */
+pub fn vmm_set_irq_affinity(vm_id: usize, irq: usize, cpu_id: usize) -> Result<usize, ()> {
+    let vm = match vm_by_id(vm_id) {
+        Some(vm) => vm,
+        None => {
+            error!("vmm_set_irq_affinity: VM [{}] does not exist", vm_id);
+            return Err(());
+        }
+    };
+    if !vm.config().passthrough_device_irqs().contains(&irq) || !vm.has_vgic() {
+        error!(
+            "vmm_set_irq_affinity: irq {} is not a passthrough irq of VM [{}]",
+            irq, vm_id
+        );
+        return Err(());
+    }
+    let affinity = if cpu_id < crate::board::static_config::CORE_NUM {
+        if vm.pcpuid_to_vcpuid(cpu_id).is_none() {
+            error!("vmm_set_irq_affinity: VM [{}] has no vcpu on core {}", vm_id, cpu_id);
+            return Err(());
+        }
+        Some(cpu_id)
+    } else {
+        None
+    };
+    if !vm.vgic().set_hw_int_affinity(&vm, irq, affinity) {
+        return Err(());
+    }
+    info!("vmm_set_irq_affinity: VM [{}] irq {} core {:?}", vm_id, irq, affinity);
+    Ok(0)
+}
+
 /* Reset vm os at current core.
  *
  * @param[in] vm : target VM structure to be reboot.
@@ -199,6 +427,11 @@ pub fn vmm_reboot() {
     vm_if_set_ivc_arg_ptr(vm.id(), 0);
 
     crate::arch::interrupt_arch_clear();
+    // the guest maps its devices on the virtual ITS again
+    #[cfg(feature = "gicv3")]
+    if let Some(vits) = vm.vits() {
+        vits.reset(&vm);
+    }
     vcpu.init(vm.config());
 
     vmm_load_image_from_mvm(&vm);
@@ -286,7 +519,9 @@ pub fn vmm_ipi_handler(msg: IpiMessage) {
                 vmm_reboot();
             }
             VmmEvent::Shutdown => {
-                todo!();
+                if vm_by_id(vmm.vmid).is_some() {
+                    vmm_remove_vm(vmm.vmid);
+                }
             }
         },
         IpiInnerMsg::VmmPercoreMsg(msg) => match msg.event {
@@ -0,0 +1,440 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use spin::Mutex;
+
+use crate::arch::{VgicCpuMigrateState, VgicIntMigrateState, VgicMigrateState, PAGE_SIZE, PTE_S2_NORMAL, PTE_S2_RO};
+use crate::device::{EmuDeviceType, VirtioMigrateState, VirtqMigrateState};
+use crate::kernel::access::copy_segment_to_vm;
+use crate::kernel::{
+    active_vm, current_cpu, hvc_send_msg_to_vm, ipi_send_msg, timer, vm_async_task_pending, vm_by_id, vm_if_get_state,
+    vm_if_set_state, HvcGuestMsg, HvcMigrateMsg, IpiHvcMsg, IpiInnerMsg, IpiType, Vcpu, VcpuMigrateState, VcpuState,
+    Vm, VmState, HVC_VMM, HVC_VMM_MIGRATE_FINISH, HVC_VMM_MIGRATE_START, HVC_VMM_MIGRATE_VM_BOOT, MIGRATE_START,
+};
+use crate::vmm::{vmm_dirty_log_fetch, vmm_init_gvm};
+
+const MIGRATE_MAGIC: usize = 0x5348_5950_4d49_4752; // "SHYPMIGR"
+
+// ipa of the window which maps the memory of a migrating VM into MVM, indexed by VM id
+static MIGRATE_WINDOW_LIST: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MigrateHeader {
+    magic: usize,
+    vcpu_num: usize,
+    spi_num: usize,
+    vgicd_ctlr: usize,
+    // virtual count of the VM when it is paused
+    vcount: usize,
+    virtio_num: usize,
+}
+
+// Sequential accessor of the MVM buffer which holds the VM state.
+struct MigrateBuf<'a> {
+    mvm: &'a Vm,
+    ipa: usize,
+    end: usize,
+}
+
+impl<'a> MigrateBuf<'a> {
+    fn new(mvm: &'a Vm, ipa: usize, len: usize) -> Self {
+        Self {
+            mvm,
+            ipa,
+            end: ipa + len,
+        }
+    }
+
+    fn put<T: Copy>(&mut self, val: &[T]) -> Result<(), ()> {
+        let size = core::mem::size_of_val(val);
+        if self.ipa + size > self.end {
+            error!("migrate: state buffer overflow, need {:#x}", size);
+            return Err(());
+        }
+        copy_segment_to_vm(self.mvm, self.ipa, val);
+        self.ipa += size;
+        Ok(())
+    }
+
+    fn get<T: Copy>(&mut self) -> Result<T, ()> {
+        let size = size_of::<T>();
+        if self.ipa + size > self.end {
+            error!("migrate: state buffer is truncated");
+            return Err(());
+        }
+        let hva = self.mvm.ipa2hva(self.ipa);
+        if hva == 0 {
+            return Err(());
+        }
+        self.ipa += size;
+        // SAFETY: the range is checked above and T is a plain old data type
+        Ok(unsafe { core::ptr::read_unaligned(hva as *const T) })
+    }
+}
+
+fn migrate_vm(vm_id: usize) -> Result<Arc<Vm>, ()> {
+    match vm_by_id(vm_id) {
+        Some(vm) if vm_id != 0 && vm.has_vgic() => {
+            // the config space of the devices on a PCI host is not carried over
+            if vm
+                .config()
+                .emulated_device_list()
+                .iter()
+                .any(|cfg| cfg.emu_type == EmuDeviceType::EmuDeviceTPciHost)
+            {
+                error!("migrate: VM[{}] has a PCI host and can not be migrated", vm_id);
+                return Err(());
+            }
+            // neither are the device and collection mappings of the virtual ITS
+            if vm
+                .config()
+                .emulated_device_list()
+                .iter()
+                .any(|cfg| cfg.emu_type == EmuDeviceType::EmuDeviceTGits)
+            {
+                error!("migrate: VM[{}] has a virtual ITS and can not be migrated", vm_id);
+                return Err(());
+            }
+            Ok(vm)
+        }
+        _ => {
+            error!("migrate: VM[{}] does not exist or can not be migrated", vm_id);
+            Err(())
+        }
+    }
+}
+
+/* Map the normal memory of vm into MVM, page by page in dirty bitmap order.
+ *
+ * @param[in] vm: the migrating VM.
+ * @param[in] map_ipa: the ipa in MVM to place the window.
+ * @param[in] pte: access permission of the window.
+ */
+fn migrate_map_window(vm: &Vm, map_ipa: usize, pte: usize) -> Result<usize, ()> {
+    let mvm = vm_by_id(0).unwrap();
+    let mut list = MIGRATE_WINDOW_LIST.lock();
+    if list.contains_key(&vm.id()) {
+        error!("migrate: VM[{}] window is already mapped", vm.id());
+        return Err(());
+    }
+    let page_num = vm.mem_page_num();
+    for idx in 0..page_num {
+        let pa = vm.ipa2pa(vm.mem_page_ipa(idx).unwrap()).ok_or(())?;
+        mvm.pt_map_range(map_ipa + idx * PAGE_SIZE, PAGE_SIZE, pa, pte, false);
+    }
+    list.insert(vm.id(), map_ipa);
+    Ok(page_num)
+}
+
+fn migrate_unmap_window(vm: &Vm) {
+    if let Some(map_ipa) = MIGRATE_WINDOW_LIST.lock().remove(&vm.id()) {
+        let mvm = vm_by_id(0).unwrap();
+        mvm.pt_unmap_range(map_ipa, vm.mem_page_num() * PAGE_SIZE, false);
+    }
+}
+
+/* Ask MVM to migrate a VM.
+ * Called by the VM itself, or by MVM with the target VM id.
+ *
+ * @param[in] vm_id: target VM id, only used when called by MVM.
+ */
+pub fn vmm_migrate_start(vm_id: usize) -> Result<usize, ()> {
+    let vm_id = match active_vm().unwrap().id() {
+        0 => vm_id,
+        id => id,
+    };
+    let vm = migrate_vm(vm_id)?;
+    let bitmap_len = (vm.mem_page_num() + usize::BITS as usize - 1) / usize::BITS as usize * size_of::<usize>();
+    let msg = HvcMigrateMsg {
+        fid: HVC_VMM,
+        event: HVC_VMM_MIGRATE_START,
+        vm_id,
+        oper: MIGRATE_START,
+        page_num: (bitmap_len + PAGE_SIZE - 1) / PAGE_SIZE,
+    };
+    if !hvc_send_msg_to_vm(0, &HvcGuestMsg::Migrate(msg)) {
+        error!("vmm_migrate_start: failed to notify MVM");
+        return Err(());
+    }
+    Ok(0)
+}
+
+/* Start dirty logging of the sender VM, and map its memory read only into MVM.
+ *
+ * @param[in] vm_id: sender VM id.
+ * @param[in] map_ipa: the ipa in MVM to map the VM memory.
+ * @return the page number of the VM memory.
+ */
+pub fn vmm_migrate_ready(vm_id: usize, map_ipa: usize) -> Result<usize, ()> {
+    let vm = migrate_vm(vm_id)?;
+    let page_num = migrate_map_window(&vm, map_ipa, PTE_S2_RO)?;
+    vm.start_dirty_log();
+    info!(
+        "VM[{}] migrate ready, {} pages mapped to MVM ipa {:#x}",
+        vm_id, page_num, map_ipa
+    );
+    Ok(page_num)
+}
+
+/* Copy the dirty bitmap of the sender VM to MVM for one copy round.
+ *
+ * @param[in] vm_id: sender VM id.
+ * @param[in] bitmap_ipa: the buffer in MVM to receive the bitmap.
+ * @param[in] len: buffer length in bytes.
+ * @return the dirty page number.
+ */
+pub fn vmm_migrate_memcpy(vm_id: usize, bitmap_ipa: usize, len: usize) -> Result<usize, ()> {
+    migrate_vm(vm_id)?;
+    vmm_dirty_log_fetch(vm_id, bitmap_ipa, len)
+}
+
+fn vmm_migrate_wait_paused(vcpu: &Vcpu) {
+    while matches!(vcpu.state(), VcpuState::Runnable | VcpuState::Running) {
+        core::hint::spin_loop();
+    }
+}
+
+/* Pause the sender VM, and save its vcpu/vgic/vtimer/virtio state to MVM.
+ * MVM should fetch the last dirty bitmap after this call.
+ * It fails with the VM resumed if its blk requests are in flight or the buffer is too small, MVM may call it
+ * again later, or cancel the migration.
+ *
+ * @param[in] vm_id: sender VM id.
+ * @param[in] state_ipa: the buffer in MVM to receive the state.
+ * @param[in] len: buffer length in bytes.
+ * @return the state size in bytes.
+ */
+pub fn vmm_migrate_finish(vm_id: usize, state_ipa: usize, len: usize) -> Result<usize, ()> {
+    let vm = migrate_vm(vm_id)?;
+    // the state stops the pmu budget timer from waking up a paused vcpu
+    vm_if_set_state(vm_id, VmState::Pending);
+    for vcpu in vm.vcpu_list() {
+        if vcpu.phys_id() == current_cpu().id {
+            vmm_migrate_pause_percore(vm_id);
+        } else {
+            let msg = IpiHvcMsg {
+                src_vmid: 0,
+                trgt_vmid: vm_id,
+                fid: HVC_VMM,
+                event: HVC_VMM_MIGRATE_FINISH,
+            };
+            if !ipi_send_msg(vcpu.phys_id(), IpiType::Hvc, IpiInnerMsg::HvcMsg(msg)) {
+                error!("vmm_migrate_finish: failed to send ipi to Core {}", vcpu.phys_id());
+                migrate_resume(&vm);
+                return Err(());
+            }
+        }
+    }
+    for vcpu in vm.vcpu_list() {
+        vmm_migrate_wait_paused(vcpu);
+    }
+    // a request taken from the avail ring is lost if it completes after the state is saved
+    if vm_async_task_pending(vm_id) {
+        warn!("vmm_migrate_finish: VM[{}] has blk requests in flight", vm_id);
+        migrate_resume(&vm);
+        return Err(());
+    }
+
+    match migrate_save(&vm, state_ipa, len) {
+        Ok(size) => {
+            info!("VM[{}] migrate finish, state size {:#x}", vm_id, size);
+            Ok(size)
+        }
+        Err(_) => {
+            migrate_resume(&vm);
+            Err(())
+        }
+    }
+}
+
+// Save the state of the paused vm to the MVM buffer, return the state size
+fn migrate_save(vm: &Vm, state_ipa: usize, len: usize) -> Result<usize, ()> {
+    let vcpu_states: Vec<VcpuMigrateState> = vm.vcpu_list().iter().map(|vcpu| vcpu.migrate_save()).collect();
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "vtimer")] {
+            let vcount = vm.vtimer();
+        } else {
+            let vcount = timer::get_counter() - vcpu_states[0].vtimer_offset();
+        }
+    }
+    let vgic = vm.vgic().migrate_save(vm);
+    let virtio_devs = vm.virtio_mmio_devs();
+    let header = MigrateHeader {
+        magic: MIGRATE_MAGIC,
+        vcpu_num: vcpu_states.len(),
+        spi_num: vgic.interrupts.len(),
+        vgicd_ctlr: vgic.ctlr as usize,
+        vcount,
+        virtio_num: virtio_devs.len(),
+    };
+
+    let mvm = active_vm().unwrap();
+    let mut buf = MigrateBuf::new(&mvm, state_ipa, len);
+    buf.put(&[header])?;
+    buf.put(&vcpu_states)?;
+    buf.put(&vgic.cpu_priv)?;
+    buf.put(&vgic.interrupts)?;
+    for dev in virtio_devs.iter() {
+        let (state, vq_states) = dev.migrate_save();
+        buf.put(&[state])?;
+        buf.put(&vq_states)?;
+    }
+    Ok(buf.ipa - state_ipa)
+}
+
+// Wake up a vcpu of the vm on its core
+fn migrate_wakeup_vcpu(vm_id: usize, vcpu: &Vcpu) {
+    if vcpu.phys_id() == current_cpu().id {
+        vmm_migrate_boot_percore(vm_id);
+    } else {
+        let msg = IpiHvcMsg {
+            src_vmid: 0,
+            trgt_vmid: vm_id,
+            fid: HVC_VMM,
+            event: HVC_VMM_MIGRATE_VM_BOOT,
+        };
+        if !ipi_send_msg(vcpu.phys_id(), IpiType::Hvc, IpiInnerMsg::HvcMsg(msg)) {
+            error!("migrate_wakeup_vcpu: failed to send ipi to Core {}", vcpu.phys_id());
+        }
+    }
+}
+
+// Let the paused sender VM run again, the vcpus which are powered off stay so
+fn migrate_resume(vm: &Vm) {
+    vm_if_set_state(vm.id(), VmState::Active);
+    for vcpu in vm.vcpu_list() {
+        if vcpu.state() != VcpuState::Inv {
+            migrate_wakeup_vcpu(vm.id(), vcpu);
+        }
+    }
+}
+
+pub fn vmm_migrate_pause_percore(vm_id: usize) {
+    current_cpu().vcpu_array.block_vcpu(vm_id);
+}
+
+/* Init the receiver VM without booting it, and map its memory into MVM.
+ *
+ * @param[in] vm_id: receiver VM id, the config must be added before.
+ * @param[in] map_ipa: the ipa in MVM to map the VM memory.
+ * @return the page number of the VM memory.
+ */
+pub fn vmm_migrate_init_vm(vm_id: usize, map_ipa: usize) -> Result<usize, ()> {
+    vmm_init_gvm(vm_id);
+    let vm = migrate_vm(vm_id)?;
+    let page_num = migrate_map_window(&vm, map_ipa, PTE_S2_NORMAL)?;
+    info!(
+        "VM[{}] migrate init, {} pages mapped to MVM ipa {:#x}",
+        vm_id, page_num, map_ipa
+    );
+    Ok(page_num)
+}
+
+/* Restore the vcpu/vgic/vtimer/virtio state of the receiver VM and boot it.
+ *
+ * @param[in] vm_id: receiver VM id.
+ * @param[in] state_ipa: the buffer in MVM which holds the state.
+ * @param[in] len: state size in bytes.
+ */
+pub fn vmm_migrate_vm_boot(vm_id: usize, state_ipa: usize, len: usize) -> Result<usize, ()> {
+    let vm = migrate_vm(vm_id)?;
+    let mvm = active_vm().unwrap();
+    let mut buf = MigrateBuf::new(&mvm, state_ipa, len);
+    let header: MigrateHeader = buf.get()?;
+    let virtio_devs = vm.virtio_mmio_devs();
+    if header.magic != MIGRATE_MAGIC || header.vcpu_num != vm.cpu_num() || header.virtio_num != virtio_devs.len() {
+        error!(
+            "vmm_migrate_vm_boot: illegal state, magic {:#x}, vcpu num {}, virtio num {}",
+            header.magic, header.vcpu_num, header.virtio_num
+        );
+        return Err(());
+    }
+
+    let now = timer::get_counter();
+    let mut vcpu_states = Vec::with_capacity(header.vcpu_num);
+    for _ in 0..header.vcpu_num {
+        let mut state: VcpuMigrateState = buf.get()?;
+        state.set_vtimer_offset(now.wrapping_sub(header.vcount));
+        vcpu_states.push(state);
+    }
+    let mut vgic = VgicMigrateState {
+        ctlr: header.vgicd_ctlr as u32,
+        cpu_priv: Vec::with_capacity(header.vcpu_num),
+        interrupts: Vec::with_capacity(header.spi_num),
+    };
+    for _ in 0..header.vcpu_num {
+        vgic.cpu_priv.push(buf.get::<VgicCpuMigrateState>()?);
+    }
+    for _ in 0..header.spi_num {
+        vgic.interrupts.push(buf.get::<VgicIntMigrateState>()?);
+    }
+    let mut virtio_states = Vec::with_capacity(header.virtio_num);
+    for _ in 0..header.virtio_num {
+        let state: VirtioMigrateState = buf.get()?;
+        let mut vq_states = Vec::with_capacity(state.vq_num());
+        for _ in 0..state.vq_num() {
+            vq_states.push(buf.get::<VirtqMigrateState>()?);
+        }
+        virtio_states.push((state, vq_states));
+    }
+
+    for (vcpu, state) in vm.vcpu_list().iter().zip(vcpu_states.iter()) {
+        vcpu.migrate_restore(state);
+    }
+    vm.vgic().migrate_restore(&vm, &vgic)?;
+    for (state, vq_states) in virtio_states.iter() {
+        match virtio_devs.iter().find(|dev| dev.base() == state.base()) {
+            Some(dev) => dev.migrate_restore(&vm, state, vq_states)?,
+            None => {
+                error!("vmm_migrate_vm_boot: no virtio device at {:#x}", state.base());
+                return Err(());
+            }
+        }
+    }
+    #[cfg(feature = "vtimer")]
+    vm.set_vtimer(header.vcount);
+    migrate_unmap_window(&vm);
+
+    vm_if_set_state(vm_id, VmState::Active);
+    for (vcpu, state) in vm.vcpu_list().iter().zip(vcpu_states.iter()) {
+        if state.powered_on {
+            migrate_wakeup_vcpu(vm_id, vcpu);
+        }
+    }
+    info!("VM[{}] migrate boot", vm_id);
+    Ok(0)
+}
+
+pub fn vmm_migrate_boot_percore(vm_id: usize) {
+    if let Some(vcpu) = current_cpu().vcpu_array.pop_vcpu_through_vmid(vm_id).cloned() {
+        current_cpu().vcpu_array.wakeup_vcpu(&vcpu);
+    }
+}
+
+/* Cancel the migration of the sender VM, stop dirty logging, unmap the window and resume the VM if it is paused.
+ *
+ * @param[in] vm_id: sender VM id.
+ */
+pub fn vmm_migrate_cancel(vm_id: usize) -> Result<usize, ()> {
+    let vm = migrate_vm(vm_id)?;
+    // the dirty log of a sender is on from vmm_migrate_ready, a receiver should be removed instead
+    if !vm.dirty_log_enabled() {
+        error!("vmm_migrate_cancel: VM[{}] is not a migration sender", vm_id);
+        return Err(());
+    }
+    vmm_migrate_clear(&vm);
+    if !matches!(vm_if_get_state(vm_id), VmState::Active) {
+        migrate_resume(&vm);
+    }
+    info!("VM[{}] migrate cancel", vm_id);
+    Ok(0)
+}
+
+// Stop dirty logging and unmap the migration window, called when the VM is removed
+pub fn vmm_migrate_clear(vm: &Vm) {
+    vm.stop_dirty_log();
+    migrate_unmap_window(vm);
+}
@@ -1,8 +1,12 @@
+pub use self::dirty::*;
 pub use self::init::*;
 pub use self::manager::*;
+pub use self::migrate::*;
 pub use self::remove::*;
 
 mod address;
+mod dirty;
 mod init;
 mod manager;
+mod migrate;
 mod remove;
@@ -19,6 +19,10 @@ pub fn vmm_remove_vm(vm_id: usize) {
     if let Some(vm) = vm_by_id(vm_id) {
         // vcpu
         vmm_remove_vcpu(&vm);
+        // migration window in MVM
+        super::vmm_migrate_clear(&vm);
+        // pending shutdown request
+        super::manager::vmm_shutdown_clear(vm_id);
         // reset vm interface
         vm_if_reset(vm_id);
         // passthrough dev
@@ -26,6 +30,9 @@ pub fn vmm_remove_vm(vm_id: usize) {
         // clear async task list
         remove_vm_async_task(vm_id);
         crate::device::remove_virtio_nic(vm_id);
+        crate::device::remove_console(vm_id);
+        #[cfg(feature = "irq-latency")]
+        let _ = crate::kernel::irq_latency_reset(vm_id);
         // remove vm cfg
         let _ = crate::config::del_vm(vm_id);
         #[cfg(feature = "unilib")]
@@ -70,4 +77,9 @@ fn vmm_remove_passthrough_device(vm: &Vm) {
         interrupt_vm_remove(vm, *irq);
         debug!("VM[{}] remove irq {}", vm.id(), irq);
     }
+    #[cfg(feature = "gicv3")]
+    for devid in vm.config().passthrough_device_msi_ids() {
+        crate::arch::its_release_device(*devid, vm.id());
+        debug!("VM[{}] remove msi device {:#x}", vm.id(), devid);
+    }
 }